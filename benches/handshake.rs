@@ -43,6 +43,7 @@ pub fn register_benches(c: &mut Criterion) {
             public_key: *resp.pubkey(),
             psk: None,
             endpoint: None,
+            local: None,
             allowed_ips: BTreeSet::new(),
             keepalive: None,
             roaming: true,
@@ -73,6 +74,7 @@ pub fn register_benches(c: &mut Criterion) {
             public_key: *resp.pubkey(),
             psk: None,
             endpoint: None,
+            local: None,
             allowed_ips: BTreeSet::new(),
             keepalive: None,
             roaming: true,
@@ -108,6 +110,7 @@ pub fn register_benches(c: &mut Criterion) {
             public_key: *resp.pubkey(),
             psk: None,
             endpoint: None,
+            local: None,
             allowed_ips: BTreeSet::new(),
             keepalive: None,
             roaming: true,
@@ -147,6 +150,7 @@ pub fn register_benches(c: &mut Criterion) {
             public_key: *resp.pubkey(),
             psk: None,
             endpoint: None,
+            local: None,
             allowed_ips: BTreeSet::new(),
             keepalive: None,
             roaming: true,
@@ -16,12 +16,13 @@
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
 use criterion::Criterion;
+use std::time::Instant;
 use titun::wireguard::load_monitor::LoadMonitor;
 
 pub fn register_benches(c: &mut Criterion) {
     c.bench_function("load monitor check", |b| {
-        let mut u = LoadMonitor::new(100);
+        let mut u = LoadMonitor::new(100, Instant::now());
 
-        b.iter(|| u.check());
+        b.iter(|| u.check(Instant::now()));
     });
 }
@@ -20,13 +20,14 @@ use std::sync::atomic::{AtomicBool, Ordering::*};
 use std::sync::Arc;
 use std::time::Duration;
 use titun::wireguard::timer::create_timer_async;
+use titun::wireguard::SystemClock;
 
 pub fn register_benches(c: &mut Criterion) {
     c.bench_function("timer adjust and activate", |b| {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async move {
             let run = Arc::new(AtomicBool::new(false));
-            let t = create_timer_async(move || {
+            let t = create_timer_async(Arc::new(SystemClock), move || {
                 run.store(true, SeqCst);
                 async {}
             });
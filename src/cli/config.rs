@@ -15,8 +15,12 @@
 // You should have received a copy of the GNU General Public License
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
+use super::MetricsListen;
 use crate::wireguard::re_exports::{DH, X25519};
-use crate::wireguard::{X25519Key, X25519Pubkey};
+use crate::wireguard::{
+    parse_prefix, FilterRule, HandshakeLimits, QueueLimits, RateLimit, SourceFilter, X25519Key,
+    X25519Pubkey,
+};
 use anyhow::Context;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::borrow::Cow;
@@ -26,7 +30,7 @@ use std::ffi::OsString;
 use std::fs::{File, OpenOptions};
 use std::io::Read;
 use std::net::{IpAddr, SocketAddr};
-use std::num::NonZeroU16;
+use std::num::{NonZeroU16, NonZeroU64, NonZeroUsize};
 use std::path::{Path, PathBuf};
 
 /// Read and parse configuration from the file at the specified path.
@@ -132,6 +136,16 @@ impl<T> Default for Config<T> {
                 private_key: X25519::genkey(),
                 fwmark: None,
                 listen_port: None,
+                listen_address: vec![],
+                dual_stack: None,
+                resolve_interval: None,
+                queue_packets: None,
+                queue_bytes: None,
+                queue_total_packets: None,
+                queue_total_bytes: None,
+                handshake_rate: None,
+                handshake_burst: None,
+                handshake_sources: None,
                 mtu: None,
                 address: BTreeSet::new(),
                 dns: vec![],
@@ -161,6 +175,13 @@ pub struct GeneralConfig {
     pub foreground: bool,
 
     pub threads: Option<usize>,
+
+    // Number of tun queues. Only supported on linux.
+    pub tun_queues: Option<NonZeroUsize>,
+
+    // Serve metrics over HTTP on this TCP address or unix socket path.
+    #[serde(default, with = "metrics_listen_optional")]
+    pub metrics_listen: Option<MetricsListen>,
 }
 
 impl Eq for GeneralConfig {}
@@ -176,6 +197,8 @@ impl PartialEq<GeneralConfig> for GeneralConfig {
             && ug
             && self.foreground == other.foreground
             && self.threads == other.threads
+            && self.tun_queues == other.tun_queues
+            && self.metrics_listen == other.metrics_listen
     }
 }
 
@@ -195,10 +218,35 @@ pub struct InterfaceConfig {
     #[serde(alias = "Port")]
     pub listen_port: Option<u16>,
 
+    // Addresses to listen on, with optional ports. Without a port,
+    // `listen_port` is used. Default is `[::]`, i.e. all addresses.
+    #[serde(alias = "ListenAddresses", default, with = "listen_address_vec")]
+    pub listen_address: Vec<SocketAddr>,
+
+    // Use dual stack sockets if supported. Otherwise use separate IPv4 and
+    // IPv6 sockets. Default is true.
+    pub dual_stack: Option<bool>,
+
+    // Resolve host names of peer endpoints again every so many seconds. Zero
+    // means only when handshakes get no response.
+    pub resolve_interval: Option<u32>,
+
+    // Limits of packets waiting for handshakes, per peer and for all peers.
+    pub queue_packets: Option<usize>,
+    pub queue_bytes: Option<usize>,
+    pub queue_total_packets: Option<usize>,
+    pub queue_total_bytes: Option<usize>,
+
+    // Limits of handshake messages from each source address.
+    pub handshake_rate: Option<u32>,
+    pub handshake_burst: Option<u32>,
+    pub handshake_sources: Option<usize>,
+
     #[serde(rename = "FwMark", alias = "Mark")]
     pub fwmark: Option<u32>,
 
-    // Only supported on Windows for now.
+    // Only configured on Windows for now. Also used as source addresses of
+    // ICMP errors.
     #[serde(default, with = "ip_prefix_len")]
     pub address: BTreeSet<(IpAddr, u32)>,
 
@@ -222,8 +270,22 @@ pub struct PeerConfig<Endpoint> {
     #[serde(alias = "PSK", default, with = "base64_u8_array_optional")]
     pub preshared_key: Option<[u8; 32]>,
 
-    /// Peer endpoint.
-    pub endpoint: Option<Endpoint>,
+    /// Peer endpoints.
+    ///
+    /// If there are more than one, they are tried in order when handshakes
+    /// get no response.
+    #[serde(
+        alias = "Endpoints",
+        default,
+        deserialize_with = "one_or_many::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub endpoint: Vec<Endpoint>,
+
+    /// Host names in `endpoint`, filled when they are resolved. They are
+    /// resolved again at runtime.
+    #[serde(skip)]
+    pub endpoint_hosts: Vec<String>,
 
     /// True endpoint: fixate route to this address.
     ///
@@ -250,6 +312,103 @@ pub struct PeerConfig<Endpoint> {
     /// Valid values: 1 - 0xfffe.
     #[serde(alias = "PersistentKeepalive")]
     pub keepalive: Option<NonZeroU16>,
+
+    /// Rate limit of packets sent to this peer, in kbit/s.
+    pub rate_limit: Option<NonZeroU64>,
+
+    /// Max burst size of the rate limit, in bytes.
+    pub rate_burst: Option<NonZeroU64>,
+
+    /// Packet filter rules, e.g. `allow in tcp to 10.0.0.1 port 22`.
+    #[serde(
+        default,
+        with = "filter_rule_vec",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub filter: Vec<FilterRule>,
+
+    /// How sources of packets from this peer are checked. Default is strict.
+    pub source_filter: Option<SourceFilterMode>,
+
+    /// Allowed sources when `source_filter` is off. Empty means any.
+    #[serde(default, with = "ip_prefix_len")]
+    pub allowed_sources: BTreeSet<(IpAddr, u32)>,
+
+    /// Keep the peer configured, but refuse its handshakes and drop packets
+    /// to and from it.
+    pub disabled: Option<bool>,
+
+    /// Answer packets to this peer with ICMP errors when it is disabled.
+    pub disabled_unreachable: Option<bool>,
+
+    /// Search for the path MTU to this peer. It must be a TiTun peer with this
+    /// enabled too.
+    pub path_mtu_discovery: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceFilterMode {
+    /// Sources must be in the allowed IPs of the peer.
+    Strict,
+    /// Sources must be in the allowed IPs of any peer.
+    Loose,
+    /// Sources must be in `allowed_sources`, if it is not empty.
+    Off,
+}
+
+impl InterfaceConfig {
+    /// Limits of packets waiting for handshakes. Defaults are used for
+    /// missing values.
+    pub fn queue_limits(&self) -> QueueLimits {
+        let default = QueueLimits::default();
+        QueueLimits {
+            packets: self.queue_packets.unwrap_or(default.packets),
+            bytes: self.queue_bytes.unwrap_or(default.bytes),
+            total_packets: self.queue_total_packets.unwrap_or(default.total_packets),
+            total_bytes: self.queue_total_bytes.unwrap_or(default.total_bytes),
+        }
+    }
+
+    /// Limits of handshake messages from each source address. Defaults are
+    /// used for missing values.
+    pub fn handshake_limits(&self) -> HandshakeLimits {
+        let default = HandshakeLimits::default();
+        HandshakeLimits {
+            rate: self.handshake_rate.unwrap_or(default.rate),
+            burst: self.handshake_burst.unwrap_or(default.burst),
+            max_sources: self.handshake_sources.unwrap_or(default.max_sources),
+        }
+    }
+}
+
+impl<Endpoint> PeerConfig<Endpoint> {
+    /// Rate limit in bytes per second.
+    pub fn to_rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit.map(|kbps| {
+            let mut limit = RateLimit::new(kbps.get() * 125);
+            if let Some(burst) = self.rate_burst {
+                limit.burst = burst.get();
+            }
+            limit
+        })
+    }
+
+    pub fn to_source_filter(&self) -> SourceFilter {
+        match self.source_filter.unwrap_or(SourceFilterMode::Strict) {
+            SourceFilterMode::Strict => SourceFilter::Strict,
+            SourceFilterMode::Loose => SourceFilter::Loose,
+            SourceFilterMode::Off => SourceFilter::Off(self.allowed_sources.clone()),
+        }
+    }
+
+    /// `disabled` and `disabled_unreachable`, false if not set.
+    pub fn to_disabled(&self) -> (bool, bool) {
+        (
+            self.disabled.unwrap_or(false),
+            self.disabled_unreachable.unwrap_or(false),
+        )
+    }
 }
 
 fn resolve_address(addr: &str) -> anyhow::Result<SocketAddr> {
@@ -267,9 +426,16 @@ impl Config<String> {
     fn resolve_addresses(self, print_warnings: bool) -> anyhow::Result<Config<SocketAddr>> {
         let mut peers = Vec::with_capacity(self.peers.len());
         for p in self.peers {
-            let endpoint = if let Some(endpoint) = p.endpoint {
+            // Keep all of them, in order, if any is a host name.
+            let endpoint_hosts = if p.endpoint.iter().all(|e| e.parse::<SocketAddr>().is_ok()) {
+                vec![]
+            } else {
+                p.endpoint.clone()
+            };
+            let mut endpoints = Vec::with_capacity(p.endpoint.len());
+            for endpoint in p.endpoint {
                 match resolve_address(&endpoint) {
-                    Ok(addr) => Some(addr),
+                    Ok(addr) => endpoints.push(addr),
                     Err(e) => {
                         if let Some(ref e) = e.downcast_ref::<std::io::Error>() {
                             // Reject invalid syntax, but warn and ignore resolution failures.
@@ -285,12 +451,9 @@ impl Config<String> {
                         } else {
                             warn!("failed to resolve {}: {:#}", endpoint, e);
                         }
-                        None
                     }
                 }
-            } else {
-                None
-            };
+            }
             let true_endpoint = if let Some(true_endpoint) = p.true_endpoint {
                 match resolve_address(&true_endpoint) {
                     Ok(addr) => Some(addr),
@@ -318,10 +481,19 @@ impl Config<String> {
             peers.push(PeerConfig {
                 public_key: p.public_key,
                 preshared_key: p.preshared_key,
-                endpoint,
+                endpoint: endpoints,
+                endpoint_hosts,
                 true_endpoint,
                 allowed_ips: p.allowed_ips,
                 keepalive: p.keepalive,
+                rate_limit: p.rate_limit,
+                rate_burst: p.rate_burst,
+                filter: p.filter,
+                source_filter: p.source_filter,
+                allowed_sources: p.allowed_sources,
+                disabled: p.disabled,
+                disabled_unreachable: p.disabled_unreachable,
+                path_mtu_discovery: p.path_mtu_discovery,
             });
         }
         Ok(Config {
@@ -399,22 +571,7 @@ mod ip_prefix_len {
 
         impl AllowedIPsVisitor {
             fn parse<E: Error>(v: &str) -> Result<(IpAddr, u32), E> {
-                let mut parts = v.splitn(2, '/');
-                let ip: IpAddr = parts
-                    .next()
-                    .unwrap()
-                    .parse()
-                    .map_err(|_| Error::custom("failed to parse allowed IPs"))?;
-                let max_prefix_len = if ip.is_ipv4() { 32 } else { 128 };
-                let prefix_len: u32 = parts
-                    .next()
-                    .map(|x| x.parse())
-                    .unwrap_or(Ok(max_prefix_len))
-                    .map_err(|_| Error::custom("failed to parse allowed IPs"))?;
-                if prefix_len > max_prefix_len {
-                    return Err(Error::custom("prefix length is too large"));
-                }
-                Ok((ip, prefix_len))
+                parse_prefix(v).map_err(Error::custom)
             }
         }
 
@@ -514,6 +671,141 @@ mod ip_addr_vec {
     }
 }
 
+// Listen addresses are `SocketAddr`s, where port 0 means no port.
+mod listen_address_vec {
+    use super::*;
+
+    fn parse(v: &str) -> Option<SocketAddr> {
+        v.parse()
+            .ok()
+            .or_else(|| v.parse::<IpAddr>().ok().map(|a| (a, 0).into()))
+            .or_else(|| {
+                // IPv6 address without port, in brackets.
+                let v = v.strip_prefix('[')?.strip_suffix(']')?;
+                v.parse::<IpAddr>().ok().map(|a| (a, 0).into())
+            })
+    }
+
+    pub fn serialize<S: Serializer>(t: &[SocketAddr], s: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+
+        let mut seq = s.serialize_seq(t.len().into())?;
+
+        for addr in t {
+            if addr.port() == 0 {
+                seq.serialize_element(&addr.ip())?;
+            } else {
+                seq.serialize_element(addr)?;
+            }
+        }
+
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<SocketAddr>, D::Error> {
+        use serde::de::{Error, SeqAccess, Visitor};
+        use std::fmt;
+
+        struct ListenAddressVecVisitor;
+
+        impl<'de> Visitor<'de> for ListenAddressVecVisitor {
+            type Value = Vec<SocketAddr>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(
+                    formatter,
+                    "an IP address with optional port or an array of them"
+                )
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                let a = parse(v).ok_or_else(|| Error::custom("failed to parse listen address"))?;
+                Ok(vec![a])
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, <A as SeqAccess<'de>>::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut result = Vec::new();
+                while let Some(v) = seq.next_element()? {
+                    let v: Cow<'_, str> = v;
+                    let a =
+                        parse(&v).ok_or_else(|| Error::custom("failed to parse listen address"))?;
+                    result.push(a);
+                }
+                Ok(result)
+            }
+        }
+
+        d.deserialize_any(ListenAddressVecVisitor)
+    }
+}
+
+// One value, or an array of them, e.g. `Endpoint` and `Filter`.
+mod one_or_many {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<T>, D::Error> {
+        Ok(match OneOrMany::deserialize(d)? {
+            OneOrMany::One(t) => vec![t],
+            OneOrMany::Many(v) => v,
+        })
+    }
+}
+
+// Filter rules are strings, e.g. `deny in udp port 53`.
+mod filter_rule_vec {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(t: &[FilterRule], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(t.iter().map(|r| r.to_string()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<FilterRule>, D::Error> {
+        use serde::de::Error;
+
+        let rules: Vec<String> = super::one_or_many::deserialize(d)?;
+        rules
+            .iter()
+            .map(|r| r.parse().map_err(|e| Error::custom(format!("{:#}", e))))
+            .collect()
+    }
+}
+
+// E.g. `127.0.0.1:9586` or `/run/titun/metrics.sock`.
+mod metrics_listen_optional {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(t: &Option<MetricsListen>, s: S) -> Result<S::Ok, S::Error> {
+        match t {
+            Some(l) => s.serialize_some(&l.to_string()),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<MetricsListen>, D::Error> {
+        use serde::de::Error;
+
+        let l = String::deserialize(d)?;
+        l.parse()
+            .map(Some)
+            .map_err(|e| Error::custom(format!("{:#}", e)))
+    }
+}
+
 mod base64_u8_array_optional {
     use super::*;
     use noise_protocol::U8Array;
@@ -576,10 +868,178 @@ Endpoint = "host.no.port.invalid"
     #[test]
     fn resolve_not_found() {
         let mut config: Config<String> = toml::from_str(EXAMPLE_CONFIG_INVALID_ENDPOINT).unwrap();
-        config.peers[0].endpoint = Some("not.found.invalid:3238".into());
+        config.peers[0].endpoint = vec!["not.found.invalid:3238".into()];
 
         let config = config.resolve_addresses(true).unwrap();
-        assert!(config.peers[0].endpoint.is_none());
+        assert!(config.peers[0].endpoint.is_empty());
+        // To be resolved again.
+        assert_eq!(config.peers[0].endpoint_hosts, ["not.found.invalid:3238"]);
+    }
+
+    #[test]
+    fn multiple_endpoints() {
+        let config: Config<String> = toml::from_str(&EXAMPLE_CONFIG.replace(
+            "\nEndpoint = \"192.168.3.1:7777\"",
+            "\nEndpoints = [\"[2001:db8::1]:7777\", \"192.168.3.1:7777\"]",
+        ))
+        .unwrap();
+        let config = config.resolve_addresses(true).unwrap();
+        assert_eq!(
+            config.peers[0].endpoint,
+            vec![
+                "[2001:db8::1]:7777".parse::<SocketAddr>().unwrap(),
+                "192.168.3.1:7777".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn queue_limits() {
+        let config: Config<String> = toml::from_str(&EXAMPLE_CONFIG.replace(
+            "FwMark",
+            "QueuePackets = 64\nQueueTotalBytes = 1000000\nFwMark",
+        ))
+        .unwrap();
+        assert_eq!(
+            config.interface.queue_limits(),
+            QueueLimits {
+                packets: 64,
+                total_bytes: 1000000,
+                ..QueueLimits::default()
+            }
+        );
+    }
+
+    #[test]
+    fn handshake_limits() {
+        let config: Config<String> = toml::from_str(&EXAMPLE_CONFIG.replace(
+            "FwMark",
+            "HandshakeRate = 0\nHandshakeSources = 1024\nFwMark",
+        ))
+        .unwrap();
+        assert_eq!(
+            config.interface.handshake_limits(),
+            HandshakeLimits {
+                rate: 0,
+                max_sources: 1024,
+                ..HandshakeLimits::default()
+            }
+        );
+    }
+
+    #[test]
+    fn filter() {
+        let parse = |v: &str| {
+            let config: Config<String> = toml::from_str(&format!("{}{}", EXAMPLE_CONFIG, v))?;
+            Ok::<_, toml::de::Error>(config.peers.into_iter().next().unwrap().filter)
+        };
+        assert_eq!(parse("").unwrap(), []);
+        let rules = parse("Filter = [\"allow in tcp port 22\", \"deny in\"]").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[1], "deny in".parse().unwrap());
+        assert_eq!(parse("Filter = \"deny\"").unwrap().len(), 1);
+        assert!(parse("Filter = \"deny sideways\"").is_err());
+    }
+
+    #[test]
+    fn source_filter() {
+        let parse = |v: &str| {
+            let config: Config<String> = toml::from_str(&format!("{}{}", EXAMPLE_CONFIG, v))?;
+            Ok::<_, toml::de::Error>(config.peers[0].to_source_filter())
+        };
+        assert_eq!(parse("").unwrap(), SourceFilter::Strict);
+        assert_eq!(
+            parse("SourceFilter = \"loose\"").unwrap(),
+            SourceFilter::Loose
+        );
+        assert_eq!(
+            parse("SourceFilter = \"off\"\nAllowedSources = [\"10.1.0.0/16\"]").unwrap(),
+            SourceFilter::Off(std::iter::once(("10.1.0.0".parse().unwrap(), 16)).collect())
+        );
+        assert!(parse("SourceFilter = \"looser\"").is_err());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let parse = |v: &str| {
+            let config: Config<String> = toml::from_str(&format!("{}{}", EXAMPLE_CONFIG, v))?;
+            Ok::<_, toml::de::Error>(config.peers[0].to_rate_limit())
+        };
+        assert_eq!(parse("").unwrap(), None);
+        assert_eq!(
+            parse("RateLimit = 8000").unwrap(),
+            Some(RateLimit {
+                rate: 1_000_000,
+                burst: 100_000,
+            })
+        );
+        assert_eq!(
+            parse("RateLimit = 80\nRateBurst = 3000").unwrap(),
+            Some(RateLimit {
+                rate: 10_000,
+                burst: 3000,
+            })
+        );
+        assert!(parse("RateLimit = 0").is_err());
+    }
+
+    #[test]
+    fn tun_queues() {
+        let config: Config<String> =
+            toml::from_str(&format!("[General]\nTunQueues = 4\n{}", EXAMPLE_CONFIG)).unwrap();
+        assert_eq!(config.general.tun_queues, NonZeroUsize::new(4));
+
+        assert!(toml::from_str::<Config<String>>(&format!(
+            "[General]\nTunQueues = 0\n{}",
+            EXAMPLE_CONFIG
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn metrics_listen() {
+        let config: Config<String> = toml::from_str(&format!(
+            "[General]\nMetricsListen = \"127.0.0.1:9586\"\n{}",
+            EXAMPLE_CONFIG
+        ))
+        .unwrap();
+        assert_eq!(
+            config.general.metrics_listen,
+            Some(MetricsListen::Tcp("127.0.0.1:9586".parse().unwrap()))
+        );
+
+        assert!(toml::from_str::<Config<String>>(&format!(
+            "[General]\nMetricsListen = \"localhost\"\n{}",
+            EXAMPLE_CONFIG
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn listen_address() {
+        let parse = |v: &str| {
+            toml::from_str::<Config<String>>(
+                &EXAMPLE_CONFIG.replace("FwMark", &format!("ListenAddress = {}\nFwMark", v)),
+            )
+        };
+
+        let config = parse(r#"["192.168.3.2", "[2001:db8::1]", "[2001:db8::1]:8888"]"#).unwrap();
+        assert_eq!(
+            config.interface.listen_address,
+            vec![
+                "192.168.3.2:0".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:0".parse().unwrap(),
+                "[2001:db8::1]:8888".parse().unwrap(),
+            ]
+        );
+        assert_eq!(
+            parse(r#""192.168.3.2:8888""#)
+                .unwrap()
+                .interface
+                .listen_address,
+            vec!["192.168.3.2:8888".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(parse(r#""192.168.3.2:""#).is_err());
     }
 
     #[test]
@@ -593,6 +1053,16 @@ Endpoint = "host.no.port.invalid"
                 interface: InterfaceConfig {
                     name: None,
                     listen_port: Some(7777),
+                    listen_address: vec![],
+                    dual_stack: None,
+                    resolve_interval: None,
+                    queue_packets: None,
+                    queue_bytes: None,
+                    queue_total_packets: None,
+                    queue_total_bytes: None,
+                    handshake_rate: None,
+                    handshake_burst: None,
+                    handshake_sources: None,
                     private_key: U8Array::from_slice(
                         &base64::decode("2BJtcgPUjHfKKN3yMvTiVQbJ/UgHj2tcZE6xU/4BdGM=").unwrap()
                     ),
@@ -608,13 +1078,22 @@ Endpoint = "host.no.port.invalid"
                     preshared_key: Some(U8Array::from_slice(
                         &base64::decode("w64eiHxoUHU8DcFexHWzqILOvbWx9U+dxxh8iQqJr+k=").unwrap()
                     )),
-                    endpoint: Some("192.168.3.1:7777".parse().unwrap()),
+                    endpoint: vec!["192.168.3.1:7777".parse().unwrap()],
+                    endpoint_hosts: vec![],
                     true_endpoint: Some("192.168.3.1:7777".parse().unwrap()),
                     allowed_ips: [("192.168.77.1".parse().unwrap(), 32)]
                         .iter()
                         .cloned()
                         .collect(),
                     keepalive: NonZeroU16::new(17),
+                    rate_limit: None,
+                    rate_burst: None,
+                    filter: vec![],
+                    source_filter: None,
+                    disabled: None,
+                    disabled_unreachable: None,
+                    path_mtu_discovery: None,
+                    allowed_sources: BTreeSet::new(),
                 }],
             }
         );
@@ -0,0 +1,65 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::show::{connect, errno_error};
+use anyhow::Context;
+use std::ffi::OsStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Disable or enable a peer of a running interface.
+///
+/// When disabling, `unreachable` chooses whether packets to the peer are
+/// answered with ICMP errors.
+pub async fn set_peer_disabled(
+    dev_name: &OsStr,
+    public_key: &str,
+    disabled: bool,
+    unreachable: bool,
+) -> anyhow::Result<()> {
+    let public_key = base64::decode(public_key).context("failed to base64 decode public key")?;
+    if public_key.len() != 32 {
+        bail!("invalid length public key");
+    }
+
+    // Everything not in the set command is unchanged. The peer is not added
+    // if it does not exist.
+    let mut command = format!(
+        "set=1\npublic_key={}\nupdate_only=true\ndisabled={}\n",
+        hex::encode(&public_key),
+        disabled
+    );
+    if disabled {
+        command.push_str(&format!("disabled_unreachable={}\n", unreachable));
+    }
+    command.push('\n');
+
+    let mut stream = connect(dev_name).await?;
+    stream.write_all(command.as_bytes()).await?;
+    let mut response = String::new();
+    stream
+        .take(1024)
+        .read_to_string(&mut response)
+        .await
+        .context("failed to read response")?;
+    match response.lines().next() {
+        Some("errno=0") => Ok(()),
+        Some(line) if line.starts_with("errno=") => {
+            Err(errno_error(line["errno=".len()..].parse()?))
+        }
+        _ => bail!("invalid response: {:?}", response),
+    }
+}
@@ -0,0 +1,691 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::wireguard::{CountersOut, DropReason, WgState, WgStateOut};
+use anyhow::Context;
+use std::fmt::{self, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Weak;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// Max size of a request line and headers.
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Wait before accepting again after an error, e.g., too many open files.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Where metrics are served, a TCP address or a unix socket path.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MetricsListen {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl FromStr for MetricsListen {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<MetricsListen> {
+        if s.starts_with('/') {
+            #[cfg(unix)]
+            return Ok(MetricsListen::Unix(s.into()));
+            #[cfg(not(unix))]
+            bail!("unix sockets are not supported: {}", s);
+        }
+        s.parse()
+            .map(MetricsListen::Tcp)
+            .with_context(|| format!("invalid metrics address: {}", s))
+    }
+}
+
+impl fmt::Display for MetricsListen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricsListen::Tcp(a) => write!(f, "{}", a),
+            #[cfg(unix)]
+            MetricsListen::Unix(p) => write!(f, "{}", p.display()),
+        }
+    }
+}
+
+/// A bound metrics listener.
+///
+/// Bind before dropping privilege, serve after.
+pub enum MetricsListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl MetricsListener {
+    pub async fn bind(listen: &MetricsListen) -> anyhow::Result<MetricsListener> {
+        Ok(match listen {
+            MetricsListen::Tcp(a) => MetricsListener::Tcp(TcpListener::bind(a).await?),
+            #[cfg(unix)]
+            MetricsListen::Unix(p) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // Remove the socket left by a previous run, but nothing else.
+                match std::fs::symlink_metadata(p) {
+                    Ok(m) if m.file_type().is_socket() => std::fs::remove_file(p)?,
+                    _ => (),
+                }
+                MetricsListener::Unix(tokio::net::UnixListener::bind(p)?)
+            }
+        })
+    }
+
+    /// Serve metrics of the interface `name`.
+    ///
+    /// Returns once `wg` is gone, which is noticed when the next connection
+    /// is accepted. Errors accepting connections are logged and retried.
+    pub async fn serve(self, name: String, wg: Weak<WgState>) {
+        while wg.strong_count() > 0 {
+            let name = name.clone();
+            let wg = wg.clone();
+            let result = match self {
+                MetricsListener::Tcp(ref l) => l.accept().await.map(|(stream, _)| {
+                    tokio::spawn(async move { serve_conn(stream, &name, &wg).await });
+                }),
+                #[cfg(unix)]
+                MetricsListener::Unix(ref l) => l.accept().await.map(|(stream, _)| {
+                    tokio::spawn(async move { serve_conn(stream, &name, &wg).await });
+                }),
+            };
+            if let Err(e) = result {
+                warn!("error accepting metrics connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn serve_conn(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    name: &str,
+    wg: &Weak<WgState>,
+) {
+    let result = async {
+        let head = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream))
+            .await
+            .context("timed out")??;
+        let (status, body) = match parse_request_line(&head) {
+            Some(("GET", "/metrics")) | Some(("HEAD", "/metrics")) => match wg.upgrade() {
+                Some(wg) => ("200 OK", render(name, &wg.get_state())),
+                None => ("503 Service Unavailable", String::new()),
+            },
+            Some(("GET", _)) | Some(("HEAD", _)) => ("404 Not Found", String::new()),
+            Some(_) => ("405 Method Not Allowed", String::new()),
+            None => ("400 Bad Request", String::new()),
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            CONTENT_TYPE,
+            body.len(),
+        );
+        stream.write_all(response.as_bytes()).await?;
+        if !head.starts_with("HEAD ") {
+            stream.write_all(body.as_bytes()).await?;
+        }
+        stream.shutdown().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    result
+        .await
+        .unwrap_or_else(|e| debug!("error serving metrics request: {:#}", e));
+}
+
+// Read until the empty line after the headers. The request body, if any, is
+// ignored.
+async fn read_request_head(mut stream: impl AsyncRead + Unpin) -> anyhow::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && !head.windows(2).any(|w| w == b"\n\n") {
+        if head.len() > MAX_REQUEST_SIZE {
+            bail!("request too large");
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            bail!("connection closed before end of request");
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+// Method and path, without the query string.
+fn parse_request_line(head: &str) -> Option<(&str, &str)> {
+    let mut words = head.lines().next()?.split_whitespace();
+    let method = words.next()?;
+    let target = words.next()?;
+    if !words.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    Some((method, target.split('?').next().unwrap()))
+}
+
+// Escape a label value.
+fn escape(v: &str) -> String {
+    v.replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+struct Metrics {
+    out: String,
+}
+
+impl Metrics {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl fmt::Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (k, v)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{}=\"{}\"", k, escape(v));
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+}
+
+// Name, help and value.
+type Counter = (&'static str, &'static str, fn(&CountersOut) -> u64);
+
+// Counters of the interface and of each peer.
+const COUNTERS: &[Counter] = &[
+    (
+        "rx_packets_total",
+        "Received packets, including handshake messages.",
+        |c| c.rx_packets,
+    ),
+    (
+        "tx_packets_total",
+        "Sent packets, including handshake messages.",
+        |c| c.tx_packets,
+    ),
+    (
+        "handshake_initiations_sent_total",
+        "Handshake initiations sent.",
+        |c| c.handshake_initiations_sent,
+    ),
+    (
+        "handshake_initiations_received_total",
+        "Handshake initiations received.",
+        |c| c.handshake_initiations_received,
+    ),
+    (
+        "handshake_failures_total",
+        "Handshake messages that fail to authenticate or decrypt, or are replayed.",
+        |c| c.handshake_failures,
+    ),
+    (
+        "shaped_packets_total",
+        "Packets delayed because of the rate limit.",
+        |c| c.shaped_packets,
+    ),
+    (
+        "loose_source_packets_total",
+        "Packets accepted from sources outside the allowed IPs.",
+        |c| c.loose_source_packets,
+    ),
+    (
+        "psk_mismatches_total",
+        "Handshake responses that fail to decrypt, most likely because the pre-shared keys differ.",
+        |c| c.psk_mismatches,
+    ),
+];
+
+/// Render the state of the interface `name` and stats of the process in the
+/// Prometheus text format.
+pub fn render(name: &str, state: &WgStateOut) -> String {
+    let mut m = Metrics { out: String::new() };
+    render_state(&mut m, name, state, SystemTime::now());
+    render_process(&mut m);
+    m.out
+}
+
+fn render_state(m: &mut Metrics, name: &str, state: &WgStateOut, now: SystemTime) {
+    let interface = [("interface", name)];
+    let peers: Vec<(&str, String)> = state
+        .peers
+        .iter()
+        .map(|p| ("public_key", base64::encode(p.public_key)))
+        .collect();
+    let peer_labels = |i: usize| [interface[0], (peers[i].0, peers[i].1.as_str())];
+
+    m.family("titun_peers", "gauge", "Number of peers.");
+    m.sample("titun_peers", &interface, state.peers.len());
+    m.family(
+        "titun_handshake_under_load",
+        "gauge",
+        "Whether handshake messages arrive too quickly, so that cookies are required.",
+    );
+    m.sample(
+        "titun_handshake_under_load",
+        &interface,
+        state.under_load as u8,
+    );
+    for &(counter, help, get) in COUNTERS {
+        let metric = format!("titun_{}", counter);
+        m.family(&metric, "counter", help);
+        m.sample(&metric, &interface, get(&state.counters));
+    }
+    m.family(
+        "titun_dropped_packets_total",
+        "counter",
+        "Dropped packets by reason.",
+    );
+    for &r in DropReason::ALL.iter() {
+        let n = state.counters.drops.get(&r).copied().unwrap_or(0);
+        m.sample(
+            "titun_dropped_packets_total",
+            &[interface[0], ("reason", r.name())],
+            n,
+        );
+    }
+
+    m.family("titun_peer_rx_bytes_total", "counter", "Received bytes.");
+    for (i, p) in state.peers.iter().enumerate() {
+        m.sample("titun_peer_rx_bytes_total", &peer_labels(i), p.rx_bytes);
+    }
+    m.family("titun_peer_tx_bytes_total", "counter", "Sent bytes.");
+    for (i, p) in state.peers.iter().enumerate() {
+        m.sample("titun_peer_tx_bytes_total", &peer_labels(i), p.tx_bytes);
+    }
+    m.family(
+        "titun_peer_last_handshake_age_seconds",
+        "gauge",
+        "Seconds since the last handshake. Absent if there has been none.",
+    );
+    for (i, p) in state.peers.iter().enumerate() {
+        if let Some(t) = p.last_handshake_time {
+            let age = now.duration_since(t).unwrap_or_default();
+            m.sample(
+                "titun_peer_last_handshake_age_seconds",
+                &peer_labels(i),
+                age.as_secs_f64(),
+            );
+        }
+    }
+    m.family("titun_peer_sessions", "gauge", "Number of sessions.");
+    for (i, p) in state.peers.iter().enumerate() {
+        m.sample("titun_peer_sessions", &peer_labels(i), p.sessions);
+    }
+    m.family(
+        "titun_peer_queued_packets",
+        "gauge",
+        "Packets waiting for a handshake to complete.",
+    );
+    for (i, p) in state.peers.iter().enumerate() {
+        m.sample(
+            "titun_peer_queued_packets",
+            &peer_labels(i),
+            p.queued_packets,
+        );
+    }
+    m.family(
+        "titun_peer_endpoint_info",
+        "gauge",
+        "Current endpoint of the peer. Absent if there is none.",
+    );
+    for (i, p) in state.peers.iter().enumerate() {
+        if let Some(e) = p.endpoint {
+            let e = e.to_string();
+            let [a, b] = peer_labels(i);
+            m.sample("titun_peer_endpoint_info", &[a, b, ("endpoint", &e)], 1);
+        }
+    }
+    m.family(
+        "titun_peer_disabled",
+        "gauge",
+        "Whether the peer is disabled.",
+    );
+    for (i, p) in state.peers.iter().enumerate() {
+        m.sample("titun_peer_disabled", &peer_labels(i), p.disabled as u8);
+    }
+    for &(counter, help, get) in COUNTERS {
+        let metric = format!("titun_peer_{}", counter);
+        m.family(&metric, "counter", help);
+        for (i, p) in state.peers.iter().enumerate() {
+            m.sample(&metric, &peer_labels(i), get(&p.counters));
+        }
+    }
+    m.family(
+        "titun_peer_dropped_packets_total",
+        "counter",
+        "Dropped packets by reason. Reasons with no drops are absent.",
+    );
+    for (i, p) in state.peers.iter().enumerate() {
+        let [a, b] = peer_labels(i);
+        for (r, n) in &p.counters.drops {
+            m.sample(
+                "titun_peer_dropped_packets_total",
+                &[a, b, ("reason", r.name())],
+                n,
+            );
+        }
+    }
+}
+
+/// Stats of the process, from `/proc/self/stat`.
+#[derive(Debug, Eq, PartialEq)]
+struct ProcStat {
+    // In clock ticks.
+    cpu_time: u64,
+    threads: u64,
+    // In clock ticks after boot.
+    start_time: u64,
+    virtual_memory: u64,
+    // In pages.
+    resident_memory: u64,
+}
+
+impl ProcStat {
+    fn parse(stat: &str) -> Option<ProcStat> {
+        // The command name in parentheses can contain spaces, so start after
+        // the last `)`, from the state, i.e. the 3rd field.
+        let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+        let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+        Some(ProcStat {
+            cpu_time: field(14)? + field(15)?,
+            threads: field(20)?,
+            start_time: field(22)?,
+            virtual_memory: field(23)?,
+            resident_memory: field(24)?,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn render_process(m: &mut Metrics) {
+    use nix::unistd::{sysconf, SysconfVar};
+
+    let stat = match std::fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|s| ProcStat::parse(&s))
+    {
+        Some(stat) => stat,
+        None => return,
+    };
+    let ticks = match sysconf(SysconfVar::CLK_TCK) {
+        Ok(Some(t)) if t > 0 => t as f64,
+        _ => return,
+    };
+    let page_size = match sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(s)) if s > 0 => s as u64,
+        _ => return,
+    };
+
+    m.family(
+        "process_cpu_seconds_total",
+        "counter",
+        "Total user and system CPU time spent in seconds.",
+    );
+    m.sample(
+        "process_cpu_seconds_total",
+        &[],
+        stat.cpu_time as f64 / ticks,
+    );
+    m.family(
+        "process_resident_memory_bytes",
+        "gauge",
+        "Resident memory size in bytes.",
+    );
+    m.sample(
+        "process_resident_memory_bytes",
+        &[],
+        stat.resident_memory * page_size,
+    );
+    m.family(
+        "process_virtual_memory_bytes",
+        "gauge",
+        "Virtual memory size in bytes.",
+    );
+    m.sample("process_virtual_memory_bytes", &[], stat.virtual_memory);
+    m.family("process_threads", "gauge", "Number of OS threads.");
+    m.sample("process_threads", &[], stat.threads);
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        m.family(
+            "process_open_fds",
+            "gauge",
+            "Number of open file descriptors.",
+        );
+        m.sample("process_open_fds", &[], fds.count());
+    }
+    let boot_time = std::fs::read_to_string("/proc/stat").ok().and_then(|s| {
+        s.lines()
+            .find_map(|l| l.strip_prefix("btime "))
+            .and_then(|t| t.trim().parse::<u64>().ok())
+    });
+    if let Some(boot_time) = boot_time {
+        m.family(
+            "process_start_time_seconds",
+            "gauge",
+            "Start time of the process since unix epoch in seconds.",
+        );
+        m.sample(
+            "process_start_time_seconds",
+            &[],
+            boot_time as f64 + stat.start_time as f64 / ticks,
+        );
+    }
+}
+
+// Not supported yet.
+#[cfg(not(target_os = "linux"))]
+fn render_process(_m: &mut Metrics) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard::{PeerStateOut, SourceFilter, X25519Key};
+    use noise_protocol::U8Array;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn metrics_listen() {
+        assert_eq!(
+            "127.0.0.1:9586".parse::<MetricsListen>().unwrap(),
+            MetricsListen::Tcp("127.0.0.1:9586".parse().unwrap())
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            "/run/titun/metrics.sock"
+                .parse::<MetricsListen>()
+                .unwrap()
+                .to_string(),
+            "/run/titun/metrics.sock"
+        );
+        assert!("localhost".parse::<MetricsListen>().is_err());
+    }
+
+    #[test]
+    fn request_line() {
+        assert_eq!(
+            parse_request_line("GET /metrics?x=1 HTTP/1.1\r\nHost: a\r\n\r\n"),
+            Some(("GET", "/metrics"))
+        );
+        assert_eq!(parse_request_line("GET /metrics\r\n\r\n"), None);
+        assert_eq!(parse_request_line("\r\n\r\n"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bind_unix() -> anyhow::Result<()> {
+        let mut path = std::env::temp_dir();
+        path.push(format!("titun_test_metrics_{}", std::process::id()));
+        let listen = MetricsListen::Unix(path.clone());
+
+        // Not a socket, so it is not removed.
+        std::fs::write(&path, b"keep")?;
+        assert!(MetricsListener::bind(&listen).await.is_err());
+        assert_eq!(std::fs::read(&path)?, b"keep");
+        std::fs::remove_file(&path)?;
+
+        // A socket left by a previous run is.
+        drop(MetricsListener::bind(&listen).await?);
+        assert!(path.exists());
+        drop(MetricsListener::bind(&listen).await?);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_request() {
+        async fn request(req: &str) -> String {
+            let (mut client, server) = tokio::io::duplex(4096);
+            client.write_all(req.as_bytes()).await.unwrap();
+            // The interface is gone.
+            serve_conn(server, "wg0", &Weak::new()).await;
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        }
+
+        assert!(request("GET /metrics HTTP/1.1\r\nHost: a\r\n\r\n")
+            .await
+            .starts_with("HTTP/1.1 503 "));
+        assert!(request("GET / HTTP/1.1\r\n\r\n")
+            .await
+            .starts_with("HTTP/1.1 404 "));
+        assert!(request("POST /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .starts_with("HTTP/1.1 405 "));
+    }
+
+    #[test]
+    fn proc_stat() {
+        let stat = "1234 (titun (a b)) S 1 1234 1234 0 -1 4194560 1000 0 0 0 \
+                    25 15 0 0 20 0 3 0 5000 123456789 2000 18446744073709551615";
+        assert_eq!(
+            ProcStat::parse(stat),
+            Some(ProcStat {
+                cpu_time: 40,
+                threads: 3,
+                start_time: 5000,
+                virtual_memory: 123456789,
+                resident_memory: 2000,
+            })
+        );
+        assert_eq!(ProcStat::parse("1234 (titun) S 1"), None);
+
+        #[cfg(target_os = "linux")]
+        {
+            let mut m = Metrics { out: String::new() };
+            render_process(&mut m);
+            assert!(m.out.contains("\nprocess_threads "));
+            assert!(m.out.contains("\nprocess_start_time_seconds "));
+        }
+    }
+
+    #[test]
+    fn render_state() {
+        let now = SystemTime::now();
+        let mut counters = CountersOut {
+            rx_packets: 10,
+            ..CountersOut::default()
+        };
+        counters.drops.insert(DropReason::NoRoute, 3);
+        let peer = PeerStateOut {
+            public_key: [1; 32],
+            preshared_key: None,
+            endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+            endpoints: Vec::new(),
+            endpoint_hosts: Vec::new(),
+            last_handshake_time: Some(now - Duration::from_secs(5)),
+            rx_bytes: 1000,
+            tx_bytes: 2000,
+            persistent_keepalive_interval: 0,
+            allowed_ips: BTreeSet::new(),
+            path_mtu_discovery: false,
+            path_mtu: None,
+            rate_limit: None,
+            filter: Vec::new(),
+            source_filter: SourceFilter::Strict,
+            disabled: false,
+            disabled_unreachable: false,
+            psk_rotated: false,
+            psk_expires_in: None,
+            psk_expired: false,
+            sessions: 2,
+            queued_packets: 4,
+            counters: counters.clone(),
+        };
+        let state = WgStateOut {
+            private_key: X25519Key::new(),
+            peers: vec![peer],
+            listen_port: 51820,
+            fwmark: 0,
+            under_load: true,
+            counters,
+        };
+        let mut m = Metrics { out: String::new() };
+        super::render_state(&mut m, "wg\"0", &state, now);
+        let lines: Vec<&str> = m.out.lines().collect();
+
+        let pk = base64::encode([1; 32]);
+        for expected in &[
+            "titun_peers{interface=\"wg\\\"0\"} 1".to_string(),
+            "titun_handshake_under_load{interface=\"wg\\\"0\"} 1".to_string(),
+            "titun_rx_packets_total{interface=\"wg\\\"0\"} 10".to_string(),
+            "titun_dropped_packets_total{interface=\"wg\\\"0\",reason=\"no_route\"} 3".to_string(),
+            "titun_dropped_packets_total{interface=\"wg\\\"0\",reason=\"too_big\"} 0".to_string(),
+            format!(
+                "titun_peer_rx_bytes_total{{interface=\"wg\\\"0\",public_key=\"{}\"}} 1000",
+                pk
+            ),
+            format!(
+                "titun_peer_last_handshake_age_seconds{{interface=\"wg\\\"0\",public_key=\"{}\"}} 5",
+                pk
+            ),
+            format!(
+                "titun_peer_sessions{{interface=\"wg\\\"0\",public_key=\"{}\"}} 2",
+                pk
+            ),
+            format!(
+                "titun_peer_queued_packets{{interface=\"wg\\\"0\",public_key=\"{}\"}} 4",
+                pk
+            ),
+            format!(
+                "titun_peer_endpoint_info{{interface=\"wg\\\"0\",public_key=\"{}\",endpoint=\"192.0.2.1:51820\"}} 1",
+                pk
+            ),
+            format!(
+                "titun_peer_dropped_packets_total{{interface=\"wg\\\"0\",public_key=\"{}\",reason=\"no_route\"}} 3",
+                pk
+            ),
+        ] {
+            assert!(lines.contains(&expected.as_str()), "missing {}", expected);
+        }
+        assert!(lines.contains(&"# TYPE titun_peer_tx_bytes_total counter"));
+    }
+}
@@ -18,6 +18,9 @@
 mod config;
 #[cfg(unix)]
 pub mod daemonize;
+#[cfg(unix)]
+mod disable;
+mod metrics;
 mod network_config;
 mod real_main;
 #[cfg(unix)]
@@ -29,12 +32,15 @@ mod systemd;
 pub mod transform;
 
 pub use config::*;
+#[cfg(unix)]
+pub use disable::set_peer_disabled;
+pub use metrics::{MetricsListen, MetricsListener};
 #[cfg(windows)]
 pub(self) use network_config::network_config;
 #[doc(hidden)]
 pub use real_main::*;
 #[cfg(unix)]
-pub use reload::reload;
+pub use reload::{configured_disabled, reload, ConfiguredDisabled};
 pub use run::*;
 #[cfg(unix)]
 pub use show::show;
@@ -176,7 +176,12 @@ pub async fn network_config(c: &Config<SocketAddr>) -> anyhow::Result<()> {
     let mut routes = Vec::new();
 
     for p in &c.peers {
-        if let Some(e) = p.true_endpoint.as_ref().or_else(|| p.endpoint.as_ref()) {
+        // Fixate routes to all the candidate endpoints.
+        let endpoints = match p.true_endpoint {
+            Some(ref e) => std::slice::from_ref(e),
+            None => &p.endpoint[..],
+        };
+        for e in endpoints {
             let e = e.ip();
             let len = if e.is_ipv4() { 32 } else { 128 };
             let e_default = if e.is_ipv4() { "0.0.0.0/0" } else { "::/0" };
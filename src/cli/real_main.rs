@@ -160,6 +160,8 @@ impl Options {
 
         info!("titun {}", version);
         info!("Will spawn {} worker threads", threads);
+        // Encryption and decryption is spread across the same number of tasks.
+        config.general.threads = Some(threads);
         #[cfg(unix)]
         let notify = if config.general.foreground {
             None
@@ -192,6 +194,22 @@ enum Cmd {
         #[structopt(help = "Interfaces to show. Omit to show all", parse(from_os_str))]
         interfaces: Vec<OsString>,
     },
+    #[structopt(about = "Disable a peer, keeping its configuration")]
+    Disable {
+        #[structopt(help = "Interface name", parse(from_os_str))]
+        interface: OsString,
+        #[structopt(help = "Public key of the peer")]
+        public_key: String,
+        #[structopt(long, help = "Answer packets to the peer with ICMP errors")]
+        unreachable: bool,
+    },
+    #[structopt(about = "Enable a disabled peer")]
+    Enable {
+        #[structopt(help = "Interface name", parse(from_os_str))]
+        interface: OsString,
+        #[structopt(help = "Public key of the peer")]
+        public_key: String,
+    },
     #[structopt(about = "Check configuration file validity")]
     Check {
         config_file: PathBuf,
@@ -225,6 +243,31 @@ impl Cmd {
                     anyhow::bail!("the show command is not implemented on this platform");
                 }
             }
+            Cmd::Disable {
+                interface,
+                public_key,
+                unreachable,
+            } => {
+                #[cfg(unix)]
+                cli::set_peer_disabled(&interface, &public_key, true, unreachable).await?;
+                #[cfg(not(unix))]
+                {
+                    drop((interface, public_key, unreachable));
+                    anyhow::bail!("the disable command is not implemented on this platform");
+                }
+            }
+            Cmd::Enable {
+                interface,
+                public_key,
+            } => {
+                #[cfg(unix)]
+                cli::set_peer_disabled(&interface, &public_key, false, false).await?;
+                #[cfg(not(unix))]
+                {
+                    drop((interface, public_key));
+                    anyhow::bail!("the enable command is not implemented on this platform");
+                }
+            }
             Cmd::Check {
                 config_file: p,
                 print,
@@ -16,26 +16,66 @@
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::{Config, PeerConfig};
-use crate::wireguard::{SetPeerCommand, WgState};
+use crate::wireguard::{SetPeerCommand, WgState, X25519Pubkey, DEFAULT_RESOLVE_INTERVAL};
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::net::SocketAddr;
 use std::num::NonZeroU16;
 use std::sync::Arc;
 
+/// Disabled states of peers in the last loaded configuration.
+pub type ConfiguredDisabled = BTreeMap<X25519Pubkey, (bool, bool)>;
+
+pub fn configured_disabled(config: &Config<SocketAddr>) -> ConfiguredDisabled {
+    config
+        .peers
+        .iter()
+        .map(|p| (p.public_key, p.to_disabled()))
+        .collect()
+}
+
 /// Reload the TiTun interface, applying configuration changes.
 ///
+/// The disabled state of a peer, e.g. set with `titun disable`, is kept unless
+/// it is changed in the configuration since `last_disabled` was loaded, which
+/// is updated to that of `new_config`.
+///
 /// Most errors are handled. Shouldn't really return `Err`.
-pub async fn reload(wg: &Arc<WgState>, new_config: Config<SocketAddr>) -> anyhow::Result<()> {
+pub async fn reload(
+    wg: &Arc<WgState>,
+    last_disabled: &mut ConfiguredDisabled,
+    new_config: Config<SocketAddr>,
+) -> anyhow::Result<()> {
     let _state_change = wg.state_change_advisory.lock().await;
 
+    let last_disabled = std::mem::replace(last_disabled, configured_disabled(&new_config));
+
     let current_state = wg.get_state();
 
+    wg.set_queue_limits(new_config.interface.queue_limits());
+    wg.set_handshake_limits(new_config.interface.handshake_limits());
+
     if new_config.interface.private_key != current_state.private_key {
         info!("setting private key");
         wg.set_key(new_config.interface.private_key);
     }
 
+    wg.set_addresses(
+        new_config
+            .interface
+            .address
+            .iter()
+            .map(|&(a, _)| a)
+            .collect(),
+    );
+
+    wg.set_resolve_interval(
+        new_config
+            .interface
+            .resolve_interval
+            .unwrap_or(DEFAULT_RESOLVE_INTERVAL),
+    );
+
     let new_fwmark = new_config.interface.fwmark.unwrap_or(0);
 
     if new_fwmark != current_state.fwmark {
@@ -46,11 +86,16 @@ pub async fn reload(wg: &Arc<WgState>, new_config: Config<SocketAddr>) -> anyhow
     }
 
     let new_port = new_config.interface.listen_port.unwrap_or(0);
-    if current_state.listen_port != new_port {
-        info!("setting listen port");
-        if let Err(e) = wg.set_port(new_port).await {
-            warn!("failed to set port to {}: {:#}", new_port, e);
-        }
+    // Does nothing if nothing changed.
+    if let Err(e) = wg
+        .set_listen(
+            new_port,
+            new_config.interface.listen_address.clone(),
+            new_config.interface.dual_stack.unwrap_or(true),
+        )
+        .await
+    {
+        warn!("failed to set port to {}: {:#}", new_port, e);
     }
 
     // I wish BTreeMap has difference and intersection.
@@ -76,31 +121,75 @@ pub async fn reload(wg: &Arc<WgState>, new_config: Config<SocketAddr>) -> anyhow
     }
 
     for pk in existing.intersection(&new) {
-        let new = new_map.remove(pk).unwrap();
+        let mut new = new_map.remove(pk).unwrap();
         let existing = existing_map.remove(pk).unwrap();
+        if existing.psk_rotated {
+            // Keep the pre-shared key pushed by the external provider.
+            new.preshared_key = existing.preshared_key;
+        }
+        let existing_rate_limit = existing.rate_limit;
+        let existing_source_filter = existing.source_filter;
+        let existing_disabled = (existing.disabled, existing.disabled_unreachable);
+        let existing_path_mtu_discovery = existing.path_mtu_discovery;
         let existing = PeerConfig {
             public_key: existing.public_key,
             preshared_key: existing.preshared_key,
-            endpoint: existing.endpoint,
+            endpoint: existing.endpoints,
+            endpoint_hosts: existing.endpoint_hosts,
             // We don't have this in PeerState.
             // Use same value from new config, so that it does not affect comparison.
             true_endpoint: new.true_endpoint,
             allowed_ips: existing.allowed_ips,
             keepalive: NonZeroU16::new(existing.persistent_keepalive_interval),
+            // Compared as `RateLimit` below, as the burst size may be the default.
+            rate_limit: new.rate_limit,
+            rate_burst: new.rate_burst,
+            filter: existing.filter.into_iter().map(|(r, _)| r).collect(),
+            // Compared as `SourceFilter` below, as strict is the default.
+            source_filter: new.source_filter,
+            allowed_sources: new.allowed_sources.clone(),
+            // Compared as `bool` below, as false is the default, and the
+            // runtime state may be kept.
+            disabled: new.disabled,
+            disabled_unreachable: new.disabled_unreachable,
+            path_mtu_discovery: new.path_mtu_discovery,
+        };
+        let rate_limit = new.to_rate_limit();
+        let source_filter = new.to_source_filter();
+        let disabled = if last_disabled.get(pk) == Some(&new.to_disabled()) {
+            existing_disabled
+        } else {
+            new.to_disabled()
         };
+        let path_mtu_discovery = new.path_mtu_discovery.unwrap_or(false);
 
         // Don't even call `set_peer` if nothing changes.
-        if new != existing {
+        if new != existing
+            || rate_limit != existing_rate_limit
+            || source_filter != existing_source_filter
+            || disabled != existing_disabled
+            || path_mtu_discovery != existing_path_mtu_discovery
+        {
             info!("setting peer {}", base64::encode(&existing.public_key));
 
             let command = SetPeerCommand {
                 public_key: existing.public_key,
-                preshared_key: new.preshared_key,
+                preshared_key: Some(new.preshared_key),
+                rotate_psk: None,
+                psk_lifetime: None,
                 endpoint: new.endpoint,
+                endpoint_hosts: new.endpoint_hosts,
                 replace_allowed_ips: true,
                 allowed_ips: new.allowed_ips,
                 // If new.keepalive is `None`, use `Some(0)` to clear it.
                 keepalive: new.keepalive.map(|k| Some(k.get())).unwrap_or(Some(0)),
+                // If there is no rate limit, use rate zero to clear it.
+                rate_limit: Some(rate_limit.unwrap_or_default()),
+                filter: Some(new.filter),
+                source_filter: Some(source_filter),
+                disabled: Some(disabled.0),
+                disabled_unreachable: Some(disabled.1),
+                path_mtu_discovery: Some(path_mtu_discovery),
             };
 
             wg.set_peer(command)?;
@@ -112,15 +201,74 @@ pub async fn reload(wg: &Arc<WgState>, new_config: Config<SocketAddr>) -> anyhow
 
         wg.add_peer(&new_peer.public_key)?;
 
+        let rate_limit = new_peer.to_rate_limit();
+        let source_filter = new_peer.to_source_filter();
         wg.set_peer(SetPeerCommand {
             public_key: new_peer.public_key,
             endpoint: new_peer.endpoint,
-            preshared_key: new_peer.preshared_key,
+            endpoint_hosts: new_peer.endpoint_hosts,
+            preshared_key: Some(new_peer.preshared_key),
+            rotate_psk: None,
+            psk_lifetime: None,
             allowed_ips: new_peer.allowed_ips,
             replace_allowed_ips: false,
             keepalive: new_peer.keepalive.map(|k| k.get()),
+            rate_limit,
+            filter: Some(new_peer.filter),
+            source_filter: Some(source_filter),
+            disabled: new_peer.disabled,
+            disabled_unreachable: new_peer.disabled_unreachable,
+            path_mtu_discovery: new_peer.path_mtu_discovery,
         })?;
     }
 
+    wg.notify_config_reloaded();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard::{ChannelPacketDevice, SystemClock, Timers};
+
+    fn config(disabled: bool) -> Config<SocketAddr> {
+        toml::from_str(&format!(
+            r##"[Interface]
+PrivateKey = "2BJtcgPUjHfKKN3yMvTiVQbJ/UgHj2tcZE6xU/4BdGM="
+
+[[Peer]]
+PublicKey = "Ck8P+fUguLIf17zmb3eWxxS7PqgN3+ciMFBlSwqRaw4="
+AllowedIPs = "192.168.77.1"
+Disabled = {}
+"##,
+            disabled
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn keep_runtime_disabled() -> anyhow::Result<()> {
+        let (device, _handle) = ChannelPacketDevice::new(1280, 4);
+        let wg = WgState::new_with_clock(vec![device], Arc::new(SystemClock), Timers::default())?;
+        let mut last_disabled = ConfiguredDisabled::new();
+        reload(&wg, &mut last_disabled, config(false)).await?;
+        let public_key = wg.get_state().peers[0].public_key;
+        let disabled = || wg.get_state().peers[0].disabled;
+        assert!(!disabled());
+
+        // Disabled at runtime, e.g. with `titun disable`.
+        wg.set_peer(SetPeerCommand {
+            disabled: Some(true),
+            ..SetPeerCommand::new(public_key)
+        })?;
+        reload(&wg, &mut last_disabled, config(false)).await?;
+        assert!(disabled());
+
+        // Changed in the configuration.
+        reload(&wg, &mut last_disabled, config(true)).await?;
+        assert!(disabled());
+        reload(&wg, &mut last_disabled, config(false)).await?;
+        assert!(!disabled());
+        Ok(())
+    }
+}
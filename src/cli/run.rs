@@ -31,18 +31,20 @@ type NotifyHandle = ();
 #[cfg(unix)]
 async fn do_reload(
     config_file_path: std::path::PathBuf,
+    last_disabled: &mut super::ConfiguredDisabled,
     wg: &std::sync::Arc<WgState>,
 ) -> anyhow::Result<()> {
     let new_config =
         tokio::task::spawn_blocking(move || super::load_config_from_path(&config_file_path, false))
             .await
             .expect("join load_config_from_path")?;
-    crate::cli::reload(wg, new_config).await
+    crate::cli::reload(wg, last_disabled, new_config).await
 }
 
 #[cfg(unix)]
 async fn reload_on_sighup(
     config_file_path: Option<std::path::PathBuf>,
+    mut last_disabled: super::ConfiguredDisabled,
     weak: std::sync::Weak<WgState>,
 ) -> anyhow::Result<()> {
     use tokio::signal::unix::{signal, SignalKind};
@@ -51,7 +53,7 @@ async fn reload_on_sighup(
         if let Some(ref config_file_path) = config_file_path {
             if let Some(wg) = weak.upgrade() {
                 info!("reloading");
-                do_reload(config_file_path.clone(), &wg)
+                do_reload(config_file_path.clone(), &mut last_disabled, &wg)
                     .await
                     .unwrap_or_else(|e| warn!("error in reloading: {:#}", e));
             }
@@ -93,7 +95,21 @@ pub async fn run(
 
     let dev_name = c.interface.name.clone().unwrap();
 
-    let tun = AsyncTun::open(&dev_name).context("failed to open tun interface")?;
+    let tun_queues = c.general.tun_queues.map_or(1, |n| n.get());
+    #[cfg(target_os = "linux")]
+    let tun = if tun_queues > 1 {
+        AsyncTun::open_multi_queue(&dev_name, tun_queues)
+    } else {
+        AsyncTun::open(&dev_name).map(|tun| vec![tun])
+    };
+    #[cfg(not(target_os = "linux"))]
+    let tun = {
+        if tun_queues > 1 {
+            warn!("multi-queue tun is only supported on linux, using one queue");
+        }
+        AsyncTun::open(&dev_name).map(|tun| vec![tun])
+    };
+    let tun = tun.context("failed to open tun interface")?;
     #[cfg(windows)]
     {
         if let Err(e) = crate::cli::network_config(&c).await {
@@ -101,26 +117,55 @@ pub async fn run(
         }
     }
 
-    let wg = WgState::new(tun)?;
+    let wg = WgState::new_multi_queue(tun)?;
+    wg.set_crypto_workers(c.general.threads.unwrap_or(1));
+    wg.set_addresses(c.interface.address.iter().map(|&(a, _)| a).collect());
+    if let Some(interval) = c.interface.resolve_interval {
+        wg.set_resolve_interval(interval);
+    }
+    wg.set_queue_limits(c.interface.queue_limits());
+    wg.set_handshake_limits(c.interface.handshake_limits());
     info!("setting privatge key");
     wg.set_key(c.interface.private_key);
-    if let Some(port) = c.interface.listen_port {
+    if c.interface.listen_port.is_some()
+        || !c.interface.listen_address.is_empty()
+        || c.interface.dual_stack.is_some()
+    {
         info!("setting port");
-        wg.set_port(port).await.context("failed to set port")?;
+        wg.set_listen(
+            c.interface.listen_port.unwrap_or(0),
+            c.interface.listen_address.clone(),
+            c.interface.dual_stack.unwrap_or(true),
+        )
+        .await
+        .context("failed to set port")?;
     }
     if let Some(fwmark) = c.interface.fwmark {
         info!("setting fwmark");
         wg.set_fwmark(fwmark).context("failed to set fwmark")?;
     }
 
+    #[cfg(unix)]
+    let last_disabled = super::configured_disabled(&c);
     for p in c.peers {
         info!("adding peer {}", base64::encode(&p.public_key));
         wg.add_peer(&p.public_key)?;
+        let rate_limit = p.to_rate_limit();
+        let source_filter = p.to_source_filter();
         wg.set_peer(SetPeerCommand {
             public_key: p.public_key,
-            preshared_key: p.preshared_key,
+            preshared_key: Some(p.preshared_key),
+            rotate_psk: None,
+            psk_lifetime: None,
             endpoint: p.endpoint,
+            endpoint_hosts: p.endpoint_hosts,
             keepalive: p.keepalive.map(|x| x.get()),
+            rate_limit,
+            filter: Some(p.filter),
+            source_filter: Some(source_filter),
+            disabled: p.disabled,
+            disabled_unreachable: p.disabled_unreachable,
+            path_mtu_discovery: p.path_mtu_discovery,
             replace_allowed_ips: true,
             allowed_ips: p.allowed_ips,
         })?;
@@ -128,8 +173,18 @@ pub async fn run(
 
     let weak = std::sync::Arc::downgrade(&wg);
 
+    if let Some(ref listen) = c.general.metrics_listen {
+        // Bind now, before dropping privilege.
+        let listener = crate::cli::MetricsListener::bind(listen)
+            .await
+            .with_context(|| format!("failed to listen for metrics on {}", listen))?;
+        info!("serving metrics on {}", listen);
+        let name = dev_name.to_string_lossy().into_owned();
+        let weak = weak.clone();
+        scope0.spawn_canceller(listener.serve(name, weak));
+    }
+
     scope0.spawn_canceller(wg.clone().task_update_cookie_secret());
-    #[cfg(not(windows))]
     scope0.spawn_canceller(wg.clone().task_update_mtu());
     scope0.spawn_canceller(wg.clone().task_rx());
     scope0.spawn_canceller(wg.clone().task_tx());
@@ -139,7 +194,7 @@ pub async fn run(
         let weak1 = weak.clone();
         let config_file_path = c.general.config_file_path.take();
         scope0.spawn_canceller(async move {
-            reload_on_sighup(config_file_path, weak1)
+            reload_on_sighup(config_file_path, last_disabled, weak1)
                 .await
                 .unwrap_or_else(|e| warn!("error in reload_on_sighup: {:#}", e))
         });
@@ -16,6 +16,7 @@
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::wireguard::re_exports::{DH, X25519};
+use crate::wireguard::SourceFilter;
 use ansi_term::{Color, Style};
 use anyhow::Context;
 use std::ffi::{OsStr, OsString};
@@ -84,13 +85,28 @@ pub async fn show(interfaces: Vec<OsString>) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn get_and_print_status(dev_name: &OsStr, is_first: bool) -> anyhow::Result<()> {
+/// Connect to the control socket of interface `dev_name`.
+pub(super) async fn connect(dev_name: &OsStr) -> anyhow::Result<UnixStream> {
     let path = Path::new("/var/run/wireguard/")
         .join(dev_name)
         .with_extension("sock");
-    let mut stream = UnixStream::connect(&path)
+    UnixStream::connect(&path)
         .await
-        .context("failed to connect to socket")?;
+        .context("failed to connect to socket")
+}
+
+/// Error for an `errno` response of the control socket.
+pub(super) fn errno_error(errno: i32) -> anyhow::Error {
+    let io_error = std::io::Error::from_raw_os_error(errno);
+    anyhow::anyhow!(
+        "socket responded with errno={}, which means {}",
+        errno,
+        io_error
+    )
+}
+
+async fn get_and_print_status(dev_name: &OsStr, is_first: bool) -> anyhow::Result<()> {
+    let mut stream = connect(dev_name).await?;
     stream.write_all(b"get=1\n\n").await?;
 
     let state_or_errno = crate::ipc::parse::parse_get_response_io(stream)
@@ -98,14 +114,7 @@ async fn get_and_print_status(dev_name: &OsStr, is_first: bool) -> anyhow::Resul
         .context("failed to read or parse response")?;
 
     match state_or_errno {
-        Err(errno) => {
-            let io_error = std::io::Error::from_raw_os_error(errno);
-            bail!(
-                "socket responed with errno={}, which means {}",
-                errno,
-                io_error
-            );
-        }
+        Err(errno) => return Err(errno_error(errno)),
         Ok(state) => {
             let is_tty = atty::is(atty::Stream::Stdout);
 
@@ -151,12 +160,37 @@ async fn get_and_print_status(dev_name: &OsStr, is_first: bool) -> anyhow::Resul
                     yellow_bold.paint("peer"),
                     yellow.paint(base64::encode(&p.public_key))
                 );
+                if p.disabled {
+                    if p.disabled_unreachable {
+                        println!("  {}: yes, unreachable", bold.paint("disabled"));
+                    } else {
+                        println!("  {}: yes", bold.paint("disabled"));
+                    }
+                }
                 if p.preshared_key.is_some() {
-                    println!("  {}: (hidden)", bold.paint("preshared key"));
+                    print!("  {}: (hidden)", bold.paint("preshared key"));
+                    if p.psk_expired {
+                        print!(", expired");
+                    } else if let Some(d) = p.psk_expires_in {
+                        print!(", expires in ");
+                        print_human_time(d.as_secs(), cyan);
+                    }
+                    println!();
                 }
                 if let Some(ref e) = p.endpoint {
                     println!("  {}: {}", bold.paint("endpoint"), e);
                 }
+                if p.endpoints.len() > 1 {
+                    println!(
+                        "  {}: {}",
+                        bold.paint("candidate endpoints"),
+                        p.endpoints
+                            .iter()
+                            .map(|e| e.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
                 if !p.allowed_ips.is_empty() {
                     println!(
                         "  {}: {}",
@@ -192,6 +226,36 @@ async fn get_and_print_status(dev_name: &OsStr, is_first: bool) -> anyhow::Resul
                     print_human_time(p.persistent_keepalive_interval.into(), cyan);
                     println!();
                 }
+                if let Some(mtu) = p.path_mtu {
+                    println!("  {}: {}", bold.paint("path mtu"), mtu);
+                }
+                if let Some(limit) = p.rate_limit {
+                    print!("  {}: ", bold.paint("rate limit"));
+                    print_human_size(limit.rate, cyan);
+                    println!("/s");
+                }
+                match p.source_filter {
+                    SourceFilter::Strict => (),
+                    SourceFilter::Loose => println!("  {}: loose", bold.paint("source filter")),
+                    SourceFilter::Off(ref sources) if sources.is_empty() => {
+                        println!("  {}: off", bold.paint("source filter"))
+                    }
+                    SourceFilter::Off(ref sources) => println!(
+                        "  {}: off, allowing {}",
+                        bold.paint("source filter"),
+                        sources
+                            .iter()
+                            .map(|(ip, plen)| format!("{}{}{}", ip, cyan.paint("/"), plen))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                }
+                if !p.filter.is_empty() {
+                    println!("  {}:", bold.paint("filter"));
+                    for (rule, matches) in &p.filter {
+                        println!("    {} ({} {})", rule, matches, cyan.paint("matches"));
+                    }
+                }
             }
         }
     }
@@ -23,6 +23,8 @@ use std::net::{IpAddr, SocketAddr};
 pub enum WgIpcCommand {
     Get,
     Set(WgSetCommand),
+    /// Keep the connection open and stream events, one per line.
+    Subscribe,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -38,9 +40,18 @@ pub struct WgSetCommand {
 pub struct WgSetPeerCommand {
     pub public_key: [u8; 32],
     pub remove: bool,
+    /// Only change the peer if it exists, instead of adding it.
+    pub update_only: bool,
+    /// Unchanged if `None`. All zeros removes it.
     pub preshared_key: Option<[u8; 32]>,
+    pub rotate_preshared_key: Option<[u8; 32]>,
+    /// In seconds. Zero means no expiry.
+    pub preshared_key_lifetime: Option<u64>,
     pub endpoint: Option<SocketAddr>,
     pub persistent_keepalive_interval: Option<u16>,
+    pub disabled: Option<bool>,
+    pub disabled_unreachable: Option<bool>,
+    pub path_mtu_discovery: Option<bool>,
     pub replace_allowed_ips: bool,
     pub allowed_ips: BTreeSet<(IpAddr, u32)>,
 }
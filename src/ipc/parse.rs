@@ -18,7 +18,9 @@
 use crate::ipc::commands::*;
 use crate::wireguard::re_exports::U8Array;
 use crate::wireguard::X25519Key;
-use crate::wireguard::{PeerStateOut, WgStateOut};
+use crate::wireguard::{
+    parse_prefix, CountersOut, DropReason, PeerStateOut, RateLimit, SourceFilter, WgStateOut,
+};
 use futures::prelude::*;
 use hex::decode;
 use std::collections::BTreeSet;
@@ -109,9 +111,15 @@ where
     let mut peer = WgSetPeerCommand {
         public_key,
         remove: false,
+        update_only: false,
         preshared_key: None,
+        rotate_preshared_key: None,
+        preshared_key_lifetime: None,
         endpoint: None,
         persistent_keepalive_interval: None,
+        disabled: None,
+        disabled_unreachable: None,
+        path_mtu_discovery: None,
         replace_allowed_ips: false,
         allowed_ips: BTreeSet::new(),
     };
@@ -132,6 +140,7 @@ where
         };
         match k {
             "remove" => peer.remove = v.parse()?,
+            "update_only" => peer.update_only = v.parse()?,
             "preshared_key" => {
                 let v = decode(v)?;
                 if v.len() != 32 {
@@ -139,24 +148,24 @@ where
                 }
                 peer.preshared_key = Some(U8Array::from_slice(&v[..]));
             }
+            "rotate_preshared_key" => {
+                let v = decode(v)?;
+                if v.len() != 32 {
+                    bail!("Invalid length for preshared key");
+                }
+                peer.rotate_preshared_key = Some(U8Array::from_slice(&v[..]));
+            }
+            "preshared_key_lifetime" => peer.preshared_key_lifetime = Some(v.parse()?),
             "endpoint" => peer.endpoint = Some(v.parse()?),
             "persistent_keepalive_interval" => {
                 peer.persistent_keepalive_interval = Some(v.parse()?)
             }
+            "disabled" => peer.disabled = Some(v.parse()?),
+            "disabled_unreachable" => peer.disabled_unreachable = Some(v.parse()?),
+            "path_mtu_discovery" => peer.path_mtu_discovery = Some(v.parse()?),
             "replace_allowed_ips" => peer.replace_allowed_ips = v.parse()?,
             "allowed_ip" => {
-                let mut parts = v.splitn(2, '/');
-                let ip = parts.next().unwrap();
-                let prefix_len = match parts.next() {
-                    None => bail!("Invalid allowed ip: {}", v),
-                    Some(x) => x,
-                };
-                let ip: std::net::IpAddr = ip.parse()?;
-                let prefix_len: u32 = prefix_len.parse()?;
-                if prefix_len > if ip.is_ipv4() { 32 } else { 128 } {
-                    bail!("Invalid prefix length: {}", prefix_len);
-                }
-                peer.allowed_ips.insert((ip, prefix_len));
+                peer.allowed_ips.insert(parse_prefix(v)?);
             }
             _ => break,
         }
@@ -234,7 +243,7 @@ where
         Some(line) => line,
     };
     match first_line.as_ref() {
-        "get=1" => {
+        "get=1" | "subscribe=1" => {
             let empty_line = match stream.try_next().await? {
                 None => bail!("Unexpected end of input stream"),
                 Some(line) => line,
@@ -242,7 +251,11 @@ where
             if !empty_line.is_empty() {
                 bail!("Expected empty line, got {}", empty_line);
             }
-            Ok(Some(WgIpcCommand::Get))
+            if first_line == "get=1" {
+                Ok(Some(WgIpcCommand::Get))
+            } else {
+                Ok(Some(WgIpcCommand::Subscribe))
+            }
         }
         "set=1" => Ok(Some(WgIpcCommand::Set(
             parse_set_command(&mut stream).await?,
@@ -265,6 +278,27 @@ where
     parse_command(lines).await
 }
 
+// Parse a counter line. Returns false if `k` is not a counter.
+fn parse_counter(c: &mut CountersOut, k: &str, v: &str) -> anyhow::Result<bool> {
+    match k {
+        "rx_packets" => c.rx_packets = v.parse()?,
+        "tx_packets" => c.tx_packets = v.parse()?,
+        "handshake_initiations_sent" => c.handshake_initiations_sent = v.parse()?,
+        "handshake_initiations_received" => c.handshake_initiations_received = v.parse()?,
+        "handshake_failures" => c.handshake_failures = v.parse()?,
+        "shaped_packets" => c.shaped_packets = v.parse()?,
+        "loose_source_packets" => c.loose_source_packets = v.parse()?,
+        "psk_mismatches" => c.psk_mismatches = v.parse()?,
+        _ => match k.strip_prefix("drop_").and_then(DropReason::from_name) {
+            Some(r) => {
+                c.drops.insert(r, v.parse()?);
+            }
+            None => return Ok(false),
+        },
+    }
+    Ok(true)
+}
+
 async fn parse_peer_state_out<S>(stream: &mut Peekable<S>) -> anyhow::Result<PeerStateOut>
 where
     S: Stream<Item = io::Result<String>> + Unpin,
@@ -292,11 +326,26 @@ where
         public_key,
         preshared_key: None,
         endpoint: None,
+        endpoints: Vec::new(),
+        endpoint_hosts: Vec::new(),
         persistent_keepalive_interval: 0,
         allowed_ips: BTreeSet::new(),
         last_handshake_time: None,
         rx_bytes: 0,
         tx_bytes: 0,
+        path_mtu_discovery: false,
+        path_mtu: None,
+        rate_limit: None,
+        filter: Vec::new(),
+        source_filter: SourceFilter::Strict,
+        disabled: false,
+        disabled_unreachable: false,
+        psk_rotated: false,
+        psk_expires_in: None,
+        psk_expired: false,
+        sessions: 0,
+        queued_packets: 0,
+        counters: Default::default(),
     };
 
     loop {
@@ -328,6 +377,42 @@ where
             }
             "rx_bytes" => peer.rx_bytes = v.parse()?,
             "tx_bytes" => peer.tx_bytes = v.parse()?,
+            "path_mtu_discovery" => peer.path_mtu_discovery = v.parse()?,
+            "path_mtu" => peer.path_mtu = Some(v.parse()?),
+            "rate_limit" => {
+                peer.rate_limit.get_or_insert_with(RateLimit::default).rate = v.parse()?
+            }
+            "rate_burst" => {
+                peer.rate_limit.get_or_insert_with(RateLimit::default).burst = v.parse()?
+            }
+            "filter" => peer.filter.push((v.parse()?, 0)),
+            "filter_matches" => match peer.filter.last_mut() {
+                Some((_, n)) => *n = v.parse()?,
+                None => bail!("Get filter_matches but no filter"),
+            },
+            "source_filter" => {
+                peer.source_filter = match v {
+                    "strict" => SourceFilter::Strict,
+                    "loose" => SourceFilter::Loose,
+                    "off" => SourceFilter::Off(BTreeSet::new()),
+                    _ => bail!("Invalid source filter: {}", v),
+                }
+            }
+            "allowed_source" => match peer.source_filter {
+                SourceFilter::Off(ref mut sources) => {
+                    sources.insert(parse_prefix(v)?);
+                }
+                _ => bail!("Get allowed_source but source filter is not off"),
+            },
+            "disabled" => peer.disabled = v.parse()?,
+            "disabled_unreachable" => peer.disabled_unreachable = v.parse()?,
+            "preshared_key_rotated" => peer.psk_rotated = v.parse()?,
+            "preshared_key_expires_in" => {
+                peer.psk_expires_in = Some(Duration::from_secs(v.parse()?))
+            }
+            "preshared_key_expired" => peer.psk_expired = v.parse()?,
+            "sessions" => peer.sessions = v.parse()?,
+            "queued_packets" => peer.queued_packets = v.parse()?,
             "preshared_key" => {
                 let v = decode(v)?;
                 if v.len() != 32 {
@@ -336,22 +421,17 @@ where
                 peer.preshared_key = Some(U8Array::from_slice(&v[..]));
             }
             "endpoint" => peer.endpoint = Some(v.parse()?),
+            "candidate_endpoint" => peer.endpoints.push(v.parse()?),
+            "endpoint_host" => peer.endpoint_hosts.push(v.into()),
             "persistent_keepalive_interval" => peer.persistent_keepalive_interval = v.parse()?,
             "allowed_ip" => {
-                let mut parts = v.splitn(2, '/');
-                let ip = parts.next().unwrap();
-                let prefix_len = match parts.next() {
-                    None => bail!("Invalid allowed ip: {}", v),
-                    Some(x) => x,
-                };
-                let ip: std::net::IpAddr = ip.parse()?;
-                let prefix_len: u32 = prefix_len.parse()?;
-                if prefix_len > if ip.is_ipv4() { 32 } else { 128 } {
-                    bail!("Invalid prefix length: {}", prefix_len);
+                peer.allowed_ips.insert(parse_prefix(v)?);
+            }
+            _ => {
+                if !parse_counter(&mut peer.counters, k, v)? {
+                    break;
                 }
-                peer.allowed_ips.insert((ip, prefix_len));
             }
-            _ => break,
         }
         stream.try_next().await.unwrap();
     }
@@ -368,6 +448,8 @@ where
         peers: vec![],
         listen_port: 0,
         fwmark: 0,
+        under_load: false,
+        counters: Default::default(),
     };
     'outer: loop {
         let line = match stream.try_peek().await? {
@@ -407,10 +489,15 @@ where
                 }
                 "fwmark" => state.fwmark = value.parse()?,
                 "listen_port" => state.listen_port = value.parse()?,
+                "under_load" => state.under_load = value.parse()?,
                 // XXX: Check protocol version and errno.
                 "errno" => break,
                 "protocol_version" => break,
-                _ => bail!("Unexpected key: {}", key),
+                _ => {
+                    if !parse_counter(&mut state.counters, key, value)? {
+                        bail!("Unexpected key: {}", key);
+                    }
+                }
             }
         }
     }
@@ -468,6 +555,10 @@ mod tests {
             let result = parse_command(stream).await;
             assert_eq!(result.unwrap(), Some(WgIpcCommand::Get));
 
+            let stream = stream::iter(vec!["subscribe=1", ""]).map(|x| Ok(x.to_owned()));
+            let result = parse_command(stream).await;
+            assert_eq!(result.unwrap(), Some(WgIpcCommand::Subscribe));
+
             let stream = stream::iter(
                 include_str!("example.txt")
                     .lines()
@@ -475,6 +566,29 @@ mod tests {
             );
             let result = parse_command(stream).await;
             assert!(result.is_ok());
+
+            let stream = stream::iter(vec![
+                "set=1",
+                "public_key=b85996fecc9c7f1fc6d2572a76eda11d59bcd20be8e543b15ce4bd85a8e75a33",
+                "update_only=true",
+                "disabled=true",
+                "disabled_unreachable=false",
+                "path_mtu_discovery=true",
+                "rotate_preshared_key=e84b5a6d2717c1003a13b431570353dbaca9146cf150c5f8575680feba52027a",
+                "preshared_key_lifetime=180",
+                "",
+            ])
+            .map(|x| Ok(x.to_owned()));
+            let peer = match parse_command(stream).await.unwrap() {
+                Some(WgIpcCommand::Set(mut c)) => c.peers.remove(0),
+                c => panic!("unexpected command {:?}", c),
+            };
+            assert!(peer.update_only);
+            assert_eq!(peer.disabled, Some(true));
+            assert_eq!(peer.disabled_unreachable, Some(false));
+            assert_eq!(peer.path_mtu_discovery, Some(true));
+            assert!(peer.rotate_preshared_key.is_some());
+            assert_eq!(peer.preshared_key_lifetime, Some(180));
         });
     }
 
@@ -499,4 +613,70 @@ mod tests {
             Ok(())
         })
     }
+
+    #[test]
+    fn test_parsing_counters() -> anyhow::Result<()> {
+        futures::executor::block_on(async {
+            let response =
+                "private_key=e84b5a6d2717c1003a13b431570353dbaca9146cf150c5f8575680feba52027a
+listen_port=12912
+rx_packets=3
+drop_no_route=2
+under_load=true
+public_key=b85996fecc9c7f1fc6d2572a76eda11d59bcd20be8e543b15ce4bd85a8e75a33
+rx_packets=1
+handshake_failures=4
+drop_allowed_ips=5
+drop_too_big=1
+rx_bytes=2224
+path_mtu_discovery=true
+path_mtu=1400
+rate_limit=125000
+rate_burst=16384
+filter=allow in tcp port 22
+filter_matches=7
+filter=deny in
+source_filter=off
+allowed_source=10.1.0.0/16
+loose_source_packets=3
+disabled=true
+preshared_key_rotated=true
+preshared_key_expires_in=90
+psk_mismatches=2
+sessions=2
+queued_packets=4
+errno=0
+
+";
+            let state = parse_get_response_io(response.as_bytes()).await?.unwrap();
+            assert_eq!(state.counters.rx_packets, 3);
+            assert_eq!(state.counters.drops[&DropReason::NoRoute], 2);
+            assert!(state.under_load);
+            let peer = &state.peers[0];
+            assert_eq!(peer.rx_bytes, 2224);
+            assert_eq!(peer.counters.rx_packets, 1);
+            assert_eq!(peer.counters.handshake_failures, 4);
+            assert_eq!(peer.counters.drops.len(), 2);
+            assert_eq!(peer.counters.drops[&DropReason::AllowedIps], 5);
+            assert!(peer.path_mtu_discovery);
+            assert_eq!(peer.path_mtu, Some(1400));
+            assert_eq!(peer.rate_limit, Some(RateLimit::new(125000)));
+            assert_eq!(peer.filter[0], ("allow in tcp port 22".parse()?, 7));
+            assert_eq!(peer.filter[1], ("deny in".parse()?, 0));
+            assert_eq!(
+                peer.source_filter,
+                SourceFilter::Off(std::iter::once(("10.1.0.0".parse()?, 16)).collect())
+            );
+            assert_eq!(peer.counters.loose_source_packets, 3);
+            assert!(peer.disabled);
+            assert!(!peer.disabled_unreachable);
+            assert!(peer.psk_rotated);
+            assert_eq!(peer.psk_expires_in, Some(Duration::from_secs(90)));
+            assert!(!peer.psk_expired);
+            assert_eq!(peer.counters.psk_mismatches, 2);
+            assert_eq!(peer.sessions, 2);
+            assert_eq!(peer.queued_packets, 4);
+            Ok(())
+        })
+    }
 }
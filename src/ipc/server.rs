@@ -19,12 +19,14 @@
 
 use crate::ipc::commands::*;
 use crate::ipc::parse::*;
-use crate::wireguard::{SetPeerCommand, WgState, WgStateOut};
+use crate::wireguard::{CountersOut, Event, SetPeerCommand, WgState, WgStateOut};
 use anyhow::Context;
 use std::ffi::OsStr;
 use std::path::Path;
 use std::sync::{Arc, Weak};
+use std::time::Duration;
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::broadcast::{self, error::RecvError};
 use tokio::sync::oneshot::Sender;
 
 #[cfg(windows)]
@@ -113,12 +115,37 @@ macro_rules! writeln {
     };
 }
 
+#[cfg(not(windows))]
+async fn write_counters(mut w: impl AsyncWrite + Unpin, c: &CountersOut) -> io::Result<()> {
+    writeln!(w, "rx_packets={}", c.rx_packets)?;
+    writeln!(w, "tx_packets={}", c.tx_packets)?;
+    writeln!(
+        w,
+        "handshake_initiations_sent={}",
+        c.handshake_initiations_sent
+    )?;
+    writeln!(
+        w,
+        "handshake_initiations_received={}",
+        c.handshake_initiations_received
+    )?;
+    writeln!(w, "handshake_failures={}", c.handshake_failures)?;
+    writeln!(w, "shaped_packets={}", c.shaped_packets)?;
+    writeln!(w, "loose_source_packets={}", c.loose_source_packets)?;
+    writeln!(w, "psk_mismatches={}", c.psk_mismatches)?;
+    for (r, n) in &c.drops {
+        writeln!(w, "drop_{}={}", r.name(), n)?;
+    }
+    Ok(())
+}
+
 #[cfg(not(windows))]
 async fn write_wg_state(
     mut w: impl AsyncWrite + Unpin + 'static,
     state: WgStateOut,
 ) -> io::Result<()> {
     use crate::wireguard::re_exports::U8Array;
+    use crate::wireguard::SourceFilter;
     use hex::encode;
     use std::time::SystemTime;
 
@@ -127,6 +154,10 @@ async fn write_wg_state(
     if state.fwmark != 0 {
         writeln!(w, "fwmark={}", state.fwmark)?;
     }
+    if state.under_load {
+        writeln!(w, "under_load=true")?;
+    }
+    write_counters(&mut w, &state.counters).await?;
     for p in &state.peers {
         writeln!(w, "public_key={}", encode(&p.public_key))?;
         if let Some(ref psk) = p.preshared_key {
@@ -143,8 +174,58 @@ async fn write_wg_state(
         if let Some(ref e) = p.endpoint {
             writeln!(w, "endpoint={}", e)?;
         }
+        for e in &p.endpoints {
+            writeln!(w, "candidate_endpoint={}", e)?;
+        }
+        for h in &p.endpoint_hosts {
+            writeln!(w, "endpoint_host={}", h)?;
+        }
         writeln!(w, "rx_bytes={}", p.rx_bytes)?;
         writeln!(w, "tx_bytes={}", p.tx_bytes)?;
+        write_counters(&mut w, &p.counters).await?;
+        if p.path_mtu_discovery {
+            writeln!(w, "path_mtu_discovery=true")?;
+        }
+        if let Some(mtu) = p.path_mtu {
+            writeln!(w, "path_mtu={}", mtu)?;
+        }
+        if let Some(limit) = p.rate_limit {
+            writeln!(w, "rate_limit={}", limit.rate)?;
+            writeln!(w, "rate_burst={}", limit.burst)?;
+        }
+        for (rule, matches) in &p.filter {
+            writeln!(w, "filter={}", rule)?;
+            writeln!(w, "filter_matches={}", matches)?;
+        }
+        if p.source_filter != SourceFilter::Strict {
+            writeln!(w, "source_filter={}", p.source_filter.mode_name())?;
+        }
+        if let SourceFilter::Off(ref sources) = p.source_filter {
+            for a in sources {
+                writeln!(w, "allowed_source={}/{}", a.0, a.1)?;
+            }
+        }
+        if p.disabled {
+            writeln!(w, "disabled=true")?;
+        }
+        if p.disabled_unreachable {
+            writeln!(w, "disabled_unreachable=true")?;
+        }
+        if p.psk_rotated {
+            writeln!(w, "preshared_key_rotated=true")?;
+        }
+        if let Some(d) = p.psk_expires_in {
+            writeln!(w, "preshared_key_expires_in={}", d.as_secs())?;
+        }
+        if p.psk_expired {
+            writeln!(w, "preshared_key_expired=true")?;
+        }
+        if p.sessions > 0 {
+            writeln!(w, "sessions={}", p.sessions)?;
+        }
+        if p.queued_packets > 0 {
+            writeln!(w, "queued_packets={}", p.queued_packets)?;
+        }
         if let Some(ref t) = p.last_handshake_time {
             let d = t.duration_since(SystemTime::UNIX_EPOCH).unwrap();
             let secs = d.as_secs();
@@ -170,6 +251,22 @@ async fn write_wg_state(
     w.flush().await
 }
 
+// Until the interface is gone or the subscriber disconnects, which is only
+// noticed on the next event.
+async fn write_events(
+    mut w: impl AsyncWrite + Unpin,
+    mut events: broadcast::Receiver<Event>,
+) -> io::Result<()> {
+    loop {
+        match events.recv().await {
+            Ok(e) => writeln!(w, "{}", e)?,
+            Err(RecvError::Lagged(n)) => writeln!(w, "event=lagged missed={}", n)?,
+            Err(RecvError::Closed) => return Ok(()),
+        }
+        w.flush().await?;
+    }
+}
+
 async fn write_error(mut stream: impl AsyncWrite + Unpin + 'static, errno: i32) -> io::Result<()> {
     writeln!(stream, "errno={}", errno)?;
     writeln!(stream)?;
@@ -210,16 +307,28 @@ async fn process_wg_set(wg: &Arc<WgState>, command: WgSetCommand) -> io::Result<
             continue;
         }
         if !wg.peer_exists(&p.public_key) {
+            if p.update_only {
+                info!("peer does not exist, skipping");
+                continue;
+            }
             info!("adding peer");
             wg.add_peer(&p.public_key).unwrap();
         }
         wg.set_peer(SetPeerCommand {
-            public_key: p.public_key,
-            preshared_key: p.preshared_key,
-            endpoint: p.endpoint,
+            preshared_key: p.preshared_key.map(|k| Some(k).filter(|k| *k != [0u8; 32])),
+            rotate_psk: p.rotate_preshared_key,
+            psk_lifetime: p
+                .preshared_key_lifetime
+                .filter(|&l| l > 0)
+                .map(Duration::from_secs),
+            endpoint: p.endpoint.into_iter().collect(),
             allowed_ips: p.allowed_ips,
             keepalive: p.persistent_keepalive_interval,
+            disabled: p.disabled,
+            disabled_unreachable: p.disabled_unreachable,
+            path_mtu_discovery: p.path_mtu_discovery,
             replace_allowed_ips: p.replace_allowed_ips,
+            ..SetPeerCommand::new(p.public_key)
         })
         .unwrap();
     }
@@ -264,6 +373,12 @@ where
             )
             .await?;
         }
+        WgIpcCommand::Subscribe => {
+            let events = wg.subscribe();
+            // Don't keep the interface alive.
+            drop(wg);
+            write_events(stream_w, events).await?;
+        }
         WgIpcCommand::Set(sc) => {
             // FnMut hack.
             let errno = match process_wg_set(&wg, sc).await {
@@ -279,7 +394,9 @@ where
 #[cfg(windows)]
 mod state_json {
     use crate::wireguard::types::{PeerStateOut, WgStateOut};
+    use crate::wireguard::{CountersOut, SourceFilter};
     use serde::Serialize;
+    use std::collections::BTreeMap;
     use std::net::SocketAddr;
     use std::time::SystemTime;
 
@@ -296,6 +413,26 @@ mod state_json {
         listen_port: u16,
         /// Fwmark.
         fwmark: u32,
+        /// Whether handshake messages arrive too quickly.
+        under_load: bool,
+        /// Counters of the whole interface.
+        counters: CountersOutJson,
+    }
+
+    /// Packet and handshake counters.
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct CountersOutJson {
+        rx_packets: u64,
+        tx_packets: u64,
+        handshake_initiations_sent: u64,
+        handshake_initiations_received: u64,
+        handshake_failures: u64,
+        shaped_packets: u64,
+        loose_source_packets: u64,
+        psk_mismatches: u64,
+        /// Dropped packets by reason, e.g., `no_route`.
+        drops: BTreeMap<&'static str, u64>,
     }
 
     /// State of a peer.
@@ -308,6 +445,10 @@ mod state_json {
         preshared_key: bool,
         /// Endpoint.
         endpoint: Option<SocketAddr>,
+        /// Candidate endpoints.
+        candidate_endpoints: Vec<SocketAddr>,
+        /// Host names of the candidate endpoints.
+        endpoint_hosts: Vec<String>,
         /// Last handshake time seconds after UNIX epoch.
         last_handshake_time_sec: Option<u64>,
         /// Received bytes.
@@ -320,6 +461,46 @@ mod state_json {
         persistent_keepalive_interval: u16,
         /// Allowed IP addresses.
         allowed_ips: Vec<String>,
+        /// Whether path MTU discovery is enabled.
+        path_mtu_discovery: bool,
+        /// Discovered path MTU.
+        path_mtu: Option<u32>,
+        /// Rate limit in bytes per second.
+        rate_limit: Option<u64>,
+        /// Max burst size of the rate limit, in bytes.
+        rate_burst: Option<u64>,
+        /// Packet filter rules.
+        filter: Vec<FilterRuleOutJson>,
+        /// Source filter mode, `strict`, `loose` or `off`.
+        source_filter: &'static str,
+        /// Allowed sources when the source filter is off.
+        allowed_sources: Vec<String>,
+        /// Whether the peer is disabled.
+        disabled: bool,
+        /// Whether packets to the disabled peer are answered with ICMP errors.
+        disabled_unreachable: bool,
+        /// Whether the pre-shared key is pushed by an external provider.
+        preshared_key_rotated: bool,
+        /// Seconds until the pushed pre-shared key expires.
+        preshared_key_expires_in: Option<u64>,
+        /// Whether the pushed pre-shared key has expired.
+        preshared_key_expired: bool,
+        /// Number of sessions.
+        sessions: usize,
+        /// Packets waiting for a handshake to complete.
+        queued_packets: usize,
+        /// Counters of the peer.
+        counters: CountersOutJson,
+    }
+
+    /// A packet filter rule.
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct FilterRuleOutJson {
+        /// The rule, e.g., `allow in tcp to 10.0.0.1/32 port 22`.
+        rule: String,
+        /// Packets that matched the rule.
+        matches: u64,
     }
 
     impl From<(String, WgStateOut)> for WgStateOutJson {
@@ -329,17 +510,37 @@ mod state_json {
                 public_key: base64::encode(state.private_key.public_key()),
                 listen_port: state.listen_port,
                 fwmark: state.fwmark,
+                under_load: state.under_load,
+                counters: state.counters.into(),
                 peers: state.peers.into_iter().map(|p| p.into()).collect(),
             }
         }
     }
 
+    impl From<CountersOut> for CountersOutJson {
+        fn from(c: CountersOut) -> CountersOutJson {
+            CountersOutJson {
+                rx_packets: c.rx_packets,
+                tx_packets: c.tx_packets,
+                handshake_initiations_sent: c.handshake_initiations_sent,
+                handshake_initiations_received: c.handshake_initiations_received,
+                handshake_failures: c.handshake_failures,
+                shaped_packets: c.shaped_packets,
+                loose_source_packets: c.loose_source_packets,
+                psk_mismatches: c.psk_mismatches,
+                drops: c.drops.into_iter().map(|(r, n)| (r.name(), n)).collect(),
+            }
+        }
+    }
+
     impl From<PeerStateOut> for PeerStateOutJson {
         fn from(p: PeerStateOut) -> PeerStateOutJson {
             PeerStateOutJson {
                 public_key: base64::encode(&p.public_key),
                 preshared_key: p.preshared_key.is_some(),
                 endpoint: p.endpoint,
+                candidate_endpoints: p.endpoints,
+                endpoint_hosts: p.endpoint_hosts,
                 last_handshake_time_sec: p
                     .last_handshake_time
                     .map(|t| t.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()),
@@ -358,6 +559,34 @@ mod state_json {
                         }
                     })
                     .collect(),
+                path_mtu_discovery: p.path_mtu_discovery,
+                path_mtu: p.path_mtu,
+                rate_limit: p.rate_limit.map(|l| l.rate),
+                rate_burst: p.rate_limit.map(|l| l.burst),
+                filter: p
+                    .filter
+                    .into_iter()
+                    .map(|(r, matches)| FilterRuleOutJson {
+                        rule: r.to_string(),
+                        matches,
+                    })
+                    .collect(),
+                source_filter: p.source_filter.mode_name(),
+                allowed_sources: match p.source_filter {
+                    SourceFilter::Off(sources) => sources
+                        .into_iter()
+                        .map(|(a, p)| format!("{}/{}", a, p))
+                        .collect(),
+                    _ => Vec::new(),
+                },
+                disabled: p.disabled,
+                disabled_unreachable: p.disabled_unreachable,
+                preshared_key_rotated: p.psk_rotated,
+                preshared_key_expires_in: p.psk_expires_in.map(|d| d.as_secs()),
+                preshared_key_expired: p.psk_expired,
+                sessions: p.sessions,
+                queued_packets: p.queued_packets,
+                counters: p.counters.into(),
             }
         }
     }
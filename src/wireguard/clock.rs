@@ -0,0 +1,100 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::wireguard::{
+    KEEPALIVE_TIMEOUT, REJECT_AFTER_TIME, REKEY_AFTER_TIME, REKEY_ATTEMPT_TIME, REKEY_TIMEOUT,
+};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::time::{Duration, Instant};
+
+/// Source of time of the protocol timers.
+///
+/// `SystemClock` is the default. `ManualClock` only moves when told to, so
+/// that timers can be tested without waiting for them.
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    /// Resolves when `now()` reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+/// The real clock, i.e., `tokio::time`.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        tokio::time::sleep_until(deadline.into()).boxed()
+    }
+}
+
+/// Protocol timers.
+///
+/// Only change them in tests, e.g., to see sessions expire in milliseconds.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Timers {
+    pub rekey_after_time: Duration,
+    pub reject_after_time: Duration,
+    pub rekey_timeout: Duration,
+    pub keepalive_timeout: Duration,
+    pub rekey_attempt_time: Duration,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Timers {
+            rekey_after_time: Duration::from_secs(REKEY_AFTER_TIME),
+            reject_after_time: Duration::from_secs(REJECT_AFTER_TIME),
+            rekey_timeout: Duration::from_secs(REKEY_TIMEOUT),
+            keepalive_timeout: Duration::from_secs(KEEPALIVE_TIMEOUT),
+            rekey_attempt_time: Duration::from_secs(REKEY_ATTEMPT_TIME),
+        }
+    }
+}
+
+impl Timers {
+    /// Multiply all timers by `factor`.
+    pub fn scaled(self, factor: f64) -> Self {
+        Timers {
+            rekey_after_time: self.rekey_after_time.mul_f64(factor),
+            reject_after_time: self.reject_after_time.mul_f64(factor),
+            rekey_timeout: self.rekey_timeout.mul_f64(factor),
+            keepalive_timeout: self.keepalive_timeout.mul_f64(factor),
+            rekey_attempt_time: self.rekey_attempt_time.mul_f64(factor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaled_timers() {
+        let timers = Timers::default().scaled(0.001);
+        assert_eq!(timers.rekey_after_time, Duration::from_millis(120));
+        assert_eq!(timers.rekey_timeout, Duration::from_millis(5));
+    }
+}
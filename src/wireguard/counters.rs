@@ -0,0 +1,204 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::wireguard::U64Counter;
+use std::collections::BTreeMap;
+
+/// Why a packet is dropped.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum DropReason {
+    /// A transport message to an unknown receiver index.
+    UnknownId,
+    /// A transport message that fails to decrypt, or is replayed.
+    DecryptionFailed,
+    /// A decrypted packet whose source is not in the peer's allowed IPs.
+    AllowedIps,
+    /// A packet that is not a valid IP packet, or is truncated.
+    InvalidPacket,
+    /// A packet from tun that no peer is routed to.
+    NoRoute,
+    /// A packet to a peer without an endpoint.
+    NoEndpoint,
+    /// A packet bigger than the path MTU, answered with an ICMP error.
+    TooBig,
+    /// The oldest packet waiting for a handshake, when the queue is full.
+    QueueFull,
+    /// A packet waiting for a handshake, when the queues of all peers are
+    /// full.
+    QueueTotalFull,
+    /// A packet to a peer that already has too many packets waiting to be
+    /// sent, e.g., because of its rate limit.
+    TxQueueFull,
+    /// A handshake message from a source that sends them too quickly.
+    HandshakeRateLimited,
+    /// A packet denied by a filter rule of the peer.
+    Filtered,
+    /// A decrypted packet whose source is not allowed by the peer's loose or
+    /// off source filter.
+    SourceFilter,
+    /// A packet to or a handshake initiation from a disabled peer.
+    PeerDisabled,
+    /// A packet to or a handshake initiation from a peer whose pushed
+    /// pre-shared key has expired.
+    PskExpired,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 15] = [
+        DropReason::UnknownId,
+        DropReason::DecryptionFailed,
+        DropReason::AllowedIps,
+        DropReason::InvalidPacket,
+        DropReason::NoRoute,
+        DropReason::NoEndpoint,
+        DropReason::TooBig,
+        DropReason::QueueFull,
+        DropReason::QueueTotalFull,
+        DropReason::TxQueueFull,
+        DropReason::HandshakeRateLimited,
+        DropReason::Filtered,
+        DropReason::SourceFilter,
+        DropReason::PeerDisabled,
+        DropReason::PskExpired,
+    ];
+
+    /// Name of the reason, e.g., `no_route`.
+    pub fn name(self) -> &'static str {
+        match self {
+            DropReason::UnknownId => "unknown_id",
+            DropReason::DecryptionFailed => "decryption_failed",
+            DropReason::AllowedIps => "allowed_ips",
+            DropReason::InvalidPacket => "invalid_packet",
+            DropReason::NoRoute => "no_route",
+            DropReason::NoEndpoint => "no_endpoint",
+            DropReason::TooBig => "too_big",
+            DropReason::QueueFull => "queue_full",
+            DropReason::QueueTotalFull => "queue_total_full",
+            DropReason::TxQueueFull => "tx_queue_full",
+            DropReason::HandshakeRateLimited => "handshake_rate_limited",
+            DropReason::Filtered => "filtered",
+            DropReason::SourceFilter => "source_filter",
+            DropReason::PeerDisabled => "peer_disabled",
+            DropReason::PskExpired => "psk_expired",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DropReason> {
+        DropReason::ALL.iter().copied().find(|r| r.name() == name)
+    }
+}
+
+/// Counters of a peer or the whole interface.
+pub struct Counters {
+    pub rx_packets: U64Counter,
+    pub tx_packets: U64Counter,
+    pub handshake_initiations_sent: U64Counter,
+    pub handshake_initiations_received: U64Counter,
+    // Handshake messages that fail to authenticate or decrypt, or are
+    // replayed.
+    pub handshake_failures: U64Counter,
+    pub shaped_packets: U64Counter,
+    // Packets accepted from sources outside the peer's allowed IPs.
+    pub loose_source_packets: U64Counter,
+    // Handshake responses that fail to decrypt, most likely because the
+    // pre-shared keys differ.
+    pub psk_mismatches: U64Counter,
+    drops: [U64Counter; DropReason::ALL.len()],
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters {
+            rx_packets: U64Counter::new(0),
+            tx_packets: U64Counter::new(0),
+            handshake_initiations_sent: U64Counter::new(0),
+            handshake_initiations_received: U64Counter::new(0),
+            handshake_failures: U64Counter::new(0),
+            shaped_packets: U64Counter::new(0),
+            loose_source_packets: U64Counter::new(0),
+            psk_mismatches: U64Counter::new(0),
+            drops: [(); DropReason::ALL.len()].map(|_| U64Counter::new(0)),
+        }
+    }
+
+    pub fn count_drop(&self, reason: DropReason) {
+        self.drops[reason as usize].fetch_add(1);
+    }
+
+    pub fn get(&self) -> CountersOut {
+        CountersOut {
+            rx_packets: self.rx_packets.load(),
+            tx_packets: self.tx_packets.load(),
+            handshake_initiations_sent: self.handshake_initiations_sent.load(),
+            handshake_initiations_received: self.handshake_initiations_received.load(),
+            handshake_failures: self.handshake_failures.load(),
+            shaped_packets: self.shaped_packets.load(),
+            loose_source_packets: self.loose_source_packets.load(),
+            psk_mismatches: self.psk_mismatches.load(),
+            drops: DropReason::ALL
+                .iter()
+                .map(|&r| (r, self.drops[r as usize].load()))
+                .filter(|&(_, n)| n > 0)
+                .collect(),
+        }
+    }
+}
+
+/// Counters of a peer or the whole interface.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CountersOut {
+    /// Received packets, including handshake messages.
+    pub rx_packets: u64,
+    /// Sent packets, including handshake messages.
+    pub tx_packets: u64,
+    pub handshake_initiations_sent: u64,
+    pub handshake_initiations_received: u64,
+    /// Handshake messages that fail to authenticate or decrypt, or are
+    /// replayed.
+    pub handshake_failures: u64,
+    /// Packets delayed because of the rate limit.
+    pub shaped_packets: u64,
+    /// Packets accepted from sources outside the peer's allowed IPs, because
+    /// its source filter is loose or off.
+    pub loose_source_packets: u64,
+    /// Handshake responses that fail to decrypt, most likely because the
+    /// pre-shared keys differ.
+    pub psk_mismatches: u64,
+    /// Dropped packets by reason. Reasons with no drops are omitted.
+    pub drops: BTreeMap<DropReason, u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_reasons() {
+        for (i, &r) in DropReason::ALL.iter().enumerate() {
+            assert_eq!(r as usize, i);
+            assert_eq!(DropReason::from_name(r.name()), Some(r));
+        }
+
+        let c = Counters::new();
+        c.count_drop(DropReason::NoRoute);
+        c.count_drop(DropReason::NoRoute);
+        assert_eq!(
+            c.get().drops.into_iter().collect::<Vec<_>>(),
+            [(DropReason::NoRoute, 2)]
+        );
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+// The data path is a two stage pipeline. The first stage reads a batch of
+// packets, does everything that needs to happen in order (e.g. assigning
+// nonces), and hands the batch to `process_in_parallel`, which splits it
+// across worker tasks. The returned future is then sent through a bounded
+// channel to the second stage, which awaits them one by one and writes the
+// results out. So packets leave in exactly the order they came in, while the
+// first stage can move on to the next batch.
+
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// How many batches can be in flight between the two stages.
+pub const PIPELINE_DEPTH: usize = 8;
+
+/// A batch that is being processed. Resolves to the processed items, in order.
+pub type PendingBatch<T> = BoxFuture<'static, Vec<T>>;
+
+/// Create the channel between the two stages.
+pub fn pipeline<T>() -> (Sender<PendingBatch<T>>, Receiver<PendingBatch<T>>) {
+    channel(PIPELINE_DEPTH)
+}
+
+/// Run `f` on every item, splitting `items` across up to `workers` tasks.
+///
+/// With one worker (or one item), `f` is run inline and the returned future is
+/// already complete.
+pub fn process_in_parallel<T, F>(mut items: Vec<T>, workers: usize, f: F) -> PendingBatch<T>
+where
+    T: Send + 'static,
+    F: Fn(&mut T) + Copy + Send + 'static,
+{
+    if workers <= 1 || items.len() <= 1 {
+        items.iter_mut().for_each(f);
+        return future::ready(items).boxed();
+    }
+
+    let chunk_size = (items.len() - 1) / workers + 1;
+    let mut handles = Vec::with_capacity(workers);
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        let mut chunk: Vec<T> = items.by_ref().take(chunk_size).collect();
+        handles.push(tokio::spawn(async move {
+            chunk.iter_mut().for_each(f);
+            chunk
+        }));
+    }
+
+    async move {
+        let mut result = Vec::new();
+        for h in handles {
+            // Only fails if the runtime is shutting down.
+            if let Ok(chunk) = h.await {
+                result.extend(chunk);
+            }
+        }
+        result
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn order_is_kept() {
+        let (sender, mut receiver) = pipeline::<(u32, u32)>();
+
+        let producer = async move {
+            for batch in 0..64u32 {
+                let items = (0..32).map(|i| (batch * 32 + i, 0)).collect();
+                let pending = process_in_parallel(items, 4, |(i, out): &mut (u32, u32)| {
+                    // Uneven amount of work so that chunks finish out of order.
+                    if *i % 7 == 0 {
+                        std::thread::sleep(std::time::Duration::from_micros(100));
+                    }
+                    *out = *i * 2;
+                });
+                sender.send(pending).await.unwrap();
+            }
+        };
+
+        let consumer = async move {
+            let mut next = 0;
+            while let Some(pending) = receiver.recv().await {
+                for (i, out) in pending.await {
+                    assert_eq!(i, next);
+                    assert_eq!(out, i * 2);
+                    next += 1;
+                }
+            }
+            next
+        };
+
+        let ((), processed) = future::join(producer, consumer).await;
+        assert_eq!(processed, 64 * 32);
+    }
+
+    #[test]
+    fn one_worker_runs_inline() {
+        let pending = process_in_parallel(vec![1, 2, 3], 1, |x: &mut i32| *x += 1);
+        assert_eq!(pending.now_or_never(), Some(vec![2, 3, 4]));
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::wireguard::X25519Pubkey;
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+// Events that a slow subscriber can fall behind by before it misses some.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Something that happened to the interface or a peer.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Event {
+    /// A handshake completed, and a new session is established.
+    HandshakeCompleted {
+        peer: X25519Pubkey,
+        initiator: bool,
+    },
+    /// Handshake with the peer failed, `timeout` or `decryption_failed`.
+    HandshakeFailed {
+        peer: X25519Pubkey,
+        reason: &'static str,
+    },
+    /// The peer is heard from a new endpoint.
+    EndpointChanged {
+        peer: X25519Pubkey,
+        endpoint: SocketAddr,
+    },
+    /// All sessions with the peer expired without a new handshake.
+    SessionExpired {
+        peer: X25519Pubkey,
+    },
+    /// The pre-shared key pushed for the peer expired.
+    PskExpired {
+        peer: X25519Pubkey,
+    },
+    PeerAdded {
+        peer: X25519Pubkey,
+    },
+    PeerRemoved {
+        peer: X25519Pubkey,
+    },
+    ConfigReloaded,
+    /// Handshake messages start or stop arriving too quickly, i.e., cookie
+    /// replies start or stop being sent.
+    UnderLoad(bool),
+}
+
+/// One line of `key=value` pairs, e.g.,
+/// `event=peer_added public_key=<hex>`.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::HandshakeCompleted { peer, initiator } => write!(
+                f,
+                "event=handshake_completed public_key={} role={}",
+                hex::encode(peer),
+                if *initiator { "initiator" } else { "responder" }
+            ),
+            Event::HandshakeFailed { peer, reason } => write!(
+                f,
+                "event=handshake_failed public_key={} reason={}",
+                hex::encode(peer),
+                reason
+            ),
+            Event::EndpointChanged { peer, endpoint } => write!(
+                f,
+                "event=endpoint_changed public_key={} endpoint={}",
+                hex::encode(peer),
+                endpoint
+            ),
+            Event::SessionExpired { peer } => {
+                write!(f, "event=session_expired public_key={}", hex::encode(peer))
+            }
+            Event::PskExpired { peer } => {
+                write!(f, "event=psk_expired public_key={}", hex::encode(peer))
+            }
+            Event::PeerAdded { peer } => {
+                write!(f, "event=peer_added public_key={}", hex::encode(peer))
+            }
+            Event::PeerRemoved { peer } => {
+                write!(f, "event=peer_removed public_key={}", hex::encode(peer))
+            }
+            Event::ConfigReloaded => write!(f, "event=config_reloaded"),
+            Event::UnderLoad(under_load) => {
+                write!(f, "event=under_load under_load={}", under_load)
+            }
+        }
+    }
+}
+
+/// Sends events to all subscribers. Events are dropped if there are none.
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Events {
+            sender: broadcast::channel(EVENTS_CAPACITY).0,
+        }
+    }
+
+    pub fn send(&self, event: Event) {
+        debug!("event: {}", event);
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribe() {
+        let events = Events::new();
+        // No subscribers.
+        events.send(Event::ConfigReloaded);
+
+        let mut rx = events.subscribe();
+        events.send(Event::PeerAdded { peer: [0xab; 32] });
+        events.send(Event::UnderLoad(true));
+        assert_eq!(
+            rx.recv().await.unwrap().to_string(),
+            format!("event=peer_added public_key={}", "ab".repeat(32))
+        );
+        assert_eq!(
+            rx.recv().await.unwrap().to_string(),
+            "event=under_load under_load=true"
+        );
+    }
+}
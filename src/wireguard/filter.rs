@@ -0,0 +1,421 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::wireguard::ip_lookup_trie::Address;
+use crate::wireguard::{
+    parse_prefix, IpHeader, U64Counter, IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP,
+};
+use anyhow::{bail, Context};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Direction of packets, relative to the peer.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FilterDirection {
+    /// Packets from the peer.
+    In,
+    /// Packets to the peer.
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FilterAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FilterProtocol {
+    Tcp,
+    Udp,
+    /// ICMP or ICMPv6.
+    Icmp,
+    /// Other protocol number.
+    Other(u8),
+}
+
+/// A packet filter rule of a peer.
+///
+/// Written as `allow|deny [in|out] [PROTOCOL] [to PREFIX,...] [port PORTS,...]`,
+/// e.g. `allow in tcp to 10.0.0.1/32 port 22,8000-8080`. Rules only match
+/// destinations of packets, and are stateless, i.e. replies are filtered by
+/// rules of the other direction.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FilterRule {
+    pub action: FilterAction,
+    /// `None` means both directions.
+    pub direction: Option<FilterDirection>,
+    /// `None` means any protocol.
+    pub protocol: Option<FilterProtocol>,
+    /// Destination prefixes. Empty means any.
+    pub destinations: Vec<(IpAddr, u32)>,
+    /// Destination port ranges, inclusive. Empty means any. Packets without
+    /// ports never match rules with ports.
+    pub ports: Vec<(u16, u16)>,
+}
+
+impl FilterRule {
+    pub fn matches(&self, direction: FilterDirection, header: &IpHeader) -> bool {
+        if self.direction.map(|d| d != direction).unwrap_or(false) {
+            return false;
+        }
+        let protocol_matches = match self.protocol {
+            None => true,
+            Some(FilterProtocol::Tcp) => header.protocol == IPPROTO_TCP,
+            Some(FilterProtocol::Udp) => header.protocol == IPPROTO_UDP,
+            Some(FilterProtocol::Icmp) => {
+                header.protocol == IPPROTO_ICMP || header.protocol == IPPROTO_ICMPV6
+            }
+            Some(FilterProtocol::Other(p)) => header.protocol == p,
+        };
+        if !protocol_matches {
+            return false;
+        }
+        if !self.destinations.is_empty()
+            && !self
+                .destinations
+                .iter()
+                .any(|&p| prefix_contains(p, header.dst))
+        {
+            return false;
+        }
+        if !self.ports.is_empty() {
+            return match header.ports {
+                Some((_, port)) => self
+                    .ports
+                    .iter()
+                    .any(|&(from, to)| from <= port && port <= to),
+                None => false,
+            };
+        }
+        true
+    }
+}
+
+impl FromStr for FilterRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<FilterRule> {
+        let mut words = s.split_whitespace().peekable();
+        let action = match words.next() {
+            Some("allow") => FilterAction::Allow,
+            Some("deny") => FilterAction::Deny,
+            _ => bail!("filter rule should start with allow or deny: {}", s),
+        };
+        let direction = match words.peek() {
+            Some(&"in") => Some(FilterDirection::In),
+            Some(&"out") => Some(FilterDirection::Out),
+            _ => None,
+        };
+        if direction.is_some() {
+            words.next();
+        }
+        let protocol = match words.peek() {
+            Some(&"to") | Some(&"port") | None => None,
+            Some(&"tcp") => Some(FilterProtocol::Tcp),
+            Some(&"udp") => Some(FilterProtocol::Udp),
+            Some(&"icmp") => Some(FilterProtocol::Icmp),
+            Some(p) => Some(match p.parse::<u8>() {
+                Ok(IPPROTO_TCP) => FilterProtocol::Tcp,
+                Ok(IPPROTO_UDP) => FilterProtocol::Udp,
+                Ok(p) => FilterProtocol::Other(p),
+                Err(_) => bail!("invalid protocol: {}", p),
+            }),
+        };
+        if protocol.is_some() {
+            words.next();
+        }
+        let mut destinations = Vec::new();
+        if words.peek() == Some(&"to") {
+            words.next();
+            let list = words.next().context("missing destinations after to")?;
+            for d in list.split(',') {
+                let (addr, len) = parse_prefix(d)?;
+                destinations.push(match addr {
+                    IpAddr::V4(a) => (a.masked(len).into(), len),
+                    IpAddr::V6(a) => (a.masked(len).into(), len),
+                });
+            }
+        }
+        let mut ports = Vec::new();
+        if words.peek() == Some(&"port") {
+            words.next();
+            let list = words.next().context("missing ports after port")?;
+            for p in list.split(',') {
+                ports.push(parse_port_range(p)?);
+            }
+        }
+        if let Some(w) = words.next() {
+            bail!("unexpected {} in filter rule: {}", w, s);
+        }
+        Ok(FilterRule {
+            action,
+            direction,
+            protocol,
+            destinations,
+            ports,
+        })
+    }
+}
+
+fn prefix_contains((prefix, len): (IpAddr, u32), addr: IpAddr) -> bool {
+    match (prefix, addr) {
+        (IpAddr::V4(p), IpAddr::V4(a)) => a.masked(len) == p.masked(len),
+        (IpAddr::V6(p), IpAddr::V6(a)) => a.masked(len) == p.masked(len),
+        _ => false,
+    }
+}
+
+fn parse_port_range(s: &str) -> anyhow::Result<(u16, u16)> {
+    let parse = |p: &str| {
+        p.parse::<u16>()
+            .with_context(|| format!("invalid port: {}", s))
+    };
+    let (from, to) = match s.find('-') {
+        Some(i) => (parse(&s[..i])?, parse(&s[i + 1..])?),
+        None => (parse(s)?, parse(s)?),
+    };
+    if from > to {
+        bail!("invalid port range: {}", s);
+    }
+    Ok((from, to))
+}
+
+impl fmt::Display for FilterRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.action {
+            FilterAction::Allow => write!(f, "allow")?,
+            FilterAction::Deny => write!(f, "deny")?,
+        }
+        match self.direction {
+            Some(FilterDirection::In) => write!(f, " in")?,
+            Some(FilterDirection::Out) => write!(f, " out")?,
+            None => (),
+        }
+        match self.protocol {
+            Some(FilterProtocol::Tcp) => write!(f, " tcp")?,
+            Some(FilterProtocol::Udp) => write!(f, " udp")?,
+            Some(FilterProtocol::Icmp) => write!(f, " icmp")?,
+            Some(FilterProtocol::Other(p)) => write!(f, " {}", p)?,
+            None => (),
+        }
+        for (i, (addr, len)) in self.destinations.iter().enumerate() {
+            let sep = if i == 0 { " to " } else { "," };
+            write!(f, "{}{}/{}", sep, addr, len)?;
+        }
+        for (i, &(from, to)) in self.ports.iter().enumerate() {
+            let sep = if i == 0 { " port " } else { "," };
+            if from == to {
+                write!(f, "{}{}", sep, from)?;
+            } else {
+                write!(f, "{}{}-{}", sep, from, to)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How sources of packets from a peer are checked, i.e. reverse path
+/// filtering.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SourceFilter {
+    /// Sources must be in the allowed IPs of the peer.
+    Strict,
+    /// Sources must be in the allowed IPs of any peer, e.g. for asymmetric
+    /// routing with a backup peer.
+    Loose,
+    /// Sources are not checked against allowed IPs. If the list is not empty,
+    /// they must be in it.
+    Off(BTreeSet<(IpAddr, u32)>),
+}
+
+impl SourceFilter {
+    /// Name of the mode, e.g., `loose`.
+    pub fn mode_name(&self) -> &'static str {
+        match self {
+            SourceFilter::Strict => "strict",
+            SourceFilter::Loose => "loose",
+            SourceFilter::Off(_) => "off",
+        }
+    }
+
+    /// Whether packets from `src` are allowed, given whether it is in the
+    /// allowed IPs of the peer, and of any peer.
+    pub fn allows(&self, src: IpAddr, in_peer_ips: bool, in_any_ips: bool) -> bool {
+        match self {
+            SourceFilter::Strict => in_peer_ips,
+            SourceFilter::Loose => in_any_ips,
+            SourceFilter::Off(sources) => {
+                sources.is_empty() || sources.iter().any(|&p| prefix_contains(p, src))
+            }
+        }
+    }
+}
+
+/// Packet filter rules of a peer, and how many packets matched each.
+#[derive(Default)]
+pub struct PacketFilter {
+    rules: Vec<(FilterRule, U64Counter)>,
+}
+
+impl PacketFilter {
+    /// Set rules. Counters are reset if the rules change.
+    pub fn set_rules(&mut self, rules: Vec<FilterRule>) {
+        if !self.rules.iter().map(|(r, _)| r).eq(rules.iter()) {
+            self.rules = rules.into_iter().map(|r| (r, U64Counter::new(0))).collect();
+        }
+    }
+
+    /// Rules and how many packets matched them.
+    pub fn get(&self) -> Vec<(FilterRule, u64)> {
+        self.rules
+            .iter()
+            .map(|(r, n)| (r.clone(), n.load()))
+            .collect()
+    }
+
+    /// Whether a packet is allowed.
+    ///
+    /// The first rule that matches decides. Packets that match no rule are
+    /// allowed.
+    pub fn check(&self, direction: FilterDirection, header: &IpHeader) -> bool {
+        for (r, n) in &self.rules {
+            if r.matches(direction, header) {
+                n.fetch_add(1);
+                return r.action == FilterAction::Allow;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(protocol: u8, dst: &str, port: Option<u16>) -> IpHeader {
+        IpHeader {
+            len: 0,
+            src: "10.0.0.1".parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            protocol,
+            ports: port.map(|p| (40000, p)),
+        }
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for s in &[
+            "allow",
+            "deny in",
+            "allow out icmp",
+            "allow in tcp to 10.0.0.0/24,2001:db8::1/128 port 22,8000-8080",
+            "deny 47 port 1",
+            "deny udp to 10.0.0.1/32",
+        ] {
+            assert_eq!(s.parse::<FilterRule>().unwrap().to_string(), *s);
+        }
+        assert_eq!(
+            "allow 6 to 10.0.0.1/8 port 53-53"
+                .parse::<FilterRule>()
+                .unwrap()
+                .to_string(),
+            "allow tcp to 10.0.0.0/8 port 53"
+        );
+        for s in &[
+            "",
+            "accept",
+            "allow tcpp",
+            "allow in to",
+            "allow to 10.0.0.1/33",
+            "allow port 80-22",
+            "allow port 65536",
+            "allow port 80 to 10.0.0.1",
+        ] {
+            assert!(s.parse::<FilterRule>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let r: FilterRule = "allow in tcp to 10.0.0.0/24 port 22,8000-8080"
+            .parse()
+            .unwrap();
+        let h = header(IPPROTO_TCP, "10.0.0.2", Some(8080));
+        assert!(r.matches(FilterDirection::In, &h));
+        assert!(!r.matches(FilterDirection::Out, &h));
+        assert!(!r.matches(
+            FilterDirection::In,
+            &header(IPPROTO_UDP, "10.0.0.2", Some(22))
+        ));
+        assert!(!r.matches(
+            FilterDirection::In,
+            &header(IPPROTO_TCP, "10.0.1.2", Some(22))
+        ));
+        assert!(!r.matches(
+            FilterDirection::In,
+            &header(IPPROTO_TCP, "10.0.0.2", Some(23))
+        ));
+        assert!(!r.matches(FilterDirection::In, &header(IPPROTO_TCP, "10.0.0.2", None)));
+
+        let r: FilterRule = "deny icmp".parse().unwrap();
+        assert!(r.matches(FilterDirection::Out, &header(IPPROTO_ICMPV6, "::1", None)));
+        assert!(r.matches(FilterDirection::In, &header(IPPROTO_ICMP, "10.0.0.1", None)));
+    }
+
+    #[test]
+    fn source_filter() {
+        let src: IpAddr = "10.1.0.1".parse().unwrap();
+        assert!(SourceFilter::Strict.allows(src, true, true));
+        assert!(!SourceFilter::Strict.allows(src, false, true));
+        assert!(SourceFilter::Loose.allows(src, false, true));
+        assert!(!SourceFilter::Loose.allows(src, false, false));
+        assert!(SourceFilter::Off(BTreeSet::new()).allows(src, false, false));
+        let sources = std::iter::once(("10.1.0.0".parse().unwrap(), 16)).collect();
+        let off = SourceFilter::Off(sources);
+        assert!(off.allows(src, false, false));
+        assert!(!off.allows("10.2.0.1".parse().unwrap(), true, true));
+    }
+
+    #[test]
+    fn packet_filter() {
+        let mut f = PacketFilter::default();
+        let ssh = header(IPPROTO_TCP, "10.0.0.2", Some(22));
+        let http = header(IPPROTO_TCP, "10.0.0.2", Some(80));
+        assert!(f.check(FilterDirection::In, &ssh));
+
+        let rules: Vec<FilterRule> = vec![
+            "allow in tcp port 22".parse().unwrap(),
+            "deny in".parse().unwrap(),
+        ];
+        f.set_rules(rules.clone());
+        assert!(f.check(FilterDirection::In, &ssh));
+        assert!(!f.check(FilterDirection::In, &http));
+        assert!(!f.check(FilterDirection::In, &http));
+        assert!(f.check(FilterDirection::Out, &http));
+        assert_eq!(f.get()[0].1, 1);
+        assert_eq!(f.get()[1].1, 2);
+
+        // Same rules keep counters.
+        f.set_rules(rules);
+        assert_eq!(f.get()[1].1, 2);
+        f.set_rules(vec!["deny in".parse().unwrap()]);
+        assert_eq!(f.get()[0].1, 0);
+    }
+}
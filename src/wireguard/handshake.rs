@@ -255,6 +255,7 @@ mod tests {
             public_key: Clone::clone(resp.pubkey()),
             psk: None,
             endpoint: None,
+            local: None,
             allowed_ips: BTreeSet::new(),
             keepalive: None,
             roaming: true,
@@ -300,6 +301,7 @@ mod tests {
             public_key: *resp.pubkey(),
             psk: Some(psk),
             endpoint: None,
+            local: None,
             allowed_ips: BTreeSet::new(),
             keepalive: None,
             roaming: true,
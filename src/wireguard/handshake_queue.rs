@@ -0,0 +1,230 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::wireguard::DropReason;
+use parking_lot::RwLock;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Limits of packets waiting for handshakes to complete.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct QueueLimits {
+    /// Max packets queued for a peer.
+    pub packets: usize,
+    /// Max bytes queued for a peer.
+    pub bytes: usize,
+    /// Max packets queued for all peers.
+    pub total_packets: usize,
+    /// Max bytes queued for all peers.
+    pub total_bytes: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            packets: 16,
+            bytes: 256 * 1024,
+            total_packets: 16384,
+            total_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+/// Limits and usage of the handshake queues of all peers of an interface.
+pub struct QueueTotals {
+    limits: RwLock<QueueLimits>,
+    packets: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl QueueTotals {
+    pub fn new() -> Self {
+        QueueTotals {
+            limits: RwLock::new(QueueLimits::default()),
+            packets: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn limits(&self) -> QueueLimits {
+        *self.limits.read()
+    }
+
+    /// Set limits. Packets that are already queued are kept.
+    pub fn set_limits(&self, limits: QueueLimits) {
+        *self.limits.write() = limits;
+    }
+
+    // Reserve room for a packet, if it fits in the total limits.
+    fn reserve(&self, limits: &QueueLimits, len: usize) -> bool {
+        let packets = self.packets.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes = self.bytes.fetch_add(len, Ordering::Relaxed) + len;
+        if packets > limits.total_packets || bytes > limits.total_bytes {
+            self.release(1, len);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn release(&self, packets: usize, bytes: usize) {
+        self.packets.fetch_sub(packets, Ordering::Relaxed);
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Packets to a peer waiting for a handshake to complete.
+pub struct HandshakeQueue {
+    totals: Arc<QueueTotals>,
+    packets: VecDeque<Vec<u8>>,
+    bytes: usize,
+}
+
+impl HandshakeQueue {
+    pub fn new(totals: Arc<QueueTotals>) -> Self {
+        HandshakeQueue {
+            totals,
+            packets: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Queue a copy of `p`.
+    ///
+    /// The oldest packets are dropped to make room for it. If the queues of
+    /// all peers are full, `p` itself is dropped. Dropped packets are passed to
+    /// `on_drop` with the reason.
+    pub fn push(&mut self, p: &[u8], mut on_drop: impl FnMut(DropReason)) {
+        let limits = self.totals.limits();
+        if p.len() > limits.bytes || limits.packets == 0 {
+            on_drop(DropReason::QueueFull);
+            return;
+        }
+        while self.packets.len() >= limits.packets || self.bytes + p.len() > limits.bytes {
+            self.pop_front();
+            on_drop(DropReason::QueueFull);
+        }
+        while !self.totals.reserve(&limits, p.len()) {
+            if self.pop_front().is_none() {
+                on_drop(DropReason::QueueTotalFull);
+                return;
+            }
+            on_drop(DropReason::QueueTotalFull);
+        }
+        self.packets.push_back(p.to_vec());
+        self.bytes += p.len();
+    }
+
+    fn pop_front(&mut self) -> Option<Vec<u8>> {
+        let p = self.packets.pop_front()?;
+        self.bytes -= p.len();
+        self.totals.release(1, p.len());
+        Some(p)
+    }
+
+    /// Number of queued packets.
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Take all queued packets.
+    pub fn take_all(&mut self) -> VecDeque<Vec<u8>> {
+        self.totals.release(self.packets.len(), self.bytes);
+        self.bytes = 0;
+        std::mem::take(&mut self.packets)
+    }
+
+    pub fn clear(&mut self) {
+        self.take_all();
+    }
+}
+
+impl Drop for HandshakeQueue {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_with_limits(limits: QueueLimits) -> (HandshakeQueue, Arc<QueueTotals>) {
+        let totals = Arc::new(QueueTotals::new());
+        totals.set_limits(limits);
+        (HandshakeQueue::new(totals.clone()), totals)
+    }
+
+    #[test]
+    fn peer_limits() {
+        let (mut q, totals) = queue_with_limits(QueueLimits {
+            packets: 3,
+            bytes: 1000,
+            ..QueueLimits::default()
+        });
+        let mut drops = Vec::new();
+        for i in 0..4 {
+            q.push(&[i; 100], |r| drops.push(r));
+        }
+        assert_eq!(drops, [DropReason::QueueFull]);
+        q.push(&[4; 801], |r| drops.push(r));
+        assert_eq!(drops.len(), 3);
+        q.push(&[5; 1001], |r| drops.push(r));
+        assert_eq!(drops.len(), 4);
+
+        assert_eq!(totals.packets.load(Ordering::Relaxed), 2);
+        assert_eq!(totals.bytes.load(Ordering::Relaxed), 901);
+        let packets = q.take_all();
+        assert_eq!(packets[0], [3; 100]);
+        assert_eq!(packets[1], [4; 801]);
+        assert_eq!(totals.packets.load(Ordering::Relaxed), 0);
+        assert_eq!(totals.bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn total_limits() {
+        let totals = Arc::new(QueueTotals::new());
+        totals.set_limits(QueueLimits {
+            total_packets: 4,
+            total_bytes: 1000,
+            ..QueueLimits::default()
+        });
+        let mut a = HandshakeQueue::new(totals.clone());
+        let mut b = HandshakeQueue::new(totals.clone());
+        let mut drops = Vec::new();
+        for _ in 0..4 {
+            a.push(&[0; 100], |r| drops.push(r));
+        }
+        assert!(drops.is_empty());
+
+        // Full, and `b` has nothing to drop.
+        b.push(&[0; 100], |r| drops.push(r));
+        assert_eq!(drops, [DropReason::QueueTotalFull]);
+        assert!(b.take_all().is_empty());
+
+        // `a` drops its own oldest packets to make room.
+        a.push(&[0; 700], |r| drops.push(r));
+        assert_eq!(drops.len(), 2);
+        assert_eq!(totals.packets.load(Ordering::Relaxed), 4);
+        assert_eq!(totals.bytes.load(Ordering::Relaxed), 1000);
+
+        drop(a);
+        assert_eq!(totals.packets.load(Ordering::Relaxed), 0);
+        assert_eq!(totals.bytes.load(Ordering::Relaxed), 0);
+    }
+}
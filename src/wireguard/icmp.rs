@@ -0,0 +1,339 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+//! ICMP errors for packets that can not be sent through the tunnel.
+//!
+//! They are written back to the tun device, so applications fail fast instead
+//! of waiting for timeouts.
+
+use crate::wireguard::{checksum_no_fold, fold, TokenBucket, IPPROTO_ICMP, IPPROTO_ICMPV6};
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+/// Why a packet can not be sent.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IcmpReason {
+    /// No peer for the destination.
+    Unreachable,
+    /// The destination peer is disabled.
+    Prohibited,
+    /// The packet is larger than the tunnel MTU.
+    TooBig { mtu: u32 },
+}
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_HOST_UNREACH: u8 = 1;
+const ICMP_FRAG_NEEDED: u8 = 4;
+const ICMP_PKT_FILTERED: u8 = 13;
+
+const ICMPV6_DEST_UNREACH: u8 = 1;
+const ICMPV6_ADM_PROHIBITED: u8 = 1;
+const ICMPV6_ADDR_UNREACH: u8 = 3;
+const ICMPV6_PKT_TOOBIG: u8 = 2;
+
+// Max size of ICMP errors, from RFC 1812 and RFC 4443.
+const ICMP_MAX_LEN: usize = 576;
+const ICMPV6_MAX_LEN: usize = 1280;
+
+/// Build an ICMP or ICMPv6 error about `packet`.
+///
+/// The error is sent from `source` if it is of the same family as the packet,
+/// otherwise from the destination of the packet.
+///
+/// Returns `None` if no error should be sent, e.g., the packet is itself an
+/// ICMP error, is not the first fragment, or is sent from an address that can
+/// not be replied to. Also for IPv4 packets that are too big but can be
+/// fragmented.
+pub fn icmp_error(packet: &[u8], reason: IcmpReason, source: Option<IpAddr>) -> Option<Vec<u8>> {
+    match packet.first()? >> 4 {
+        4 => icmp4_error(packet, reason, source),
+        6 => icmp6_error(packet, reason, source),
+        _ => None,
+    }
+}
+
+fn icmp4_error(packet: &[u8], reason: IcmpReason, source: Option<IpAddr>) -> Option<Vec<u8>> {
+    if packet.len() < 20 {
+        return None;
+    }
+    let ihl = usize::from(packet[0] & 0x0f) * 4;
+    if ihl < 20 || packet.len() < ihl {
+        return None;
+    }
+    let flags_and_offset = u16::from_be_bytes([packet[6], packet[7]]);
+    // Not the first fragment.
+    if flags_and_offset & 0x1fff != 0 {
+        return None;
+    }
+    if packet[9] == IPPROTO_ICMP {
+        match packet.get(ihl) {
+            // Echo reply, echo request and other queries.
+            Some(0) | Some(8) | Some(13..=18) => (),
+            _ => return None,
+        }
+    }
+    let src: [u8; 4] = packet[12..16].try_into().unwrap();
+    let src = Ipv4Addr::from(src);
+    let dst: [u8; 4] = packet[16..20].try_into().unwrap();
+    let dst = Ipv4Addr::from(dst);
+    if src.is_unspecified() || src.is_multicast() || src.is_broadcast() {
+        return None;
+    }
+    if dst.is_multicast() || dst.is_broadcast() {
+        return None;
+    }
+
+    let (code, mtu) = match reason {
+        IcmpReason::Unreachable => (ICMP_HOST_UNREACH, 0),
+        IcmpReason::Prohibited => (ICMP_PKT_FILTERED, 0),
+        IcmpReason::TooBig { mtu } => {
+            // DF not set, the packet can be fragmented.
+            if flags_and_offset & 0x4000 == 0 {
+                return None;
+            }
+            (ICMP_FRAG_NEEDED, std::cmp::min(mtu, 0xffff) as u16)
+        }
+    };
+    let source = match source {
+        Some(IpAddr::V4(s)) => s,
+        _ => dst,
+    };
+
+    let quote = &packet[..std::cmp::min(packet.len(), ICMP_MAX_LEN - 28)];
+    let len = 28 + quote.len();
+    let mut out = vec![0u8; len];
+    out[0] = 0x45;
+    // Internetwork control, like the kernel.
+    out[1] = 0xc0;
+    out[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    out[8] = 64;
+    out[9] = IPPROTO_ICMP;
+    out[12..16].copy_from_slice(&source.octets());
+    out[16..20].copy_from_slice(&src.octets());
+    let c = !fold(checksum_no_fold(&out[..20], 0));
+    out[10..12].copy_from_slice(&c.to_be_bytes());
+
+    out[20] = ICMP_DEST_UNREACH;
+    out[21] = code;
+    out[26..28].copy_from_slice(&mtu.to_be_bytes());
+    out[28..].copy_from_slice(quote);
+    let c = !fold(checksum_no_fold(&out[20..], 0));
+    out[22..24].copy_from_slice(&c.to_be_bytes());
+    Some(out)
+}
+
+fn icmp6_error(packet: &[u8], reason: IcmpReason, source: Option<IpAddr>) -> Option<Vec<u8>> {
+    if packet.len() < 40 {
+        return None;
+    }
+    if packet[6] == IPPROTO_ICMPV6 {
+        match packet.get(40) {
+            // Informational messages.
+            Some(&t) if t >= 128 => (),
+            _ => return None,
+        }
+    }
+    let src: [u8; 16] = packet[8..24].try_into().unwrap();
+    let src = Ipv6Addr::from(src);
+    let dst: [u8; 16] = packet[24..40].try_into().unwrap();
+    let dst = Ipv6Addr::from(dst);
+    if src.is_unspecified() || src.is_multicast() {
+        return None;
+    }
+
+    let (typ, code, mtu) = match reason {
+        IcmpReason::Unreachable => {
+            if dst.is_multicast() {
+                return None;
+            }
+            (ICMPV6_DEST_UNREACH, ICMPV6_ADDR_UNREACH, 0)
+        }
+        IcmpReason::Prohibited => {
+            if dst.is_multicast() {
+                return None;
+            }
+            (ICMPV6_DEST_UNREACH, ICMPV6_ADM_PROHIBITED, 0)
+        }
+        // Packet too big is also sent for multicast destinations.
+        IcmpReason::TooBig { mtu } => (ICMPV6_PKT_TOOBIG, 0, mtu),
+    };
+    let source = match source {
+        Some(IpAddr::V6(s)) => s,
+        _ => dst,
+    };
+
+    let quote = &packet[..std::cmp::min(packet.len(), ICMPV6_MAX_LEN - 48)];
+    let len = 48 + quote.len();
+    let mut out = vec![0u8; len];
+    out[0] = 0x60;
+    out[4..6].copy_from_slice(&((len - 40) as u16).to_be_bytes());
+    out[6] = IPPROTO_ICMPV6;
+    out[7] = 64;
+    out[8..24].copy_from_slice(&source.octets());
+    out[24..40].copy_from_slice(&src.octets());
+
+    out[40] = typ;
+    out[41] = code;
+    out[44..48].copy_from_slice(&mtu.to_be_bytes());
+    out[48..].copy_from_slice(quote);
+    let pseudo_header =
+        checksum_no_fold(&out[8..40], (len - 40) as u64 + u64::from(IPPROTO_ICMPV6));
+    let c = !fold(checksum_no_fold(&out[40..], pseudo_header));
+    out[42..44].copy_from_slice(&c.to_be_bytes());
+    Some(out)
+}
+
+/// Limit how many ICMP errors are sent.
+pub struct IcmpRateLimiter {
+    bucket: TokenBucket,
+}
+
+impl IcmpRateLimiter {
+    /// Allow `per_sec` errors per second, and bursts of the same size.
+    pub fn new(per_sec: u32, now: Instant) -> Self {
+        Self {
+            bucket: TokenBucket::new(per_sec.into(), per_sec.into(), now),
+        }
+    }
+
+    /// Returns whether an error can be sent at `now`.
+    pub fn check(&mut self, now: Instant) -> bool {
+        self.bucket.take(1, now).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard::parse_ip_packet;
+    use std::time::Duration;
+
+    fn ipv4_packet(proto: u8, flags: u8, len: usize) -> Vec<u8> {
+        let mut p = vec![0u8; len];
+        p[0] = 0x45;
+        p[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        p[6] = flags;
+        p[8] = 64;
+        p[9] = proto;
+        p[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        p[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        p
+    }
+
+    fn ipv6_packet(next_header: u8, len: usize) -> Vec<u8> {
+        let mut p = vec![0u8; len];
+        p[0] = 0x60;
+        p[4..6].copy_from_slice(&((len - 40) as u16).to_be_bytes());
+        p[6] = next_header;
+        p[7] = 64;
+        p[8..24].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        p[24..40].copy_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        p
+    }
+
+    #[test]
+    fn unreachable_v4() {
+        let p = ipv4_packet(17, 0, 1000);
+        let e = icmp_error(&p, IcmpReason::Unreachable, None).unwrap();
+        assert_eq!(e.len(), ICMP_MAX_LEN);
+        let h = parse_ip_packet(&e).unwrap();
+        assert_eq!(usize::from(h.len), e.len());
+        // From the original destination.
+        assert_eq!(h.src, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(h.dst, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(fold(checksum_no_fold(&e[..20], 0)), 0xffff);
+        assert_eq!(fold(checksum_no_fold(&e[20..], 0)), 0xffff);
+        assert_eq!(&e[20..22], &[ICMP_DEST_UNREACH, ICMP_HOST_UNREACH]);
+        assert_eq!(&e[28..], &p[..ICMP_MAX_LEN - 28]);
+
+        let source = "192.168.0.1".parse().unwrap();
+        let e = icmp_error(&p, IcmpReason::Unreachable, Some(source)).unwrap();
+        assert_eq!(parse_ip_packet(&e).unwrap().src, source);
+    }
+
+    #[test]
+    fn prohibited() {
+        let e = icmp_error(&ipv4_packet(17, 0, 100), IcmpReason::Prohibited, None).unwrap();
+        assert_eq!(&e[20..22], &[ICMP_DEST_UNREACH, ICMP_PKT_FILTERED]);
+        assert_eq!(fold(checksum_no_fold(&e[20..], 0)), 0xffff);
+
+        let e = icmp_error(&ipv6_packet(17, 100), IcmpReason::Prohibited, None).unwrap();
+        assert_eq!(&e[40..42], &[ICMPV6_DEST_UNREACH, ICMPV6_ADM_PROHIBITED]);
+    }
+
+    #[test]
+    fn frag_needed_v4() {
+        let reason = IcmpReason::TooBig { mtu: 1400 };
+        // Without DF, no error.
+        assert!(icmp_error(&ipv4_packet(6, 0, 1500), reason, None).is_none());
+
+        let p = ipv4_packet(6, 0x40, 1500);
+        let e = icmp_error(&p, reason, None).unwrap();
+        assert_eq!(&e[20..22], &[ICMP_DEST_UNREACH, ICMP_FRAG_NEEDED]);
+        assert_eq!(&e[26..28], &1400u16.to_be_bytes());
+        assert_eq!(fold(checksum_no_fold(&e[20..], 0)), 0xffff);
+    }
+
+    #[test]
+    fn no_error_for_errors() {
+        let mut p = ipv4_packet(IPPROTO_ICMP, 0, 100);
+        p[20] = ICMP_DEST_UNREACH;
+        assert!(icmp_error(&p, IcmpReason::Unreachable, None).is_none());
+        // Echo request is OK.
+        p[20] = 8;
+        assert!(icmp_error(&p, IcmpReason::Unreachable, None).is_some());
+
+        let mut p = ipv6_packet(IPPROTO_ICMPV6, 100);
+        p[40] = ICMPV6_PKT_TOOBIG;
+        assert!(icmp_error(&p, IcmpReason::Unreachable, None).is_none());
+        // Echo request is OK.
+        p[40] = 128;
+        assert!(icmp_error(&p, IcmpReason::Unreachable, None).is_some());
+
+        // Non-first fragment.
+        let mut p = ipv4_packet(17, 0, 100);
+        p[7] = 1;
+        assert!(icmp_error(&p, IcmpReason::Unreachable, None).is_none());
+    }
+
+    #[test]
+    fn packet_too_big_v6() {
+        let p = ipv6_packet(17, 2000);
+        let e = icmp_error(&p, IcmpReason::TooBig { mtu: 1380 }, None).unwrap();
+        assert_eq!(e.len(), ICMPV6_MAX_LEN);
+        let h = parse_ip_packet(&e).unwrap();
+        assert_eq!(usize::from(h.len), e.len());
+        assert_eq!(h.src, "2001:db8::2".parse::<IpAddr>().unwrap());
+        assert_eq!(h.dst, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(&e[40..42], &[ICMPV6_PKT_TOOBIG, 0]);
+        assert_eq!(&e[44..48], &1380u32.to_be_bytes());
+        let pseudo_header = checksum_no_fold(&e[8..40], (e.len() - 40) as u64 + 58);
+        assert_eq!(fold(checksum_no_fold(&e[40..], pseudo_header)), 0xffff);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let now = Instant::now();
+        let mut l = IcmpRateLimiter::new(10, now);
+        for _ in 0..10 {
+            assert!(l.check(now));
+        }
+        assert!(!l.check(now));
+        assert!(l.check(now + Duration::from_millis(100)));
+    }
+}
@@ -15,11 +15,37 @@
 // You should have received a copy of the GNU General Public License
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
+use anyhow::Context;
 use std::convert::TryInto;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
 
-/// Parse an IPv4/v6 packet, returns total length, source, destination.
-pub fn parse_ip_packet(packet: &[u8]) -> Result<(u16, IpAddr, IpAddr), ()> {
+/// Header fields of an IPv4/v6 packet.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IpHeader {
+    /// Total length.
+    pub len: u16,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// Upper layer protocol, after IPv6 extension headers.
+    pub protocol: u8,
+    /// Source and destination ports of TCP, UDP and SCTP. `None` for other
+    /// protocols, non-first fragments and truncated headers.
+    pub ports: Option<(u16, u16)>,
+}
+
+pub const IPPROTO_HOPOPTS: u8 = 0;
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const IPPROTO_ROUTING: u8 = 43;
+pub const IPPROTO_FRAGMENT: u8 = 44;
+pub const IPPROTO_AH: u8 = 51;
+pub const IPPROTO_ICMPV6: u8 = 58;
+pub const IPPROTO_DSTOPTS: u8 = 60;
+pub const IPPROTO_SCTP: u8 = 132;
+
+/// Parse an IPv4/v6 packet.
+pub fn parse_ip_packet(packet: &[u8]) -> Result<IpHeader, ()> {
     if packet.len() < 20 {
         return Err(());
     }
@@ -34,13 +60,30 @@ pub fn parse_ip_packet(packet: &[u8]) -> Result<(u16, IpAddr, IpAddr), ()> {
         let src = Ipv4Addr::from(addr);
         addr.copy_from_slice(&packet[16..20]);
         let dst = Ipv4Addr::from(addr);
-        Ok((len, From::from(src), From::from(dst)))
+        let protocol = packet[9];
+        let header_len = usize::from(packet[0] & 0x0f) * 4;
+        let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+        let ports = if fragment_offset == 0 {
+            parse_ports(protocol, packet.get(header_len..).unwrap_or(&[]))
+        } else {
+            None
+        };
+        Ok(IpHeader {
+            len,
+            src: src.into(),
+            dst: dst.into(),
+            protocol,
+            ports,
+        })
     } else if v == 6 {
         // IPv6.
         if packet.len() < 40 {
             return Err(());
         }
-        let len = u16::from_be_bytes(packet[4..6].try_into().unwrap());
+        // Payload length, plus the fixed header.
+        let len = u16::from_be_bytes(packet[4..6].try_into().unwrap())
+            .checked_add(40)
+            .ok_or(())?;
         if packet.len() < len as usize {
             return Err(());
         }
@@ -49,12 +92,74 @@ pub fn parse_ip_packet(packet: &[u8]) -> Result<(u16, IpAddr, IpAddr), ()> {
         let src = Ipv6Addr::from(addr);
         addr.copy_from_slice(&packet[24..40]);
         let dst = Ipv6Addr::from(addr);
-        Ok((len, From::from(src), From::from(dst)))
+
+        // Skip extension headers.
+        let mut protocol = packet[6];
+        let mut offset = 40;
+        let mut first_fragment = true;
+        while let Some(ext) = packet.get(offset..offset + 8) {
+            let ext_len = match protocol {
+                IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_DSTOPTS => {
+                    (usize::from(ext[1]) + 1) * 8
+                }
+                IPPROTO_AH => (usize::from(ext[1]) + 2) * 4,
+                IPPROTO_FRAGMENT => {
+                    first_fragment = u16::from_be_bytes([ext[2], ext[3]]) >> 3 == 0;
+                    8
+                }
+                _ => break,
+            };
+            protocol = ext[0];
+            offset += ext_len;
+        }
+        let ports = if first_fragment {
+            parse_ports(protocol, packet.get(offset..).unwrap_or(&[]))
+        } else {
+            None
+        };
+        Ok(IpHeader {
+            len,
+            src: src.into(),
+            dst: dst.into(),
+            protocol,
+            ports,
+        })
     } else {
         Err(())
     }
 }
 
+fn parse_ports(protocol: u8, payload: &[u8]) -> Option<(u16, u16)> {
+    match protocol {
+        IPPROTO_TCP | IPPROTO_UDP | IPPROTO_SCTP if payload.len() >= 4 => Some((
+            u16::from_be_bytes([payload[0], payload[1]]),
+            u16::from_be_bytes([payload[2], payload[3]]),
+        )),
+        _ => None,
+    }
+}
+
+/// Internet checksum of `b`, added to `initial`, not folded.
+pub fn checksum_no_fold(b: &[u8], initial: u64) -> u64 {
+    let mut sum = initial;
+    let mut chunks = b.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u64::from(u16::from_be_bytes([c[0], c[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u64::from(*last) << 8;
+    }
+    sum
+}
+
+/// Fold a checksum to 16 bits.
+pub fn fold(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+    sum as u16
+}
+
 /// Convert IPv4 address to IPv4-mapped IPv6 address.
 pub fn map_ipv4_to_ipv6(addr: SocketAddr) -> SocketAddrV6 {
     match addr {
@@ -63,15 +168,41 @@ pub fn map_ipv4_to_ipv6(addr: SocketAddr) -> SocketAddrV6 {
     }
 }
 
+/// Whether `ip` is an IPv4-mapped IPv6 address.
+pub fn is_ipv4_mapped(ip: &Ipv6Addr) -> bool {
+    ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff]
+}
+
 /// Convert IPv4-mapped IPv6 address back to IPv4.
 pub fn unmap_ipv4_from_ipv6(addr: SocketAddrV6) -> SocketAddr {
-    if addr.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff] {
+    if is_ipv4_mapped(addr.ip()) {
         (addr.ip().to_ipv4().unwrap(), addr.port()).into()
     } else {
         addr.into()
     }
 }
 
+/// Parse an IP prefix, e.g. `10.0.0.0/8`. Without a prefix length, it is a
+/// single address.
+pub fn parse_prefix(s: &str) -> anyhow::Result<(IpAddr, u32)> {
+    let mut parts = s.splitn(2, '/');
+    let addr: IpAddr = parts
+        .next()
+        .unwrap()
+        .parse()
+        .with_context(|| format!("invalid IP prefix: {}", s))?;
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let len = match parts.next() {
+        Some(len) => len
+            .parse()
+            .ok()
+            .filter(|&l| l <= max_len)
+            .with_context(|| format!("invalid prefix length: {}", s))?,
+        None => max_len,
+    };
+    Ok((addr, len))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -87,4 +218,73 @@ mod tests {
         let addr = "[::1]:7819".parse().unwrap();
         assert_eq!(addr, unmap_ipv4_from_ipv6(map_ipv4_to_ipv6(addr)));
     }
+
+    #[test]
+    fn ipv6_total_length() {
+        let mut p = vec![0u8; 60];
+        p[0] = 0x60;
+        p[4..6].copy_from_slice(&20u16.to_be_bytes());
+        assert_eq!(parse_ip_packet(&p).unwrap().len, 60);
+        // Truncated.
+        assert!(parse_ip_packet(&p[..59]).is_err());
+        // Payload length too big to add the header to.
+        p[4..6].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(parse_ip_packet(&p).is_err());
+    }
+
+    #[test]
+    fn parse_ipv4() {
+        let mut p = vec![0u8; 28];
+        p[0] = 0x45;
+        p[2..4].copy_from_slice(&28u16.to_be_bytes());
+        p[9] = IPPROTO_UDP;
+        p[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        p[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        p[20..24].copy_from_slice(&[0x30, 0x39, 0, 53]);
+        assert_eq!(
+            parse_ip_packet(&p),
+            Ok(IpHeader {
+                len: 28,
+                src: "10.0.0.1".parse().unwrap(),
+                dst: "10.0.0.2".parse().unwrap(),
+                protocol: IPPROTO_UDP,
+                ports: Some((12345, 53)),
+            })
+        );
+
+        // Non-first fragment.
+        p[7] = 1;
+        assert_eq!(parse_ip_packet(&p).unwrap().ports, None);
+        // Truncated.
+        p[7] = 0;
+        assert_eq!(parse_ip_packet(&p[..22]).unwrap().ports, None);
+    }
+
+    #[test]
+    fn parse_ipv6() {
+        // TCP after hop-by-hop options and a first fragment header.
+        let mut p = vec![0u8; 60];
+        p[0] = 0x60;
+        p[4..6].copy_from_slice(&20u16.to_be_bytes());
+        p[6] = IPPROTO_HOPOPTS;
+        p[8..24].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        p[24..40].copy_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        p[40] = IPPROTO_FRAGMENT;
+        p[48] = IPPROTO_TCP;
+        p[51] = 1;
+        p[56..60].copy_from_slice(&[0, 80, 1, 187]);
+        let h = parse_ip_packet(&p).unwrap();
+        assert_eq!(h.len, 60);
+        assert_eq!(h.dst, "2001:db8::2".parse::<IpAddr>().unwrap());
+        assert_eq!(h.protocol, IPPROTO_TCP);
+        assert_eq!(h.ports, Some((80, 443)));
+
+        // Non-first fragment.
+        p[50] = 1;
+        let h = parse_ip_packet(&p).unwrap();
+        assert_eq!(h.protocol, IPPROTO_TCP);
+        assert_eq!(h.ports, None);
+
+        assert!(parse_ip_packet(&p[..59]).is_err());
+    }
 }
@@ -15,18 +15,15 @@
 // You should have received a copy of the GNU General Public License
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
-use tokio::time::Instant;
+use crate::wireguard::TokenBucket;
+use std::time::Instant;
 
 /// Monitors the frequency of handshake messages and determine whether
 /// they are arriving too quickly.
 ///
 /// Implemented with a (deep) token bucket.
 pub struct LoadMonitor {
-    // Constant. How many messages are allowed every seconds.
-    freq: u32,
-    // Scaled to 10^9, to match timer precision.
-    bucket: u64,
-    last_check: Instant,
+    bucket: TokenBucket,
     under_load: bool,
 }
 
@@ -40,39 +37,32 @@ impl LoadMonitor {
     ///
     /// If there are less than `freq` messages per second, it will slowly
     /// but eventually determine that we are no longer under load.
-    pub fn new(freq: u32) -> Self {
+    pub fn new(freq: u32, now: Instant) -> Self {
+        let freq = u64::from(freq);
         LoadMonitor {
-            freq,
-            bucket: CAP_RATIO * u64::from(freq) * NANOS_PER_SEC,
-            last_check: Instant::now(),
+            bucket: TokenBucket::new(freq, CAP_RATIO * freq, now),
             under_load: false,
         }
     }
 
+    pub fn under_load(&self) -> bool {
+        self.under_load
+    }
+
     /// Call this when receiving a message.
     ///
     /// Returns whether we are under load.
-    pub fn check(&mut self) -> bool {
-        let freq = u64::from(self.freq);
-        let cap = CAP_RATIO * freq * NANOS_PER_SEC;
-
-        let now = Instant::now();
-        let passed = now.duration_since(self.last_check);
-        let bucket_add =
-            (passed.as_secs() * freq * NANOS_PER_SEC) + u64::from(passed.subsec_nanos()) * freq;
-        self.last_check = now;
-        self.bucket = std::cmp::min(cap, self.bucket + bucket_add);
-
-        self.bucket = self.bucket.saturating_sub(NANOS_PER_SEC);
-
-        // println!("bucket: {}", self.bucket as f64 / NANOS_PER_SEC as f64);
+    pub fn check(&mut self, now: Instant) -> bool {
+        let _ = self.bucket.take(1, now);
+        let cap = self.bucket.capacity();
+        let tokens = self.bucket.tokens(now);
 
         if self.under_load {
-            if self.bucket >= 7 * cap / 8 {
+            if tokens >= 7 * cap / 8 {
                 self.under_load = false;
                 debug!("No longer under load.");
             }
-        } else if self.bucket <= 3 * cap / 4 {
+        } else if tokens <= 3 * cap / 4 {
             self.under_load = true;
             debug!("Under load!");
         }
@@ -81,26 +71,22 @@ impl LoadMonitor {
     }
 }
 
-const NANOS_PER_SEC: u64 = 1_000_000_000;
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread::sleep;
     use std::time::Duration;
 
     #[test]
     fn load_monitor() {
-        let mut u = LoadMonitor::new(100);
+        let now = Instant::now();
+        let mut u = LoadMonitor::new(100, now);
 
         for _ in 0..110 {
-            u.check();
+            u.check(now);
         }
 
-        assert!(u.check());
-
-        sleep(Duration::from_secs(1));
+        assert!(u.check(now));
 
-        assert!(!u.check());
+        assert!(!u.check(now + Duration::from_secs(1)));
     }
 }
@@ -0,0 +1,176 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::wireguard::Clock;
+use fnv::FnvHashMap;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use parking_lot::Mutex;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// A clock that only moves forward with `advance`.
+pub struct ManualClock {
+    start: Instant,
+    state: Arc<Mutex<ManualClockState>>,
+}
+
+struct ManualClockState {
+    elapsed: Duration,
+    next_id: u64,
+    // Tasks sleeping on the clock, and their deadlines.
+    sleepers: FnvHashMap<u64, (Instant, Waker)>,
+}
+
+// Yields in `ManualClock::settle`. Enough for a timer to fire, and for the
+// tasks its action wakes to run.
+const SETTLE_YIELDS: usize = 32;
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            start: Instant::now(),
+            state: Arc::new(Mutex::new(ManualClockState {
+                elapsed: Duration::from_secs(0),
+                next_id: 0,
+                sleepers: FnvHashMap::default(),
+            })),
+        }
+    }
+
+    /// Move the clock forward, and wake up sleepers that are due.
+    ///
+    /// They run when the test yields to the runtime, e.g., with `settle`.
+    pub fn advance(&self, duration: Duration) {
+        let mut wakers = Vec::new();
+        {
+            let mut state = self.state.lock();
+            state.elapsed += duration;
+            let now = self.start + state.elapsed;
+            state.sleepers.retain(|_, (deadline, waker)| {
+                if *deadline <= now {
+                    wakers.push(waker.clone());
+                }
+                *deadline > now
+            });
+        }
+        for w in wakers {
+            w.wake();
+        }
+    }
+
+    /// Yield to the runtime, so that tasks woken by `advance`, and the tasks
+    /// they wake in turn, run.
+    ///
+    /// Only works on the current thread runtime, i.e., in `#[tokio::test]`.
+    pub async fn settle(&self) {
+        for _ in 0..SETTLE_YIELDS {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.state.lock().elapsed
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let id = {
+            let mut state = self.state.lock();
+            state.next_id += 1;
+            state.next_id
+        };
+        ManualSleep {
+            id,
+            deadline,
+            start: self.start,
+            state: self.state.clone(),
+        }
+        .boxed()
+    }
+}
+
+// Registers one waker per sleep, replaced on every poll, and removed when the
+// sleep is dropped.
+struct ManualSleep {
+    id: u64,
+    deadline: Instant,
+    start: Instant,
+    state: Arc<Mutex<ManualClockState>>,
+}
+
+impl Future for ManualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock();
+        if self.start + state.elapsed >= self.deadline {
+            state.sleepers.remove(&self.id);
+            Poll::Ready(())
+        } else {
+            state
+                .sleepers
+                .insert(self.id, (self.deadline, cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for ManualSleep {
+    fn drop(&mut self) {
+        self.state.lock().sleepers.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new();
+        let t0 = clock.now();
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        assert!((&mut sleep).now_or_never().is_none());
+        // One waker per sleeper, however many times it is polled.
+        assert!((&mut sleep).now_or_never().is_none());
+        assert_eq!(clock.state.lock().sleepers.len(), 1);
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(clock.now() - t0, Duration::from_secs(9));
+        assert!((&mut sleep).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(sleep.now_or_never().is_some());
+        assert!(clock.state.lock().sleepers.is_empty());
+
+        // Removed when dropped.
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        assert!((&mut sleep).now_or_never().is_none());
+        drop(sleep);
+        assert!(clock.state.lock().sleepers.is_empty());
+    }
+}
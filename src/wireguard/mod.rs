@@ -20,12 +20,26 @@
 /// Anti-Replay algorithm.
 #[doc(hidden)]
 pub mod anti_replay;
+/// Clock of protocol timers.
+mod clock;
 /// Cookie reply messages generation and parsing.
 #[doc(hidden)]
 pub mod cookie;
+/// Packet and drop counters.
+mod counters;
+/// Parallel encryption and decryption.
+mod crypto_pool;
+/// Events of the interface and peers, for IPC subscribers.
+mod events;
+/// Per-peer packet filter rules and source filtering.
+mod filter;
 /// Handshake messages generation and parsing.
 #[doc(hidden)]
 pub mod handshake;
+/// Packets waiting for handshakes.
+mod handshake_queue;
+/// ICMP errors.
+mod icmp;
 /// IP packet parsing.
 mod ip;
 #[doc(hidden)]
@@ -33,19 +47,44 @@ pub mod ip_lookup_trie;
 /// Determine load.
 #[doc(hidden)]
 pub mod load_monitor;
+/// A clock for tests.
+#[cfg(any(test, feature = "sim"))]
+mod manual_clock;
+/// Datagram socket abstraction.
+mod network;
+/// Packet device abstraction.
+mod packet_device;
 /// Peer state.
 mod peer_state;
+/// Path MTU discovery.
+mod pmtu;
+/// Per-peer rate limits and fair scheduling of sent packets.
+mod qos;
+/// Per-source rate limits of handshake messages.
+mod ratelimiter;
+/// Host name resolution of peer endpoints.
+mod resolver;
+/// In-memory network for tests.
+#[cfg(any(test, feature = "sim"))]
+mod sim;
 /// The timer state machine, and actual IO stuff.
 mod state;
 #[doc(hidden)]
 pub mod timer;
+/// Token buckets, for rate limits.
+mod token_bucket;
 /// Transport, i.e., sessions.
 mod transport;
 /// Common types.
 #[doc(hidden)]
 pub mod types;
 mod u64_counter;
+/// UDP socket with batched receive and send.
+mod udp_socket;
 
+/// Tun offloads on linux.
+#[cfg(target_os = "linux")]
+mod tun_offload;
 /// Tun interface support on linux and BSDs.
 mod tun_unix;
 mod tun_windows;
@@ -58,18 +97,47 @@ pub use self::tun_windows::*;
 pub mod re_exports;
 
 use self::anti_replay::*;
+pub use self::clock::{Clock, SystemClock, Timers};
 use self::cookie::*;
+use self::counters::*;
+pub use self::counters::{CountersOut, DropReason};
+use self::crypto_pool::*;
+pub use self::events::Event;
+use self::events::*;
+use self::filter::*;
+pub use self::filter::{FilterAction, FilterDirection, FilterProtocol, FilterRule, SourceFilter};
 use self::handshake::*;
+pub use self::handshake_queue::QueueLimits;
+use self::handshake_queue::*;
+use self::icmp::*;
+#[doc(hidden)]
+pub use self::ip::parse_prefix;
 use self::ip::*;
 use self::ip_lookup_trie::*;
 use self::load_monitor::*;
+#[cfg(any(test, feature = "sim"))]
+pub use self::manual_clock::ManualClock;
+pub use self::network::{DatagramSocket, Network, SystemNetwork};
+pub use self::packet_device::{ChannelPacketDevice, ChannelPacketDeviceHandle, PacketDevice};
 use self::peer_state::*;
+use self::pmtu::*;
+pub use self::qos::RateLimit;
+use self::qos::*;
+pub use self::ratelimiter::HandshakeLimits;
+use self::ratelimiter::*;
+use self::resolver::*;
+pub use self::resolver::{Resolver, SystemResolver};
+#[cfg(any(test, feature = "sim"))]
+pub use self::sim::{LinkConditions, SimHost, SimNetwork};
 use self::state::*;
-pub use self::state::{SetPeerCommand, WgState};
+pub use self::state::{SetPeerCommand, WgState, DEFAULT_RESOLVE_INTERVAL};
 use self::timer::*;
+use self::token_bucket::*;
 use self::transport::*;
 use self::types::*;
 #[doc(hidden)]
-pub use self::types::{PeerInfo, WgInfo};
+pub use self::types::{LocalAddr, PeerInfo, WgInfo};
 pub use self::types::{PeerStateOut, WgStateOut, X25519Key, X25519Pubkey};
 use self::u64_counter::U64Counter;
+use self::udp_socket::*;
+pub use self::udp_socket::{RecvMeta, SendBatch};
@@ -0,0 +1,228 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::wireguard::{BatchUdpSocket, RecvMeta, SendBatch};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// A socket that UDP datagrams are received from and sent to.
+///
+/// `BatchUdpSocket` is the usual implementation. Implement this trait to run
+/// `WgState` over something else, e.g., the in-memory `SimNetwork`.
+pub trait DatagramSocket: Send + Sync + 'static {
+    /// The address the socket is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Receive at least one packet. Packet `i` is received into `bufs[i]`, and
+    /// its length and source address is stored in `meta[i]`.
+    ///
+    /// Returns the number of packets received.
+    fn recv_batch<'a>(
+        &'a self,
+        bufs: &'a mut [Vec<u8>],
+        meta: &'a mut [RecvMeta],
+    ) -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Send a packet, from the local address `source` if it is not `None`.
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+        source: Option<IpAddr>,
+    ) -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Send all packets in `batch`.
+    ///
+    /// Packets that fail to send are skipped. Returns the first error, if
+    /// any. The default implementation sends packets one by one.
+    fn send_batch<'a>(&'a self, batch: &'a SendBatch) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            let mut result = Ok(());
+            for i in 0..batch.len() {
+                let (buf, target) = batch.get(i);
+                if let Err(e) = self.send_to(buf, target, batch.source(i)).await {
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+            result
+        }
+        .boxed()
+    }
+
+    /// Set fwmark of the socket. The default implementation does nothing.
+    fn set_fwmark(&self, fwmark: u32) -> io::Result<()> {
+        let _ = fwmark;
+        Ok(())
+    }
+
+    /// Send packets with the don't fragment bit set, regardless of the path
+    /// MTU, so that path MTU probes work. The default implementation does
+    /// nothing.
+    fn set_pmtu_probe(&self, enabled: bool) -> io::Result<()> {
+        let _ = enabled;
+        Ok(())
+    }
+}
+
+/// Where sockets of a `WgState` are bound.
+pub trait Network: Send + Sync + 'static {
+    /// Bind a socket to `addr`.
+    ///
+    /// IPv6 sockets also take IPv4-mapped addresses if `dual_stack` is true.
+    /// `fwmark` is set if it is not 0.
+    fn bind(
+        &self,
+        addr: SocketAddr,
+        dual_stack: bool,
+        fwmark: u32,
+    ) -> io::Result<Arc<dyn DatagramSocket>>;
+
+    /// Whether dual stack IPv6 sockets can be bound.
+    fn dual_stack_supported(&self) -> bool;
+}
+
+/// UDP sockets of the operating system.
+pub struct SystemNetwork;
+
+impl Network for SystemNetwork {
+    fn bind(
+        &self,
+        addr: SocketAddr,
+        dual_stack: bool,
+        fwmark: u32,
+    ) -> io::Result<Arc<dyn DatagramSocket>> {
+        let socket = prepare_socket(addr, dual_stack, fwmark)?;
+        Ok(Arc::new(BatchUdpSocket::from_std(socket)?))
+    }
+
+    fn dual_stack_supported(&self) -> bool {
+        use socket2::*;
+
+        Socket::new(Domain::IPV6, Type::DGRAM, Protocol::UDP.into())
+            .and_then(|s| s.set_only_v6(false))
+            .is_ok()
+    }
+}
+
+// Create a new socket, set IPv6 only, set fwmark, and bind.
+fn prepare_socket(
+    addr: SocketAddr,
+    dual_stack: bool,
+    fwmark: u32,
+) -> io::Result<std::net::UdpSocket> {
+    use socket2::*;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Protocol::UDP.into())?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.set_nonblocking(true)?;
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsRawSocket;
+        use std::ptr::null_mut;
+        use winapi::um::mswsock::SIO_UDP_CONNRESET;
+        use winapi::um::winsock2::WSAIoctl;
+
+        let mut bytes_returned = 0u32;
+        let mut new_behaviour = 0i32;
+
+        let r = unsafe {
+            WSAIoctl(
+                socket.as_raw_socket() as usize,
+                SIO_UDP_CONNRESET,
+                &mut new_behaviour as *mut _ as *mut _,
+                4,
+                null_mut(),
+                0,
+                &mut bytes_returned,
+                null_mut(),
+                None,
+            )
+        };
+        if r != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    socket.bind(&addr.into())?;
+    let socket: std::net::UdpSocket = socket.into();
+
+    if fwmark != 0 {
+        set_fwmark(&socket, fwmark)?;
+    }
+    Ok(socket)
+}
+
+impl DatagramSocket for BatchUdpSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        BatchUdpSocket::local_addr(self)
+    }
+
+    fn recv_batch<'a>(
+        &'a self,
+        bufs: &'a mut [Vec<u8>],
+        meta: &'a mut [RecvMeta],
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        BatchUdpSocket::recv_batch(self, bufs, meta).boxed()
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+        source: Option<IpAddr>,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        BatchUdpSocket::send_to(self, buf, target, source).boxed()
+    }
+
+    fn send_batch<'a>(&'a self, batch: &'a SendBatch) -> BoxFuture<'a, io::Result<()>> {
+        BatchUdpSocket::send_batch(self, batch).boxed()
+    }
+
+    fn set_fwmark(&self, fwmark: u32) -> io::Result<()> {
+        set_fwmark(self, fwmark)
+    }
+
+    fn set_pmtu_probe(&self, enabled: bool) -> io::Result<()> {
+        BatchUdpSocket::set_pmtu_probe(self, enabled)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_fwmark<Socket>(s: &Socket, fwmark: u32) -> io::Result<()>
+where
+    Socket: std::os::unix::io::AsRawFd,
+{
+    use nix::sys::socket::setsockopt;
+    use nix::sys::socket::sockopt::Mark;
+
+    setsockopt(s.as_raw_fd(), Mark, &fwmark).map_err(|_| std::io::Error::last_os_error())?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+#[allow(clippy::unnecessary_wraps)]
+fn set_fwmark<T>(_s: &T, _fwmark: u32) -> io::Result<()> {
+    warn!("fwmark is not supported on this platform.");
+    Ok(())
+}
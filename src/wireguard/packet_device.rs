@@ -0,0 +1,235 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{Mutex as AsyncMutex, Notify};
+
+/// A device that IP packets are read from and written to.
+///
+/// `AsyncTun` is the usual implementation. Implement this trait to feed
+/// packets to `WgState` from somewhere else, e.g., a userspace network stack.
+pub trait PacketDevice: Send + Sync + 'static {
+    /// Read a packet into `buf`, returns the length of the packet.
+    ///
+    /// Packets are expected to be plain IPv4 or IPv6 packets, without any
+    /// headers before them.
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Read a packet into `buf` if one is available right now.
+    ///
+    /// Returns an error of kind `WouldBlock` otherwise. Used to read packets
+    /// in batches. The default implementation always returns `WouldBlock`.
+    fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let _ = buf;
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    /// Write a packet.
+    fn write<'a>(&'a self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Write multiple packets.
+    ///
+    /// Packets that fail to write are skipped. Returns the first error, if
+    /// any. The default implementation writes packets one by one.
+    /// Implementations may coalesce them, e.g., TCP segments of the same flow.
+    fn write_batch<'a>(&'a self, packets: &'a [&'a [u8]]) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            let mut result = Ok(());
+            for p in packets {
+                if let Err(e) = self.write(p).await {
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+            result
+        }
+        .boxed()
+    }
+
+    /// Get the MTU of the device.
+    fn mtu(&self) -> io::Result<u32>;
+
+    /// Resolves when properties of the device, e.g., the MTU, may have
+    /// changed.
+    ///
+    /// `WgState` calls `mtu` again after it resolves.
+    fn changed(&self) -> BoxFuture<'_, ()>;
+}
+
+/// An in-memory packet device backed by channels.
+///
+/// Packets sent with `ChannelPacketDeviceHandle::tx` are read from the
+/// device, and packets written to the device are received with
+/// `ChannelPacketDeviceHandle::rx`.
+pub struct ChannelPacketDevice {
+    rx: AsyncMutex<Receiver<Vec<u8>>>,
+    tx: Sender<Vec<u8>>,
+    mtu: Arc<AtomicU32>,
+    mtu_changed: Arc<Notify>,
+}
+
+/// The other end of a `ChannelPacketDevice`.
+pub struct ChannelPacketDeviceHandle {
+    /// Send packets to be read from the device.
+    pub tx: Sender<Vec<u8>>,
+    /// Receive packets written to the device.
+    pub rx: Receiver<Vec<u8>>,
+    mtu: Arc<AtomicU32>,
+    mtu_changed: Arc<Notify>,
+}
+
+impl ChannelPacketDevice {
+    /// Create a new channel packet device with the specified MTU.
+    ///
+    /// At most `capacity` packets are buffered in each direction.
+    pub fn new(mtu: u32, capacity: usize) -> (ChannelPacketDevice, ChannelPacketDeviceHandle) {
+        let (in_tx, in_rx) = channel(capacity);
+        let (out_tx, out_rx) = channel(capacity);
+        let mtu = Arc::new(AtomicU32::new(mtu));
+        let mtu_changed = Arc::new(Notify::new());
+        let device = ChannelPacketDevice {
+            rx: AsyncMutex::new(in_rx),
+            tx: out_tx,
+            mtu: mtu.clone(),
+            mtu_changed: mtu_changed.clone(),
+        };
+        let handle = ChannelPacketDeviceHandle {
+            tx: in_tx,
+            rx: out_rx,
+            mtu,
+            mtu_changed,
+        };
+        (device, handle)
+    }
+}
+
+impl ChannelPacketDeviceHandle {
+    /// Change the MTU of the device.
+    pub fn set_mtu(&self, mtu: u32) {
+        self.mtu.store(mtu, Ordering::Relaxed);
+        self.mtu_changed.notify_one();
+    }
+}
+
+impl PacketDevice for ChannelPacketDevice {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        async move {
+            let p = self.rx.lock().await.recv().await;
+            let p =
+                p.ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "packet device closed"))?;
+            // Truncate, like a tun device does.
+            let len = std::cmp::min(p.len(), buf.len());
+            buf[..len].copy_from_slice(&p[..len]);
+            Ok(len)
+        }
+        .boxed()
+    }
+
+    fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let p = self
+            .rx
+            .try_lock()
+            .ok()
+            .and_then(|mut rx| rx.recv().now_or_never())
+            .ok_or(io::ErrorKind::WouldBlock)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "packet device closed"))?;
+        let len = std::cmp::min(p.len(), buf.len());
+        buf[..len].copy_from_slice(&p[..len]);
+        Ok(len)
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        async move {
+            self.tx
+                .send(buf.to_vec())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "packet device closed"))?;
+            Ok(buf.len())
+        }
+        .boxed()
+    }
+
+    fn mtu(&self) -> io::Result<u32> {
+        Ok(self.mtu.load(Ordering::Relaxed))
+    }
+
+    fn changed(&self) -> BoxFuture<'_, ()> {
+        self.mtu_changed.notified().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard::WgState;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    #[tokio::test]
+    async fn channel_packet_device() -> anyhow::Result<()> {
+        let (device, mut handle) = ChannelPacketDevice::new(1280, 4);
+
+        handle.tx.send(vec![1, 2, 3]).await?;
+        let mut buf = [0u8; 2];
+        assert_eq!(device.read(&mut buf).await?, 2);
+        assert_eq!(buf, [1, 2]);
+
+        assert_eq!(
+            device.try_read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        handle.tx.send(vec![7]).await?;
+        assert_eq!(device.try_read(&mut buf)?, 1);
+        assert_eq!(buf[0], 7);
+
+        device.write(&[4, 5, 6]).await?;
+        assert_eq!(handle.rx.recv().await, Some(vec![4, 5, 6]));
+
+        assert_eq!(device.mtu()?, 1280);
+        handle.set_mtu(1400);
+        timeout(Duration::from_secs(1), device.changed()).await?;
+        assert_eq!(device.mtu()?, 1400);
+
+        drop(handle);
+        assert!(device.read(&mut buf).await.is_err());
+        assert!(device.write(&[1]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wg_state_follows_mtu_changes() -> anyhow::Result<()> {
+        let (device, handle) = ChannelPacketDevice::new(1280, 4);
+        let wg = WgState::new(device)?;
+        assert_eq!(wg.mtu.load(Ordering::Relaxed), 1280);
+
+        tokio::spawn(wg.clone().task_update_mtu());
+        handle.set_mtu(1420);
+        timeout(Duration::from_secs(1), async {
+            while wg.mtu.load(Ordering::Relaxed) != 1420 {
+                sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+}
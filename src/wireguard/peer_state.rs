@@ -23,10 +23,10 @@ use rand::{thread_rng, Rng};
 use std::collections::VecDeque;
 use std::net::SocketAddrV6;
 use std::ops::Deref;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
 use tai64::TAI64N;
-use tokio::time::sleep;
 
 pub type SharedPeerState = Arc<RwLock<PeerState>>;
 
@@ -56,6 +56,16 @@ impl<T> Deref for InitLater<T> {
 
 pub struct PeerState {
     pub info: PeerInfo,
+    // Candidate endpoints, tried in order when handshakes get no response.
+    // `info.endpoint` is the active one.
+    pub endpoints: Vec<SocketAddrV6>,
+    // Index of the candidate last used, which is gone back to when the peer
+    // roams away from the candidates and stops responding.
+    pub endpoint_index: usize,
+    // Host names that `endpoints` are resolved from, with the addresses they
+    // last resolved to.
+    pub endpoint_hosts: Vec<(String, Option<SocketAddrV6>)>,
+    pub last_resolve: Option<Instant>,
     pub last_handshake: Option<TAI64N>,
     pub cookie: Option<(Cookie, Instant)>,
     pub last_mac1: Option<[u8; 16]>,
@@ -64,11 +74,30 @@ pub struct PeerState {
 
     pub rx_bytes: U64Counter,
     pub tx_bytes: U64Counter,
-
-    pub queue: Mutex<VecDeque<Vec<u8>>>,
+    pub counters: Arc<Counters>,
+    // Counters of the interface.
+    pub wg_counters: Arc<Counters>,
+    pub qos: Arc<PeerQos>,
+    pub filter: PacketFilter,
+    pub source_filter: SourceFilter,
+    // Refuse handshakes and drop packets, but keep the config.
+    pub disabled: bool,
+    // Answer packets to a disabled peer with ICMP errors.
+    pub disabled_unreachable: bool,
+    // The pre-shared key is pushed by an external provider.
+    pub psk_rotated: bool,
+    pub psk_expires: Option<Instant>,
+    // Like disabled, until a new pre-shared key is pushed.
+    pub psk_expired: bool,
+
+    pub queue: Mutex<HandshakeQueue>,
 
     pub transports: ArrayVec<Arc<Transport>, 3>,
 
+    // Search for the path MTU to the peer.
+    pub path_mtu_discovery: bool,
+    pub pmtu: Mutex<PathMtu>,
+
     // Rekey because of send but not recv in...
     pub rekey_no_recv: InitLater<TimerHandle>,
     // Keep alive because of recv but not send in...
@@ -79,6 +108,16 @@ pub struct PeerState {
     pub stop_handshake: InitLater<TimerHandle>,
     // Clear all sessions if no new handshake in REJECT_AFTER_TIME * 3.
     pub clear: InitLater<TimerHandle>,
+    // Send the next path MTU probe.
+    pub pmtu_probe: InitLater<TimerHandle>,
+    // Resolve endpoint host names again.
+    pub resolve: InitLater<TimerHandle>,
+    // The pushed pre-shared key expires.
+    pub psk_expire: InitLater<TimerHandle>,
+
+    // Of the interface.
+    pub clock: Arc<dyn Clock>,
+    pub timers: Timers,
 }
 
 pub struct Handshake {
@@ -91,14 +130,115 @@ impl PeerState {
         self.info.endpoint
     }
 
-    pub fn set_endpoint(&mut self, a: SocketAddrV6) {
+    /// Returns whether the endpoint is changed.
+    pub fn set_endpoint(&mut self, a: SocketAddrV6) -> bool {
         assert!(self.info.roaming);
-        self.info.endpoint = Some(a)
+        if self.info.endpoint != Some(a) {
+            self.info.endpoint = Some(a);
+            self.on_endpoint_change();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Set candidate endpoints. The active endpoint is kept if it is still a
+    /// candidate.
+    pub fn set_endpoints(&mut self, endpoints: Vec<SocketAddrV6>) {
+        let endpoint = self
+            .info
+            .endpoint
+            .filter(|e| endpoints.contains(e))
+            .or_else(|| endpoints.first().copied());
+        if self.info.endpoint != endpoint {
+            debug!("{}: setting endpoint {:?}.", self.info.log_id(), endpoint);
+            self.info.endpoint = endpoint;
+            // The old local address may not reach the new endpoint.
+            self.info.local = None;
+            self.on_endpoint_change();
+        }
+        self.endpoint_index = endpoint
+            .and_then(|e| endpoints.iter().position(|&c| c == e))
+            .unwrap_or(0);
+        self.endpoints = endpoints;
+    }
+
+    /// Resolve endpoint host names again now, unless they are just resolved.
+    pub fn resolve_endpoints_soon(&self) {
+        if self.endpoint_hosts.is_empty() {
+            return;
+        }
+        if let Some(t) = self.last_resolve {
+            if self.clock.now() - t < Duration::from_secs(MIN_RESOLVE_INTERVAL) {
+                return;
+            }
+        }
+        self.resolve.adjust_and_activate(Duration::from_secs(0));
+    }
+
+    /// Switch to the next candidate endpoint, or back to the candidate last
+    /// used if the peer has roamed to another endpoint. Returns whether it is
+    /// changed.
+    pub fn next_endpoint(&mut self) -> bool {
+        let current = self
+            .info
+            .endpoint
+            .and_then(|e| self.endpoints.iter().position(|&c| c == e));
+        let next = match current {
+            Some(i) => (i + 1) % self.endpoints.len(),
+            None if !self.endpoints.is_empty() => self.endpoint_index,
+            None => return false,
+        };
+        if current == Some(next) {
+            return false;
+        }
+        self.endpoint_index = next;
+        self.info.endpoint = Some(self.endpoints[next]);
+        // The old local address may not reach the new endpoint.
+        self.info.local = None;
+        self.on_endpoint_change();
+        true
+    }
+
+    /// Path MTU of the old endpoint is no longer valid.
+    pub fn on_endpoint_change(&self) {
+        self.pmtu.lock().reset();
+        if !self.transports.is_empty() {
+            self.pmtu_probe_soon();
+        }
+    }
+
+    /// Enable or disable path MTU discovery. The interface MTU is used when
+    /// it is disabled.
+    pub fn set_path_mtu_discovery(&mut self, enabled: bool) {
+        if self.path_mtu_discovery == enabled {
+            return;
+        }
+        self.path_mtu_discovery = enabled;
+        self.pmtu.lock().reset();
+        self.pmtu_probe.de_activate();
+        if !self.transports.is_empty() {
+            self.pmtu_probe_soon();
+        }
+    }
+
+    fn pmtu_probe_soon(&self) {
+        if self.path_mtu_discovery && self.pmtu.lock().is_due() {
+            self.pmtu_probe.adjust_and_activate_secs(1);
+        }
+    }
+
+    /// Max size of inner packets to this peer, given the interface MTU.
+    pub fn effective_mtu(&self, mtu: u32) -> u32 {
+        match self.pmtu.lock().mtu() {
+            Some(m) if m < mtu => m,
+            _ => mtu,
+        }
     }
 
     pub fn get_cookie(&self) -> Option<&Cookie> {
         self.cookie?;
-        if self.cookie.as_ref().unwrap().1.elapsed() >= Duration::from_secs(120) {
+        if self.clock.now() - self.cookie.as_ref().unwrap().1 >= Duration::from_secs(120) {
             return None;
         }
         Some(&self.cookie.as_ref().unwrap().0)
@@ -106,7 +246,7 @@ impl PeerState {
 
     pub fn get_last_handshake_time(&self) -> Option<SystemTime> {
         self.transports.iter().next().map(|t| {
-            let dur = t.created.elapsed();
+            let dur = self.clock.now() - t.created;
             SystemTime::now() - dur
         })
     }
@@ -117,39 +257,107 @@ impl PeerState {
         self.transports.clear();
 
         self.queue.lock().clear();
+        self.pmtu.lock().pause();
 
         self.rekey_no_recv.de_activate();
         self.keepalive.de_activate();
         self.clear.de_activate();
+        self.pmtu_probe.de_activate();
+    }
+
+    /// Clear all sessions and stop sending anything to the peer, until it is
+    /// enabled again.
+    pub fn disable(&mut self) {
+        self.disabled = true;
+        self.clear();
+        self.stop_handshake.de_activate();
+        self.persistent_keepalive.de_activate();
+    }
+
+    pub fn enable(&mut self) {
+        self.disabled = false;
+        if self.info.keepalive.is_some() {
+            self.persistent_keepalive.adjust_and_activate_secs(5);
+        }
+    }
+
+    /// Replace the pre-shared key, keeping the current sessions. The next
+    /// handshake uses the new key.
+    ///
+    /// If `lifetime` is `Some`, the peer is treated like disabled when it
+    /// passes without a new key.
+    pub fn rotate_psk(&mut self, psk: [u8; 32], lifetime: Option<Duration>) {
+        self.info.psk = Some(psk);
+        self.psk_rotated = true;
+        self.psk_expired = false;
+        // An ongoing handshake uses the old key. It will be resent.
+        self.handshake = None;
+        match lifetime {
+            Some(l) => {
+                self.psk_expires = Some(self.clock.now() + l);
+                self.psk_expire.adjust_and_activate(l);
+            }
+            None => {
+                self.psk_expires = None;
+                self.psk_expire.de_activate();
+            }
+        }
+    }
+
+    /// Set the pre-shared key from config, clearing all sessions.
+    pub fn set_psk(&mut self, psk: Option<[u8; 32]>) {
+        self.clear();
+        self.info.psk = psk;
+        self.psk_rotated = false;
+        self.psk_expires = None;
+        self.psk_expired = false;
+        self.psk_expire.de_activate();
     }
 
     pub fn on_new_transport(&self) {
         self.stop_handshake.de_activate();
-        self.clear.adjust_and_activate_secs(3 * REJECT_AFTER_TIME);
+        self.clear
+            .adjust_and_activate(3 * self.timers.reject_after_time);
+        self.pmtu_probe_soon();
     }
 
-    /// Add `size` bytes to the received bytes counter.
+    /// Count a received packet of `size` bytes.
     pub fn count_recv(&self, size: usize) {
         self.rx_bytes.fetch_add(size as u64);
+        self.count(|c| &c.rx_packets);
     }
 
-    /// Add `size` bytes to the sent bytes counter.
+    /// Count a sent packet of `size` bytes.
     pub fn count_send(&self, size: usize) {
         self.tx_bytes.fetch_add(size as u64);
+        self.count(|c| &c.tx_packets);
+    }
+
+    /// Increment a counter of the peer and the interface.
+    pub fn count(&self, counter: impl Fn(&Counters) -> &U64Counter) {
+        counter(&self.counters).fetch_add(1);
+        counter(&self.wg_counters).fetch_add(1);
+    }
+
+    /// Count a dropped packet, for the peer and the interface.
+    pub fn count_drop(&self, reason: DropReason) {
+        self.counters.count_drop(reason);
+        self.wg_counters.count_drop(reason);
     }
 
     pub fn on_recv(&self, is_keepalive: bool) {
         self.rekey_no_recv.de_activate();
         if !is_keepalive {
             self.keepalive
-                .adjust_and_activate_if_not_activated(KEEPALIVE_TIMEOUT);
+                .adjust_and_activate_if_not_activated(self.timers.keepalive_timeout);
         }
     }
 
     pub fn on_send_transport(&self) {
         self.keepalive.de_activate();
-        self.rekey_no_recv
-            .adjust_and_activate_if_not_activated(KEEPALIVE_TIMEOUT + REKEY_TIMEOUT);
+        self.rekey_no_recv.adjust_and_activate_if_not_activated(
+            self.timers.keepalive_timeout + self.timers.rekey_timeout,
+        );
         if let Some(i) = self.info.keepalive {
             self.persistent_keepalive
                 .adjust_and_activate_secs(u64::from(i.get()));
@@ -182,7 +390,7 @@ impl PeerState {
     /// thundering herd situation.
     pub fn have_fresh_unconfirmed_transport(&self) -> bool {
         for t in &self.transports {
-            if !t.is_initiator && t.created.elapsed() < Duration::from_secs(10) {
+            if !t.is_initiator && self.clock.now() - t.created < Duration::from_secs(10) {
                 return true;
             }
         }
@@ -190,7 +398,7 @@ impl PeerState {
     }
 
     /// Find a transport to send packet.
-    pub fn find_transport_to_send(&self) -> Option<&Transport> {
+    pub fn find_transport_to_send(&self) -> Option<&Arc<Transport>> {
         for t in &self.transports {
             if t.get_should_send() {
                 return Some(t);
@@ -199,7 +407,7 @@ impl PeerState {
         None
     }
 
-    pub fn find_transport_by_id(&self, id: Id) -> Option<&Transport> {
+    pub fn find_transport_by_id(&self, id: Id) -> Option<&Arc<Transport>> {
         for t in &self.transports {
             if t.get_self_id() == id {
                 return Some(t);
@@ -210,20 +418,13 @@ impl PeerState {
 
     pub fn enqueue_packet(&self, p: &[u8]) {
         self.stop_handshake
-            .adjust_and_activate_secs(REKEY_ATTEMPT_TIME);
+            .adjust_and_activate(self.timers.rekey_attempt_time);
 
-        let mut queue = self.queue.lock();
-        while queue.len() >= QUEUE_SIZE {
-            queue.pop_front();
-        }
-        queue.push_back(p.to_vec());
+        self.queue.lock().push(p, |reason| self.count_drop(reason));
     }
 
     pub fn dequeue_all(&self) -> VecDeque<Vec<u8>> {
-        let mut queue = self.queue.lock();
-        let mut out = VecDeque::with_capacity(QUEUE_SIZE);
-        std::mem::swap(&mut out, &mut queue);
-        out
+        self.queue.lock().take_all()
     }
 }
 
@@ -238,15 +439,21 @@ pub(crate) fn wg_add_peer(wg: &Arc<WgState>, public_key: &X25519Pubkey) -> anyho
         bail!("Public key already exists.");
     }
 
+    let counters = Arc::new(Counters::new());
     let ps = PeerState {
         info: PeerInfo {
             public_key: *public_key,
             endpoint: None,
+            local: None,
             keepalive: None,
             psk: None,
             allowed_ips: Default::default(),
             roaming: true,
         },
+        endpoints: Vec::new(),
+        endpoint_index: 0,
+        endpoint_hosts: Vec::new(),
+        last_resolve: None,
         last_handshake: None,
         last_mac1: None,
         cookie: None,
@@ -254,21 +461,39 @@ pub(crate) fn wg_add_peer(wg: &Arc<WgState>, public_key: &X25519Pubkey) -> anyho
         handshake_resend_scope: None,
         rx_bytes: U64Counter::new(0),
         tx_bytes: U64Counter::new(0),
-        queue: Mutex::new(VecDeque::with_capacity(QUEUE_SIZE)),
+        counters: counters.clone(),
+        wg_counters: wg.counters.clone(),
+        qos: Arc::new(PeerQos::new(counters, wg.counters.clone())),
+        filter: PacketFilter::default(),
+        source_filter: SourceFilter::Strict,
+        disabled: false,
+        disabled_unreachable: false,
+        psk_rotated: false,
+        psk_expires: None,
+        psk_expired: false,
+        queue: Mutex::new(HandshakeQueue::new(wg.handshake_queues.clone())),
         transports: ArrayVec::new(),
+        path_mtu_discovery: false,
+        pmtu: Mutex::new(PathMtu::new()),
         rekey_no_recv: None.into(),
         keepalive: None.into(),
         stop_handshake: None.into(),
         persistent_keepalive: None.into(),
         clear: None.into(),
+        pmtu_probe: None.into(),
+        resolve: None.into(),
+        psk_expire: None.into(),
+        clock: wg.clock.clone(),
+        timers: wg.timers,
     };
     let ps = Arc::new(RwLock::new(ps));
 
     macro_rules! timer {
         ($action:expr) => {{
+            let clock = wg.clock.clone();
             let wg = Arc::downgrade(wg);
             let ps = Arc::downgrade(&ps);
-            create_timer_async(move || {
+            create_timer_async(clock, move || {
                 let wg = wg.clone();
                 let ps = ps.clone();
                 async move {
@@ -290,9 +515,13 @@ pub(crate) fn wg_add_peer(wg: &Arc<WgState>, public_key: &X25519Pubkey) -> anyho
         psw.persistent_keepalive = timer!(persistent_keepalive);
         psw.stop_handshake = timer!(stop_handshake);
         psw.clear = timer!(clear);
+        psw.pmtu_probe = timer!(pmtu_probe);
+        psw.resolve = timer!(resolve_endpoints);
+        psw.psk_expire = timer!(psk_expire);
     }
 
     pubkey_map.insert(*public_key, ps);
+    wg.events.send(Event::PeerAdded { peer: *public_key });
 
     Ok(())
 }
@@ -317,21 +546,141 @@ async fn persistent_keepalive(wg: Arc<WgState>, ps: SharedPeerState) {
         do_handshake(&wg, &ps);
     }
     let p = ps.read();
+    if p.disabled {
+        return;
+    }
     if let Some(i) = p.info.keepalive {
         p.persistent_keepalive
             .adjust_and_activate_secs(u64::from(i.get()));
     }
 }
 
-async fn stop_handshake(_: Arc<WgState>, ps: SharedPeerState) {
-    debug!("{}: timer: stop handshake.", ps.read().info.log_id());
-    ps.write().handshake_resend_scope = None;
-    ps.write().handshake = None;
+async fn stop_handshake(wg: Arc<WgState>, ps: SharedPeerState) {
+    let mut peer = ps.write();
+    debug!("{}: timer: stop handshake.", peer.info.log_id());
+    if peer.handshake_resend_scope.take().is_some() {
+        wg.events.send(Event::HandshakeFailed {
+            peer: peer.info.public_key,
+            reason: "timeout",
+        });
+    }
+    peer.handshake = None;
+}
+
+async fn psk_expire(wg: Arc<WgState>, ps: SharedPeerState) {
+    let mut peer = ps.write();
+    warn!("{}: pre-shared key expired.", peer.info.log_id());
+    wg.events.send(Event::PskExpired {
+        peer: peer.info.public_key,
+    });
+    peer.psk_expired = true;
+    peer.psk_expires = None;
+    peer.clear();
+    peer.stop_handshake.de_activate();
 }
 
-async fn clear(_: Arc<WgState>, ps: SharedPeerState) {
-    debug!("{}: timer: clear.", ps.read().info.log_id());
-    ps.write().clear();
+async fn clear(wg: Arc<WgState>, ps: SharedPeerState) {
+    let mut peer = ps.write();
+    debug!("{}: timer: clear.", peer.info.log_id());
+    peer.clear();
+    wg.events.send(Event::SessionExpired {
+        peer: peer.info.public_key,
+    });
+}
+
+async fn pmtu_probe(wg: Arc<WgState>, ps: SharedPeerState) {
+    let mtu = wg.mtu.load(Ordering::Relaxed);
+    let (out, endpoint, local) = {
+        // Lock peer.
+        let peer = ps.read();
+
+        if !peer.path_mtu_discovery {
+            return;
+        }
+        let endpoint = match peer.get_endpoint() {
+            Some(e) => e,
+            None => return,
+        };
+        let t = match peer.find_transport_to_send() {
+            Some(t) => t,
+            None => {
+                peer.pmtu.lock().pause();
+                return;
+            }
+        };
+
+        let mut pmtu = peer.pmtu.lock();
+        let (id, size) = match pmtu.next_probe(mtu) {
+            Some(p) => p,
+            None => {
+                debug!("{}: path MTU: {:?}.", peer.info.log_id(), pmtu.mtu());
+                peer.pmtu_probe
+                    .adjust_and_activate_secs(PMTU_REPROBE_INTERVAL);
+                return;
+            }
+        };
+        debug!("{}: timer: path MTU probe {}.", peer.info.log_id(), size);
+
+        let msg = pmtu_probe_message(id, size as usize);
+        let mut out = vec![0u8; msg.len() + 32];
+        if t.encrypt(&msg, &mut out).0.is_err() {
+            return;
+        }
+        // Also fires if the probe is lost.
+        peer.pmtu_probe.adjust_and_activate(PMTU_PROBE_TIMEOUT);
+
+        peer.count_send(out.len());
+        peer.on_send_keepalive();
+        (out, endpoint, peer.info.local)
+        // Release peer.
+    };
+    let _ = wg.send_to(&out, endpoint, local).await;
+}
+
+async fn resolve_endpoints(wg: Arc<WgState>, ps: SharedPeerState) {
+    let hosts: Vec<String> = {
+        // Lock peer.
+        let mut peer = ps.write();
+        debug!("{}: timer: resolve endpoints.", peer.info.log_id());
+        peer.last_resolve = Some(peer.clock.now());
+        peer.endpoint_hosts.iter().map(|(h, _)| h.clone()).collect()
+    };
+    if hosts.is_empty() {
+        return;
+    }
+
+    let resolver = wg.resolver.read().clone();
+    let mut results = Vec::with_capacity(hosts.len());
+    for h in &hosts {
+        results.push(resolve_endpoint(&*resolver, h).await);
+    }
+
+    // Lock peer.
+    let mut peer = ps.write();
+    let peer = &mut *peer;
+    // Changed while resolving.
+    if !peer.endpoint_hosts.iter().map(|(h, _)| h).eq(hosts.iter()) {
+        return;
+    }
+    for ((h, addr), r) in peer.endpoint_hosts.iter_mut().zip(results) {
+        match r {
+            Ok(a) => *addr = Some(a),
+            // Keep the old address.
+            Err(e) => warn!("{}: failed to resolve {}: {}", peer.info.log_id(), h, e),
+        }
+    }
+    let endpoints: Vec<_> = peer.endpoint_hosts.iter().filter_map(|(_, a)| *a).collect();
+    if peer.info.roaming && peer.info.endpoint.is_some() {
+        // Heard from the peer before any host name is resolved. Don't
+        // disturb it.
+    } else if !endpoints.is_empty() && endpoints != peer.endpoints {
+        peer.set_endpoints(endpoints);
+        peer.info.roaming = false;
+    }
+    let interval = wg.resolve_interval.load(Ordering::Relaxed);
+    if interval > 0 {
+        peer.resolve.adjust_and_activate_secs(interval.into());
+    }
 }
 
 /// Start handshake.
@@ -339,12 +688,13 @@ async fn clear(_: Arc<WgState>, ps: SharedPeerState) {
 /// This function takes a write lock on `peer0`.
 //
 /// Nothing happens if there is already an ongoing handshake for this peer.
-/// Nothing happens if we don't know peer endpoint.
+/// Nothing happens if we don't know peer endpoint, or the peer is disabled,
+/// or its pre-shared key has expired.
 pub fn do_handshake(wg: &Arc<WgState>, peer0: &SharedPeerState) {
     let scope = AsyncScope::new();
 
     let mut peer = peer0.write();
-    if peer.handshake_resend_scope.is_some() {
+    if peer.handshake_resend_scope.is_some() || peer.disabled || peer.psk_expired {
         return;
     }
     if peer.get_endpoint().is_none() {
@@ -353,20 +703,24 @@ pub fn do_handshake(wg: &Arc<WgState>, peer0: &SharedPeerState) {
 
     peer.handshake_resend_scope = Some(scope.clone());
     peer.stop_handshake
-        .adjust_and_activate_if_not_activated(REKEY_ATTEMPT_TIME);
+        .adjust_and_activate_if_not_activated(wg.timers.rekey_attempt_time);
     peer.clear
-        .adjust_and_activate_if_not_activated(3 * REJECT_AFTER_TIME);
+        .adjust_and_activate_if_not_activated(3 * wg.timers.reject_after_time);
 
+    let clock = wg.clock.clone();
+    let rekey_timeout = wg.timers.rekey_timeout;
     let wg = Arc::downgrade(wg);
     let peer = Arc::downgrade(peer0);
     scope.spawn_async(async move {
+        // When the last initiation is sent.
+        let mut sent_at = None;
         loop {
             // This loop is only to be breaked out when the handshake initiation fails or completes.
             // Replace with label block when `label_break_value` is stable.
             #[allow(clippy::never_loop)]
             'inner: loop {
                 if let (Some(wg), Some(peer0)) = (wg.upgrade(), peer.upgrade()) {
-                    let (init_msg, endpoint) = {
+                    let (init_msg, endpoint, local) = {
                         // Lock info.
                         let info = wg.info.read();
 
@@ -375,6 +729,23 @@ pub fn do_handshake(wg: &Arc<WgState>, peer0: &SharedPeerState) {
                         if peer.info.endpoint.is_none() {
                             break 'inner;
                         }
+                        // No response from the current endpoint, try the next one.
+                        // A cookie reply is a response: the peer is just under load.
+                        let cookie_reply = match (sent_at, &peer.cookie) {
+                            (Some(sent_at), Some((_, received_at))) => *received_at >= sent_at,
+                            _ => false,
+                        };
+                        if sent_at.is_some() && !cookie_reply {
+                            if peer.next_endpoint() {
+                                debug!(
+                                    "{}: Handshake init: switch to endpoint {}.",
+                                    peer.info.log_id(),
+                                    peer.info.endpoint.unwrap()
+                                );
+                            }
+                            // Host names may resolve to new addresses.
+                            peer.resolve_endpoints_soon();
+                        }
                         debug!("{}: Handshake init.", peer.info.log_id());
 
                         let id = Id::gen();
@@ -402,21 +773,24 @@ pub fn do_handshake(wg: &Arc<WgState>, peer0: &SharedPeerState) {
                         });
 
                         peer.count_send(init_msg.len());
+                        peer.count(|c| &c.handshake_initiations_sent);
+                        sent_at = Some(clock.now());
 
-                        (init_msg, peer.info.endpoint.unwrap())
+                        (init_msg, peer.info.endpoint.unwrap(), peer.info.local)
                     };
-                    let _ = wg.send_to(&init_msg, endpoint).await;
+                    let _ = wg.send_to(&init_msg, endpoint, local).await;
                 }
                 break 'inner;
             }
-            let delay_ms = thread_rng().gen_range(5_000..5_300);
-            sleep(Duration::from_millis(delay_ms)).await;
+            let delay = rekey_timeout.mul_f64(thread_rng().gen_range(1.0..1.06));
+            clock.sleep(delay).await;
         }
     });
 }
 
 pub async fn do_keepalive1<'a>(peer0: &'a SharedPeerState, wg: &'a WgState) -> bool {
     let endpoint;
+    let local;
     let mut out = [0u8; 32];
     let should_handshake = {
         let peer = peer0.read();
@@ -425,6 +799,7 @@ pub async fn do_keepalive1<'a>(peer0: &'a SharedPeerState, wg: &'a WgState) -> b
             Some(e) => e,
             None => return false,
         };
+        local = peer.info.local;
 
         let t = match peer.find_transport_to_send() {
             Some(t) => t,
@@ -442,6 +817,34 @@ pub async fn do_keepalive1<'a>(peer0: &'a SharedPeerState, wg: &'a WgState) -> b
         peer.on_send_keepalive();
         should_handshake
     };
-    let _ = wg.send_to(&out, endpoint).await;
+    let _ = wg.send_to(&out, endpoint, local).await;
     should_handshake
 }
+
+/// Answer a path MTU probe.
+pub async fn send_pmtu_ack(wg: Arc<WgState>, peer0: SharedPeerState, id: u32) {
+    let msg = pmtu_ack_message(id);
+    let mut out = [0u8; 48];
+    let (endpoint, local) = {
+        // Lock peer.
+        let peer = peer0.read();
+
+        let endpoint = match peer.get_endpoint() {
+            Some(e) => e,
+            None => return,
+        };
+        let t = match peer.find_transport_to_send() {
+            Some(t) => t,
+            None => return,
+        };
+        if t.encrypt(&msg, &mut out).0.is_err() {
+            return;
+        }
+
+        peer.count_send(out.len());
+        peer.on_send_keepalive();
+        (endpoint, peer.info.local)
+        // Release peer.
+    };
+    let _ = wg.send_to(&out, endpoint, local).await;
+}
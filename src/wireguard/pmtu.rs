@@ -0,0 +1,283 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Per-peer path MTU discovery.
+//!
+//! Probes are transport messages whose plaintext is padded to the size being
+//! tested, sent with the don't fragment bit set. The peer answers each probe
+//! with a small ack. The largest size that gets acked is found with binary
+//! search, between `PMTU_MIN` and the interface MTU.
+//!
+//! This is an extension of the WireGuard protocol, so it is only enabled for
+//! peers configured with it. Probes are always answered. The first byte of a
+//! probe or an ack is not a valid IP version, so other WireGuard
+//! implementations just drop them. That's why the first probe is always of
+//! `PMTU_MIN` bytes: if it is not acked, the peer probably does not support
+//! this, and the interface MTU is used.
+
+use std::convert::TryInto;
+use std::time::Duration;
+
+/// Lower bound of the search, which is also the minimum MTU of IPv6.
+pub const PMTU_MIN: u32 = 1280;
+/// How long to wait for an ack.
+pub const PMTU_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Search again after this many seconds.
+pub const PMTU_REPROBE_INTERVAL: u64 = 600;
+
+// A probe is considered lost after this many attempts.
+const PMTU_PROBE_ATTEMPTS: u32 = 3;
+
+const PMTU_PROBE: u8 = 0x11;
+const PMTU_ACK: u8 = 0x12;
+const PMTU_HEADER_LEN: usize = 8;
+const PMTU_ACK_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PmtuMessage {
+    Probe(u32),
+    Ack(u32),
+}
+
+/// Plaintext of a probe of `size` bytes.
+pub fn pmtu_probe_message(id: u32, size: usize) -> Vec<u8> {
+    let mut m = vec![0u8; std::cmp::max(size, PMTU_HEADER_LEN)];
+    m[0] = PMTU_PROBE;
+    m[4..8].copy_from_slice(&id.to_le_bytes());
+    m
+}
+
+/// Plaintext of an ack.
+pub fn pmtu_ack_message(id: u32) -> [u8; PMTU_ACK_LEN] {
+    let mut m = [0u8; PMTU_ACK_LEN];
+    m[0] = PMTU_ACK;
+    m[4..8].copy_from_slice(&id.to_le_bytes());
+    m
+}
+
+pub fn parse_pmtu_message(p: &[u8]) -> Option<PmtuMessage> {
+    if p.len() < PMTU_HEADER_LEN || p[1..4] != [0, 0, 0] {
+        return None;
+    }
+    let id = u32::from_le_bytes(p[4..8].try_into().unwrap());
+    match p[0] {
+        PMTU_PROBE => Some(PmtuMessage::Probe(id)),
+        PMTU_ACK => Some(PmtuMessage::Ack(id)),
+        _ => None,
+    }
+}
+
+/// Path MTU discovery state of a peer.
+///
+/// MTU here means the max size of inner packets.
+pub struct PathMtu {
+    // Result of the last search.
+    mtu: Option<u32>,
+    search: Option<Search>,
+    // Should search when there is a session.
+    due: bool,
+    next_id: u32,
+}
+
+struct Search {
+    // `lo` works once `verified`. `hi` might.
+    lo: u32,
+    hi: u32,
+    verified: bool,
+    tried_hi: bool,
+    probe: Option<Probe>,
+}
+
+struct Probe {
+    size: u32,
+    // Ids of all attempts, as acks of earlier attempts can arrive late.
+    first_id: u32,
+    last_id: u32,
+    attempts: u32,
+}
+
+impl Default for PathMtu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PathMtu {
+    pub fn new() -> Self {
+        Self {
+            mtu: None,
+            search: None,
+            due: true,
+            next_id: 0,
+        }
+    }
+
+    /// Result of the last search.
+    pub fn mtu(&self) -> Option<u32> {
+        self.mtu
+    }
+
+    /// Whether a search should be started when a session is established.
+    pub fn is_due(&self) -> bool {
+        self.due && self.search.is_none()
+    }
+
+    /// Forget the result and stop searching, e.g., when the endpoint changes.
+    pub fn reset(&mut self) {
+        self.mtu = None;
+        self.search = None;
+        self.due = true;
+    }
+
+    /// Stop searching because there is no session, and search again when
+    /// there is one.
+    pub fn pause(&mut self) {
+        self.search = None;
+        self.due = true;
+    }
+
+    /// Called when the probe timer fires. `max` is the interface MTU.
+    ///
+    /// Starts a new search if not already searching. Returns the id and size of
+    /// the next probe to send, or `None` if the search has finished.
+    pub fn next_probe(&mut self, max: u32) -> Option<(u32, u32)> {
+        self.due = false;
+        let search = self.search.get_or_insert(Search {
+            lo: std::cmp::min(PMTU_MIN, max),
+            hi: max,
+            verified: false,
+            tried_hi: false,
+            probe: None,
+        });
+
+        if let Some(ref mut p) = search.probe {
+            if p.attempts < PMTU_PROBE_ATTEMPTS {
+                p.attempts += 1;
+                p.last_id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(1);
+                return Some((p.last_id, p.size));
+            }
+            if !search.verified {
+                // No answer at all.
+                self.mtu = None;
+                self.search = None;
+                return None;
+            }
+            // Lost every time, too big.
+            search.hi = p.size - 1;
+            search.probe = None;
+        }
+
+        let size = if !search.verified {
+            search.lo
+        } else if search.lo >= search.hi {
+            self.mtu = Some(search.lo);
+            self.search = None;
+            return None;
+        } else if !search.tried_hi {
+            // Try the upper bound first, it usually works.
+            search.tried_hi = true;
+            search.hi
+        } else {
+            (search.lo + search.hi) / 2 + 1
+        };
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        search.probe = Some(Probe {
+            size,
+            first_id: id,
+            last_id: id,
+            attempts: 1,
+        });
+        Some((id, size))
+    }
+
+    /// Called when an ack is received.
+    ///
+    /// Returns whether the next probe should be sent now.
+    pub fn on_ack(&mut self, id: u32) -> bool {
+        if let Some(ref mut search) = self.search {
+            if let Some(ref p) = search.probe {
+                if id.wrapping_sub(p.first_id) <= p.last_id.wrapping_sub(p.first_id) {
+                    search.verified = true;
+                    search.lo = p.size;
+                    search.probe = None;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run a search against a path that carries packets up to `path_mtu`,
+    // dropping every `lose`th probe.
+    fn search(max: u32, path_mtu: u32, lose: Option<u32>) -> (u32, u32) {
+        let mut p = PathMtu::new();
+        let mut probes = 0;
+        while let Some((id, size)) = p.next_probe(max) {
+            probes += 1;
+            let lost = matches!(lose, Some(n) if probes % n == 0);
+            if size <= path_mtu && !lost {
+                p.on_ack(id);
+            }
+        }
+        (p.mtu().unwrap(), probes)
+    }
+
+    #[test]
+    fn binary_search() {
+        // Upper bound works.
+        assert_eq!(search(1420, 1500, None), (1420, 2));
+        for &path_mtu in &[1280, 1281, 1300, 1379, 1380, 1419] {
+            assert_eq!(search(1420, path_mtu, None).0, path_mtu);
+        }
+        // Every 3rd probe is lost, but probes are retried.
+        assert_eq!(search(1420, 1392, Some(3)).0, 1392);
+        // Interface MTU is smaller than PMTU_MIN.
+        assert_eq!(search(1200, 1500, None).0, 1200);
+    }
+
+    #[test]
+    fn peer_without_support() {
+        let mut p = PathMtu::new();
+        assert!(p.is_due());
+        for _ in 0..PMTU_PROBE_ATTEMPTS {
+            assert_eq!(p.next_probe(1420).unwrap().1, PMTU_MIN);
+        }
+        assert!(p.next_probe(1420).is_none());
+        assert_eq!(p.mtu(), None);
+        assert!(!p.is_due());
+    }
+
+    #[test]
+    fn messages() {
+        let probe = pmtu_probe_message(7, 1280);
+        assert_eq!(probe.len(), 1280);
+        assert_eq!(parse_pmtu_message(&probe), Some(PmtuMessage::Probe(7)));
+        assert_eq!(
+            parse_pmtu_message(&pmtu_ack_message(8)),
+            Some(PmtuMessage::Ack(8))
+        );
+        assert!(crate::wireguard::parse_ip_packet(&probe).is_err());
+        assert!(parse_pmtu_message(&[0x45; 20]).is_none());
+    }
+}
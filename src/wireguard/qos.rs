@@ -0,0 +1,321 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+// Transport packets read from tun are not sent in the order they are read.
+// Each TX loop puts them in per-peer queues, and takes them out with deficit
+// round robin, so that a peer doing a bulk transfer only gets its fair share
+// when the socket is busy. A peer with a rate limit is skipped while its
+// token bucket is empty.
+//
+// This happens before packets get their nonces, so however long a packet is
+// held back, it is not behind packets with later nonces, which could push it
+// out of the receiver's anti-replay window.
+//
+// Handshake messages, keep-alives and packets that waited for a handshake
+// are sent directly, and are not rate limited.
+
+use crate::wireguard::{Counters, DropReason, TokenBucket};
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+use std::cmp::max;
+use std::collections::hash_map::Entry;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Min burst size of a rate limit, in bytes.
+pub const MIN_BURST: u64 = 16384;
+
+// Max packets queued for a peer in a TX loop.
+const TX_QUEUE_SIZE: usize = 256;
+
+// How many bytes a peer can send in a round, about one full sized packet.
+const QUANTUM: usize = 1500;
+
+/// Rate limit of packets sent to a peer.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RateLimit {
+    /// Bytes per second. Zero means no limit.
+    pub rate: u64,
+    /// Max burst size, in bytes.
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// Limit to `rate` bytes per second. Bursts of 100ms worth of traffic,
+    /// and at least `MIN_BURST` bytes, are allowed.
+    pub fn new(rate: u64) -> Self {
+        RateLimit {
+            rate,
+            burst: max(rate / 10, MIN_BURST),
+        }
+    }
+}
+
+/// Rate limit and counters of a peer, shared by the TX loops.
+pub struct PeerQos {
+    // In bytes.
+    bucket: Mutex<Option<TokenBucket>>,
+    counters: Arc<Counters>,
+    wg_counters: Arc<Counters>,
+}
+
+impl PeerQos {
+    pub fn new(counters: Arc<Counters>, wg_counters: Arc<Counters>) -> Self {
+        PeerQos {
+            bucket: Mutex::new(None),
+            counters,
+            wg_counters,
+        }
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.bucket.lock().as_ref().map(|b| RateLimit {
+            rate: b.rate(),
+            burst: b.capacity(),
+        })
+    }
+
+    /// Set rate limit. Rate zero means no limit.
+    pub fn set_rate_limit(&self, limit: RateLimit, now: Instant) {
+        if self.rate_limit() == Some(limit) {
+            return;
+        }
+        *self.bucket.lock() = if limit.rate > 0 {
+            Some(TokenBucket::new(limit.rate, limit.burst, now))
+        } else {
+            None
+        };
+    }
+
+    // Take tokens for a packet of `len` bytes, or return how long until there
+    // are enough tokens.
+    //
+    // Packets bigger than the burst size only need a full bucket.
+    fn try_take(&self, len: usize, now: Instant) -> Result<(), Duration> {
+        match *self.bucket.lock() {
+            Some(ref mut b) => b.take(len as u64, now),
+            None => Ok(()),
+        }
+    }
+
+    fn count_shaped(&self) {
+        self.counters.shaped_packets.fetch_add(1);
+        self.wg_counters.shaped_packets.fetch_add(1);
+    }
+
+    fn count_drop(&self, reason: DropReason) {
+        self.counters.count_drop(reason);
+        self.wg_counters.count_drop(reason);
+    }
+}
+
+struct TxQueue<T> {
+    qos: Arc<PeerQos>,
+    // Packets and their lengths.
+    packets: VecDeque<(usize, T)>,
+    deficit: usize,
+    // Whether the queue has got its quantum for this round.
+    in_round: bool,
+    // Whether the first packet is already counted as shaped.
+    shaped: bool,
+}
+
+/// Deficit round robin over per-peer queues, with rate limits.
+pub struct TxScheduler<T> {
+    // Keyed by address of `PeerQos`.
+    queues: FnvHashMap<usize, TxQueue<T>>,
+    // Queues that can send, in round robin order.
+    active: VecDeque<usize>,
+    // Queues that wait for their rate limits, and until when.
+    waiting: Vec<(Instant, usize)>,
+}
+
+impl<T> TxScheduler<T> {
+    pub fn new() -> Self {
+        TxScheduler {
+            queues: FnvHashMap::default(),
+            active: VecDeque::new(),
+            waiting: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    /// Queue a packet of `len` bytes to a peer.
+    ///
+    /// If the peer already has too many packets queued, the packet is dropped.
+    pub fn push(&mut self, qos: &Arc<PeerQos>, len: usize, packet: T) {
+        let key = Arc::as_ptr(qos) as usize;
+        let queue = match self.queues.entry(key) {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(v) => {
+                self.active.push_back(key);
+                v.insert(TxQueue {
+                    qos: qos.clone(),
+                    packets: VecDeque::new(),
+                    deficit: 0,
+                    in_round: false,
+                    shaped: false,
+                })
+            }
+        };
+        if queue.packets.len() >= TX_QUEUE_SIZE {
+            qos.count_drop(DropReason::TxQueueFull);
+            return;
+        }
+        queue.packets.push_back((len, packet));
+    }
+
+    /// Take the next packet to send.
+    ///
+    /// If no packet can be sent now, returns when one can be sent, or `None`
+    /// if there are no packets.
+    pub fn pop(&mut self, now: Instant) -> Result<T, Option<Instant>> {
+        let active = &mut self.active;
+        self.waiting.retain(|&(t, key)| {
+            if t <= now {
+                active.push_back(key);
+            }
+            t > now
+        });
+
+        while let Some(&key) = self.active.front() {
+            let queue = self.queues.get_mut(&key).unwrap();
+            let len = queue.packets.front().unwrap().0;
+            if !queue.in_round {
+                queue.deficit += QUANTUM;
+                queue.in_round = true;
+            }
+            if queue.deficit < len {
+                // Next peer's turn.
+                queue.in_round = false;
+                self.active.rotate_left(1);
+                continue;
+            }
+            if let Err(wait) = queue.qos.try_take(len, now) {
+                if !queue.shaped {
+                    queue.shaped = true;
+                    queue.qos.count_shaped();
+                }
+                queue.in_round = false;
+                self.active.pop_front();
+                self.waiting.push((now + wait, key));
+                continue;
+            }
+            let (_, packet) = queue.packets.pop_front().unwrap();
+            queue.deficit -= len;
+            queue.shaped = false;
+            if queue.packets.is_empty() {
+                self.active.pop_front();
+                self.queues.remove(&key);
+            }
+            return Ok(packet);
+        }
+        Err(self.waiting.iter().map(|&(t, _)| t).min())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn qos() -> (Arc<PeerQos>, Arc<Counters>) {
+        let counters = Arc::new(Counters::new());
+        let qos = PeerQos::new(counters.clone(), Arc::new(Counters::new()));
+        (Arc::new(qos), counters)
+    }
+
+    #[test]
+    fn round_robin() {
+        let (a, _) = qos();
+        let (b, _) = qos();
+        let mut s = TxScheduler::new();
+        for i in 0..4 {
+            s.push(&a, 1400, ('a', i));
+        }
+        s.push(&b, 100, ('b', 0));
+        s.push(&b, 100, ('b', 1));
+        s.push(&b, 1400, ('b', 2));
+
+        let now = Instant::now();
+        let mut order = Vec::new();
+        while let Ok(p) = s.pop(now) {
+            order.push(p);
+        }
+        assert_eq!(
+            order,
+            [
+                ('a', 0),
+                ('b', 0),
+                ('b', 1),
+                ('a', 1),
+                ('b', 2),
+                ('a', 2),
+                ('a', 3)
+            ]
+        );
+        assert!(s.is_empty());
+        assert_eq!(s.pop(now), Err(None));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let (a, a_counters) = qos();
+        let (b, _) = qos();
+        let now = Instant::now();
+        a.set_rate_limit(
+            RateLimit {
+                rate: 1000,
+                burst: 1000,
+            },
+            now,
+        );
+        let mut s = TxScheduler::new();
+        for i in 0..3 {
+            s.push(&a, 1000, ('a', i));
+            s.push(&b, 1000, ('b', i));
+        }
+
+        let mut order = Vec::new();
+        let wait = loop {
+            match s.pop(now) {
+                Ok(p) => order.push(p),
+                Err(wait) => break wait.unwrap(),
+            }
+        };
+        assert_eq!(order, [('a', 0), ('b', 0), ('b', 1), ('b', 2)]);
+        assert!(wait > now && wait <= now + Duration::from_secs(1));
+        assert_eq!(a_counters.get().shaped_packets, 1);
+
+        assert_eq!(s.pop(wait), Ok(('a', 1)));
+        assert!(s.pop(wait).is_err());
+        assert_eq!(a_counters.get().shaped_packets, 2);
+    }
+
+    #[test]
+    fn queue_full() {
+        let (a, counters) = qos();
+        let mut s = TxScheduler::new();
+        for i in 0..TX_QUEUE_SIZE + 2 {
+            s.push(&a, 100, i);
+        }
+        assert_eq!(counters.get().drops.get(&DropReason::TxQueueFull), Some(&2));
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::wireguard::TokenBucket;
+use fnv::FnvHashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Limits of handshake messages from each source address.
+///
+/// They only apply under load, to messages with a valid cookie, i.e., from
+/// sources that are known to be real. IPv6 sources are limited per /64, as
+/// one host usually has all of them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct HandshakeLimits {
+    /// Handshake messages per second from a source. Zero means no limit.
+    pub rate: u32,
+    /// Messages from a source allowed in a burst.
+    pub burst: u32,
+    /// Max sources tracked. When the table is full, the least active sources
+    /// are forgotten to make room for new ones.
+    pub max_sources: usize,
+}
+
+impl Default for HandshakeLimits {
+    fn default() -> Self {
+        HandshakeLimits {
+            rate: 20,
+            burst: 5,
+            max_sources: 65536,
+        }
+    }
+}
+
+/// Token buckets of handshake messages, one per source.
+///
+/// Idle sources, i.e. those whose buckets are full again, are removed every
+/// second.
+pub struct HandshakeRateLimiter {
+    limits: HandshakeLimits,
+    sources: FnvHashMap<u128, TokenBucket>,
+    last_gc: Instant,
+}
+
+const GC_INTERVAL: Duration = Duration::from_secs(1);
+// When the table is full, this fraction of it is evicted at once.
+const EVICT_DIVISOR: usize = 8;
+
+impl HandshakeRateLimiter {
+    pub fn new(now: Instant) -> Self {
+        HandshakeRateLimiter {
+            limits: HandshakeLimits::default(),
+            sources: FnvHashMap::default(),
+            last_gc: now,
+        }
+    }
+
+    /// Set limits. If they change, all sources start with full buckets again.
+    pub fn set_limits(&mut self, limits: HandshakeLimits) {
+        if self.limits != limits {
+            self.limits = limits;
+            self.sources.clear();
+        }
+    }
+
+    /// Returns whether a handshake message from `source` is allowed at `now`.
+    pub fn allow(&mut self, source: IpAddr, now: Instant) -> bool {
+        if self.limits.rate == 0 {
+            return true;
+        }
+        if now.saturating_duration_since(self.last_gc) >= GC_INTERVAL {
+            self.last_gc = now;
+            self.sources.retain(|_, b| !b.is_full(now));
+        }
+
+        let key = source_key(source);
+        if let Some(b) = self.sources.get_mut(&key) {
+            return b.take(1, now).is_ok();
+        }
+        if self.sources.len() >= self.limits.max_sources {
+            self.evict(now);
+        }
+        let rate = self.limits.rate.into();
+        let burst = std::cmp::max(1, self.limits.burst).into();
+        let mut b = TokenBucket::new(rate, burst, now);
+        let _ = b.take(1, now);
+        self.sources.insert(key, b);
+        true
+    }
+
+    // Forget the least active sources, i.e. those with the fullest buckets.
+    // An eighth of the table goes at once, so that the scan is amortized over
+    // the new sources taking their place.
+    fn evict(&mut self, now: Instant) {
+        let mut by_tokens: Vec<(u64, u128)> = self
+            .sources
+            .iter_mut()
+            .map(|(&k, b)| (b.tokens(now), k))
+            .collect();
+        if by_tokens.is_empty() {
+            return;
+        }
+        let n = std::cmp::max(1, by_tokens.len() / EVICT_DIVISOR);
+        by_tokens.select_nth_unstable_by(n - 1, |a, b| b.cmp(a));
+        for (_, k) in &by_tokens[..n] {
+            self.sources.remove(k);
+        }
+    }
+}
+
+// IPv4 addresses as is, IPv6 addresses by /64 prefix. Either may come in as
+// an IPv4-mapped IPv6 address.
+fn source_key(source: IpAddr) -> u128 {
+    let ip = match source {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    let ip = u128::from(ip);
+    if ip >> 32 == 0xffff {
+        ip
+    } else {
+        ip & !u128::from(u64::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_and_burst() {
+        let t0 = Instant::now();
+        let mut l = HandshakeRateLimiter::new(t0);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        for _ in 0..5 {
+            assert!(l.allow(a, t0));
+        }
+        assert!(!l.allow(a, t0));
+        // Other sources are not affected.
+        assert!(l.allow(b, t0));
+
+        // One message every 50ms.
+        assert!(!l.allow(a, t0 + Duration::from_millis(49)));
+        assert!(l.allow(a, t0 + Duration::from_millis(50)));
+        assert!(!l.allow(a, t0 + Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn sources() {
+        let t0 = Instant::now();
+        let mut l = HandshakeRateLimiter::new(t0);
+        l.set_limits(HandshakeLimits {
+            rate: 1,
+            burst: 1,
+            max_sources: 3,
+        });
+        // Same /64.
+        assert!(l.allow("2001:db8::1".parse().unwrap(), t0));
+        assert!(!l.allow("2001:db8::2:1".parse().unwrap(), t0));
+        assert!(l.allow("2001:db8:0:1::1".parse().unwrap(), t0));
+        // IPv4-mapped and plain IPv4 are the same source.
+        assert!(l.allow("::ffff:192.0.2.1".parse().unwrap(), t0));
+        assert!(!l.allow("192.0.2.1".parse().unwrap(), t0));
+        assert_eq!(l.sources.len(), 3);
+
+        // Garbage collected once idle.
+        let c: IpAddr = "192.0.2.3".parse().unwrap();
+        assert!(l.allow(c, t0 + Duration::from_secs(1)));
+        assert_eq!(l.sources.len(), 1);
+        assert!(!l.allow(c, t0 + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn full_table() {
+        let t0 = Instant::now();
+        let mut l = HandshakeRateLimiter::new(t0);
+        l.set_limits(HandshakeLimits {
+            rate: 1,
+            burst: 2,
+            max_sources: 2,
+        });
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let c: IpAddr = "192.0.2.3".parse().unwrap();
+        assert!(l.allow(a, t0));
+        assert!(l.allow(a, t0));
+        assert!(!l.allow(a, t0));
+        assert!(l.allow(b, t0));
+
+        // `b` is forgotten to make room for `c`, and `a` is still limited.
+        assert!(l.allow(c, t0));
+        assert!(l.allow(c, t0));
+        assert!(!l.allow(c, t0));
+        assert_eq!(l.sources.len(), 2);
+        assert!(!l.sources.contains_key(&source_key(b)));
+        assert!(!l.allow(a, t0));
+    }
+
+    #[test]
+    fn evict_in_bulk() {
+        let t0 = Instant::now();
+        let mut l = HandshakeRateLimiter::new(t0);
+        l.set_limits(HandshakeLimits {
+            rate: 1,
+            burst: 2,
+            max_sources: 16,
+        });
+        let source = |i: u8| IpAddr::from([192, 0, 2, i]);
+        for i in 0..16 {
+            assert!(l.allow(source(i), t0));
+        }
+        // The first 14 are more active.
+        for i in 0..14 {
+            assert!(l.allow(source(i), t0));
+        }
+
+        // The last two are forgotten at once.
+        assert!(l.allow(source(16), t0));
+        assert_eq!(l.sources.len(), 15);
+        assert!(!l.sources.contains_key(&source_key(source(14))));
+        assert!(!l.sources.contains_key(&source_key(source(15))));
+        assert!(l.allow(source(17), t0));
+        assert_eq!(l.sources.len(), 16);
+        assert!(!l.allow(source(0), t0));
+    }
+
+    #[test]
+    fn no_limit() {
+        let t0 = Instant::now();
+        let mut l = HandshakeRateLimiter::new(t0);
+        l.set_limits(HandshakeLimits {
+            rate: 0,
+            ..HandshakeLimits::default()
+        });
+        for _ in 0..100 {
+            assert!(l.allow("192.0.2.1".parse().unwrap(), t0));
+        }
+        assert_eq!(l.sources.len(), 0);
+    }
+}
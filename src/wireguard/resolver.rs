@@ -0,0 +1,54 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::wireguard::map_ipv4_to_ipv6;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV6};
+
+/// Resolves host names of peer endpoints.
+///
+/// `SystemResolver` is the default. Implement this trait to resolve names
+/// some other way, e.g., in tests.
+pub trait Resolver: Send + Sync + 'static {
+    /// Resolve `host`, e.g., `vpn.example.com:51820`.
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+/// Resolve names with the system resolver, i.e., `getaddrinfo`.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        async move { Ok(tokio::net::lookup_host(host).await?.collect()) }.boxed()
+    }
+}
+
+/// Resolve `host` to its first address. IP addresses are used as is.
+pub(crate) async fn resolve_endpoint(
+    resolver: &dyn Resolver,
+    host: &str,
+) -> io::Result<SocketAddrV6> {
+    if let Ok(a) = host.parse() {
+        return Ok(map_ipv4_to_ipv6(a));
+    }
+    match resolver.resolve(host).await?.first() {
+        Some(&a) => Ok(map_ipv4_to_ipv6(a)),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "host not found")),
+    }
+}
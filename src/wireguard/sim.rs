@@ -0,0 +1,908 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+// A simulated network that `WgState`s can be connected with in tests,
+// without root, tun devices or network namespaces.
+//
+// Each host has some IP addresses, and is the `Network` of a `WgState`.
+// Packets sent on the network are delivered to the socket bound to the
+// target address on any host, after `latency`. They may also be lost,
+// duplicated or reordered. A socket can be put behind a NAT, and the NAT can
+// change its mapping, so that the socket suddenly sends from another address.
+
+use crate::wireguard::*;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use parking_lot::Mutex;
+use rand::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// Ports are allocated from here for sockets bound to port 0.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// Conditions of a `SimNetwork`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Probability that a packet is lost.
+    pub loss: f64,
+    /// Probability that a packet is delivered twice.
+    pub duplicate: f64,
+    /// Probability that a packet is delayed by another `latency`, so that
+    /// packets sent after it may arrive first.
+    pub reorder: f64,
+    /// One way delay.
+    pub latency: Duration,
+}
+
+/// An in-memory network for tests.
+#[derive(Clone)]
+pub struct SimNetwork {
+    inner: Arc<NetInner>,
+}
+
+struct NetInner {
+    clock: Arc<dyn Clock>,
+    state: Mutex<NetState>,
+}
+
+struct NetState {
+    conditions: LinkConditions,
+    // Sockets by the addresses they are bound to. A socket bound to an
+    // unspecified address is bound to each address of its host.
+    sockets: HashMap<SocketAddr, Weak<SimQueue>>,
+    // NAT mappings, from inner to outer address, and back.
+    nat_out: HashMap<SocketAddr, SocketAddr>,
+    nat_in: HashMap<SocketAddr, SocketAddr>,
+    next_port: u16,
+}
+
+impl SimNetwork {
+    pub fn new() -> Self {
+        SimNetwork::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Create a network whose latency is measured with `clock`.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        SimNetwork {
+            inner: Arc::new(NetInner {
+                clock,
+                state: Mutex::new(NetState {
+                    conditions: LinkConditions::default(),
+                    sockets: HashMap::new(),
+                    nat_out: HashMap::new(),
+                    nat_in: HashMap::new(),
+                    next_port: FIRST_EPHEMERAL_PORT,
+                }),
+            }),
+        }
+    }
+
+    /// Add a host with addresses `ips`.
+    pub fn host(&self, ips: &[IpAddr]) -> SimHost {
+        SimHost {
+            net: self.inner.clone(),
+            ips: ips.to_vec(),
+        }
+    }
+
+    /// Set conditions of the network. Packets that are already sent are not
+    /// affected.
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.inner.state.lock().conditions = conditions;
+    }
+
+    /// Put the socket bound to `inner` behind a NAT.
+    ///
+    /// Packets sent from `inner` appear to come from `outer`, and packets sent
+    /// to `outer` go to `inner`. Setting a new `outer` for the same `inner`
+    /// simulates NAT rebinding. `None` removes the mapping.
+    pub fn set_nat(&self, inner: SocketAddr, outer: Option<SocketAddr>) {
+        let inner = normalize(inner);
+        let mut state = self.inner.state.lock();
+        if let Some(old) = state.nat_out.remove(&inner) {
+            state.nat_in.remove(&old);
+        }
+        if let Some(outer) = outer {
+            let outer = normalize(outer);
+            state.nat_out.insert(inner, outer);
+            state.nat_in.insert(outer, inner);
+        }
+    }
+}
+
+impl Default for SimNetwork {
+    fn default() -> Self {
+        SimNetwork::new()
+    }
+}
+
+/// A host on a `SimNetwork`.
+#[derive(Clone)]
+pub struct SimHost {
+    net: Arc<NetInner>,
+    ips: Vec<IpAddr>,
+}
+
+impl Network for SimHost {
+    fn bind(
+        &self,
+        addr: SocketAddr,
+        dual_stack: bool,
+        _fwmark: u32,
+    ) -> io::Result<Arc<dyn DatagramSocket>> {
+        let ip = normalize(addr).ip();
+        let ips: Vec<IpAddr> = if ip.is_unspecified() {
+            self.ips
+                .iter()
+                .copied()
+                .filter(|i| i.is_ipv4() == ip.is_ipv4() || (dual_stack && ip.is_ipv6()))
+                .collect()
+        } else if self.ips.contains(&ip) {
+            vec![ip]
+        } else {
+            return Err(io::ErrorKind::AddrNotAvailable.into());
+        };
+
+        let mut state = self.net.state.lock();
+        let in_use = |state: &NetState, port| {
+            ips.iter().any(|&ip| {
+                state
+                    .sockets
+                    .get(&(ip, port).into())
+                    .map(|s| s.strong_count() > 0)
+                    .unwrap_or(false)
+            })
+        };
+        let port = if addr.port() != 0 {
+            if in_use(&state, addr.port()) {
+                return Err(io::ErrorKind::AddrInUse.into());
+            }
+            addr.port()
+        } else {
+            loop {
+                let port = state.next_port;
+                state.next_port = state
+                    .next_port
+                    .checked_add(1)
+                    .unwrap_or(FIRST_EPHEMERAL_PORT);
+                if !in_use(&state, port) {
+                    break port;
+                }
+            }
+        };
+
+        let queue = Arc::new(SimQueue {
+            packets: Mutex::new(VecDeque::new()),
+            arrived: Notify::new(),
+        });
+        let addrs: Vec<SocketAddr> = ips.iter().map(|&ip| (ip, port).into()).collect();
+        for a in &addrs {
+            state.sockets.insert(*a, Arc::downgrade(&queue));
+        }
+        let mut local = addr;
+        local.set_port(port);
+        Ok(Arc::new(SimSocket {
+            net: self.net.clone(),
+            local,
+            addrs,
+            queue,
+        }))
+    }
+
+    fn dual_stack_supported(&self) -> bool {
+        true
+    }
+}
+
+struct SimPacket {
+    // When it arrives.
+    at: Instant,
+    from: SocketAddr,
+    to: IpAddr,
+    data: Vec<u8>,
+}
+
+// Packets on the way to a socket, in the order they arrive.
+struct SimQueue {
+    packets: Mutex<VecDeque<SimPacket>>,
+    arrived: Notify,
+}
+
+struct SimSocket {
+    net: Arc<NetInner>,
+    local: SocketAddr,
+    addrs: Vec<SocketAddr>,
+    queue: Arc<SimQueue>,
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        let mut state = self.net.state.lock();
+        for a in &self.addrs {
+            if let Some(q) = state.sockets.get(a) {
+                if Weak::as_ptr(q) == Arc::as_ptr(&self.queue) {
+                    state.sockets.remove(a);
+                }
+            }
+        }
+    }
+}
+
+impl SimSocket {
+    fn send(&self, buf: &[u8], target: SocketAddr, source: Option<IpAddr>) -> io::Result<usize> {
+        let target = normalize(target);
+        let from = self
+            .addrs
+            .iter()
+            .copied()
+            .find(|a| Some(a.ip()) == source)
+            .or_else(|| {
+                self.addrs
+                    .iter()
+                    .copied()
+                    .find(|a| a.is_ipv4() == target.is_ipv4())
+            })
+            .ok_or(io::ErrorKind::AddrNotAvailable)?;
+
+        let now = self.net.clock.now();
+        let state = self.net.state.lock();
+        let from = state.nat_out.get(&from).copied().unwrap_or(from);
+        let to = state.nat_in.get(&target).copied().unwrap_or(target);
+        let queue = match state.sockets.get(&to).and_then(|q| q.upgrade()) {
+            Some(q) => q,
+            // Nobody is listening.
+            None => return Ok(buf.len()),
+        };
+        let c = state.conditions;
+        drop(state);
+
+        let mut rng = thread_rng();
+        if rng.gen_bool(c.loss) {
+            return Ok(buf.len());
+        }
+        let copies = if rng.gen_bool(c.duplicate) { 2 } else { 1 };
+        let mut at = now + c.latency;
+        if rng.gen_bool(c.reorder) {
+            at += c.latency;
+        }
+        let mut packets = queue.packets.lock();
+        for _ in 0..copies {
+            let i = packets
+                .iter()
+                .rposition(|p| p.at <= at)
+                .map_or(0, |i| i + 1);
+            packets.insert(
+                i,
+                SimPacket {
+                    at,
+                    from,
+                    to: to.ip(),
+                    data: buf.to_vec(),
+                },
+            );
+        }
+        queue.arrived.notify_one();
+        Ok(buf.len())
+    }
+}
+
+impl DatagramSocket for SimSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn recv_batch<'a>(
+        &'a self,
+        bufs: &'a mut [Vec<u8>],
+        meta: &'a mut [RecvMeta],
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        async move {
+            loop {
+                let next = {
+                    let mut packets = self.queue.packets.lock();
+                    let now = self.net.clock.now();
+                    let mut n = 0;
+                    while n < bufs.len() && matches!(packets.front(), Some(p) if p.at <= now) {
+                        let p = packets.pop_front().unwrap();
+                        let len = std::cmp::min(p.data.len(), bufs[n].len());
+                        bufs[n][..len].copy_from_slice(&p.data[..len]);
+                        meta[n] = RecvMeta {
+                            len,
+                            addr: p.from,
+                            segment_size: len,
+                            dst: Some(p.to),
+                        };
+                        n += 1;
+                    }
+                    if n > 0 {
+                        return Ok(n);
+                    }
+                    packets.front().map(|p| p.at)
+                };
+                let arrived = self.queue.arrived.notified();
+                match next {
+                    Some(at) => {
+                        futures::select_biased! {
+                            _ = arrived.fuse() => (),
+                            _ = self.net.clock.sleep_until(at).fuse() => (),
+                        }
+                    }
+                    None => arrived.await,
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+        source: Option<IpAddr>,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        future::ready(self.send(buf, target, source)).boxed()
+    }
+}
+
+// IPv4-mapped addresses are plain IPv4 addresses on the network.
+fn normalize(addr: SocketAddr) -> SocketAddr {
+    unmap_ipv4_from_ipv6(map_ipv4_to_ipv6(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_utils::AsyncScope;
+    use crate::wireguard::re_exports::{DH, X25519};
+    use tokio::time::timeout;
+
+    struct Node {
+        wg: Arc<WgState>,
+        tun: ChannelPacketDeviceHandle,
+        public_key: X25519Pubkey,
+        // Inner address.
+        ip: [u8; 4],
+        _scope: Arc<AsyncScope>,
+    }
+
+    impl Node {
+        async fn new(net: &SimNetwork, addr: &str, ip: [u8; 4], timers: Timers) -> Node {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let (device, tun) = ChannelPacketDevice::new(1420, 64);
+            let host = Arc::new(net.host(&[addr.ip()]));
+            let wg = WgState::new_with_network(vec![device], host, net.inner.clock.clone(), timers)
+                .unwrap();
+            let key = X25519::genkey();
+            let public_key = X25519::pubkey(&key);
+            wg.set_key(key);
+            wg.set_port(addr.port()).await.unwrap();
+            let scope = AsyncScope::new();
+            scope.spawn_canceller(wg.clone().task_rx());
+            scope.spawn_canceller(wg.clone().task_tx());
+            Node {
+                wg,
+                tun,
+                public_key,
+                ip,
+                _scope: scope,
+            }
+        }
+
+        fn add_peer(&self, other: &Node, endpoint: Option<&str>) {
+            self.wg.add_peer(&other.public_key).unwrap();
+            self.wg
+                .set_peer(SetPeerCommand {
+                    endpoint: endpoint.map(|e| e.parse().unwrap()).into_iter().collect(),
+                    replace_allowed_ips: true,
+                    allowed_ips: std::iter::once((other.ip.into(), 32)).collect(),
+                    ..SetPeerCommand::new(other.public_key)
+                })
+                .unwrap();
+        }
+
+        fn set_disabled(&self, other: &Node, disabled: bool, unreachable: bool) {
+            self.wg
+                .set_peer(SetPeerCommand {
+                    disabled: Some(disabled),
+                    disabled_unreachable: Some(unreachable),
+                    ..SetPeerCommand::new(other.public_key)
+                })
+                .unwrap();
+        }
+
+        fn rotate_psk(&self, other: &Node, psk: [u8; 32], lifetime: Option<Duration>) {
+            self.wg
+                .set_peer(SetPeerCommand {
+                    rotate_psk: Some(psk),
+                    psk_lifetime: lifetime,
+                    ..SetPeerCommand::new(other.public_key)
+                })
+                .unwrap();
+        }
+
+        fn peer(&self) -> PeerStateOut {
+            self.wg.get_state().peers.remove(0)
+        }
+
+        async fn send(&self, to: &Node, payload: &[u8]) {
+            let p = udp_packet(self.ip, to.ip, payload);
+            self.tun.tx.send(p).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Option<Vec<u8>> {
+            let p = timeout(Duration::from_secs(1), self.tun.rx.recv())
+                .await
+                .ok()??;
+            Some(p[28..].to_vec())
+        }
+    }
+
+    // An IPv4 UDP packet.
+    fn udp_packet(src: [u8; 4], dst: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut p = vec![0u8; 28];
+        p[0] = 0x45;
+        p[2..4].copy_from_slice(&(28 + payload.len() as u16).to_be_bytes());
+        p[8] = 64;
+        p[9] = 17;
+        p[12..16].copy_from_slice(&src);
+        p[16..20].copy_from_slice(&dst);
+        p[24..26].copy_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        p.extend_from_slice(payload);
+        p
+    }
+
+    // A network whose nodes use a manual clock, for tests of protocol timers.
+    fn manual_network() -> (SimNetwork, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        (SimNetwork::with_clock(clock.clone()), clock)
+    }
+
+    // Let tasks start their sleeps, then move `clock` forward and let the
+    // timers that are due fire.
+    async fn advance(clock: &ManualClock, duration: Duration) {
+        clock.settle().await;
+        clock.advance(duration);
+        clock.settle().await;
+    }
+
+    // Two nodes, `a` knows the endpoint of `b`.
+    async fn pair(net: &SimNetwork, timers: Timers) -> (Node, Node) {
+        let a = Node::new(net, "10.0.0.1:5000", [192, 168, 0, 1], timers).await;
+        let b = Node::new(net, "10.0.0.2:5000", [192, 168, 0, 2], timers).await;
+        a.add_peer(&b, Some("10.0.0.2:5000"));
+        b.add_peer(&a, None);
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn handshake_and_transport() {
+        let net = SimNetwork::new();
+        let (mut a, mut b) = pair(&net, Timers::default()).await;
+
+        a.send(&b, b"hello").await;
+        assert_eq!(b.recv().await.unwrap(), b"hello");
+        b.send(&a, b"world").await;
+        assert_eq!(a.recv().await.unwrap(), b"world");
+
+        assert_eq!(b.peer().endpoint, Some("10.0.0.1:5000".parse().unwrap()));
+        assert_eq!(a.wg.get_state().counters.handshake_initiations_sent, 1);
+    }
+
+    #[tokio::test]
+    async fn packet_filter() {
+        let net = SimNetwork::new();
+        let (a, mut b) = pair(&net, Timers::default()).await;
+        b.wg.set_peer(SetPeerCommand {
+            filter: Some(vec![
+                "allow in udp to 192.168.0.2".parse().unwrap(),
+                "deny in".parse().unwrap(),
+            ]),
+            ..SetPeerCommand::new(a.public_key)
+        })
+        .unwrap();
+
+        a.send(&b, b"udp").await;
+        assert_eq!(b.recv().await.unwrap(), b"udp");
+        // Not UDP.
+        let mut p = udp_packet(a.ip, b.ip, b"tcp");
+        p[9] = 6;
+        a.tun.tx.send(p).await.unwrap();
+        assert_eq!(b.recv().await, None);
+
+        let peer = b.peer();
+        assert_eq!(peer.counters.drops[&DropReason::Filtered], 1);
+        assert_eq!(peer.filter[0].1, 1);
+        assert_eq!(peer.filter[1].1, 1);
+    }
+
+    #[tokio::test]
+    async fn source_filter() {
+        let net = SimNetwork::new();
+        let (a, mut b) = pair(&net, Timers::default()).await;
+        let c = Node::new(&net, "10.0.0.3:5000", [192, 168, 0, 3], Timers::default()).await;
+        b.add_peer(&c, None);
+        let wg = b.wg.clone();
+        let set_source_filter = |source_filter| {
+            wg.set_peer(SetPeerCommand {
+                source_filter: Some(source_filter),
+                ..SetPeerCommand::new(a.public_key)
+            })
+            .unwrap()
+        };
+        // From `a`, with the source of `c` or of no peer.
+        let b_ip = b.ip;
+        let send_from = |src: [u8; 4]| a.tun.tx.send(udp_packet(src, b_ip, b"hi"));
+        let counters = || {
+            wg.get_state()
+                .peers
+                .into_iter()
+                .find(|p| p.public_key == a.public_key)
+                .unwrap()
+                .counters
+        };
+
+        send_from(c.ip).await.unwrap();
+        assert_eq!(b.recv().await, None);
+        assert_eq!(counters().drops[&DropReason::AllowedIps], 1);
+
+        set_source_filter(SourceFilter::Loose);
+        send_from(c.ip).await.unwrap();
+        assert_eq!(b.recv().await.unwrap(), b"hi");
+        send_from([10, 9, 0, 1]).await.unwrap();
+        assert_eq!(b.recv().await, None);
+        assert_eq!(counters().drops[&DropReason::SourceFilter], 1);
+
+        set_source_filter(SourceFilter::Off(
+            std::iter::once(("10.9.0.0".parse().unwrap(), 16)).collect(),
+        ));
+        send_from([10, 9, 0, 1]).await.unwrap();
+        assert_eq!(b.recv().await.unwrap(), b"hi");
+        send_from(c.ip).await.unwrap();
+        assert_eq!(b.recv().await, None);
+
+        assert_eq!(counters().drops[&DropReason::SourceFilter], 2);
+        assert_eq!(counters().loose_source_packets, 2);
+    }
+
+    #[tokio::test]
+    async fn disabled_peer() {
+        let net = SimNetwork::new();
+        let (mut a, mut b) = pair(&net, Timers::default()).await;
+        a.send(&b, b"hello").await;
+        assert_eq!(b.recv().await.unwrap(), b"hello");
+
+        b.set_disabled(&a, true, true);
+        // Clear the sessions of `a` too, so that it has to handshake again.
+        a.set_disabled(&b, true, false);
+        a.set_disabled(&b, false, false);
+
+        a.send(&b, b"refused").await;
+        assert_eq!(b.recv().await, None);
+        assert!(b.peer().counters.drops[&DropReason::PeerDisabled] >= 1);
+
+        // Answered with an ICMP error.
+        b.send(&a, b"dropped").await;
+        let e = timeout(Duration::from_secs(1), b.tun.rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&e[20..22], &[3, 13]);
+
+        // Endpoint and routes are kept.
+        b.set_disabled(&a, false, false);
+        let peer = b.peer();
+        assert!(!peer.disabled);
+        assert_eq!(peer.endpoint, Some("10.0.0.1:5000".parse().unwrap()));
+        assert!(peer.allowed_ips.contains(&(a.ip.into(), 32)));
+        b.send(&a, b"back").await;
+        assert_eq!(a.recv().await.unwrap(), b"back");
+    }
+
+    #[tokio::test]
+    async fn rotate_psk() {
+        let (net, clock) = manual_network();
+        let timers = Timers::default();
+        let (mut a, mut b) = pair(&net, timers).await;
+        a.rotate_psk(&b, [1; 32], None);
+        b.rotate_psk(&a, [1; 32], None);
+        a.send(&b, b"hello").await;
+        assert_eq!(b.recv().await.unwrap(), b"hello");
+
+        // The session is kept.
+        b.rotate_psk(&a, [2; 32], None);
+        a.send(&b, b"kept").await;
+        assert_eq!(b.recv().await.unwrap(), b"kept");
+
+        // But new handshakes fail until `a` gets the new key too.
+        a.set_disabled(&b, true, false);
+        a.set_disabled(&b, false, false);
+        a.send(&b, b"mismatch").await;
+        assert_eq!(b.recv().await, None);
+        assert!(a.peer().counters.psk_mismatches >= 1);
+        a.rotate_psk(&b, [2; 32], None);
+        // The handshake is resent with the new key.
+        advance(&clock, timers.rekey_timeout * 2).await;
+        assert_eq!(b.recv().await.unwrap(), b"mismatch");
+
+        b.rotate_psk(&a, [3; 32], Some(Duration::from_secs(10)));
+        assert!(b.peer().psk_expires_in.is_some());
+        advance(&clock, Duration::from_secs(10)).await;
+        let peer = b.peer();
+        assert!(peer.psk_rotated);
+        assert!(peer.psk_expired);
+        b.send(&a, b"expired").await;
+        assert_eq!(a.recv().await, None);
+        assert!(b.peer().counters.drops[&DropReason::PskExpired] >= 1);
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let net = SimNetwork::new();
+        let (a, mut b) = pair(&net, Timers::default()).await;
+        let mut a_events = a.wg.subscribe();
+        let mut b_events = b.wg.subscribe();
+
+        a.send(&b, b"hello").await;
+        assert!(b.recv().await.is_some());
+        assert_eq!(
+            a_events.recv().await.unwrap(),
+            Event::HandshakeCompleted {
+                peer: b.public_key,
+                initiator: true
+            }
+        );
+        assert_eq!(
+            b_events.recv().await.unwrap(),
+            Event::EndpointChanged {
+                peer: a.public_key,
+                endpoint: "10.0.0.1:5000".parse().unwrap()
+            }
+        );
+        assert_eq!(
+            b_events.recv().await.unwrap(),
+            Event::HandshakeCompleted {
+                peer: a.public_key,
+                initiator: false
+            }
+        );
+
+        b.wg.remove_peer(&a.public_key);
+        assert_eq!(
+            b_events.recv().await.unwrap(),
+            Event::PeerRemoved { peer: a.public_key }
+        );
+    }
+
+    #[tokio::test]
+    async fn reorder_and_duplicate() {
+        let net = SimNetwork::new();
+        let (a, mut b) = pair(&net, Timers::default()).await;
+        a.send(&b, b"hello").await;
+        assert!(b.recv().await.is_some());
+
+        net.set_conditions(LinkConditions {
+            duplicate: 0.5,
+            reorder: 0.5,
+            latency: Duration::from_millis(5),
+            ..LinkConditions::default()
+        });
+        for i in 0..50u8 {
+            a.send(&b, &[i]).await;
+        }
+        let mut received = Vec::new();
+        while let Some(p) = b.recv().await {
+            received.push(p[0]);
+            if received.len() == 50 {
+                break;
+            }
+        }
+        // Each packet is received exactly once, maybe out of order.
+        received.sort_unstable();
+        assert_eq!(received, (0..50).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn handshake_retries() {
+        let (net, clock) = manual_network();
+        let timers = Timers::default();
+        let (a, mut b) = pair(&net, timers).await;
+
+        net.set_conditions(LinkConditions {
+            loss: 1.0,
+            ..LinkConditions::default()
+        });
+        a.send(&b, b"hello").await;
+        advance(&clock, timers.rekey_timeout * 2).await;
+        assert!(a.wg.get_state().counters.handshake_initiations_sent >= 2);
+        assert!(b.peer().last_handshake_time.is_none());
+
+        // The queued packet is sent once a handshake gets through.
+        net.set_conditions(LinkConditions::default());
+        advance(&clock, timers.rekey_timeout * 2).await;
+        assert_eq!(b.recv().await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn roaming_after_nat_rebinding() {
+        let net = SimNetwork::new();
+        let inner: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        net.set_nat(inner, Some("203.0.113.1:40000".parse().unwrap()));
+        let (mut a, mut b) = pair(&net, Timers::default()).await;
+
+        a.send(&b, b"hello").await;
+        assert!(b.recv().await.is_some());
+        assert_eq!(
+            b.peer().endpoint,
+            Some("203.0.113.1:40000".parse().unwrap())
+        );
+
+        let outer: SocketAddr = "203.0.113.1:40001".parse().unwrap();
+        net.set_nat(inner, Some(outer));
+        a.send(&b, b"hello").await;
+        assert!(b.recv().await.is_some());
+        assert_eq!(b.peer().endpoint, Some(outer));
+        // Replies go to the new address.
+        b.send(&a, b"world").await;
+        assert_eq!(a.recv().await.unwrap(), b"world");
+    }
+
+    // Send `n` handshake initiations from `from` to `b`. `from` may be the
+    // address of another node, i.e., spoofed.
+    //
+    // Returns the socket they are sent from, and the initiation.
+    async fn flood_handshakes(
+        net: &SimNetwork,
+        b: &Node,
+        from: &str,
+        n: usize,
+    ) -> (Arc<dyn DatagramSocket>, Vec<u8>) {
+        let attacker = net
+            .host(&[from.parse().unwrap()])
+            .bind("0.0.0.0:0".parse().unwrap(), false, 0)
+            .unwrap();
+        let info = WgInfo {
+            port: 0,
+            fwmark: 0,
+            key: X25519::genkey(),
+        };
+        let peer = PeerInfo {
+            public_key: b.public_key,
+            psk: None,
+            endpoint: None,
+            local: None,
+            allowed_ips: Default::default(),
+            keepalive: None,
+            roaming: true,
+        };
+        let (init, _) = initiate(&info, &peer, Id::gen()).unwrap();
+        for _ in 0..n {
+            attacker
+                .send_to(&init, "10.0.0.2:5000".parse().unwrap(), None)
+                .await
+                .unwrap();
+        }
+        (attacker, init.to_vec())
+    }
+
+    #[tokio::test]
+    async fn cookie_reply_under_load() {
+        let net = SimNetwork::new();
+        let (a, mut b) = pair(&net, Timers::default().scaled(0.01)).await;
+        // Nothing is at the second candidate.
+        a.wg.set_peer(SetPeerCommand {
+            endpoint: vec!["10.0.0.2:5000".parse().unwrap(), "10.0.0.9:5000".parse().unwrap()],
+            ..SetPeerCommand::new(b.public_key)
+        })
+        .unwrap();
+        // So that one source is enough to put `b` under load.
+        b.wg.set_handshake_limits(HandshakeLimits {
+            rate: 0,
+            ..HandshakeLimits::default()
+        });
+
+        let (attacker, _) = flood_handshakes(&net, &b, "10.0.0.66", 1000).await;
+        let mut bufs = vec![vec![0u8; 256]; 32];
+        let mut meta = [RecvMeta::default(); 32];
+        let n = timeout(
+            Duration::from_secs(1),
+            attacker.recv_batch(&mut bufs, &mut meta),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(bufs[..n].iter().all(|p| p[0] == 3));
+
+        // `a` gets a cookie reply, and the next initiation with mac2 is
+        // accepted. It is sent to the same endpoint, which did respond.
+        a.send(&b, b"hello").await;
+        assert_eq!(b.recv().await.unwrap(), b"hello");
+        assert!(a.wg.get_state().counters.handshake_initiations_sent >= 2);
+        assert_eq!(a.peer().endpoint, Some("10.0.0.2:5000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn handshake_rate_limit() {
+        let (net, clock) = manual_network();
+        let timers = Timers::default();
+        let (a, mut b) = pair(&net, timers).await;
+
+        // Put `b` under load, and get a cookie.
+        let (attacker, mut init) = flood_handshakes(&net, &b, "10.0.0.66", 1000).await;
+        let mut bufs = vec![vec![0u8; 256]; 32];
+        let mut meta = [RecvMeta::default(); 32];
+        timeout(
+            Duration::from_secs(1),
+            attacker.recv_batch(&mut bufs, &mut meta),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let reply = &bufs[0][..meta[0].len];
+        let cookie = process_cookie_reply(&b.public_key, &get_mac1(&init), reply).unwrap();
+
+        // With the cookie, all but the first few initiations are dropped.
+        cookie_sign(&mut init, Some(&cookie));
+        for _ in 0..1000 {
+            attacker
+                .send_to(&init, "10.0.0.2:5000".parse().unwrap(), None)
+                .await
+                .unwrap();
+        }
+        clock.settle().await;
+        let counters = b.wg.get_state().counters;
+        assert!(counters.drops[&DropReason::HandshakeRateLimited] >= 900);
+
+        // Other sources are not affected. `a` gets a cookie reply first, and
+        // resends the initiation with mac2.
+        a.send(&b, b"hello").await;
+        advance(&clock, timers.rekey_timeout * 2).await;
+        assert_eq!(b.recv().await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn spoofed_handshake_flood() {
+        let net = SimNetwork::new();
+        let (a, mut b) = pair(&net, Timers::default().scaled(0.01)).await;
+
+        // From the address of `a`, but without cookies, so they don't count
+        // towards its limit.
+        flood_handshakes(&net, &b, "10.0.0.1", 1000).await;
+        a.send(&b, b"hello").await;
+        assert_eq!(b.recv().await.unwrap(), b"hello");
+        let counters = b.wg.get_state().counters;
+        assert_eq!(counters.drops.get(&DropReason::HandshakeRateLimited), None);
+    }
+
+    #[tokio::test]
+    async fn rekey() {
+        let (net, clock) = manual_network();
+        // REKEY_AFTER_TIME is 120s, and REJECT_AFTER_TIME is 180s.
+        let (a, mut b) = pair(&net, Timers::default()).await;
+
+        for i in 0..80u8 {
+            a.send(&b, &[i]).await;
+            assert_eq!(b.recv().await.unwrap(), [i]);
+            advance(&clock, Duration::from_secs(4)).await;
+        }
+        assert!(a.wg.get_state().counters.handshake_initiations_sent >= 2);
+    }
+}
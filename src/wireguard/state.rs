@@ -15,10 +15,12 @@
 // You should have received a copy of the GNU General Public License
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::async_utils::AsyncScope;
 use crate::wireguard::re_exports::{DH, X25519};
 use crate::wireguard::*;
 use anyhow::Context;
-use fnv::FnvHashMap;
+use arrayvec::ArrayVec;
+use fnv::{FnvHashMap, FnvHasher};
 use futures::prelude::*;
 use noise_protocol::U8Array;
 use parking_lot::{Mutex, RwLock};
@@ -26,19 +28,19 @@ use rand::prelude::*;
 use rand::rngs::OsRng;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::{Arc, Weak};
-use std::time::{Duration, Instant};
-use tokio::net::UdpSocket;
+use std::time::Duration;
+use tokio::sync::broadcast;
 use tokio::sync::mpsc::*;
 use tokio::task::yield_now;
-use tokio::time::sleep;
 
 // Some Constants.
 
-// Timeouts, in seconds.
+// Timeouts, in seconds. They are the defaults of `Timers`.
 pub const REKEY_AFTER_TIME: u64 = 120;
 pub const REJECT_AFTER_TIME: u64 = 180;
 pub const REKEY_TIMEOUT: u64 = 5;
@@ -47,12 +49,17 @@ pub const REKEY_ATTEMPT_TIME: u64 = 90;
 
 const BUFSIZE: usize = 65536;
 
-// How many packets to queue.
-pub const QUEUE_SIZE: usize = 16;
-
 // How many handshake messages per second is considered normal load.
 const HANDSHAKES_PER_SEC: u32 = 250;
 
+// How many ICMP errors to write to tun per second.
+const ICMP_ERRORS_PER_SEC: u32 = 100;
+
+// Resolve endpoint host names again every... in seconds.
+pub const DEFAULT_RESOLVE_INTERVAL: u32 = 300;
+// Min interval between resolutions because of failed handshakes, in seconds.
+pub const MIN_RESOLVE_INTERVAL: u64 = 3 * REKEY_TIMEOUT;
+
 // Locking order:
 //   state_change_advisory >
 //   info > pubkey_map > any peers > id_map > anything else
@@ -78,13 +85,43 @@ pub struct WgState {
     pub(crate) rt6: RwLock<IpLookupTable<Ipv6Addr, SharedPeerState>>,
 
     pub(crate) load_monitor: Mutex<LoadMonitor>,
+    // Limits handshake messages from each source address.
+    pub(crate) handshake_limiter: Mutex<HandshakeRateLimiter>,
     // The secret used to calc cookie.
     pub(crate) cookie_secret: RwLock<[u8; 32]>,
 
-    pub(crate) socket: Mutex<Arc<UdpSocket>>,
-    pub(crate) socket_sender: Mutex<Option<Sender<UdpSocket>>>,
-    pub(crate) tun: AsyncTun,
+    // Listen addresses. Port 0 means `info.port`. Empty means `[::]:port`.
+    pub(crate) listen_addrs: Mutex<Vec<SocketAddr>>,
+    // Whether to use dual stack sockets, if supported.
+    pub(crate) dual_stack: AtomicBool,
+    // Never empty.
+    pub(crate) sockets: Mutex<Arc<Vec<ListenSocket>>>,
+    pub(crate) sockets_sender: Mutex<Option<Sender<Vec<ListenSocket>>>>,
+    // Whether sockets are in path MTU probe mode, i.e., some peer has path
+    // MTU discovery on.
+    pub(crate) pmtu_probe_mode: AtomicBool,
+    // Queues of the tun device. Never empty. MTU is taken from the first queue.
+    pub(crate) tun_queues: Vec<Box<dyn PacketDevice>>,
     pub(crate) mtu: AtomicU32,
+    // How many tasks to split encryption and decryption of a batch across.
+    pub(crate) crypto_workers: AtomicUsize,
+    // Addresses of the interface. ICMP errors are sent from them.
+    pub(crate) addresses: RwLock<Vec<IpAddr>>,
+    pub(crate) icmp_limiter: Mutex<IcmpRateLimiter>,
+    pub(crate) counters: Arc<Counters>,
+    // Sends events to IPC subscribers.
+    pub(crate) events: Events,
+    // Limits and usage of the handshake queues of all peers.
+    pub(crate) handshake_queues: Arc<QueueTotals>,
+    // Resolves host names of peer endpoints.
+    pub(crate) resolver: RwLock<Arc<dyn Resolver>>,
+    // In seconds. Zero means only resolve again when handshakes fail.
+    pub(crate) resolve_interval: AtomicU32,
+    // Where sockets are bound.
+    pub(crate) network: Arc<dyn Network>,
+    // Source of time of the protocol timers, and their durations.
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) timers: Timers,
 
     // An advisory lock to prevent possible races between reloading and multiple IPC set requests.
     pub(crate) state_change_advisory: tokio::sync::Mutex<()>,
@@ -123,10 +160,52 @@ impl IdMapGuard {
     }
 }
 
+/// A UDP socket bound to one of the listen addresses.
+#[derive(Clone)]
+pub(crate) struct ListenSocket {
+    // The address it is actually bound to. IPv4 addresses are mapped.
+    pub(crate) addr: SocketAddr,
+    pub(crate) socket: Arc<dyn DatagramSocket>,
+    // An AF_INET socket, which takes IPv4 addresses.
+    pub(crate) ipv4_socket: bool,
+    // Whether it can send to IPv4 and IPv6 addresses.
+    pub(crate) to_v4: bool,
+    pub(crate) to_v6: bool,
+}
+
+impl ListenSocket {
+    fn can_send_to(&self, target: SocketAddrV6) -> bool {
+        if is_ipv4_mapped(target.ip()) {
+            self.to_v4
+        } else {
+            self.to_v6
+        }
+    }
+
+    // Convert `target` to an address of the socket's family.
+    fn target(&self, target: SocketAddrV6) -> SocketAddr {
+        if self.ipv4_socket {
+            unmap_ipv4_from_ipv6(target)
+        } else {
+            target.into()
+        }
+    }
+
+    // The source address to send to `target` with, if `local` is on this
+    // socket and of the same family.
+    fn source(&self, local: Option<LocalAddr>, target: SocketAddrV6) -> Option<IpAddr> {
+        let local = local.filter(|l| l.socket == self.addr)?;
+        local
+            .ip
+            .filter(|ip| ip.is_ipv4() == is_ipv4_mapped(target.ip()))
+    }
+}
+
 fn udp_process_handshake_init<'a>(
     wg: &'a Arc<WgState>,
     p: &'a [u8],
     addr: SocketAddrV6,
+    local: LocalAddr,
 ) -> impl Future<Output = ()> + 'a {
     let no_action = async {}.right_future();
 
@@ -148,12 +227,14 @@ fn udp_process_handshake_init<'a>(
             let mac1 = get_mac1(p);
             let reply = cookie_reply(info.pubkey(), &cookie, peer_id, &mac1);
             return async move {
-                let _ = wg.send_to(&reply[..], addr).await;
+                let _ = wg.send_to(&reply[..], addr, Some(local)).await;
             }
             .left_future()
             .left_future();
-        } else {
-            debug!("Mac2 verify OK.");
+        }
+        debug!("Mac2 verify OK.");
+        if !wg.check_handshake_rate(&addr) {
+            return no_action;
         }
     }
 
@@ -164,12 +245,31 @@ fn udp_process_handshake_init<'a>(
             let mut peer = peer0.write();
 
             peer.count_recv(p.len());
+            peer.count(|c| &c.handshake_initiations_received);
+
+            if peer.disabled {
+                debug!(
+                    "{}: Handshake init, but peer is disabled.",
+                    peer.info.log_id()
+                );
+                peer.count_drop(DropReason::PeerDisabled);
+                return no_action;
+            }
+            if peer.psk_expired {
+                debug!(
+                    "{}: Handshake init, but psk has expired.",
+                    peer.info.log_id()
+                );
+                peer.count_drop(DropReason::PskExpired);
+                return no_action;
+            }
 
             // Compare timestamp.
             if Some(r.timestamp) > peer.last_handshake {
                 peer.last_handshake = Some(r.timestamp);
             } else {
                 debug!("{}: Handshake timestamp smaller.", peer.info.log_id());
+                peer.count(|c| &c.handshake_failures);
                 return no_action;
             }
 
@@ -198,29 +298,38 @@ fn udp_process_handshake_init<'a>(
                 IdMapGuard::new(Arc::downgrade(wg), self_id),
                 r.peer_id,
                 &r.handshake_state,
+                &wg.clock,
+                &wg.timers,
             );
-            if peer.info.roaming {
-                peer.set_endpoint(addr);
+            if peer.info.roaming && peer.set_endpoint(addr) {
+                wg.emit_endpoint_changed(&peer);
             }
+            peer.info.local = Some(local);
             peer.push_transport(t);
             // Now that handshake is successful as responder, no need to do
             // handshake as initiator.
             peer.handshake = None;
             peer.handshake_resend_scope = None;
+            wg.events.send(Event::HandshakeCompleted {
+                peer: peer.info.public_key,
+                initiator: false,
+            });
 
             // Lock id_map.
             wg.id_map.write().insert(self_id, peer0.clone());
             debug!("{}: Handshake successful as responder.", peer.info.log_id());
             return async move {
-                let _ = wg.send_to(&response[..], addr).await;
+                let _ = wg.send_to(&response[..], addr, Some(local)).await;
             }
             .right_future()
             .left_future();
         } else {
             debug!("Get handshake init, but can't find peer by pubkey.");
+            wg.counters.handshake_failures.fetch_add(1);
         }
     } else {
         debug!("Get handshake init, but authentication/decryption failed.");
+        wg.counters.handshake_failures.fetch_add(1);
     }
     no_action
 }
@@ -229,6 +338,7 @@ fn udp_process_handshake_resp<'a>(
     wg: &'a WgState,
     p: &'a [u8],
     addr: SocketAddrV6,
+    local: LocalAddr,
     buffer: &'a mut [u8],
 ) -> impl Future<Output = ()> + Send + 'a {
     let no_action = async {}.left_future();
@@ -251,12 +361,14 @@ fn udp_process_handshake_resp<'a>(
             let mac1 = get_mac1(p);
             let reply = cookie_reply(info.pubkey(), &cookie, peer_id, &mac1);
             return async move {
-                let _ = wg.send_to(&reply, addr).await;
+                let _ = wg.send_to(&reply, addr, Some(local)).await;
             }
             .left_future()
             .right_future();
-        } else {
-            debug!("Mac2 verify OK.");
+        }
+        debug!("Mac2 verify OK.");
+        if !wg.check_handshake_rate(&addr) {
+            return no_action;
         }
     }
 
@@ -274,6 +386,7 @@ fn udp_process_handshake_resp<'a>(
                         "{}: Get handshake response message, but don't know id.",
                         peer.info.log_id()
                     );
+                    peer.count(|c| &c.handshake_failures);
                     return no_action;
                 }
             };
@@ -282,6 +395,7 @@ fn udp_process_handshake_resp<'a>(
                     "{}: Get handshake response message, but don't know id.",
                     peer.info.log_id()
                 );
+                peer.count(|c| &c.handshake_failures);
                 return no_action;
             }
 
@@ -294,22 +408,42 @@ fn udp_process_handshake_resp<'a>(
                     "{}: Get handshake response message, auth/decryption failed.",
                     peer.info.log_id()
                 );
+                peer.count(|c| &c.handshake_failures);
+                // The peer has decrypted our initiation, so the static keys
+                // are right.
+                peer.count(|c| &c.psk_mismatches);
+                wg.events.send(Event::HandshakeFailed {
+                    peer: peer.info.public_key,
+                    reason: "decryption_failed",
+                });
                 return no_action;
             }
             // Release peer.
         };
+        // Lock peer.
+        let mut peer = peer0.write();
+        let handle = match peer.handshake.take() {
+            Some(h) if h.self_id.id == self_id => h.self_id,
+            // Stopped or restarted meanwhile.
+            h => {
+                peer.handshake = h;
+                return no_action;
+            }
+        };
         // Lock id_map.
         wg.id_map.write().insert(self_id, peer0.clone());
         // Release id_map.
-        // Lock peer.
-        let mut peer = peer0.write();
         peer.handshake_resend_scope = None;
-        let handle = peer.handshake.take().unwrap().self_id;
-        let t = Transport::new_from_hs(handle, peer_id, &hs);
+        let t = Transport::new_from_hs(handle, peer_id, &hs, &wg.clock, &wg.timers);
         peer.push_transport(t.clone());
-        if peer.info.roaming {
-            peer.set_endpoint(addr);
+        if peer.info.roaming && peer.set_endpoint(addr) {
+            wg.emit_endpoint_changed(&peer);
         }
+        peer.info.local = Some(local);
+        wg.events.send(Event::HandshakeCompleted {
+            peer: peer.info.public_key,
+            initiator: true,
+        });
 
         let queued_packets = peer.dequeue_all();
         if queued_packets.is_empty() {
@@ -326,7 +460,7 @@ fn udp_process_handshake_resp<'a>(
                 for p in queued_packets {
                     let encrypted = &mut buffer[..p.len() + 32];
                     t.encrypt(&p, encrypted).0.unwrap();
-                    let _ = wg.send_to(encrypted, addr).await;
+                    let _ = wg.send_to(encrypted, addr, Some(local)).await;
                 }
             }
             .right_future()
@@ -334,6 +468,7 @@ fn udp_process_handshake_resp<'a>(
         }
     } else {
         debug!("Get handshake response message, but don't know id.");
+        wg.counters.handshake_failures.fetch_add(1);
     }
     no_action
 }
@@ -347,7 +482,7 @@ fn udp_process_cookie_reply(wg: &WgState, p: &[u8]) {
         peer.count_recv(p.len());
         if let Some(mac1) = peer.last_mac1 {
             if let Ok(cookie) = process_cookie_reply(&peer.info.public_key, &mac1, p) {
-                peer.cookie = Some((cookie, Instant::now()));
+                peer.cookie = Some((cookie, wg.clock.now()));
             } else {
                 debug!(
                     "{}: Process cookie reply: auth/decryption failed.",
@@ -358,11 +493,34 @@ fn udp_process_cookie_reply(wg: &WgState, p: &[u8]) {
     }
 }
 
-async fn udp_process_transport<'a>(
-    wg: &'a Arc<WgState>,
-    p: &'a [u8],
+/// A transport message to be decrypted.
+struct DecryptJob {
+    peer: SharedPeerState,
+    transport: Arc<Transport>,
     addr: SocketAddrV6,
-    buffer: &'a mut [u8],
+    local: LocalAddr,
+    // The encrypted message, replaced with the decrypted packet.
+    packet: Vec<u8>,
+    // Result of `Transport::decrypt`.
+    result: Result<bool, ()>,
+}
+
+impl DecryptJob {
+    fn decrypt(&mut self) {
+        let mut decrypted = vec![0u8; self.packet.len() - 32];
+        self.result = self.transport.decrypt(&self.packet, &mut decrypted);
+        self.packet = decrypted;
+    }
+}
+
+// Find the transport for a transport message. The message is added to `jobs`
+// to be decrypted.
+fn udp_process_transport(
+    wg: &WgState,
+    p: &[u8],
+    addr: SocketAddrV6,
+    local: LocalAddr,
+    jobs: &mut Vec<DecryptJob>,
 ) {
     if p.len() < 32 {
         return;
@@ -374,117 +532,278 @@ async fn udp_process_transport<'a>(
         Some(p) => p,
         None => {
             debug!("Get transport message, but don't know id.");
+            wg.counters.count_drop(DropReason::UnknownId);
             return;
         }
     };
 
-    let decrypted = &mut buffer[..p.len() - 32];
-    let mut should_write = false;
-    let mut packet_len = 0;
+    // Lock peer.
+    let peer = peer0.read();
+    peer.count_recv(p.len());
+    if let Some(t) = peer.find_transport_by_id(self_id) {
+        jobs.push(DecryptJob {
+            peer: peer0.clone(),
+            transport: t.clone(),
+            addr,
+            local,
+            packet: p.to_vec(),
+            result: Err(()),
+        });
+    }
+    // Release peer.
+}
 
+// Handle a decrypted transport message. The packet is added to `tun_batch`.
+fn udp_process_decrypted(wg: &Arc<WgState>, job: DecryptJob, tun_batch: &mut TunWriteBatch) {
+    let DecryptJob {
+        peer: peer0,
+        addr,
+        local,
+        packet: mut decrypted,
+        result,
+        ..
+    } = job;
+
+    let mut should_write = None;
     let mut should_set_endpoint = false;
     let mut should_handshake = false;
+    let mut should_ack = None;
     {
         // Lock peer.
         let peer = peer0.read();
-        peer.count_recv(p.len());
-        if let Some(t) = peer.find_transport_by_id(self_id) {
-            match t.decrypt(p, decrypted) {
-                Ok(h) => {
-                    should_handshake = h && peer.really_should_handshake();
-                    peer.on_recv(decrypted.is_empty());
-                    if peer.info.endpoint != Some(addr) && peer.info.roaming {
-                        should_set_endpoint = true;
+        match result {
+            Ok(h) => {
+                should_handshake = h && peer.really_should_handshake();
+                let pmtu_message = parse_pmtu_message(&decrypted);
+                peer.on_recv(decrypted.is_empty() || pmtu_message.is_some());
+                if (peer.info.endpoint != Some(addr) && peer.info.roaming)
+                    || peer.info.local != Some(local)
+                {
+                    should_set_endpoint = true;
+                }
+                match pmtu_message {
+                    Some(PmtuMessage::Probe(id)) => should_ack = Some(id),
+                    // Send the next probe now if this one is acked.
+                    Some(PmtuMessage::Ack(id)) if peer.pmtu.lock().on_ack(id) => {
+                        peer.pmtu_probe.adjust_and_activate(Duration::from_secs(0));
                     }
-                    if let Ok((len, src, _)) = parse_ip_packet(decrypted) {
-                        // Reverse path filtering.
-                        let peer1 = wg.find_peer_by_ip(src);
-                        if peer1.is_none() || !Arc::ptr_eq(&peer0, &peer1.unwrap()) {
-                            debug!(
-                                "{}: Get transport message: allowed IPs check failed.",
-                                peer.info.log_id()
-                            );
-                        } else if len as usize <= decrypted.len() {
-                            should_write = true;
-                            packet_len = len as usize;
-                        } else {
-                            debug!(
-                                "{}: Get transport message: packet truncated?",
-                                peer.info.log_id()
-                            );
+                    _ => (),
+                }
+                if let Ok(header) = parse_ip_packet(&decrypted) {
+                    // Reverse path filtering.
+                    let peer1 = wg.find_peer_by_ip(header.src);
+                    let in_peer_ips = peer1.as_ref().map(|p| Arc::ptr_eq(&peer0, p));
+                    if !peer.source_filter.allows(
+                        header.src,
+                        in_peer_ips == Some(true),
+                        in_peer_ips.is_some(),
+                    ) {
+                        debug!(
+                            "{}: Get transport message: {} source filter check failed.",
+                            peer.info.log_id(),
+                            peer.source_filter.mode_name(),
+                        );
+                        peer.count_drop(match peer.source_filter {
+                            SourceFilter::Strict => DropReason::AllowedIps,
+                            _ => DropReason::SourceFilter,
+                        });
+                    } else if !peer.filter.check(FilterDirection::In, &header) {
+                        debug!(
+                            "{}: Get transport message: packet filtered.",
+                            peer.info.log_id()
+                        );
+                        peer.count_drop(DropReason::Filtered);
+                    } else if header.len as usize <= decrypted.len() {
+                        should_write = Some(wg.tun_queue_for(header.src, header.dst));
+                        decrypted.truncate(header.len as usize);
+                        if in_peer_ips != Some(true) {
+                            peer.count(|c| &c.loose_source_packets);
                         }
+                    } else {
+                        debug!(
+                            "{}: Get transport message: packet truncated?",
+                            peer.info.log_id()
+                        );
+                        peer.count_drop(DropReason::InvalidPacket);
                     }
                 }
-                Err(_) => {
-                    debug!(
-                        "{}: Get transport message, decryption failed.",
-                        peer.info.log_id()
-                    );
-                }
+            }
+            Err(_) => {
+                debug!(
+                    "{}: Get transport message, decryption failed.",
+                    peer.info.log_id()
+                );
+                peer.count_drop(DropReason::DecryptionFailed);
             }
         }
         // Release peer.
     };
-    if should_write {
-        let _ = wg.tun.write(&decrypted[..packet_len]).await;
+    if let Some(queue) = should_write {
+        tun_batch.push(queue, decrypted);
     }
     if should_set_endpoint {
         // Lock peer.
-        peer0.write().set_endpoint(addr);
+        let mut peer = peer0.write();
+        if peer.info.roaming && peer.set_endpoint(addr) {
+            wg.emit_endpoint_changed(&peer);
+        }
+        peer.info.local = Some(local);
+    }
+    if let Some(id) = should_ack {
+        tokio::spawn(send_pmtu_ack(wg.clone(), peer0.clone(), id));
     }
     if should_handshake {
-        do_handshake(&wg, &peer0);
+        do_handshake(wg, &peer0);
+    }
+}
+
+// Max number of packets written to tun in one batch.
+const TUN_WRITE_BATCH_SIZE: usize = 64;
+
+/// Decrypted packets to be written to tun, with the queues to write them to.
+struct TunWriteBatch {
+    packets: Vec<(usize, Vec<u8>)>,
+}
+
+impl TunWriteBatch {
+    fn new() -> Self {
+        Self {
+            packets: Vec::with_capacity(TUN_WRITE_BATCH_SIZE),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.packets.len() == TUN_WRITE_BATCH_SIZE
+    }
+
+    fn push(&mut self, queue: usize, packet: Vec<u8>) {
+        self.packets.push((queue, packet));
+    }
+
+    async fn flush(&mut self, wg: &WgState) {
+        for (queue, tun) in wg.tun_queues.iter().enumerate() {
+            let packets: ArrayVec<&[u8], TUN_WRITE_BATCH_SIZE> = self
+                .packets
+                .iter()
+                .filter(|(q, _)| *q == queue)
+                .map(|(_, p)| &p[..])
+                .collect();
+            if !packets.is_empty() {
+                let _ = tun.write_batch(&packets).await;
+            }
+        }
+        self.packets.clear();
     }
 }
 
 /// Receiving loop.
-async fn udp_processing(wg: Arc<WgState>, mut receiver: Receiver<UdpSocket>) {
-    let mut p = vec![0u8; BUFSIZE];
+///
+/// Transport messages are decrypted in parallel, and written to tun in the
+/// order they are received.
+async fn udp_processing(wg: Arc<WgState>, receiver: Receiver<Vec<ListenSocket>>) {
+    let (jobs_sender, jobs_receiver) = pipeline();
+    future::join(
+        udp_receive_all(&wg, receiver, jobs_sender),
+        tun_write_decrypted(&wg, jobs_receiver),
+    )
+    .await;
+}
+
+// Receive on all sockets, until they are replaced.
+async fn udp_receive_all(
+    wg: &Arc<WgState>,
+    mut receiver: Receiver<Vec<ListenSocket>>,
+    jobs_sender: Sender<PendingBatch<DecryptJob>>,
+) {
+    loop {
+        let sockets = wg.sockets.lock().clone();
+        let receive = future::join_all(
+            sockets
+                .iter()
+                .map(|s| udp_receive(wg, s, jobs_sender.clone())),
+        );
+
+        futures::select_biased! {
+            sockets = receiver.recv().fuse() => {
+                if let Some(sockets) = sockets {
+                    *wg.sockets.lock() = Arc::new(sockets);
+                } else {
+                    // The sender is dropped, this means that there is
+                    // now another rx task, and we should return.
+                    return;
+                }
+            }
+            _ = receive.fuse() => unreachable!(),
+        }
+    }
+}
+
+async fn udp_receive(
+    wg: &Arc<WgState>,
+    socket: &ListenSocket,
+    jobs_sender: Sender<PendingBatch<DecryptJob>>,
+) {
+    let mut bufs = vec![vec![0u8; BUFSIZE]; BATCH_SIZE];
+    let mut meta = [RecvMeta::default(); BATCH_SIZE];
     let mut buffer = vec![0u8; BUFSIZE];
     loop {
         for _ in 0..1024 {
-            let (len, addr) = {
-                let socket = wg.socket.lock().clone();
-
-                futures::select_biased! {
-                    recv_result = socket.recv_from(&mut p).fuse() => recv_result.unwrap(),
-                    socket = receiver.recv().fuse() => {
-                        if let Some(socket) = socket {
-                            *wg.socket.lock() = Arc::new(socket);
-                            continue;
-                        } else {
-                            // The sender is dropped, this means that there is
-                            // now another rx task, and we should return.
-                            return;
-                        }
-                    }
-                }
-            };
+            let n = socket
+                .socket
+                .recv_batch(&mut bufs, &mut meta)
+                .await
+                .unwrap();
+
+            let mut jobs = Vec::new();
+            for (buf, m) in bufs.iter().zip(&meta[..n]) {
+                // Addresses are always IPv6 internally.
+                let addr = map_ipv4_to_ipv6(m.addr);
+                let local = LocalAddr {
+                    socket: socket.addr,
+                    ip: m.dst,
+                };
 
-            let addr = match addr {
-                SocketAddr::V6(a6) => a6,
-                _ => unreachable!(),
-            };
+                // Split GRO coalesced datagrams.
+                for p in m.segments(buf) {
+                    if p.len() < 12 {
+                        continue;
+                    }
 
-            if len < 12 {
-                continue;
+                    match p[0] {
+                        1 => udp_process_handshake_init(wg, p, addr, local).await,
+                        2 => udp_process_handshake_resp(wg, p, addr, local, &mut buffer).await,
+                        3 => udp_process_cookie_reply(wg, p),
+                        4 => udp_process_transport(wg, p, addr, local, &mut jobs),
+                        _ => (),
+                    }
+                }
             }
-
-            let type_ = p[0];
-            let p = &p[..len];
-
-            match type_ {
-                1 => udp_process_handshake_init(&wg, p, addr).await,
-                2 => udp_process_handshake_resp(&wg, p, addr, &mut buffer).await,
-                3 => udp_process_cookie_reply(&wg, p),
-                4 => udp_process_transport(&wg, p, addr, &mut buffer).await,
-                _ => (),
+            if !jobs.is_empty() {
+                let pending = process_in_parallel(jobs, wg.crypto_workers(), DecryptJob::decrypt);
+                let _ = jobs_sender.send(pending).await;
             }
         }
         yield_now().await;
     }
 }
 
+async fn tun_write_decrypted(
+    wg: &Arc<WgState>,
+    mut jobs_receiver: Receiver<PendingBatch<DecryptJob>>,
+) {
+    let mut tun_batch = TunWriteBatch::new();
+    while let Some(pending) = jobs_receiver.recv().await {
+        for job in pending.await {
+            udp_process_decrypted(wg, job, &mut tun_batch);
+            if tun_batch.is_full() {
+                tun_batch.flush(wg).await;
+            }
+        }
+        // Write decrypted packets together, so that they can be coalesced.
+        tun_batch.flush(wg).await;
+    }
+}
+
 /// Calculate padded length, i.e., next multiple of 16.
 ///
 /// The length will be at most `mtu`.
@@ -525,103 +844,477 @@ fn padding() {
     }
 }
 
-async fn tun_packet_processing(wg: Arc<WgState>) -> anyhow::Result<()> {
+/// A packet read from tun, waiting to be let through by `TxScheduler`.
+struct TxPacket {
+    peer: SharedPeerState,
+    qos: Arc<PeerQos>,
+    // The padded packet.
+    packet: Vec<u8>,
+}
+
+/// A packet to be encrypted.
+struct EncryptJob {
+    transport: Arc<Transport>,
+    counter: u64,
+    endpoint: SocketAddrV6,
+    local: Option<LocalAddr>,
+    // The padded packet, replaced with the encrypted message.
+    packet: Vec<u8>,
+}
+
+impl EncryptJob {
+    fn encrypt(&mut self) {
+        let mut encrypted = vec![0u8; self.packet.len() + 32];
+        self.transport
+            .encrypt_with_counter(self.counter, &self.packet, &mut encrypted);
+        self.packet = encrypted;
+    }
+}
+
+/// Sending loop of a tun queue.
+///
+/// Packets are shaped with `TxScheduler` first. Packets it lets through get
+/// nonces in that order, and are encrypted in parallel, so packets to the
+/// same peer are sent in the order of their nonces.
+async fn tun_packet_processing(wg: Arc<WgState>, queue: usize) -> anyhow::Result<()> {
+    let (packets_sender, packets_receiver) = channel(PIPELINE_DEPTH);
+    let (jobs_sender, jobs_receiver) = pipeline();
+    future::join3(
+        tun_read(&wg, queue, packets_sender),
+        tx_schedule(&wg, packets_receiver, jobs_sender),
+        udp_send_encrypted(&wg, jobs_receiver),
+    )
+    .await
+    .0
+}
+
+async fn tun_read(
+    wg: &Arc<WgState>,
+    queue: usize,
+    packets_sender: Sender<Vec<TxPacket>>,
+) -> anyhow::Result<()> {
+    let tun = &wg.tun_queues[queue];
     let mut pkt = vec![0u8; BUFSIZE];
-    let mut encrypted = vec![0u8; BUFSIZE + 32];
+    let mut icmp_errors = Vec::new();
     loop {
         for _ in 0..1024 {
-            let len = wg
-                .tun
-                .read(&mut pkt)
-                .await
-                .context("read from tun device")?;
+            let mut packets = Vec::with_capacity(BATCH_SIZE);
+            let len = tun.read(&mut pkt).await.context("read from tun device")?;
+            tun_process_packet(wg, &mut pkt, len, &mut packets, &mut icmp_errors);
+
+            // Process packets that are already available, and send them
+            // together.
+            for _ in 1..BATCH_SIZE {
+                match tun.try_read(&mut pkt) {
+                    Ok(len) => {
+                        tun_process_packet(wg, &mut pkt, len, &mut packets, &mut icmp_errors)
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e).context("read from tun device"),
+                }
+            }
 
-            let padded_len = pad_len(len, wg.mtu.load(Ordering::Relaxed) as usize);
-            // Do not leak other packets' data!
-            for b in &mut pkt[len..padded_len] {
-                *b = 0;
+            if !packets.is_empty() {
+                let _ = packets_sender.send(packets).await;
             }
-            let pkt = &pkt[..padded_len];
 
-            let dst = match parse_ip_packet(pkt) {
-                Ok((_, _, dst)) => dst,
-                Err(_) => {
-                    error!("Get packet from TUN interface, but failed to parse it!");
-                    continue;
-                }
-            };
+            if !icmp_errors.is_empty() {
+                let errors: Vec<&[u8]> = icmp_errors.iter().map(|e| &e[..]).collect();
+                let _ = tun.write_batch(&errors).await;
+                icmp_errors.clear();
+            }
+        }
+        yield_now().await;
+    }
+}
 
-            let peer0 = match wg.find_peer_by_ip(dst) {
-                Some(peer) => peer,
+async fn tx_schedule(
+    wg: &Arc<WgState>,
+    mut packets_receiver: Receiver<Vec<TxPacket>>,
+    jobs_sender: Sender<PendingBatch<EncryptJob>>,
+) {
+    let mut scheduler = TxScheduler::new();
+    let mut packets = Vec::with_capacity(BATCH_SIZE);
+    loop {
+        if scheduler.is_empty() {
+            match packets_receiver.recv().await {
+                Some(p) => schedule(&mut scheduler, p),
+                None => return,
+            }
+        }
+        // Take batches that are already available, so that there are more
+        // peers to choose from.
+        while let Some(Some(p)) = packets_receiver.recv().now_or_never() {
+            schedule(&mut scheduler, p);
+        }
+
+        let now = wg.clock.now();
+        let wake_up = loop {
+            if packets.len() == BATCH_SIZE {
+                break None;
+            }
+            match scheduler.pop(now) {
+                Ok(p) => packets.push(p),
+                Err(wake_up) => break wake_up,
+            }
+        };
+
+        if !packets.is_empty() {
+            let jobs = reserve_counters(wg, packets.drain(..));
+            if !jobs.is_empty() {
+                let pending = process_in_parallel(jobs, wg.crypto_workers(), EncryptJob::encrypt);
+                let _ = jobs_sender.send(pending).await;
+            }
+        } else if let Some(wake_up) = wake_up {
+            // All queued packets are rate limited. Wait for them, or for more
+            // packets.
+            futures::select_biased! {
+                p = packets_receiver.recv().fuse() => match p {
+                    Some(p) => schedule(&mut scheduler, p),
+                    None => wg.clock.sleep_until(wake_up).await,
+                },
+                _ = wg.clock.sleep_until(wake_up).fuse() => (),
+            }
+        }
+    }
+}
+
+fn schedule(scheduler: &mut TxScheduler<TxPacket>, packets: Vec<TxPacket>) {
+    for p in packets {
+        let qos = p.qos.clone();
+        scheduler.push(&qos, p.packet.len() + 32, p);
+    }
+}
+
+// Assign nonces to packets let through by the scheduler, in order.
+fn reserve_counters(wg: &Arc<WgState>, packets: impl Iterator<Item = TxPacket>) -> Vec<EncryptJob> {
+    let mut jobs = Vec::with_capacity(BATCH_SIZE);
+    for p in packets {
+        let should_handshake = {
+            let peer = p.peer.read();
+            if peer.disabled || peer.psk_expired {
+                peer.count_drop(if peer.disabled {
+                    DropReason::PeerDisabled
+                } else {
+                    DropReason::PskExpired
+                });
+                continue;
+            }
+            let endpoint = match peer.get_endpoint() {
+                Some(e) => e,
                 None => {
-                    // TODO ICMP no route to host.
-                    match dst {
-                        IpAddr::V6(i) if i.segments()[0] == 0xff02 => (),
-                        _ => debug!("No route to host: {}", dst),
-                    };
+                    peer.count_drop(DropReason::NoEndpoint);
                     continue;
                 }
             };
-
-            let encrypted = &mut encrypted[..pkt.len() + 32];
-            let endpoint;
-            let mut should_send = false;
-
-            let should_handshake = {
-                // Lock peer.
-                let peer = peer0.read();
-                endpoint = match peer.get_endpoint() {
-                    None => continue,
-                    Some(e) => e,
-                };
-
-                if let Some(t) = peer.find_transport_to_send() {
-                    let (result, should_handshake) = t.encrypt(pkt, encrypted);
-                    if result.is_ok() {
-                        should_send = true;
-                        peer.count_send(encrypted.len());
-                        peer.on_send_transport();
-                    }
+            if let Some(t) = peer.find_transport_to_send() {
+                let (counter, should_handshake) = t.reserve_counter();
+                if let Ok(counter) = counter {
+                    peer.count_send(p.packet.len() + 32);
+                    peer.on_send_transport();
+                    jobs.push(EncryptJob {
+                        transport: t.clone(),
+                        counter,
+                        endpoint,
+                        local: peer.info.local,
+                        packet: p.packet,
+                    });
                     should_handshake && peer.really_should_handshake()
                 } else {
-                    peer.enqueue_packet(pkt);
-
+                    // The session has used up its nonces.
+                    peer.enqueue_packet(&p.packet);
                     peer.really_should_handshake()
                 }
-                // Release peer.
+            } else {
+                // The sessions are gone while it was queued.
+                peer.enqueue_packet(&p.packet);
+                peer.really_should_handshake()
+            }
+        };
+        if should_handshake {
+            do_handshake(wg, &p.peer);
+        }
+    }
+    jobs
+}
+
+async fn udp_send_encrypted(wg: &WgState, mut jobs_receiver: Receiver<PendingBatch<EncryptJob>>) {
+    let mut batch = SendBatch::new(BUFSIZE + 32);
+    while let Some(pending) = jobs_receiver.recv().await {
+        send_encrypted(wg, &mut batch, pending.await.into_iter()).await;
+    }
+}
+
+async fn send_encrypted(
+    wg: &WgState,
+    batch: &mut SendBatch,
+    jobs: impl Iterator<Item = EncryptJob>,
+) {
+    // Packets in a batch are sent from the same socket. It is chosen by the
+    // local address of the peer and the family of the endpoint.
+    let mut batch_key = None;
+    let mut batch_socket = None;
+    for job in jobs {
+        let key = Some((
+            job.local.map(|l| l.socket),
+            is_ipv4_mapped(job.endpoint.ip()),
+        ));
+        if key != batch_key {
+            if let Some(ref s) = batch_socket {
+                let _ = send_batch(s, batch).await;
+            }
+            batch_key = key;
+            batch_socket = Some(wg.socket_for(job.local, job.endpoint));
+        }
+        let socket = batch_socket.as_ref().unwrap();
+        if batch.is_full() {
+            let _ = send_batch(socket, batch).await;
+        }
+        let len = job.packet.len();
+        batch.next_buf()[..len].copy_from_slice(&job.packet);
+        batch.push(
+            len,
+            socket.target(job.endpoint),
+            socket.source(job.local, job.endpoint),
+        );
+    }
+    if let Some(ref s) = batch_socket {
+        let _ = send_batch(s, batch).await;
+    }
+}
+
+// Send and clear `batch`.
+async fn send_batch(socket: &ListenSocket, batch: &mut SendBatch) -> io::Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let result = socket.socket.send_batch(batch).await;
+    batch.clear();
+    result
+}
+
+// Process a packet read from tun. Packet to send is added to `packets`. ICMP
+// errors to write back to tun are added to `icmp_errors`.
+fn tun_process_packet(
+    wg: &Arc<WgState>,
+    pkt: &mut [u8],
+    len: usize,
+    packets: &mut Vec<TxPacket>,
+    icmp_errors: &mut Vec<Vec<u8>>,
+) {
+    let header = match parse_ip_packet(&pkt[..len]) {
+        Ok(h) => h,
+        Err(_) => {
+            error!("Get packet from TUN interface, but failed to parse it!");
+            wg.counters.count_drop(DropReason::InvalidPacket);
+            return;
+        }
+    };
+
+    let peer0 = match wg.find_peer_by_ip(header.dst) {
+        Some(peer) => peer,
+        None => {
+            match header.dst {
+                IpAddr::V6(i) if i.segments()[0] == 0xff02 => (),
+                _ => debug!("No route to host: {}", header.dst),
             };
+            wg.counters.count_drop(DropReason::NoRoute);
+            icmp_errors.extend(wg.make_icmp_error(&pkt[..len], IcmpReason::Unreachable));
+            return;
+        }
+    };
+
+    let should_handshake = {
+        // Lock peer.
+        let peer = peer0.read();
 
-            if should_send {
-                let _ = wg.send_to(encrypted, endpoint).await;
+        if peer.disabled {
+            peer.count_drop(DropReason::PeerDisabled);
+            if peer.disabled_unreachable {
+                icmp_errors.extend(wg.make_icmp_error(&pkt[..len], IcmpReason::Prohibited));
             }
+            return;
+        }
+        if peer.psk_expired {
+            peer.count_drop(DropReason::PskExpired);
+            return;
+        }
 
-            if should_handshake {
-                do_handshake(&wg, &peer0);
+        if !peer.filter.check(FilterDirection::Out, &header) {
+            debug!("{}: Packet to peer filtered.", peer.info.log_id());
+            peer.count_drop(DropReason::Filtered);
+            return;
+        }
+
+        let mtu = peer.effective_mtu(wg.mtu.load(Ordering::Relaxed));
+        if len > mtu as usize {
+            if let Some(e) = wg.make_icmp_error(&pkt[..len], IcmpReason::TooBig { mtu }) {
+                icmp_errors.push(e);
+                peer.count_drop(DropReason::TooBig);
+                return;
             }
+            // Otherwise it's an IPv4 packet without DF, or the error is rate
+            // limited. Send it anyway.
         }
-        yield_now().await;
+
+        let padded_len = pad_len(len, mtu as usize);
+        // Do not leak other packets' data!
+        for b in &mut pkt[len..padded_len] {
+            *b = 0;
+        }
+        let pkt = &pkt[..padded_len];
+
+        if peer.get_endpoint().is_none() {
+            debug!("{}: No endpoint.", peer.info.log_id());
+            peer.count_drop(DropReason::NoEndpoint);
+            icmp_errors.extend(wg.make_icmp_error(&pkt[..len], IcmpReason::Unreachable));
+            return;
+        }
+
+        if peer.find_transport_to_send().is_some() {
+            // Nonces are assigned after shaping, in `reserve_counters`.
+            packets.push(TxPacket {
+                peer: peer0.clone(),
+                qos: peer.qos.clone(),
+                packet: pkt.to_vec(),
+            });
+            false
+        } else {
+            peer.enqueue_packet(pkt);
+
+            peer.really_should_handshake()
+        }
+        // Release peer.
+    };
+
+    if should_handshake {
+        do_handshake(wg, &peer0);
     }
 }
 
 /// Data structure passed to [WgState::set_peer].
 pub struct SetPeerCommand {
     pub public_key: [u8; 32],
-    /// Update if `Some`.
-    pub preshared_key: Option<[u8; 32]>,
-    /// Update if `Some`.
-    pub endpoint: Option<SocketAddr>,
+    /// Update if `Some`. `Some(None)` removes it.
+    pub preshared_key: Option<Option<[u8; 32]>>,
+    /// Replace the pre-shared key without clearing the sessions, e.g., by an
+    /// external post-quantum key exchange. `preshared_key` is ignored if this
+    /// is `Some`.
+    pub rotate_psk: Option<[u8; 32]>,
+    /// How long the rotated pre-shared key is valid. When it expires, the
+    /// peer refuses handshakes and drops packets until a new one is pushed.
+    ///
+    /// Only used with `rotate_psk`. `None` means it never expires.
+    pub psk_lifetime: Option<Duration>,
+    /// Candidate endpoints, tried in order when handshakes get no response.
+    ///
+    /// Update if not empty.
+    pub endpoint: Vec<SocketAddr>,
+    /// Host names of the candidate endpoints, e.g., `vpn.example.com:51820`.
+    ///
+    /// They are resolved again periodically and when handshakes get no
+    /// response, and the candidate endpoints are updated. Replaced whenever
+    /// `endpoint` is updated, or if not empty.
+    pub endpoint_hosts: Vec<String>,
     /// Update if `Some`.
     ///
     /// Update to `None` if it is `Some(0)`.
     pub keepalive: Option<u16>,
+    /// Rate limit of packets sent to the peer.
+    ///
+    /// Update if `Some`. Rate zero means no limit.
+    pub rate_limit: Option<RateLimit>,
+    /// Packet filter rules.
+    ///
+    /// Replace if `Some`.
+    pub filter: Option<Vec<FilterRule>>,
+    /// How sources of packets from the peer are checked.
+    ///
+    /// Update if `Some`.
+    pub source_filter: Option<SourceFilter>,
+    /// Disable or enable the peer.
+    ///
+    /// A disabled peer keeps its config, but refuses handshakes, drops
+    /// packets and has its sessions cleared. Update if `Some`.
+    pub disabled: Option<bool>,
+    /// Answer packets to the peer with ICMP errors when it is disabled.
+    ///
+    /// Update if `Some`.
+    pub disabled_unreachable: Option<bool>,
+    /// Search for the path MTU to the peer. The peer must support it too.
+    ///
+    /// Update if `Some`.
+    pub path_mtu_discovery: Option<bool>,
     pub replace_allowed_ips: bool,
     /// Replace if `replace_allowed_ips`, append otherwise.
     pub allowed_ips: BTreeSet<(IpAddr, u32)>,
 }
 
+impl SetPeerCommand {
+    /// A command that changes nothing.
+    pub fn new(public_key: [u8; 32]) -> Self {
+        SetPeerCommand {
+            public_key,
+            preshared_key: None,
+            rotate_psk: None,
+            psk_lifetime: None,
+            endpoint: vec![],
+            endpoint_hosts: vec![],
+            keepalive: None,
+            rate_limit: None,
+            filter: None,
+            source_filter: None,
+            disabled: None,
+            disabled_unreachable: None,
+            path_mtu_discovery: None,
+            replace_allowed_ips: false,
+            allowed_ips: BTreeSet::new(),
+        }
+    }
+}
+
 impl WgState {
-    /// Create a new `WgState`, start worker threads.
-    pub fn new(tun: AsyncTun) -> anyhow::Result<Arc<WgState>> {
+    /// Create a new `WgState` that reads and writes packets from `tun`.
+    pub fn new<D: PacketDevice>(tun: D) -> anyhow::Result<Arc<WgState>> {
+        WgState::new_multi_queue(vec![tun])
+    }
+
+    /// Create a new `WgState` that reads and writes packets from multiple
+    /// queues of a tun device.
+    ///
+    /// Each queue gets its own TX loop, and packets received from the network
+    /// are spread across the queues by flow.
+    pub fn new_multi_queue<D: PacketDevice>(queues: Vec<D>) -> anyhow::Result<Arc<WgState>> {
+        WgState::new_with_clock(queues, Arc::new(SystemClock), Timers::default())
+    }
+
+    /// Create a new `WgState` with a custom clock and protocol timers.
+    ///
+    /// For tests, e.g., with a `ManualClock` to let sessions expire without
+    /// waiting for minutes.
+    #[doc(hidden)]
+    pub fn new_with_clock<D: PacketDevice>(
+        queues: Vec<D>,
+        clock: Arc<dyn Clock>,
+        timers: Timers,
+    ) -> anyhow::Result<Arc<WgState>> {
+        WgState::new_with_network(queues, Arc::new(SystemNetwork), clock, timers)
+    }
+
+    /// Create a new `WgState` that binds its sockets on `network`.
+    ///
+    /// For tests, e.g., with a host of a `SimNetwork`.
+    #[doc(hidden)]
+    pub fn new_with_network<D: PacketDevice>(
+        queues: Vec<D>,
+        network: Arc<dyn Network>,
+        clock: Arc<dyn Clock>,
+        timers: Timers,
+    ) -> anyhow::Result<Arc<WgState>> {
+        if queues.is_empty() {
+            bail!("no tun queues");
+        }
+
         let mut info = WgInfo {
             port: 0,
             fwmark: 0,
@@ -631,12 +1324,12 @@ impl WgState {
         let mut cookie = [0u8; 32];
         OsRng.fill_bytes(&mut cookie);
 
-        let socket = WgState::prepare_socket(&mut info.port, info.fwmark)?;
-        #[cfg(not(windows))]
-        let mtu = tun.get_mtu().context("failed to get mtu")?.into();
-        // TODO: Implement get MTU on Windows.
-        #[cfg(windows)]
-        let mtu = 1280.into();
+        let sockets = WgState::prepare_sockets(&*network, &mut info.port, &[], true, info.fwmark)?;
+        let mtu = queues[0].mtu().context("failed to get mtu")?.into();
+        let tun_queues = queues
+            .into_iter()
+            .map(|q| Box::new(q) as Box<dyn PacketDevice>)
+            .collect();
 
         let wg = Arc::new(WgState {
             info: RwLock::new(info),
@@ -644,32 +1337,109 @@ impl WgState {
             id_map: RwLock::new(Default::default()),
             rt4: RwLock::new(IpLookupTable::new()),
             rt6: RwLock::new(IpLookupTable::new()),
-            load_monitor: Mutex::new(LoadMonitor::new(HANDSHAKES_PER_SEC)),
+            load_monitor: Mutex::new(LoadMonitor::new(HANDSHAKES_PER_SEC, clock.now())),
+            handshake_limiter: Mutex::new(HandshakeRateLimiter::new(clock.now())),
             cookie_secret: RwLock::new(cookie),
-            socket: Mutex::new(Arc::new(socket)),
-            socket_sender: Mutex::new(None),
-            tun,
+            listen_addrs: Mutex::new(Vec::new()),
+            dual_stack: AtomicBool::new(true),
+            sockets: Mutex::new(Arc::new(sockets)),
+            sockets_sender: Mutex::new(None),
+            pmtu_probe_mode: AtomicBool::new(false),
+            tun_queues,
             mtu,
+            crypto_workers: AtomicUsize::new(1),
+            addresses: RwLock::new(Vec::new()),
+            icmp_limiter: Mutex::new(IcmpRateLimiter::new(ICMP_ERRORS_PER_SEC, clock.now())),
+            counters: Arc::new(Counters::new()),
+            events: Events::new(),
+            handshake_queues: Arc::new(QueueTotals::new()),
+            resolver: RwLock::new(Arc::new(SystemResolver)),
+            resolve_interval: AtomicU32::new(DEFAULT_RESOLVE_INTERVAL),
+            network,
+            clock,
+            timers,
             state_change_advisory: ().into(),
         });
         Ok(wg)
     }
 
+    /// Set how many tasks encryption and decryption are split across.
+    ///
+    /// Usually the number of runtime worker threads. Default is 1, i.e.
+    /// packets are encrypted and decrypted in the TX and RX tasks.
+    pub fn set_crypto_workers(&self, workers: usize) {
+        self.crypto_workers
+            .store(std::cmp::max(1, workers), Ordering::Relaxed);
+    }
+
+    fn crypto_workers(&self) -> usize {
+        self.crypto_workers.load(Ordering::Relaxed)
+    }
+
+    /// Set addresses of the interface.
+    ///
+    /// They are used as source addresses of ICMP errors. Without an address of
+    /// the same family, ICMP errors are sent from the destination of the
+    /// original packet.
+    pub fn set_addresses(&self, addresses: Vec<IpAddr>) {
+        *self.addresses.write() = addresses;
+    }
+
+    /// Set the resolver of endpoint host names. Default is `SystemResolver`.
+    pub fn set_resolver(&self, resolver: Arc<dyn Resolver>) {
+        *self.resolver.write() = resolver;
+    }
+
+    /// Set how often endpoint host names are resolved again, in seconds.
+    ///
+    /// Zero means they are only resolved again when handshakes get no
+    /// response. Default is `DEFAULT_RESOLVE_INTERVAL`. Takes effect after the
+    /// next resolution.
+    pub fn set_resolve_interval(&self, secs: u32) {
+        self.resolve_interval.store(secs, Ordering::Relaxed);
+    }
+
+    /// Set limits of packets waiting for handshakes.
+    pub fn set_queue_limits(&self, limits: QueueLimits) {
+        self.handshake_queues.set_limits(limits);
+    }
+
+    /// Set limits of handshake messages from each source address.
+    pub fn set_handshake_limits(&self, limits: HandshakeLimits) {
+        self.handshake_limiter.lock().set_limits(limits);
+    }
+
+    // Build an ICMP error about `packet`, unless it is rate limited.
+    fn make_icmp_error(&self, packet: &[u8], reason: IcmpReason) -> Option<Vec<u8>> {
+        let is_v4 = packet[0] >> 4 == 4;
+        let source = self
+            .addresses
+            .read()
+            .iter()
+            .find(|a| a.is_ipv4() == is_v4)
+            .cloned();
+        let e = icmp_error(packet, reason, source)?;
+        if self.icmp_limiter.lock().check(self.clock.now()) {
+            Some(e)
+        } else {
+            None
+        }
+    }
+
     /// Update cookie secret every two minutes.
     pub async fn task_update_cookie_secret(self: Arc<WgState>) {
         loop {
-            sleep(Duration::from_secs(120)).await;
+            self.clock.sleep(Duration::from_secs(120)).await;
             let mut cookie = self.cookie_secret.write();
             OsRng.fill_bytes(&mut cookie[..]);
         }
     }
 
-    /// Update MTU every 10 seconds.
-    #[cfg(not(windows))]
+    /// Update MTU when the packet device changes.
     pub async fn task_update_mtu(self: Arc<WgState>) {
         loop {
-            sleep(Duration::from_secs(10)).await;
-            match self.tun.get_mtu() {
+            self.tun_queues[0].changed().await;
+            match self.tun_queues[0].mtu() {
                 Ok(mtu) => {
                     let old_mtu = self.mtu.load(Ordering::Relaxed);
                     if mtu != old_mtu {
@@ -683,78 +1453,133 @@ impl WgState {
     }
 
     /// TX. Tun -> Socket.
+    ///
+    /// Runs a loop for each tun queue.
     pub async fn task_tx(self: Arc<WgState>) {
-        match tun_packet_processing(self).await {
-            Err(e) => error!("error in tx task: {:#}", e),
-            _ => unreachable!(),
+        let scope = AsyncScope::new();
+        for queue in 0..self.tun_queues.len() {
+            let wg = self.clone();
+            scope.spawn_canceller(async move {
+                match tun_packet_processing(wg, queue).await {
+                    Err(e) => error!("error in tx task: {:#}", e),
+                    _ => unreachable!(),
+                }
+            });
         }
+        scope.cancelled().await;
+    }
+
+    // Choose a tun queue to write a packet to. Packets of the same flow always
+    // go to the same queue, so they are not reordered.
+    fn tun_queue_for(&self, src: IpAddr, dst: IpAddr) -> usize {
+        if self.tun_queues.len() == 1 {
+            return 0;
+        }
+        let mut hasher = FnvHasher::default();
+        (src, dst).hash(&mut hasher);
+        hasher.finish() as usize % self.tun_queues.len()
     }
 
     /// RX. Socket -> Tun.
     pub async fn task_rx(self: Arc<WgState>) {
         let (sender, receiver) = channel(1);
-        *self.socket_sender.lock() = Some(sender);
+        *self.sockets_sender.lock() = Some(sender);
         udp_processing(self, receiver).await;
     }
 
-    // Create a new socket, set IPv6 only to false, set fwmark, and bind.
-    fn prepare_socket(port: &mut u16, fwmark: u32) -> io::Result<UdpSocket> {
-        use socket2::*;
+    // Create sockets for the listen addresses. The first one bound with port
+    // 0 chooses `port` if it is 0.
+    //
+    // With dual stack sockets, IPv4 addresses are bound as IPv4-mapped
+    // addresses, and `[::]` covers both families. Otherwise, or if dual stack
+    // is not supported, IPv4 addresses get AF_INET sockets, and the default is
+    // `0.0.0.0` plus `[::]`, or just `0.0.0.0` if IPv6 is not available.
+    fn prepare_sockets(
+        network: &dyn Network,
+        port: &mut u16,
+        addrs: &[SocketAddr],
+        dual_stack: bool,
+        fwmark: u32,
+    ) -> io::Result<Vec<ListenSocket>> {
+        let dual_stack = dual_stack && network.dual_stack_supported();
+        if !dual_stack {
+            debug!("Using separate IPv4 and IPv6 sockets.");
+        }
 
-        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Protocol::UDP.into())?;
-        socket.set_only_v6(false)?;
-        socket.set_nonblocking(true)?;
+        let default_addrs = [
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        ];
+        let is_default = addrs.is_empty();
+        let addrs = match (is_default, dual_stack) {
+            (true, true) => &default_addrs[1..],
+            (true, false) => &default_addrs[..],
+            (false, _) => addrs,
+        };
 
-        #[cfg(windows)]
-        {
-            use std::os::windows::io::AsRawSocket;
-            use std::ptr::null_mut;
-            use winapi::um::mswsock::SIO_UDP_CONNRESET;
-            use winapi::um::winsock2::WSAIoctl;
-
-            let mut bytes_returned = 0u32;
-            let mut new_behaviour = 0i32;
-
-            let r = unsafe {
-                WSAIoctl(
-                    socket.as_raw_socket() as usize,
-                    SIO_UDP_CONNRESET,
-                    &mut new_behaviour as *mut _ as *mut _,
-                    4,
-                    null_mut(),
-                    0,
-                    &mut bytes_returned,
-                    null_mut(),
-                    None,
-                )
+        let mut sockets = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let mut addr = if dual_stack {
+                map_ipv4_to_ipv6(*addr).into()
+            } else {
+                unmap_ipv4_from_ipv6(map_ipv4_to_ipv6(*addr))
             };
-            if r != 0 {
-                return Err(io::Error::last_os_error());
+            if addr.port() == 0 {
+                addr.set_port(*port);
             }
+            let socket = match network.bind(addr, dual_stack, fwmark) {
+                Err(e) if is_default && addr.is_ipv6() => {
+                    warn!("Failed to create IPv6 socket, using IPv4 only: {}", e);
+                    continue;
+                }
+                r => r?,
+            };
+            let local = socket.local_addr()?;
+            if *port == 0 {
+                *port = local.port();
+            }
+            let local = map_ipv4_to_ipv6(local);
+            let ipv4_socket = addr.is_ipv4();
+            let to_v4 = ipv4_socket
+                || is_ipv4_mapped(local.ip())
+                || (dual_stack && local.ip().is_unspecified());
+            let to_v6 = !ipv4_socket && !is_ipv4_mapped(local.ip());
+            sockets.push(ListenSocket {
+                addr: local.into(),
+                socket,
+                ipv4_socket,
+                to_v4,
+                to_v6,
+            });
         }
-        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, *port)).into())?;
-        let socket: std::net::UdpSocket = socket.into();
-
-        if *port == 0 {
-            *port = socket.local_addr()?.port();
-        }
-
-        let socket = UdpSocket::from_std(socket)?;
+        Ok(sockets)
+    }
 
-        if fwmark != 0 {
-            set_fwmark(&socket, fwmark)?;
-        }
-        Ok(socket)
+    // Find the socket `local` is on, or the first socket that can send to
+    // `target` if there is no such socket (any more), or it can't.
+    fn socket_for(&self, local: Option<LocalAddr>, target: SocketAddrV6) -> ListenSocket {
+        let sockets = self.sockets.lock();
+        local
+            .and_then(|l| sockets.iter().find(|s| s.addr == l.socket))
+            .filter(|s| s.can_send_to(target))
+            .or_else(|| sockets.iter().find(|s| s.can_send_to(target)))
+            .unwrap_or(&sockets[0])
+            .clone()
     }
 
-    pub(crate) async fn send_to<'a>(
-        &'a self,
-        buf: &'a [u8],
-        target: impl Into<SocketAddr> + 'static,
+    /// Send from `local`.
+    pub(crate) async fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddrV6,
+        local: Option<LocalAddr>,
     ) -> io::Result<usize> {
-        let target = target.into();
-        let socket = self.socket.lock().clone();
-        socket.send_to(buf, &target).await
+        let socket = self.socket_for(local, target);
+        let source = socket.source(local, target);
+        socket
+            .socket
+            .send_to(buf, socket.target(target), source)
+            .await
     }
 
     /// Add a pper.
@@ -783,14 +1608,63 @@ impl WgState {
         }
     }
 
+    // Whether a handshake message from `addr` is within its rate limit.
+    //
+    // Like wireguard-go, this is only checked under load, after mac2 is
+    // verified. Only then is the source address known to be real, so that a
+    // flood with a spoofed address can't lock it out. All messages still
+    // count towards the load, so one flooding source does make every peer go
+    // through cookies.
+    fn check_handshake_rate(&self, addr: &SocketAddrV6) -> bool {
+        let now = self.clock.now();
+        if self
+            .handshake_limiter
+            .lock()
+            .allow((*addr.ip()).into(), now)
+        {
+            true
+        } else {
+            self.counters.count_drop(DropReason::HandshakeRateLimited);
+            false
+        }
+    }
+
     fn check_handshake_load(&self) -> bool {
         if std::env::var("TITUN_INTEROPE_TEST").is_ok() {
             true
         } else {
-            self.load_monitor.lock().check()
+            let mut load_monitor = self.load_monitor.lock();
+            let was_under_load = load_monitor.under_load();
+            let under_load = load_monitor.check(self.clock.now());
+            if under_load != was_under_load {
+                self.events.send(Event::UnderLoad(under_load));
+            }
+            under_load
+        }
+    }
+
+    fn emit_endpoint_changed(&self, peer: &PeerState) {
+        if let Some(e) = peer.info.endpoint {
+            self.events.send(Event::EndpointChanged {
+                peer: peer.info.public_key,
+                endpoint: unmap_ipv4_from_ipv6(e),
+            });
         }
     }
 
+    /// Subscribe to events of the interface and peers.
+    ///
+    /// Events are only sent to subscribers that exist at the time. A
+    /// subscriber that falls too far behind misses the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Tell subscribers that the config is reloaded.
+    pub fn notify_config_reloaded(&self) {
+        self.events.send(Event::ConfigReloaded);
+    }
+
     fn get_cookie_secret(&self) -> [u8; 32] {
         *self.cookie_secret.read()
     }
@@ -805,6 +1679,7 @@ impl WgState {
 
     /// Get interface and peer state.
     pub fn get_state(&self) -> WgStateOut {
+        let mtu = self.mtu.load(Ordering::Relaxed);
         let peers = {
             // Lock pubkey map.
             let pubkey_map = self.pubkey_map.read();
@@ -814,16 +1689,43 @@ impl WgState {
                 .map(|p| {
                     // Lock peer.
                     let peer = p.read();
+                    let path_mtu = peer.pmtu.lock().mtu().map(|m| std::cmp::min(m, mtu));
+                    let queued_packets = peer.queue.lock().len();
 
                     PeerStateOut {
                         public_key: peer.info.public_key,
                         preshared_key: peer.info.psk,
                         endpoint: peer.info.endpoint.map(unmap_ipv4_from_ipv6),
+                        endpoints: peer
+                            .endpoints
+                            .iter()
+                            .map(|&e| unmap_ipv4_from_ipv6(e))
+                            .collect(),
+                        endpoint_hosts: peer
+                            .endpoint_hosts
+                            .iter()
+                            .map(|(h, _)| h.clone())
+                            .collect(),
                         last_handshake_time: peer.get_last_handshake_time(),
                         rx_bytes: peer.rx_bytes.load(),
                         tx_bytes: peer.tx_bytes.load(),
                         persistent_keepalive_interval: peer.info.keepalive.map_or(0, |x| x.get()),
                         allowed_ips: peer.info.allowed_ips.clone(),
+                        path_mtu_discovery: peer.path_mtu_discovery,
+                        path_mtu,
+                        rate_limit: peer.qos.rate_limit(),
+                        filter: peer.filter.get(),
+                        source_filter: peer.source_filter.clone(),
+                        disabled: peer.disabled,
+                        disabled_unreachable: peer.disabled_unreachable,
+                        psk_rotated: peer.psk_rotated,
+                        psk_expires_in: peer
+                            .psk_expires
+                            .map(|t| t.saturating_duration_since(peer.clock.now())),
+                        psk_expired: peer.psk_expired,
+                        sessions: peer.transports.len(),
+                        queued_packets,
+                        counters: peer.counters.get(),
                     }
                     // Release peer.
                 })
@@ -838,6 +1740,8 @@ impl WgState {
             peers,
             fwmark: info.fwmark,
             listen_port: info.port,
+            under_load: self.load_monitor.lock().under_load(),
+            counters: self.counters.get(),
         }
         // Release info.
     }
@@ -865,34 +1769,88 @@ impl WgState {
     }
 
     /// Change listen port.
-    pub async fn set_port(&self, mut new_port: u16) -> io::Result<()> {
-        if new_port == self.info.read().port {
-            return Ok(());
+    pub async fn set_port(&self, new_port: u16) -> io::Result<()> {
+        let addrs = self.listen_addrs.lock().clone();
+        let dual_stack = self.dual_stack.load(Ordering::Relaxed);
+        self.set_listen(new_port, addrs, dual_stack).await
+    }
+
+    /// Change listen port and addresses.
+    ///
+    /// A socket is bound to each of `addrs`, and port 0 in them means
+    /// `port`. If `addrs` is empty, one dual stack socket is bound to
+    /// `[::]:port`.
+    ///
+    /// If `dual_stack` is false, or dual stack sockets are not supported,
+    /// separate IPv4 and IPv6 sockets are used instead. Packets are sent from
+    /// a socket of the right family.
+    pub async fn set_listen(
+        &self,
+        mut new_port: u16,
+        addrs: Vec<SocketAddr>,
+        dual_stack: bool,
+    ) -> io::Result<()> {
+        let fwmark = {
+            let info = self.info.read();
+            if new_port == info.port
+                && addrs == *self.listen_addrs.lock()
+                && dual_stack == self.dual_stack.load(Ordering::Relaxed)
+            {
+                return Ok(());
+            }
+            info.fwmark
+        };
+        let new_sockets =
+            WgState::prepare_sockets(&*self.network, &mut new_port, &addrs, dual_stack, fwmark)?;
+        if self.pmtu_probe_mode.load(Ordering::Relaxed) {
+            for s in &new_sockets {
+                s.socket.set_pmtu_probe(true)?;
+            }
         }
-        let new_socket = WgState::prepare_socket(&mut new_port, self.info.read().fwmark)?;
         // XXX: possible race condition between this and `run`.
-        let sender = self.socket_sender.lock().as_ref().cloned();
+        let sender = self.sockets_sender.lock().as_ref().cloned();
         if let Some(sender) = sender {
             sender
-                .send(new_socket)
+                .send(new_sockets)
                 .await
-                .unwrap_or_else(|e| panic!("failed to send socket: {}", e));
+                .unwrap_or_else(|e| panic!("failed to send sockets: {}", e));
         } else {
-            *self.socket.lock() = new_socket.into();
+            *self.sockets.lock() = new_sockets.into();
         }
         self.info.write().port = new_port;
+        *self.listen_addrs.lock() = addrs;
+        self.dual_stack.store(dual_stack, Ordering::Relaxed);
         Ok(())
     }
 
-    /// Set fwmark of the UDP socket.
+    // Put the sockets in path MTU probe mode while some peer has path MTU
+    // discovery on. Otherwise they are left in the system default mode, so
+    // that packets too big for the path can still be fragmented.
+    fn update_pmtu_probe_mode(&self) {
+        let enabled = self
+            .pubkey_map
+            .read()
+            .values()
+            .any(|p| p.read().path_mtu_discovery);
+        if self.pmtu_probe_mode.swap(enabled, Ordering::Relaxed) == enabled {
+            return;
+        }
+        debug!("setting path MTU probe mode: {}", enabled);
+        for s in self.sockets.lock().iter() {
+            if let Err(e) = s.socket.set_pmtu_probe(enabled) {
+                warn!("failed to set path MTU probe mode: {}", e);
+            }
+        }
+    }
+
+    /// Set fwmark of the UDP sockets.
     pub fn set_fwmark(&self, new_fwmark: u32) -> io::Result<()> {
         let mut info = self.info.write();
         if info.fwmark == new_fwmark {
             return Ok(());
         }
-        {
-            let socket: &UdpSocket = &self.socket.lock();
-            set_fwmark(socket, new_fwmark)?;
+        for s in self.sockets.lock().iter() {
+            s.socket.set_fwmark(new_fwmark)?;
         }
         info.fwmark = new_fwmark;
         Ok(())
@@ -961,20 +1919,47 @@ impl WgState {
         // Lock peer.
         let mut peer = peer0.write();
 
-        if peer.info.psk != command.preshared_key {
-            debug!("setting peer psk");
-            peer.clear();
-            peer.info.psk = command.preshared_key;
+        if let Some(psk) = command.rotate_psk {
+            debug!("rotating peer psk");
+            peer.rotate_psk(psk, command.psk_lifetime);
+        } else if let Some(psk) = command.preshared_key {
+            if peer.info.psk != psk {
+                debug!("setting peer psk");
+                peer.set_psk(psk);
+            }
         }
 
-        if let Some(endpoint) = command.endpoint {
-            if peer.info.endpoint != Some(map_ipv4_to_ipv6(endpoint)) {
-                debug!("setting peer endpoint");
-                peer.info.endpoint = Some(map_ipv4_to_ipv6(endpoint));
-            }
+        if !command.endpoint.is_empty() {
+            let endpoints = command
+                .endpoint
+                .iter()
+                .map(|&e| map_ipv4_to_ipv6(e))
+                .collect();
+            peer.set_endpoints(endpoints);
             peer.info.roaming = false;
         }
 
+        if (!command.endpoint.is_empty() || !command.endpoint_hosts.is_empty())
+            && !peer
+                .endpoint_hosts
+                .iter()
+                .map(|(h, _)| h)
+                .eq(command.endpoint_hosts.iter())
+        {
+            debug!("setting peer endpoint hosts");
+            peer.endpoint_hosts = std::mem::take(&mut command.endpoint_hosts)
+                .into_iter()
+                .map(|h| (h, None))
+                .collect();
+            // Resolve now to find out which host resolves to which address.
+            peer.last_resolve = None;
+            if peer.endpoint_hosts.is_empty() {
+                peer.resolve.de_activate();
+            } else {
+                peer.resolve_endpoints_soon();
+            }
+        }
+
         if let Some(interval) = command.keepalive {
             if peer.info.keepalive != std::num::NonZeroU16::new(interval) {
                 debug!("setting peer keepalive");
@@ -987,6 +1972,39 @@ impl WgState {
             }
         }
 
+        if let Some(limit) = command.rate_limit {
+            peer.qos.set_rate_limit(limit, self.clock.now());
+        }
+
+        if let Some(rules) = command.filter {
+            peer.filter.set_rules(rules);
+        }
+
+        if let Some(source_filter) = command.source_filter {
+            peer.source_filter = source_filter;
+        }
+
+        if let Some(unreachable) = command.disabled_unreachable {
+            peer.disabled_unreachable = unreachable;
+        }
+
+        match command.disabled {
+            Some(true) if !peer.disabled => {
+                info!("{}: disabled.", peer.info.log_id());
+                peer.disable();
+            }
+            Some(false) if peer.disabled => {
+                info!("{}: enabled.", peer.info.log_id());
+                peer.enable();
+            }
+            _ => (),
+        }
+
+        if let Some(enabled) = command.path_mtu_discovery {
+            peer.set_path_mtu_discovery(enabled);
+        }
+        let update_pmtu_probe_mode = command.path_mtu_discovery.is_some();
+
         command.allowed_ips = command
             .allowed_ips
             .into_iter()
@@ -1054,7 +2072,11 @@ impl WgState {
             );
             assert!(old_peer.info.allowed_ips.remove(&(a, m)));
         }
+        drop(peer);
 
+        if update_pmtu_probe_mode {
+            self.update_pmtu_probe_mode();
+        }
         Ok(())
     }
 
@@ -1072,6 +2094,7 @@ impl WgState {
         let mut peer = p.write();
         // This will remove peer from `id_map` through `IdMapGuard`.
         peer.clear();
+        self.events.send(Event::PeerRemoved { peer: *peer_pubkey });
 
         // Remove from rt4 / rt6.
 
@@ -1085,37 +2108,265 @@ impl WgState {
                 IpAddr::V6(a) => rt6.remove(a, m),
             };
         }
+        drop(rt6);
+        drop(rt4);
 
+        if peer.path_mtu_discovery {
+            drop(peer);
+            self.update_pmtu_probe_mode();
+        }
         true
     }
 }
 
-#[cfg(target_os = "linux")]
-fn set_fwmark<Socket>(s: &Socket, fwmark: u32) -> io::Result<()>
-where
-    Socket: std::os::unix::io::AsRawFd,
-{
-    use nix::sys::socket::setsockopt;
-    use nix::sys::socket::sockopt::Mark;
-
-    setsockopt(s.as_raw_fd(), Mark, &fwmark).map_err(|_| std::io::Error::last_os_error())?;
-    Ok(())
-}
-
-#[cfg(not(target_os = "linux"))]
-#[allow(clippy::unnecessary_wraps)]
-fn set_fwmark<T>(_s: &T, _fwmark: u32) -> io::Result<()> {
-    warn!("fwmark is not supported on this platform.");
-    Ok(())
-}
-
-#[cfg(all(test, feature = "sudo-tests"))]
+#[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "sudo-tests")]
     use crate::wireguard::peer_state::wg_add_peer;
-    use crate::wireguard::re_exports::{DH, X25519};
+    #[cfg(feature = "sudo-tests")]
     use std::ffi::OsStr;
 
+    // A `WgState` on a channel device, driven by a manual clock.
+    fn new_wg() -> anyhow::Result<(Arc<WgState>, ChannelPacketDeviceHandle, Arc<ManualClock>)> {
+        let (device, handle) = ChannelPacketDevice::new(1280, 4);
+        let clock = Arc::new(ManualClock::new());
+        let wg = WgState::new_with_clock(vec![device], clock.clone(), Timers::default())?;
+        Ok((wg, handle, clock))
+    }
+
+    fn new_peer(wg: &Arc<WgState>) -> anyhow::Result<([u8; 32], Arc<RwLock<PeerState>>)> {
+        let public_key = X25519::pubkey(&X25519::genkey());
+        wg.add_peer(&public_key)?;
+        let peer = wg.find_peer_by_pubkey(&public_key).unwrap();
+        Ok((public_key, peer))
+    }
+
+    #[tokio::test]
+    async fn icmp_unreachable() -> anyhow::Result<()> {
+        let (wg, mut handle, _clock) = new_wg()?;
+        wg.set_addresses(vec!["10.0.0.1".parse()?]);
+        tokio::spawn(wg.clone().task_tx());
+
+        // UDP from 10.0.0.2 to 10.0.0.3, which has no peer.
+        let mut p = vec![0u8; 28];
+        p[0] = 0x45;
+        p[2..4].copy_from_slice(&28u16.to_be_bytes());
+        p[9] = 17;
+        p[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        p[16..20].copy_from_slice(&[10, 0, 0, 3]);
+        handle.tx.send(p.clone()).await?;
+
+        let e = tokio::time::timeout(Duration::from_secs(1), handle.rx.recv())
+            .await?
+            .unwrap();
+        let h = parse_ip_packet(&e).unwrap();
+        assert_eq!(h.src, "10.0.0.1".parse::<IpAddr>()?);
+        assert_eq!(h.dst, "10.0.0.2".parse::<IpAddr>()?);
+        // Destination unreachable, quoting the original packet.
+        assert_eq!(e[20], 3);
+        assert_eq!(&e[28..], &p[..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listen_addresses() -> anyhow::Result<()> {
+        let (wg, _handle, _clock) = new_wg()?;
+        wg.set_listen(0, vec!["127.0.0.1:0".parse()?, "[::1]:0".parse()?], true)
+            .await?;
+        let port = wg.get_state().listen_port;
+        let sockets = wg.sockets.lock().clone();
+        let addrs: Vec<_> = sockets.iter().map(|s| s.addr).collect();
+        // Port 0 means the same port for all addresses.
+        assert_eq!(
+            addrs,
+            vec![
+                SocketAddr::from(map_ipv4_to_ipv6(([127, 0, 0, 1], port).into())),
+                (Ipv6Addr::LOCALHOST, port).into(),
+            ]
+        );
+        let v4 = map_ipv4_to_ipv6(([127, 0, 0, 1], 1).into());
+        let v6 = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 1, 0, 0);
+        let is = |a: ListenSocket, b: &ListenSocket| {
+            Arc::as_ptr(&a.socket) as *const u8 == Arc::as_ptr(&b.socket) as *const u8
+        };
+        assert!(is(wg.socket_for(None, v4), &sockets[0]));
+        assert!(is(wg.socket_for(None, v6), &sockets[1]));
+        let local = LocalAddr {
+            socket: addrs[1],
+            ip: Some(Ipv6Addr::LOCALHOST.into()),
+        };
+        assert!(is(wg.socket_for(Some(local), v6), &sockets[1]));
+        assert_eq!(sockets[1].source(Some(local), v6), local.ip);
+        // Can't send to IPv4 from the IPv6 socket.
+        assert!(is(wg.socket_for(Some(local), v4), &sockets[0]));
+        assert_eq!(sockets[0].source(Some(local), v4), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn separate_sockets() -> anyhow::Result<()> {
+        let (wg, _handle, _clock) = new_wg()?;
+        wg.set_listen(0, vec!["127.0.0.1:0".parse()?], false)
+            .await?;
+        assert!(wg.sockets.lock()[0].ipv4_socket);
+
+        // IPv4 endpoints are mapped internally.
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let target = map_ipv4_to_ipv6(receiver.local_addr()?);
+        wg.send_to(&[1, 2, 3], target, None).await?;
+        let mut buf = [0u8; 16];
+        let (len, from) = receiver.recv_from(&mut buf)?;
+        assert_eq!(&buf[..len], [1, 2, 3]);
+        assert_eq!(from.port(), wg.get_state().listen_port);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn endpoint_failover() -> anyhow::Result<()> {
+        let (wg, _handle, _clock) = new_wg()?;
+        let (public_key, peer0) = new_peer(&wg)?;
+        let set = |endpoint| SetPeerCommand {
+            endpoint,
+            ..SetPeerCommand::new(public_key)
+        };
+        let endpoint = || wg.get_state().peers[0].endpoint;
+
+        let a: SocketAddr = "[2001:db8::1]:7777".parse()?;
+        let b: SocketAddr = "192.0.2.1:7777".parse()?;
+        wg.set_peer(set(vec![a, b]))?;
+        assert_eq!(endpoint(), Some(a));
+        assert!(peer0.write().next_endpoint());
+        assert_eq!(endpoint(), Some(b));
+        // Keeps the active endpoint.
+        wg.set_peer(set(vec![a, b]))?;
+        assert_eq!(endpoint(), Some(b));
+        assert!(peer0.write().next_endpoint());
+        assert_eq!(endpoint(), Some(a));
+
+        wg.set_peer(set(vec![b]))?;
+        assert_eq!(endpoint(), Some(b));
+        assert!(!peer0.write().next_endpoint());
+
+        // Back to the candidate last used after roaming elsewhere.
+        wg.set_peer(set(vec![a, b]))?;
+        let c: SocketAddr = "192.0.2.2:7777".parse()?;
+        assert!(peer0.write().set_endpoint(map_ipv4_to_ipv6(c)));
+        assert!(peer0.write().next_endpoint());
+        assert_eq!(endpoint(), Some(b));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolve_endpoint_hosts() -> anyhow::Result<()> {
+        struct StubResolver(Mutex<HashMap<String, SocketAddr>>);
+
+        impl Resolver for StubResolver {
+            fn resolve<'a>(
+                &'a self,
+                host: &'a str,
+            ) -> futures::future::BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+                let addrs = self.0.lock().get(host).copied().into_iter().collect();
+                future::ready(Ok(addrs)).boxed()
+            }
+        }
+
+        let (wg, _handle, clock) = new_wg()?;
+        let resolver = Arc::new(StubResolver(Mutex::new(HashMap::new())));
+        wg.set_resolver(resolver.clone());
+        let (public_key, peer0) = new_peer(&wg)?;
+        let endpoint = || wg.get_state().peers[0].endpoint;
+        let resolve_again = || {
+            peer0.write().last_resolve = None;
+            peer0.read().resolve_endpoints_soon();
+        };
+
+        let host = "vpn.example.com:7777";
+        let a: SocketAddr = "192.0.2.1:7777".parse()?;
+        let b: SocketAddr = "192.0.2.2:7777".parse()?;
+        // Failed to resolve when loading config.
+        wg.set_peer(SetPeerCommand {
+            endpoint_hosts: vec![host.into()],
+            ..SetPeerCommand::new(public_key)
+        })?;
+        clock.settle().await;
+        assert_eq!(endpoint(), None);
+
+        resolver.0.lock().insert(host.into(), a);
+        resolve_again();
+        clock.settle().await;
+        assert_eq!(endpoint(), Some(a));
+
+        resolver.0.lock().insert(host.into(), b);
+        resolve_again();
+        clock.settle().await;
+        assert_eq!(endpoint(), Some(b));
+
+        // Keep the old address if it fails to resolve.
+        resolver.0.lock().clear();
+        resolve_again();
+        clock.settle().await;
+        assert_eq!(endpoint(), Some(b));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_preshared_key() -> anyhow::Result<()> {
+        let (wg, _handle, _clock) = new_wg()?;
+        let (public_key, _) = new_peer(&wg)?;
+        let psk = || wg.get_state().peers[0].preshared_key;
+
+        wg.set_peer(SetPeerCommand {
+            preshared_key: Some(Some([1; 32])),
+            ..SetPeerCommand::new(public_key)
+        })?;
+        assert_eq!(psk(), Some([1; 32]));
+        // Unchanged if not set.
+        wg.set_peer(SetPeerCommand {
+            disabled: Some(true),
+            ..SetPeerCommand::new(public_key)
+        })?;
+        assert_eq!(psk(), Some([1; 32]));
+        wg.set_peer(SetPeerCommand {
+            preshared_key: Some(None),
+            ..SetPeerCommand::new(public_key)
+        })?;
+        assert_eq!(psk(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn protocol_timers() -> anyhow::Result<()> {
+        let (wg, _handle, clock) = new_wg()?;
+        let (_, peer0) = new_peer(&wg)?;
+        let handshaking = || peer0.read().handshake_resend_scope.is_some();
+        let advance = |secs| {
+            clock.advance(Duration::from_secs(secs));
+            clock.settle()
+        };
+
+        // Stop handshake after REKEY_ATTEMPT_TIME.
+        peer0.write().handshake_resend_scope = Some(AsyncScope::new());
+        peer0.read().enqueue_packet(&[0; 100]);
+        advance(REKEY_ATTEMPT_TIME - 1).await;
+        assert!(handshaking());
+        advance(1).await;
+        assert!(!handshaking());
+
+        // Clear sessions if no new handshake in REJECT_AFTER_TIME * 3.
+        {
+            let mut peer = peer0.write();
+            peer.handshake_resend_scope = Some(AsyncScope::new());
+            peer.on_new_transport();
+        }
+        advance(3 * REJECT_AFTER_TIME - 1).await;
+        assert!(handshaking());
+        advance(1).await;
+        assert!(!handshaking());
+        Ok(())
+    }
+
+    #[cfg(feature = "sudo-tests")]
     #[tokio::test]
     async fn wg_state_tests() -> anyhow::Result<()> {
         let state = WgState::new(AsyncTun::open(OsStr::new("tun37"))?)?;
@@ -1161,17 +2412,22 @@ mod tests {
             }
             allowed_ips.extend(previous_allowed_ips.choose_multiple(&mut rng, 8));
             let endpoint = if rng.gen() {
-                Some((Ipv4Addr::from(rng.next_u32()), rng.gen()).into())
+                vec![(Ipv4Addr::from(rng.next_u32()), rng.gen()).into()]
             } else {
-                None
+                vec![]
             };
             state.set_peer(SetPeerCommand {
-                public_key,
                 preshared_key: rng.gen(),
                 endpoint,
                 replace_allowed_ips: rng.gen(),
                 allowed_ips,
                 keepalive: rng.gen(),
+                rate_limit: if rng.gen() {
+                    Some(RateLimit::new(rng.gen_range(0..2)))
+                } else {
+                    None
+                },
+                ..SetPeerCommand::new(public_key)
             })?;
             state.check_route_consistency()?;
         }
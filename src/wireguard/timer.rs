@@ -17,24 +17,25 @@
 
 //! Timer that is optimized for frequent, repeated de-activation and adjust.
 //!
-//! Use tokio-timer under the hood.
+//! Sleeps on a `Clock`, which is `tokio::time` unless in tests.
 
-use futures::future::poll_fn;
+use crate::wireguard::Clock;
 use futures::prelude::*;
-use futures::ready;
 use parking_lot::Mutex;
 use std::future::Future;
-use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering::*};
 use std::sync::Arc;
-use std::task::Poll;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot::{channel, Sender};
-use tokio::time::{sleep, Instant, Sleep};
+use tokio::sync::Notify;
 
 struct TimerOptions {
+    clock: Arc<dyn Clock>,
     activated: AtomicBool,
-    delay: Mutex<Pin<Box<Sleep>>>,
+    deadline: Mutex<Instant>,
+    // Wake up the timer task when the deadline is moved earlier, or when it
+    // is activated again after firing.
+    adjusted: Notify,
 }
 
 pub struct TimerHandle {
@@ -42,45 +43,45 @@ pub struct TimerHandle {
     options: Arc<TimerOptions>,
 }
 
-pub fn create_timer_async<F, Fut>(action: F) -> TimerHandle
+pub fn create_timer_async<F, Fut>(clock: Arc<dyn Clock>, action: F) -> TimerHandle
 where
     F: Fn() -> Fut + Send + Sync + 'static,
     Fut: Future<Output = ()> + Send,
 {
-    let (tx, mut rx) = channel();
+    let (tx, rx) = channel();
     let options0 = Arc::new(TimerOptions {
         activated: AtomicBool::new(false),
-        delay: Mutex::new(Box::pin(sleep(Duration::from_secs(0)))),
+        deadline: Mutex::new(clock.now()),
+        adjusted: Notify::new(),
+        clock,
     });
     let options = options0.clone();
     tokio::spawn(async move {
+        // Resolves when the handle is dropped.
+        let mut rx = rx.fuse();
         loop {
-            let wait_result = poll_fn(|cx| {
-                // First check whether the handle is dropped.
-                if rx.poll_unpin(cx).is_ready() {
-                    return Poll::Ready(Err(()));
-                }
-                let mut delay = options.delay.lock();
-                ready!(delay.as_mut().poll(cx));
-                // Reset delay to get notified again.
-                delay
-                    .as_mut()
-                    .reset(Instant::now() + Duration::from_secs(600));
-                match delay.poll_unpin(cx) {
-                    Poll::Pending => (),
-                    _ => unreachable!(),
+            let deadline = *options.deadline.lock();
+            futures::select_biased! {
+                _ = rx => break,
+                _ = options.adjusted.notified().fuse() => continue,
+                _ = options.clock.sleep_until(deadline).fuse() => (),
+            }
+            let fire = {
+                let deadline = options.deadline.lock();
+                if *deadline > options.clock.now() {
+                    // Adjusted to some time later.
+                    continue;
                 }
-                if options.activated.swap(false, SeqCst) {
-                    Poll::Ready(Ok(()))
-                } else {
-                    Poll::Pending
+                options.activated.swap(false, SeqCst)
+            };
+            if fire {
+                action().await;
+            } else {
+                futures::select_biased! {
+                    _ = rx => break,
+                    _ = options.adjusted.notified().fuse() => (),
                 }
-            })
-            .await;
-            if wait_result.is_err() {
-                break;
             }
-            action().await;
         }
     });
     TimerHandle {
@@ -92,18 +93,23 @@ where
 impl TimerHandle {
     /// Reset the timer to some timer later.
     pub fn adjust_and_activate(&self, delay: Duration) {
-        let mut d = self.options.delay.lock();
-        d.as_mut().reset(Instant::now() + delay);
-        self.options.activated.store(true, SeqCst);
+        let mut deadline = self.options.deadline.lock();
+        let new_deadline = self.options.clock.now() + delay;
+        let earlier = new_deadline < *deadline;
+        *deadline = new_deadline;
+        let was_activated = self.options.activated.swap(true, SeqCst);
+        if earlier || !was_activated {
+            self.options.adjusted.notify_one();
+        }
     }
 
     pub fn adjust_and_activate_secs(&self, secs: u64) {
         self.adjust_and_activate(Duration::from_secs(secs));
     }
 
-    pub fn adjust_and_activate_if_not_activated(&self, secs: u64) {
+    pub fn adjust_and_activate_if_not_activated(&self, delay: Duration) {
         if !self.options.activated.load(SeqCst) {
-            self.adjust_and_activate_secs(secs);
+            self.adjust_and_activate(delay);
         }
     }
 
@@ -116,14 +122,14 @@ impl TimerHandle {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Duration;
+    use crate::wireguard::{ManualClock, SystemClock};
     use tokio::sync::mpsc::channel;
     use tokio::time::timeout;
 
     #[tokio::test]
     async fn smoke() {
         let (tx, mut rx) = channel(1);
-        let t = create_timer_async(move || {
+        let t = create_timer_async(Arc::new(SystemClock), move || {
             let tx = tx.clone();
             async move {
                 tx.send(()).await.unwrap();
@@ -139,7 +145,7 @@ mod tests {
         let (tx, mut rx) = channel(1);
 
         let t = {
-            create_timer_async(move || {
+            create_timer_async(Arc::new(SystemClock), move || {
                 let tx = tx.clone();
                 async move {
                     tx.send(()).await.unwrap();
@@ -168,4 +174,36 @@ mod tests {
             .await
             .is_err());
     }
+
+    #[tokio::test]
+    async fn manual_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let (tx, mut rx) = channel(1);
+        let t = create_timer_async(clock.clone(), move || {
+            let tx = tx.clone();
+            async move {
+                tx.send(()).await.unwrap();
+            }
+        });
+
+        t.adjust_and_activate(Duration::from_secs(120));
+        clock.settle().await;
+        clock.advance(Duration::from_secs(119));
+        clock.settle().await;
+        assert!(rx.recv().now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        clock.settle().await;
+        assert!(rx.recv().now_or_never().is_some());
+
+        // Moved later before it fires.
+        t.adjust_and_activate(Duration::from_secs(10));
+        t.adjust_and_activate(Duration::from_secs(60));
+        clock.settle().await;
+        clock.advance(Duration::from_secs(30));
+        clock.settle().await;
+        assert!(rx.recv().now_or_never().is_none());
+        clock.advance(Duration::from_secs(30));
+        clock.settle().await;
+        assert!(rx.recv().now_or_never().is_some());
+    }
 }
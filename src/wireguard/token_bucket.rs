@@ -0,0 +1,118 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cmp::{max, min};
+use std::time::{Duration, Instant};
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// A token bucket, refilled at `rate` tokens per second up to `capacity`.
+///
+/// The current time is passed in, so that it can be driven by a `Clock`.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    // Scaled to 10^9, so that refills are exact. Negative after taking more
+    // than the capacity.
+    level: i128,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(rate: u64, capacity: u64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            capacity,
+            level: i128::from(capacity) * NANOS_PER_SEC,
+            last_refill: now,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let passed = now.saturating_duration_since(self.last_refill).as_nanos();
+        self.last_refill = max(self.last_refill, now);
+        let add = (passed as i128).saturating_mul(i128::from(self.rate));
+        self.level = min(
+            self.level.saturating_add(add),
+            i128::from(self.capacity) * NANOS_PER_SEC,
+        );
+    }
+
+    /// Whole tokens in the bucket at `now`.
+    pub fn tokens(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        max(0, self.level / NANOS_PER_SEC) as u64
+    }
+
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.tokens(now) >= self.capacity
+    }
+
+    /// Take `n` tokens at `now`, or return how long until there are enough.
+    ///
+    /// Taking more than the capacity only needs a full bucket, and leaves it
+    /// negative.
+    pub fn take(&mut self, n: u64, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        let needed = i128::from(min(n, self.capacity)) * NANOS_PER_SEC;
+        if self.level >= needed {
+            self.level -= i128::from(n) * NANOS_PER_SEC;
+            Ok(())
+        } else if self.rate == 0 {
+            Err(Duration::from_nanos(u64::MAX))
+        } else {
+            let rate = i128::from(self.rate);
+            let nanos = (needed - self.level + rate - 1) / rate;
+            Err(Duration::from_nanos(min(nanos, i128::from(u64::MAX)) as u64))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let now = Instant::now();
+        let mut b = TokenBucket::new(1000, 2000, now);
+        assert!(b.take(1500, now).is_ok());
+        assert_eq!(b.tokens(now), 500);
+        assert_eq!(b.take(1000, now), Err(Duration::from_millis(500)));
+        let now = now + Duration::from_millis(500);
+        assert!(b.take(1000, now).is_ok());
+        // Bigger than capacity, needs a full bucket.
+        let now = now + Duration::from_secs(10);
+        assert!(b.is_full(now));
+        assert!(b.take(5000, now).is_ok());
+        assert_eq!(b.tokens(now), 0);
+        assert_eq!(
+            b.take(1, now),
+            Err(Duration::from_secs(3) + Duration::from_millis(1))
+        );
+    }
+}
@@ -23,8 +23,7 @@ use parking_lot::Mutex;
 use std::convert::TryInto;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::time::sleep;
+use std::time::Instant;
 
 // That is, 2 ^ 64 - 2 ^ 16 - 1;
 const REKEY_AFTER_MESSAGES: u64 = 0xffff_ffff_fffe_ffff;
@@ -58,7 +57,13 @@ pub struct Transport {
 }
 
 impl Transport {
-    pub fn new_from_hs(self_id: IdMapGuard, peer_id: Id, hs: &HS) -> Arc<Transport> {
+    pub fn new_from_hs(
+        self_id: IdMapGuard,
+        peer_id: Id,
+        hs: &HS,
+        clock: &Arc<dyn Clock>,
+        timers: &Timers,
+    ) -> Arc<Transport> {
         let (x, y) = hs.get_ciphers();
         let (s, r) = if hs.get_is_initiator() {
             (x, y)
@@ -77,29 +82,31 @@ impl Transport {
             not_too_old: AtomicBool::new(true),
             send_key: sk,
             recv_key: rk,
-            created: Instant::now(),
+            created: clock.now(),
             recv_ar: Mutex::new(AntiReplay::new()),
             send_counter: U64Counter::new(0),
             scope: AsyncScope::new(),
         });
 
         let handshake_after = if transport.is_initiator {
-            REKEY_AFTER_TIME
+            timers.rekey_after_time
         } else {
-            REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
+            timers.reject_after_time - timers.keepalive_timeout - timers.rekey_timeout
         };
 
         let weak = Arc::downgrade(&transport);
+        let sleep = clock.sleep(handshake_after);
         transport.scope.spawn_async(async move {
-            sleep(Duration::from_secs(handshake_after)).await;
+            sleep.await;
             if let Some(t) = weak.upgrade() {
                 t.should_handshake.store(true, Ordering::Relaxed);
             }
         });
 
         let weak = Arc::downgrade(&transport);
+        let sleep = clock.sleep(timers.reject_after_time);
         transport.scope.spawn_async(async move {
-            sleep(Duration::from_secs(REJECT_AFTER_TIME)).await;
+            sleep.await;
             if let Some(t) = weak.upgrade() {
                 t.not_too_old.store(false, Ordering::Relaxed);
             }
@@ -124,6 +131,22 @@ impl Transport {
     ///
     /// Length: out.len() = msg.len() + 32.
     pub fn encrypt(&self, msg: &[u8], out: &mut [u8]) -> (Result<(), ()>, bool) {
+        let (c, should_rekey) = self.reserve_counter();
+        match c {
+            Ok(c) => {
+                self.encrypt_with_counter(c, msg, out);
+                (Ok(()), should_rekey)
+            }
+            Err(()) => (Err(()), should_rekey),
+        }
+    }
+
+    /// Reserve a counter for a packet, which is then encrypted with
+    /// `encrypt_with_counter`, possibly on another thread.
+    ///
+    /// Returns: The counter, or error if too many messages have been sent.
+    /// Whether this transport thinks we should rekey.
+    pub fn reserve_counter(&self) -> (Result<u64, ()>, bool) {
         let c = self.send_counter.fetch_add(1);
         let should_rekey = self.is_initiator
             && (self.should_handshake.load(Ordering::Relaxed) || c >= REKEY_AFTER_MESSAGES);
@@ -134,13 +157,16 @@ impl Transport {
             return (Err(()), should_rekey);
         }
 
+        (Ok(c), should_rekey)
+    }
+
+    /// Length: out.len() = msg.len() + 32.
+    pub fn encrypt_with_counter(&self, c: u64, msg: &[u8], out: &mut [u8]) {
         out[0..4].copy_from_slice(&[4, 0, 0, 0]);
         out[4..8].copy_from_slice(self.peer_id.as_slice());
         out[8..16].copy_from_slice(&c.to_le_bytes());
 
         <ChaCha20Poly1305 as Cipher>::encrypt(&self.send_key, c, &[], msg, &mut out[16..]);
-
-        (Ok(()), should_rekey)
     }
 
     /// Returns packet maybe with padding, and whether this transport thinks we
@@ -0,0 +1,606 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Tun offloads with virtio-net headers.
+//!
+//! When a tun device is opened with `IFF_VNET_HDR`, each packet read or
+//! written is prefixed with a `virtio_net_hdr`. With TSO enabled, packets read
+//! can be large TCP segments, which are split here. And TCP segments of the
+//! same flow are coalesced before they are written.
+
+use crate::wireguard::{checksum_no_fold, fold, IPPROTO_TCP};
+use std::convert::TryInto;
+use std::ops::Range;
+
+pub const VIRTIO_NET_HDR_LEN: usize = 10;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+const TCP_CWR: u8 = 0x80;
+
+// Max number of segments coalesced into one packet.
+const MAX_SEGMENTS: usize = 64;
+
+/// `struct virtio_net_hdr`, in native byte order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    pub fn decode(b: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_ne_bytes(b[i..i + 2].try_into().unwrap());
+        Self {
+            flags: b[0],
+            gso_type: b[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        }
+    }
+
+    pub fn encode(&self, b: &mut [u8]) {
+        b[0] = self.flags;
+        b[1] = self.gso_type;
+        b[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        b[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        b[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        b[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+    }
+}
+
+fn pseudo_header_sum(src: &[u8], dst: &[u8], len: usize) -> u64 {
+    checksum_no_fold(
+        dst,
+        checksum_no_fold(src, u64::from(IPPROTO_TCP) + len as u64),
+    )
+}
+
+fn read_u16(b: &[u8], i: usize) -> u16 {
+    u16::from_be_bytes(b[i..i + 2].try_into().unwrap())
+}
+
+fn write_u16(b: &mut [u8], i: usize, v: u16) {
+    b[i..i + 2].copy_from_slice(&v.to_be_bytes());
+}
+
+fn read_u32(b: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes(b[i..i + 4].try_into().unwrap())
+}
+
+fn ipv4_update_checksum(p: &mut [u8], ip_hl: usize) {
+    write_u16(p, 10, 0);
+    let c = !fold(checksum_no_fold(&p[..ip_hl], 0));
+    write_u16(p, 10, c);
+}
+
+// Source and destination address of an IP packet.
+fn ip_addrs(p: &[u8], is_v4: bool) -> (&[u8], &[u8]) {
+    if is_v4 {
+        (&p[12..16], &p[16..20])
+    } else {
+        (&p[8..24], &p[24..40])
+    }
+}
+
+fn tcp_update_checksum(p: &mut [u8], ip_hl: usize, is_v4: bool) {
+    write_u16(p, ip_hl + 16, 0);
+    let (src, dst) = ip_addrs(p, is_v4);
+    let sum = checksum_no_fold(&p[ip_hl..], pseudo_header_sum(src, dst, p.len() - ip_hl));
+    let c = !fold(sum);
+    write_u16(p, ip_hl + 16, c);
+}
+
+fn tcp_checksum_ok(p: &[u8], ip_hl: usize, is_v4: bool) -> bool {
+    let (src, dst) = ip_addrs(p, is_v4);
+    fold(checksum_no_fold(
+        &p[ip_hl..],
+        pseudo_header_sum(src, dst, p.len() - ip_hl),
+    )) == 0xffff
+}
+
+/// Splits packets read from a tun device with virtio-net headers.
+pub struct TsoSplitter {
+    raw: Vec<u8>,
+    out: Vec<u8>,
+    segments: Vec<Range<usize>>,
+    next: usize,
+}
+
+impl TsoSplitter {
+    pub fn new() -> Self {
+        Self {
+            raw: vec![0u8; VIRTIO_NET_HDR_LEN + 65535],
+            out: Vec::with_capacity(65535 * 2),
+            segments: Vec::with_capacity(MAX_SEGMENTS),
+            next: 0,
+        }
+    }
+
+    /// Buffer to read the next packet, including the header, into.
+    pub fn raw_buf(&mut self) -> &mut [u8] {
+        &mut self.raw
+    }
+
+    /// Process a packet of `len` bytes in `raw_buf()`.
+    pub fn process(&mut self, len: usize) -> Result<(), ()> {
+        self.out.clear();
+        self.segments.clear();
+        self.next = 0;
+        if len < VIRTIO_NET_HDR_LEN {
+            return Err(());
+        }
+        let hdr = VirtioNetHdr::decode(&self.raw);
+        let pkt = &mut self.raw[VIRTIO_NET_HDR_LEN..len];
+        match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+            VIRTIO_NET_HDR_GSO_NONE => {
+                if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                    complete_checksum(&hdr, pkt)?;
+                }
+                self.out.extend_from_slice(pkt);
+                self.segments.push(0..pkt.len());
+                Ok(())
+            }
+            VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => {
+                tso_split(&hdr, pkt, &mut self.out, &mut self.segments)
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Copy the next segment into `buf`. Returns its length, or `None` if all
+    /// segments have been returned.
+    pub fn next_segment(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let r = self.segments.get(self.next)?.clone();
+        self.next += 1;
+        let len = std::cmp::min(r.len(), buf.len());
+        buf[..len].copy_from_slice(&self.out[r.start..r.start + len]);
+        Some(len)
+    }
+}
+
+/// Calculate the checksum from `csum_start` to the end of the packet, and
+/// store it at `csum_start + csum_offset`.
+fn complete_checksum(hdr: &VirtioNetHdr, pkt: &mut [u8]) -> Result<(), ()> {
+    let start = hdr.csum_start as usize;
+    let offset = start + hdr.csum_offset as usize;
+    if offset + 2 > pkt.len() {
+        return Err(());
+    }
+    let c = !fold(checksum_no_fold(&pkt[start..], 0));
+    write_u16(pkt, offset, c);
+    Ok(())
+}
+
+/// Split a TCP packet into segments of at most `hdr.gso_size` bytes of
+/// payload. Segments are appended to `out`, and their ranges to `segments`.
+fn tso_split(
+    hdr: &VirtioNetHdr,
+    pkt: &[u8],
+    out: &mut Vec<u8>,
+    segments: &mut Vec<Range<usize>>,
+) -> Result<(), ()> {
+    let is_v4 = hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN == VIRTIO_NET_HDR_GSO_TCPV4;
+    let ip_hl = if is_v4 {
+        if pkt.len() < 20 || pkt[0] >> 4 != 4 {
+            return Err(());
+        }
+        let ip_hl = usize::from(pkt[0] & 0x0f) * 4;
+        if ip_hl < 20 {
+            return Err(());
+        }
+        ip_hl
+    } else {
+        // Extension headers are not supported.
+        if pkt.len() < 40 || pkt[0] >> 4 != 6 || pkt[6] != IPPROTO_TCP {
+            return Err(());
+        }
+        40
+    };
+    if pkt.len() < ip_hl + 20 {
+        return Err(());
+    }
+    let tcp_hl = usize::from(pkt[ip_hl + 12] >> 4) * 4;
+    let hdr_len = ip_hl + tcp_hl;
+    let mss = usize::from(hdr.gso_size);
+    if tcp_hl < 20 || pkt.len() < hdr_len || mss == 0 {
+        return Err(());
+    }
+
+    let payload = &pkt[hdr_len..];
+    let seq = read_u32(pkt, ip_hl + 4);
+    let id = read_u16(pkt, 4);
+    let flags = pkt[ip_hl + 13];
+    let count = std::cmp::max(1, payload.chunks(mss).len());
+    for i in 0..count {
+        let from = std::cmp::min(i * mss, payload.len());
+        let to = std::cmp::min(from + mss, payload.len());
+        let chunk = &payload[from..to];
+        let start = out.len();
+        out.extend_from_slice(&pkt[..hdr_len]);
+        out.extend_from_slice(chunk);
+        let seg = &mut out[start..];
+        let total = seg.len();
+
+        if is_v4 {
+            write_u16(seg, 2, total as u16);
+            write_u16(seg, 4, id.wrapping_add(i as u16));
+            ipv4_update_checksum(seg, ip_hl);
+        } else {
+            write_u16(seg, 4, (total - 40) as u16);
+        }
+
+        let seg_seq = seq.wrapping_add((i * mss) as u32);
+        seg[ip_hl + 4..ip_hl + 8].copy_from_slice(&seg_seq.to_be_bytes());
+        let mut seg_flags = flags;
+        if i != count - 1 {
+            seg_flags &= !(TCP_FIN | TCP_PSH);
+        }
+        if i != 0 {
+            seg_flags &= !TCP_CWR;
+        }
+        seg[ip_hl + 13] = seg_flags;
+        tcp_update_checksum(seg, ip_hl, is_v4);
+
+        segments.push(start..out.len());
+    }
+    Ok(())
+}
+
+// The coalesced packet being built.
+struct Group {
+    is_v4: bool,
+    ip_hl: usize,
+    tcp_hl: usize,
+    gso_size: usize,
+    segments: usize,
+    next_seq: u32,
+    psh: bool,
+    // No more segments can be added.
+    closed: bool,
+}
+
+// Parse a packet that may be coalesced. Returns whether it is IPv4, IP header
+// length and TCP header length.
+fn parse_tcp(p: &[u8]) -> Option<(bool, usize, usize)> {
+    if p.len() < 20 {
+        return None;
+    }
+    let (is_v4, ip_hl) = match p[0] >> 4 {
+        4 => {
+            // No options, no fragments.
+            if p[0] & 0x0f != 5
+                || read_u16(p, 6) & 0x3fff != 0
+                || p[9] != IPPROTO_TCP
+                || usize::from(read_u16(p, 2)) != p.len()
+            {
+                return None;
+            }
+            (true, 20)
+        }
+        6 => {
+            if p.len() < 40 || p[6] != IPPROTO_TCP || usize::from(read_u16(p, 4)) + 40 != p.len() {
+                return None;
+            }
+            (false, 40)
+        }
+        _ => return None,
+    };
+    if p.len() < ip_hl + 20 {
+        return None;
+    }
+    let tcp_hl = usize::from(p[ip_hl + 12] >> 4) * 4;
+    let flags = p[ip_hl + 13];
+    if tcp_hl < 20
+        || p.len() <= ip_hl + tcp_hl
+        || flags & TCP_ACK == 0
+        || flags & !(TCP_ACK | TCP_PSH) != 0
+        || !tcp_checksum_ok(p, ip_hl, is_v4)
+    {
+        return None;
+    }
+    Some((is_v4, ip_hl, tcp_hl))
+}
+
+/// Coalesces TCP segments of the same flow, to be written to a tun device
+/// with virtio-net headers.
+///
+/// Push packets with `push`. When it returns false, `take` the current packet
+/// and push again.
+pub struct TcpCoalescer {
+    buf: Vec<u8>,
+    group: Option<Group>,
+    // `buf` has been taken, and should be cleared on the next push.
+    taken: bool,
+}
+
+impl TcpCoalescer {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::with_capacity(VIRTIO_NET_HDR_LEN + 65535),
+            group: None,
+            taken: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.taken || self.buf.is_empty()
+    }
+
+    /// Add a packet. Returns false if it can not be coalesced with the current
+    /// packet.
+    pub fn push(&mut self, p: &[u8]) -> bool {
+        if self.is_empty() {
+            self.buf.clear();
+            self.taken = false;
+            self.buf.resize(VIRTIO_NET_HDR_LEN, 0);
+            self.buf.extend_from_slice(p);
+            self.group = parse_tcp(p).map(|(is_v4, ip_hl, tcp_hl)| {
+                let payload = p.len() - ip_hl - tcp_hl;
+                let flags = p[ip_hl + 13];
+                Group {
+                    is_v4,
+                    ip_hl,
+                    tcp_hl,
+                    gso_size: payload,
+                    segments: 1,
+                    next_seq: read_u32(p, ip_hl + 4).wrapping_add(payload as u32),
+                    psh: flags & TCP_PSH != 0,
+                    closed: flags & TCP_PSH != 0,
+                }
+            });
+            return true;
+        }
+
+        let g = match self.group {
+            Some(ref mut g) if !g.closed && g.segments < MAX_SEGMENTS => g,
+            _ => return false,
+        };
+        let head = &self.buf[VIRTIO_NET_HDR_LEN..];
+        match parse_tcp(p) {
+            Some((is_v4, ip_hl, tcp_hl))
+                if is_v4 == g.is_v4 && ip_hl == g.ip_hl && tcp_hl == g.tcp_hl => {}
+            _ => return false,
+        }
+        let same_ip_header = if g.is_v4 {
+            // Everything except length, id and checksum.
+            head[..2] == p[..2] && head[6..10] == p[6..10] && head[12..20] == p[12..20]
+        } else {
+            // Everything except payload length.
+            head[..4] == p[..4] && head[6..40] == p[6..40]
+        };
+        let t = g.ip_hl;
+        let hdr_len = g.ip_hl + g.tcp_hl;
+        // Ports, ack number, data offset and options.
+        let same_tcp_header = head[t..t + 4] == p[t..t + 4]
+            && head[t + 8..t + 13] == p[t + 8..t + 13]
+            && head[t + 20..hdr_len] == p[t + 20..hdr_len];
+        let payload = &p[hdr_len..];
+        if !same_ip_header
+            || !same_tcp_header
+            || read_u32(p, t + 4) != g.next_seq
+            || payload.len() > g.gso_size
+            || self.buf.len() - VIRTIO_NET_HDR_LEN + payload.len() > 65535
+        {
+            return false;
+        }
+
+        self.buf.extend_from_slice(payload);
+        let psh = p[t + 13] & TCP_PSH != 0;
+        g.segments += 1;
+        g.next_seq = g.next_seq.wrapping_add(payload.len() as u32);
+        g.psh |= psh;
+        g.closed = psh || payload.len() < g.gso_size;
+        true
+    }
+
+    /// Take the current packet, with virtio-net header. Returns `None` if
+    /// there is no packet.
+    pub fn take(&mut self) -> Option<&[u8]> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut hdr = VirtioNetHdr::default();
+        if let Some(g) = self.group.take().filter(|g| g.segments > 1) {
+            let p = &mut self.buf[VIRTIO_NET_HDR_LEN..];
+            let len = p.len();
+            if g.is_v4 {
+                write_u16(p, 2, len as u16);
+                ipv4_update_checksum(p, g.ip_hl);
+            } else {
+                write_u16(p, 4, (len - 40) as u16);
+            }
+            let t = g.ip_hl;
+            if g.psh {
+                p[t + 13] |= TCP_PSH;
+            }
+            // The kernel expects the pseudo header checksum.
+            let (src, dst) = ip_addrs(p, g.is_v4);
+            let c = fold(pseudo_header_sum(src, dst, len - t));
+            write_u16(p, t + 16, c);
+
+            hdr = VirtioNetHdr {
+                flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+                gso_type: if g.is_v4 {
+                    VIRTIO_NET_HDR_GSO_TCPV4
+                } else {
+                    VIRTIO_NET_HDR_GSO_TCPV6
+                },
+                hdr_len: (g.ip_hl + g.tcp_hl) as u16,
+                gso_size: g.gso_size as u16,
+                csum_start: t as u16,
+                csum_offset: 16,
+            };
+        }
+        hdr.encode(&mut self.buf);
+        self.taken = true;
+        Some(&self.buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A TCP packet with valid checksums.
+    fn tcp_packet(is_v4: bool, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let ip_hl = if is_v4 { 20 } else { 40 };
+        let mut p = vec![0u8; ip_hl + 20];
+        if is_v4 {
+            p[0] = 0x45;
+            write_u16(&mut p, 2, (ip_hl + 20 + payload.len()) as u16);
+            write_u16(&mut p, 6, 0x4000);
+            p[8] = 64;
+            p[9] = IPPROTO_TCP;
+            p[12..16].copy_from_slice(&[10, 0, 0, 1]);
+            p[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        } else {
+            p[0] = 0x60;
+            write_u16(&mut p, 4, (20 + payload.len()) as u16);
+            p[6] = IPPROTO_TCP;
+            p[7] = 64;
+            p[23] = 1;
+            p[39] = 2;
+        }
+        write_u16(&mut p, ip_hl, 443);
+        write_u16(&mut p, ip_hl + 2, 50000);
+        p[ip_hl + 4..ip_hl + 8].copy_from_slice(&seq.to_be_bytes());
+        p[ip_hl + 8..ip_hl + 12].copy_from_slice(&7u32.to_be_bytes());
+        p[ip_hl + 12] = 5 << 4;
+        p[ip_hl + 13] = flags;
+        write_u16(&mut p, ip_hl + 14, 1000);
+        p.extend_from_slice(payload);
+        if is_v4 {
+            ipv4_update_checksum(&mut p, ip_hl);
+        }
+        tcp_update_checksum(&mut p, ip_hl, is_v4);
+        p
+    }
+
+    fn split_and_coalesce(is_v4: bool) {
+        let ip_hl = if is_v4 { 20 } else { 40 };
+        let payload: Vec<u8> = (0..2500u32).map(|x| x as u8).collect();
+        let big = tcp_packet(is_v4, 100, TCP_ACK | TCP_PSH, &payload);
+
+        // Split.
+        let mut splitter = TsoSplitter::new();
+        let hdr = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: if is_v4 {
+                VIRTIO_NET_HDR_GSO_TCPV4
+            } else {
+                VIRTIO_NET_HDR_GSO_TCPV6
+            },
+            hdr_len: (ip_hl + 20) as u16,
+            gso_size: 1000,
+            csum_start: ip_hl as u16,
+            csum_offset: 16,
+        };
+        hdr.encode(splitter.raw_buf());
+        splitter.raw_buf()[VIRTIO_NET_HDR_LEN..][..big.len()].copy_from_slice(&big);
+        splitter.process(VIRTIO_NET_HDR_LEN + big.len()).unwrap();
+
+        let mut segments = Vec::new();
+        let mut buf = [0u8; 2048];
+        while let Some(len) = splitter.next_segment(&mut buf) {
+            segments.push(buf[..len].to_vec());
+        }
+        assert_eq!(segments.len(), 3);
+        for (i, s) in segments.iter().enumerate() {
+            let last = i == 2;
+            let expected = tcp_packet(
+                is_v4,
+                100 + 1000 * i as u32,
+                if last { TCP_ACK | TCP_PSH } else { TCP_ACK },
+                &payload[i * 1000..std::cmp::min(payload.len(), (i + 1) * 1000)],
+            );
+            if is_v4 {
+                assert_eq!(s[..4], expected[..4]);
+                assert_eq!(read_u16(s, 4), i as u16);
+                assert_eq!(fold(checksum_no_fold(&s[..20], 0)), 0xffff);
+                assert_eq!(s[20..], expected[20..]);
+            } else {
+                assert_eq!(s, &expected);
+            }
+        }
+
+        // Coalesce.
+        let mut coalescer = TcpCoalescer::new();
+        for s in &segments {
+            assert!(coalescer.push(s));
+        }
+        let out = coalescer.take().unwrap();
+        let hdr = VirtioNetHdr::decode(out);
+        assert_eq!(hdr.gso_size, 1000);
+        assert_eq!(usize::from(hdr.hdr_len), ip_hl + 20);
+        assert_eq!(usize::from(hdr.csum_start), ip_hl);
+        let mut out = out[VIRTIO_NET_HDR_LEN..].to_vec();
+        assert_eq!(out.len(), big.len());
+        complete_checksum(&hdr, &mut out).unwrap();
+        assert_eq!(out[ip_hl..], big[ip_hl..]);
+        assert!(coalescer.take().is_none());
+    }
+
+    #[test]
+    fn split_and_coalesce_v4() {
+        split_and_coalesce(true);
+    }
+
+    #[test]
+    fn split_and_coalesce_v6() {
+        split_and_coalesce(false);
+    }
+
+    #[test]
+    fn coalesce_rejects_non_consecutive() {
+        let mut coalescer = TcpCoalescer::new();
+        assert!(coalescer.push(&tcp_packet(true, 0, TCP_ACK, &[0; 100])));
+        // Gap in sequence numbers.
+        assert!(!coalescer.push(&tcp_packet(true, 200, TCP_ACK, &[0; 100])));
+        // Single packet is written without GSO.
+        let out = coalescer.take().unwrap();
+        assert_eq!(VirtioNetHdr::decode(out), VirtioNetHdr::default());
+        assert_eq!(
+            out[VIRTIO_NET_HDR_LEN..],
+            tcp_packet(true, 0, TCP_ACK, &[0; 100])[..]
+        );
+
+        assert!(coalescer.push(&tcp_packet(true, 0, TCP_ACK, &[0; 100])));
+        // Larger than the first segment.
+        assert!(!coalescer.push(&tcp_packet(true, 100, TCP_ACK, &[0; 200])));
+        coalescer.take();
+
+        let mut bad = tcp_packet(true, 0, TCP_ACK, &[0; 100]);
+        bad[50] = 1;
+        assert!(coalescer.push(&bad));
+        // Bad checksum, not coalesced.
+        assert!(!coalescer.push(&tcp_packet(true, 100, TCP_ACK, &[0; 100])));
+    }
+}
@@ -8,12 +8,19 @@
 #endif
 
 #ifdef linux
-int tunsetiff(int tun_fd, const char* name) {
+int tunsetiff(int tun_fd, const char* name, int multi_queue) {
     struct ifreq req;
     strncpy(req.ifr_name, name, IF_NAMESIZE);
-    req.ifr_flags = IFF_TUN | IFF_NO_PI;
+    req.ifr_flags = IFF_TUN | IFF_NO_PI | IFF_VNET_HDR;
+    if (multi_queue) {
+        req.ifr_flags |= IFF_MULTI_QUEUE;
+    }
     return ioctl(tun_fd, TUNSETIFF, &req);
 }
+
+int tunsetoffload(int tun_fd) {
+    return ioctl(tun_fd, TUNSETOFFLOAD, TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6);
+}
 #endif
 
 #ifdef __FreeBSD__
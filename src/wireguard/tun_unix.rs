@@ -17,18 +17,27 @@
 
 #![cfg(unix)]
 
+#[cfg(target_os = "linux")]
+use crate::wireguard::tun_offload::*;
+use crate::wireguard::PacketDevice;
 use anyhow::Context as _;
+use futures::future::BoxFuture;
+use futures::prelude::*;
 use nix::fcntl::{open, OFlag};
 use nix::libc;
 use nix::sys::stat::Mode;
 use nix::unistd::{close, read, write};
 use std::ffi::CString;
 use std::ffi::OsStr;
+use std::fmt;
 use std::io;
 use std::mem;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::time::Duration;
 use tokio::io::unix::AsyncFd;
+#[cfg(target_os = "linux")]
+use tokio::sync::Mutex as AsyncMutex;
 
 mod ffi {
     use nix::libc;
@@ -36,26 +45,60 @@ mod ffi {
     extern "C" {
         pub fn get_mtu(socket_fd: libc::c_int, ifindex: libc::c_uint) -> libc::c_int;
         #[cfg(target_os = "linux")]
-        pub fn tunsetiff(tun_fd: libc::c_int, name: *const u8) -> libc::c_int;
+        pub fn tunsetiff(
+            tun_fd: libc::c_int,
+            name: *const u8,
+            multi_queue: libc::c_int,
+        ) -> libc::c_int;
+        #[cfg(target_os = "linux")]
+        pub fn tunsetoffload(tun_fd: libc::c_int) -> libc::c_int;
         #[cfg(target_os = "freebsd")]
         pub fn tunsifhead(tun_fd: libc::c_int) -> libc::c_int;
     }
 }
 
 /// A tun interface.
-#[derive(Debug)]
 pub struct AsyncTun {
     io: AsyncFd<Tun>,
+    // On linux, the tun is opened with virtio-net headers.
+    #[cfg(target_os = "linux")]
+    splitter: AsyncMutex<TsoSplitter>,
+    #[cfg(target_os = "linux")]
+    coalescer: AsyncMutex<TcpCoalescer>,
+}
+
+impl fmt::Debug for AsyncTun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncTun").field("io", &self.io).finish()
+    }
 }
 
 impl AsyncTun {
     pub fn open(name: &OsStr) -> anyhow::Result<AsyncTun> {
         let tun = Tun::open(name, OFlag::O_NONBLOCK)?;
+        AsyncTun::from_tun(tun)
+    }
+
+    fn from_tun(tun: Tun) -> anyhow::Result<AsyncTun> {
         Ok(AsyncTun {
             io: AsyncFd::new(tun)?,
+            #[cfg(target_os = "linux")]
+            splitter: AsyncMutex::new(TsoSplitter::new()),
+            #[cfg(target_os = "linux")]
+            coalescer: AsyncMutex::new(TcpCoalescer::new()),
         })
     }
 
+    /// Open a multi-queue tun interface with `queues` queues.
+    ///
+    /// Returns an `AsyncTun` for each queue.
+    #[cfg(target_os = "linux")]
+    pub fn open_multi_queue(name: &OsStr, queues: usize) -> anyhow::Result<Vec<AsyncTun>> {
+        (0..queues)
+            .map(|_| AsyncTun::from_tun(Tun::open_queue(name, OFlag::O_NONBLOCK, true)?))
+            .collect()
+    }
+
     pub(crate) fn get_mtu(&self) -> io::Result<u32> {
         use nix::sys::socket::*;
         let socket = socket(
@@ -77,7 +120,44 @@ impl AsyncTun {
     }
 
     // Should be used from only one task.
+    #[cfg(not(target_os = "linux"))]
     pub(crate) async fn read<'a>(&'a self, buf: &'a mut [u8]) -> io::Result<usize> {
+        self.read_raw(buf).await
+    }
+
+    // Large TCP segments are split. Segments are returned one by one.
+    #[cfg(target_os = "linux")]
+    pub(crate) async fn read<'a>(&'a self, buf: &'a mut [u8]) -> io::Result<usize> {
+        let mut splitter = self.splitter.lock().await;
+        loop {
+            if let Some(len) = splitter.next_segment(buf) {
+                return Ok(len);
+            }
+            let len = self.read_raw(splitter.raw_buf()).await?;
+            if splitter.process(len).is_err() {
+                debug!("failed to process packet from tun");
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut splitter = self
+            .splitter
+            .try_lock()
+            .map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+        loop {
+            if let Some(len) = splitter.next_segment(buf) {
+                return Ok(len);
+            }
+            let len = self.io.get_ref().read(splitter.raw_buf())?;
+            if splitter.process(len).is_err() {
+                debug!("failed to process packet from tun");
+            }
+        }
+    }
+
+    async fn read_raw(&self, buf: &mut [u8]) -> io::Result<usize> {
         loop {
             let mut guard = self.io.readable().await?;
             match guard.try_io(|t| t.get_ref().read(buf)) {
@@ -88,7 +168,48 @@ impl AsyncTun {
     }
 
     // Should be used from only one task.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) async fn write<'a>(&'a self, buf: &'a [u8]) -> io::Result<usize> {
+        self.write_raw(buf).await
+    }
+
+    #[cfg(target_os = "linux")]
     pub(crate) async fn write<'a>(&'a self, buf: &'a [u8]) -> io::Result<usize> {
+        let mut coalescer = self.coalescer.lock().await;
+        coalescer.push(buf);
+        let packet = coalescer.take().unwrap();
+        self.write_raw(packet).await?;
+        Ok(buf.len())
+    }
+
+    // TCP segments of the same flow are coalesced.
+    #[cfg(target_os = "linux")]
+    async fn write_batch(&self, packets: &[&[u8]]) -> io::Result<()> {
+        let mut coalescer = self.coalescer.lock().await;
+        let mut result = Ok(());
+        for p in packets {
+            if coalescer.push(p) {
+                continue;
+            }
+            let packet = coalescer.take().unwrap();
+            if let Err(e) = self.write_raw(packet).await {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+            coalescer.push(p);
+        }
+        if let Some(packet) = coalescer.take() {
+            if let Err(e) = self.write_raw(packet).await {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    async fn write_raw(&self, buf: &[u8]) -> io::Result<usize> {
         loop {
             let mut guard = self.io.writable().await?;
             match guard.try_io(|t| t.get_ref().write(buf)) {
@@ -99,6 +220,40 @@ impl AsyncTun {
     }
 }
 
+impl PacketDevice for AsyncTun {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        AsyncTun::read(self, buf).boxed()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        AsyncTun::try_read(self, buf)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.get_ref().read(buf)
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        AsyncTun::write(self, buf).boxed()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn write_batch<'a>(&'a self, packets: &'a [&'a [u8]]) -> BoxFuture<'a, io::Result<()>> {
+        AsyncTun::write_batch(self, packets).boxed()
+    }
+
+    fn mtu(&self) -> io::Result<u32> {
+        self.get_mtu()
+    }
+
+    fn changed(&self) -> BoxFuture<'_, ()> {
+        // We are not notified of MTU changes, so just poll every 10 seconds.
+        tokio::time::sleep(Duration::from_secs(10)).boxed()
+    }
+}
+
 #[derive(Debug)]
 struct Tun {
     fd: i32,
@@ -120,6 +275,13 @@ impl Tun {
     /// O_CLOEXEC, IFF_NO_PI.
     #[cfg(target_os = "linux")]
     pub fn open(name: &OsStr, extra_flags: OFlag) -> anyhow::Result<Tun> {
+        Tun::open_queue(name, extra_flags, false)
+    }
+
+    /// Create a tun interface, or attach a new queue to a multi-queue tun
+    /// interface if `multi_queue` is true.
+    #[cfg(target_os = "linux")]
+    pub fn open_queue(name: &OsStr, extra_flags: OFlag, multi_queue: bool) -> anyhow::Result<Tun> {
         if name.len() > nix::libc::IF_NAMESIZE - 1 {
             bail!("interface name is too long.");
         }
@@ -137,10 +299,18 @@ impl Tun {
         // error occurs below, the `fd` is `close`d.
         let mut tun = Tun { fd, index: 0 };
 
-        if unsafe { ffi::tunsetiff(fd, name_c_bytes.as_ptr()) } < 0 {
+        if unsafe { ffi::tunsetiff(fd, name_c_bytes.as_ptr(), multi_queue.into()) } < 0 {
             return Err(io::Error::last_os_error().into());
         }
 
+        // Packets are still prefixed with virtio-net headers without offloads.
+        if unsafe { ffi::tunsetoffload(fd) } < 0 {
+            warn!(
+                "failed to enable tun offloads: {}",
+                io::Error::last_os_error()
+            );
+        }
+
         // XXX: possible race if another process changes the name before we get
         // the index.
         tun.index = {
@@ -322,6 +492,18 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_multi_queue() -> anyhow::Result<()> {
+        let name = OsStr::new("tun11");
+        let queues = AsyncTun::open_multi_queue(name, 4)?;
+        assert_eq!(queues.len(), 4);
+        for q in &queues {
+            assert_eq!(q.io.get_ref().index, queues[0].io.get_ref().index);
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_get_mtu() -> anyhow::Result<()> {
         let name = OsStr::new("tun10");
@@ -28,7 +28,10 @@ use std::ptr::{null, null_mut};
 use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
 use std::sync::Arc;
 
+use crate::wireguard::PacketDevice;
 use anyhow::Context;
+use futures::future::BoxFuture;
+use futures::prelude::*;
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
 use tokio::sync::Mutex as AsyncMutex;
@@ -269,6 +272,33 @@ impl AsyncTun {
     }
 }
 
+impl PacketDevice for AsyncTun {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        AsyncTun::read(self, buf).boxed()
+    }
+
+    fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let _read_lock_guard = self
+            .read_lock
+            .try_lock()
+            .map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+        unsafe { self.rings.try_read(buf) }
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        AsyncTun::write(self, buf).boxed()
+    }
+
+    // TODO: Implement get MTU on Windows.
+    fn mtu(&self) -> io::Result<u32> {
+        Ok(1280)
+    }
+
+    fn changed(&self) -> BoxFuture<'_, ()> {
+        future::pending().boxed()
+    }
+}
+
 impl Drop for AsyncTun {
     fn drop(&mut self) {
         self.close()
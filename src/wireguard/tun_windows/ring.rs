@@ -196,6 +196,16 @@ impl TunRegisterRings {
         }
     }
 
+    /// Read a packet if one is available, does not block.
+    ///
+    /// # Safety
+    ///
+    /// Only one thread should call this or `read`.
+    pub unsafe fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let send_ring = self.send.ring.get().as_mut().unwrap();
+        send_ring.read(buf)
+    }
+
     /// # Safety
     ///
     /// Only one thread should call this.
@@ -16,6 +16,7 @@
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::crypto::noise_crypto_impls::X25519;
+use crate::wireguard::{CountersOut, FilterRule, RateLimit, SourceFilter};
 use noise_protocol::DH;
 use rand::prelude::*;
 use rand::rngs::OsRng;
@@ -23,7 +24,7 @@ use std::collections::BTreeSet;
 use std::net::{IpAddr, SocketAddr, SocketAddrV6};
 use std::num::NonZeroU16;
 use std::ops::Deref;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 /// X25519 private key.
 pub type X25519Key = <X25519 as DH>::Key;
@@ -39,6 +40,9 @@ pub struct PeerInfo {
     pub psk: Option<[u8; 32]>,
     /// Peer endpoint.
     pub endpoint: Option<SocketAddrV6>,
+    /// Local address that packets from the peer last arrived on. Packets to
+    /// the peer are sent from it.
+    pub local: Option<LocalAddr>,
     /// Allowed source IPs.
     pub allowed_ips: BTreeSet<(IpAddr, u32)>,
     /// Persistent keep-alive interval in seconds.
@@ -48,6 +52,15 @@ pub struct PeerInfo {
     pub roaming: bool,
 }
 
+/// A local address of the interface.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LocalAddr {
+    /// Address of the listening socket.
+    pub socket: SocketAddr,
+    /// Destination address of the packets. Only available on linux.
+    pub ip: Option<IpAddr>,
+}
+
 /// Config info about a WireGuard interface.
 pub struct WgInfo {
     /// Self private key.
@@ -73,6 +86,11 @@ pub struct WgStateOut {
     pub listen_port: u16,
     /// Fwmark.
     pub fwmark: u32,
+    /// Whether handshake messages arrive too quickly, so that cookies are
+    /// required.
+    pub under_load: bool,
+    /// Counters of the whole interface.
+    pub counters: CountersOut,
 }
 
 /// State of a peer.
@@ -83,6 +101,10 @@ pub struct PeerStateOut {
     pub preshared_key: Option<[u8; 32]>,
     /// Endpoint.
     pub endpoint: Option<SocketAddr>,
+    /// Candidate endpoints. `endpoint` is the active one.
+    pub endpoints: Vec<SocketAddr>,
+    /// Host names of the candidate endpoints.
+    pub endpoint_hosts: Vec<String>,
     /// Last handshake time.
     pub last_handshake_time: Option<SystemTime>,
     /// Received bytes.
@@ -95,6 +117,33 @@ pub struct PeerStateOut {
     pub persistent_keepalive_interval: u16,
     /// Allowed IP addresses.
     pub allowed_ips: BTreeSet<(IpAddr, u32)>,
+    /// Whether path MTU discovery is enabled.
+    pub path_mtu_discovery: bool,
+    /// Discovered path MTU, i.e., max size of inner packets.
+    pub path_mtu: Option<u32>,
+    /// Rate limit of packets sent to the peer.
+    pub rate_limit: Option<RateLimit>,
+    /// Packet filter rules, and how many packets matched each.
+    pub filter: Vec<(FilterRule, u64)>,
+    /// How sources of packets from the peer are checked.
+    pub source_filter: SourceFilter,
+    /// Whether the peer is disabled.
+    pub disabled: bool,
+    /// Whether packets to the peer are answered with ICMP errors when it is
+    /// disabled.
+    pub disabled_unreachable: bool,
+    /// Whether the pre-shared key is pushed by an external provider.
+    pub psk_rotated: bool,
+    /// How long until the pushed pre-shared key expires.
+    pub psk_expires_in: Option<Duration>,
+    /// Whether the pushed pre-shared key has expired.
+    pub psk_expired: bool,
+    /// Number of sessions, the current one and older ones still kept.
+    pub sessions: usize,
+    /// Packets waiting for a handshake to complete.
+    pub queued_packets: usize,
+    /// Counters of the peer.
+    pub counters: CountersOut,
 }
 
 /// Sender index or receiver index.
@@ -0,0 +1,882 @@
+// Copyright 2021 Yin Guanhao <sopium@mysterious.site>
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// Max number of packets received or sent in one batch.
+pub const BATCH_SIZE: usize = 32;
+
+/// Information about a received packet.
+#[derive(Debug, Clone, Copy)]
+pub struct RecvMeta {
+    pub len: usize,
+    pub addr: SocketAddr,
+    /// With GRO, the buffer may contain multiple coalesced datagrams from the
+    /// same source. Each datagram is `segment_size` bytes, except that the
+    /// last one may be shorter.
+    ///
+    /// Equals to `len` if there is only one datagram.
+    pub segment_size: usize,
+    /// Destination address of the packet, i.e. the local address it arrived
+    /// on. Only available on linux.
+    pub dst: Option<IpAddr>,
+}
+
+impl RecvMeta {
+    /// Split the received buffer into datagrams.
+    pub fn segments<'a>(&self, buf: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        buf[..self.len].chunks(self.segment_size.max(1))
+    }
+}
+
+impl Default for RecvMeta {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            addr: (Ipv6Addr::UNSPECIFIED, 0).into(),
+            segment_size: 0,
+            dst: None,
+        }
+    }
+}
+
+/// A batch of packets to send.
+///
+/// Buffers are allocated once and reused.
+pub struct SendBatch {
+    bufs: Vec<Vec<u8>>,
+    lens: Vec<usize>,
+    targets: Vec<SocketAddr>,
+    sources: Vec<Option<IpAddr>>,
+}
+
+impl SendBatch {
+    pub fn new(buf_size: usize) -> Self {
+        Self {
+            bufs: vec![vec![0u8; buf_size]; BATCH_SIZE],
+            lens: Vec::with_capacity(BATCH_SIZE),
+            targets: Vec::with_capacity(BATCH_SIZE),
+            sources: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lens.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == BATCH_SIZE
+    }
+
+    /// Buffer for the next packet. Call `push` after filling it.
+    ///
+    /// Panics if the batch is full.
+    pub fn next_buf(&mut self) -> &mut [u8] {
+        &mut self.bufs[self.lens.len()]
+    }
+
+    /// Add the packet in `next_buf()[..len]` to the batch, to be sent from
+    /// the local address `source` if it is not `None`.
+    pub fn push(&mut self, len: usize, target: SocketAddr, source: Option<IpAddr>) {
+        assert!(!self.is_full());
+        self.lens.push(len);
+        self.targets.push(target);
+        self.sources.push(source);
+    }
+
+    pub fn get(&self, i: usize) -> (&[u8], SocketAddr) {
+        (&self.bufs[i][..self.lens[i]], self.targets[i])
+    }
+
+    pub fn source(&self, i: usize) -> Option<IpAddr> {
+        self.sources[i]
+    }
+
+    pub fn clear(&mut self) {
+        self.lens.clear();
+        self.targets.clear();
+        self.sources.clear();
+    }
+}
+
+/// A UDP socket that receives and sends packets in batches.
+///
+/// Uses `recvmmsg` and `sendmmsg` on linux, and UDP GSO/GRO if the kernel
+/// supports them. On other platforms, packets are received and sent one by
+/// one.
+///
+/// On linux, the destination address of received packets is recorded, and
+/// packets can be sent from a specific local address. This is useful when the
+/// socket is bound to a wildcard address on a multi-homed host.
+pub struct BatchUdpSocket {
+    #[cfg(target_os = "linux")]
+    io: tokio::io::unix::AsyncFd<std::net::UdpSocket>,
+    // Whether to send with UDP GSO. Disabled on the first EIO error, which
+    // means that the NIC can not do checksum offloading.
+    #[cfg(target_os = "linux")]
+    gso: std::sync::atomic::AtomicBool,
+    // IPv4 and IPv6 path MTU discovery modes the socket is created with.
+    #[cfg(target_os = "linux")]
+    pmtu_modes: linux::PmtuModes,
+    #[cfg(not(target_os = "linux"))]
+    io: tokio::net::UdpSocket,
+}
+
+#[cfg(target_os = "linux")]
+impl std::os::unix::io::AsRawFd for BatchUdpSocket {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.io.as_raw_fd()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl BatchUdpSocket {
+    /// The socket should be in non-blocking mode.
+    ///
+    /// UDP GSO and GRO are enabled if supported.
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        let (gso, gro) = linux::enable_offload(&socket);
+        let pktinfo = linux::enable_pktinfo(&socket);
+        debug!("UDP GSO: {}, GRO: {}, PKTINFO: {}", gso, gro, pktinfo);
+        let pmtu_modes = linux::get_pmtu_modes(&socket);
+        Ok(Self {
+            io: tokio::io::unix::AsyncFd::new(socket)?,
+            gso: gso.into(),
+            pmtu_modes,
+        })
+    }
+
+    /// Send packets with the don't fragment bit set, regardless of the path
+    /// MTU cached by the kernel, so that path MTU probes work. Otherwise the
+    /// modes the socket is created with are used.
+    ///
+    /// This applies to all packets, as switching it for each probe would also
+    /// affect packets sent concurrently.
+    pub fn set_pmtu_probe(&self, enabled: bool) -> io::Result<()> {
+        let modes = if enabled {
+            let (v4, v6) = self.pmtu_modes;
+            (
+                v4.map(|_| linux::PMTUDISC_PROBE),
+                v6.map(|_| linux::PMTUDISC_PROBE),
+            )
+        } else {
+            self.pmtu_modes
+        };
+        linux::set_pmtu_modes(self.io.get_ref(), modes)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.get_ref().local_addr()
+    }
+
+    /// Send a packet, from the local address `source` if it is not `None`.
+    pub async fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        source: Option<IpAddr>,
+    ) -> io::Result<usize> {
+        loop {
+            let mut guard = self.io.writable().await?;
+            match guard.try_io(|s| linux::send_one(s.get_ref(), buf, target, source)) {
+                Err(_) => continue,
+                Ok(result) => return result,
+            }
+        }
+    }
+
+    /// Receive at least one packet. Packet `i` is received into `bufs[i]`, and
+    /// its length and source address is stored in `meta[i]`.
+    ///
+    /// Returns the number of packets received.
+    pub async fn recv_batch(
+        &self,
+        bufs: &mut [Vec<u8>],
+        meta: &mut [RecvMeta],
+    ) -> io::Result<usize> {
+        loop {
+            let mut guard = self.io.readable().await?;
+            match guard.try_io(|s| linux::recvmmsg(s.get_ref(), bufs, meta)) {
+                Err(_) => continue,
+                Ok(result) => return result,
+            }
+        }
+    }
+
+    /// Send all packets in `batch`.
+    ///
+    /// Consecutive packets to the same target are sent as one GSO buffer if
+    /// possible.
+    ///
+    /// Packets that fail to send are skipped. Returns the first error, if any.
+    pub async fn send_batch(&self, batch: &SendBatch) -> io::Result<()> {
+        use std::sync::atomic::Ordering;
+
+        let mut result = Ok(());
+        let mut sent = 0;
+        while sent < batch.len() {
+            let gso = self.gso.load(Ordering::Relaxed);
+            let mut guard = self.io.writable().await?;
+            match guard.try_io(|s| linux::sendmmsg(s.get_ref(), batch, sent, gso)) {
+                Err(_) => continue,
+                Ok(Ok(n)) => sent += n,
+                Ok(Err(e)) if gso && e.raw_os_error() == Some(nix::libc::EIO) => {
+                    warn!("UDP GSO failed, disabling it: {}", e);
+                    self.gso.store(false, Ordering::Relaxed);
+                }
+                Ok(Err(e)) => {
+                    // The first message failed.
+                    sent += linux::gso_segments(batch, sent, gso);
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl BatchUdpSocket {
+    /// The socket should be in non-blocking mode.
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        Ok(Self {
+            io: tokio::net::UdpSocket::from_std(socket)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+
+    /// Not supported on this platform, so path MTU probes may be fragmented.
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn set_pmtu_probe(&self, _enabled: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Send a packet. `source` is ignored on this platform.
+    pub async fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        _source: Option<IpAddr>,
+    ) -> io::Result<usize> {
+        self.io.send_to(buf, target).await
+    }
+
+    /// Receive at least one packet. Packet `i` is received into `bufs[i]`, and
+    /// its length and source address is stored in `meta[i]`.
+    ///
+    /// Returns the number of packets received.
+    pub async fn recv_batch(
+        &self,
+        bufs: &mut [Vec<u8>],
+        meta: &mut [RecvMeta],
+    ) -> io::Result<usize> {
+        let (len, addr) = self.io.recv_from(&mut bufs[0]).await?;
+        meta[0] = RecvMeta {
+            len,
+            addr,
+            segment_size: len,
+            dst: None,
+        };
+        let mut n = 1;
+        for (buf, m) in bufs.iter_mut().zip(meta.iter_mut()).skip(1) {
+            match self.io.try_recv_from(buf) {
+                Ok((len, addr)) => {
+                    *m = RecvMeta {
+                        len,
+                        addr,
+                        segment_size: len,
+                        dst: None,
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Send all packets in `batch`.
+    ///
+    /// Packets that fail to send are skipped. Returns the first error, if any.
+    pub async fn send_batch(&self, batch: &SendBatch) -> io::Result<()> {
+        let mut result = Ok(());
+        for i in 0..batch.len() {
+            let (buf, target) = batch.get(i);
+            if let Err(e) = self.io.send_to(buf, target).await {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+    use nix::libc;
+    use std::mem;
+    use std::net::{Ipv4Addr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr::null_mut;
+
+    // Not in libc yet.
+    const UDP_SEGMENT: libc::c_int = 103;
+    const UDP_GRO: libc::c_int = 104;
+
+    const IP_PKTINFO: libc::c_int = 8;
+    const IPV6_RECVPKTINFO: libc::c_int = 49;
+    const IPV6_PKTINFO: libc::c_int = 50;
+
+    // `in_pktinfo` and `in6_pktinfo`, with addresses as bytes.
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct InPktinfo {
+        ipi_ifindex: libc::c_int,
+        ipi_spec_dst: [u8; 4],
+        ipi_addr: [u8; 4],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct In6Pktinfo {
+        ipi6_addr: [u8; 16],
+        ipi6_ifindex: libc::c_uint,
+    }
+
+    const IP_MTU_DISCOVER: libc::c_int = 10;
+    const IPV6_MTU_DISCOVER: libc::c_int = 23;
+    // Set DF and ignore the cached path MTU. Same value for v4 and v6.
+    pub const PMTUDISC_PROBE: libc::c_int = 3;
+
+    // Max size of a GSO buffer.
+    const GSO_MAX_BYTES: usize = 65000;
+
+    // Buffer for control messages. Aligned for `cmsghdr`.
+    type Control = [u64; 16];
+
+    fn setsockopt_int(
+        socket: &std::net::UdpSocket,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        let r = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const _ as *const _,
+                mem::size_of::<libc::c_int>() as _,
+            )
+        };
+        if r < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn getsockopt_int(
+        socket: &std::net::UdpSocket,
+        level: libc::c_int,
+        name: libc::c_int,
+    ) -> io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let r = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut _ as *mut _,
+                &mut len,
+            )
+        };
+        if r < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// IPv4 and IPv6 path MTU discovery modes, `None` if not supported.
+    pub type PmtuModes = (Option<libc::c_int>, Option<libc::c_int>);
+
+    pub fn get_pmtu_modes(socket: &std::net::UdpSocket) -> PmtuModes {
+        // Both for dual stack sockets. Only one of them works otherwise.
+        (
+            getsockopt_int(socket, libc::IPPROTO_IP, IP_MTU_DISCOVER).ok(),
+            getsockopt_int(socket, libc::IPPROTO_IPV6, IPV6_MTU_DISCOVER).ok(),
+        )
+    }
+
+    pub fn set_pmtu_modes(socket: &std::net::UdpSocket, modes: PmtuModes) -> io::Result<()> {
+        if let Some(m) = modes.0 {
+            setsockopt_int(socket, libc::IPPROTO_IP, IP_MTU_DISCOVER, m)?;
+        }
+        if let Some(m) = modes.1 {
+            setsockopt_int(socket, libc::IPPROTO_IPV6, IPV6_MTU_DISCOVER, m)?;
+        }
+        Ok(())
+    }
+
+    /// Enable receiving destination addresses of packets.
+    pub fn enable_pktinfo(socket: &std::net::UdpSocket) -> bool {
+        // Both for dual stack sockets. Only one of them works otherwise.
+        let v4 = setsockopt_int(socket, libc::IPPROTO_IP, IP_PKTINFO, 1).is_ok();
+        let v6 = setsockopt_int(socket, libc::IPPROTO_IPV6, IPV6_RECVPKTINFO, 1).is_ok();
+        v4 || v6
+    }
+
+    // Sending from `source` fails if it is no longer a local address. Route
+    // lookup fails with ENETUNREACH for IPv4 then.
+    fn is_bad_source(e: &io::Error) -> bool {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::EINVAL) | Some(libc::EADDRNOTAVAIL) | Some(libc::ENETUNREACH)
+        )
+    }
+
+    /// Send a packet, from `source` if it is not `None`.
+    ///
+    /// If `source` is no longer usable, the packet is sent without it.
+    pub fn send_one(
+        socket: &std::net::UdpSocket,
+        buf: &[u8],
+        target: SocketAddr,
+        source: Option<IpAddr>,
+    ) -> io::Result<usize> {
+        if source.is_none() {
+            return socket.send_to(buf, target);
+        }
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut _,
+            iov_len: buf.len(),
+        };
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut control = Control::default();
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = &mut name as *mut _ as *mut _;
+        hdr.msg_namelen = from_socket_addr(target, &mut name);
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        write_control(&mut hdr, &mut control, None, source);
+        let r = unsafe { libc::sendmsg(socket.as_raw_fd(), &hdr, 0) };
+        if r < 0 {
+            let e = io::Error::last_os_error();
+            if is_bad_source(&e) {
+                return socket.send_to(buf, target);
+            }
+            return Err(e);
+        }
+        Ok(r as usize)
+    }
+
+    // Write control messages for the GSO segment size and the source address.
+    fn write_control(
+        hdr: &mut libc::msghdr,
+        control: &mut Control,
+        segment_size: Option<u16>,
+        source: Option<IpAddr>,
+    ) {
+        let data_len = |len: usize| unsafe { libc::CMSG_SPACE(len as _) as usize };
+        let mut len = 0;
+        if segment_size.is_some() {
+            len += data_len(mem::size_of::<u16>());
+        }
+        match source {
+            Some(IpAddr::V4(_)) => len += data_len(mem::size_of::<InPktinfo>()),
+            Some(IpAddr::V6(_)) => len += data_len(mem::size_of::<In6Pktinfo>()),
+            None => (),
+        }
+        if len == 0 {
+            return;
+        }
+        hdr.msg_control = control.as_mut_ptr() as *mut _;
+        hdr.msg_controllen = len as _;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            if let Some(size) = segment_size {
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as _) as _;
+                std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, size);
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+            match source {
+                Some(IpAddr::V4(a)) => {
+                    (*cmsg).cmsg_level = libc::IPPROTO_IP;
+                    (*cmsg).cmsg_type = IP_PKTINFO;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<InPktinfo>() as _) as _;
+                    let info = InPktinfo {
+                        ipi_ifindex: 0,
+                        ipi_spec_dst: a.octets(),
+                        ipi_addr: [0; 4],
+                    };
+                    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut _, info);
+                }
+                Some(IpAddr::V6(a)) => {
+                    (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+                    (*cmsg).cmsg_type = IPV6_PKTINFO;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<In6Pktinfo>() as _) as _;
+                    let info = In6Pktinfo {
+                        ipi6_addr: a.octets(),
+                        ipi6_ifindex: 0,
+                    };
+                    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut _, info);
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// Check whether UDP GSO is supported, and enable UDP GRO.
+    pub fn enable_offload(socket: &std::net::UdpSocket) -> (bool, bool) {
+        // Setting the default segment size to 0 does nothing but tells us
+        // whether it is supported.
+        let gso = setsockopt_int(socket, libc::SOL_UDP, UDP_SEGMENT, 0).is_ok();
+        let gro = setsockopt_int(socket, libc::SOL_UDP, UDP_GRO, 1).is_ok();
+        (gso, gro)
+    }
+
+    pub fn recvmmsg(
+        socket: &std::net::UdpSocket,
+        bufs: &mut [Vec<u8>],
+        meta: &mut [RecvMeta],
+    ) -> io::Result<usize> {
+        let n = bufs.len().min(meta.len()).min(BATCH_SIZE);
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut controls: [Control; BATCH_SIZE] = [Control::default(); BATCH_SIZE];
+        let mut hdrs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for ((((buf, iov), name), control), hdr) in bufs
+            .iter_mut()
+            .zip(&mut iovecs)
+            .zip(&mut names)
+            .zip(&mut controls)
+            .zip(&mut hdrs)
+            .take(n)
+        {
+            iov.iov_base = buf.as_mut_ptr() as *mut _;
+            iov.iov_len = buf.len();
+            hdr.msg_hdr.msg_name = name as *mut _ as *mut _;
+            hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            hdr.msg_hdr.msg_iov = iov;
+            hdr.msg_hdr.msg_iovlen = 1;
+            hdr.msg_hdr.msg_control = control.as_mut_ptr() as *mut _;
+            hdr.msg_hdr.msg_controllen = mem::size_of::<Control>() as _;
+        }
+        let r =
+            unsafe { libc::recvmmsg(socket.as_raw_fd(), hdrs.as_mut_ptr(), n as _, 0, null_mut()) };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let r = r as usize;
+        for ((m, hdr), name) in meta.iter_mut().zip(&hdrs).zip(&names).take(r) {
+            let len = hdr.msg_len as usize;
+            let mut segment_size = len;
+            let mut dst = None;
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(&hdr.msg_hdr);
+                while !cmsg.is_null() {
+                    match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                        (libc::SOL_UDP, UDP_GRO) => {
+                            let size: libc::c_int =
+                                std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _);
+                            segment_size = size as usize;
+                        }
+                        (libc::IPPROTO_IP, IP_PKTINFO) => {
+                            let info: InPktinfo =
+                                std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _);
+                            dst = Some(Ipv4Addr::from(info.ipi_addr).into());
+                        }
+                        (libc::IPPROTO_IPV6, IPV6_PKTINFO) => {
+                            let info: In6Pktinfo =
+                                std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _);
+                            let a = Ipv6Addr::from(info.ipi6_addr);
+                            // IPv4 packets on dual stack sockets.
+                            dst = Some(if crate::wireguard::is_ipv4_mapped(&a) {
+                                let o = a.octets();
+                                Ipv4Addr::new(o[12], o[13], o[14], o[15]).into()
+                            } else {
+                                a.into()
+                            });
+                        }
+                        _ => (),
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&hdr.msg_hdr, cmsg);
+                }
+            }
+            *m = RecvMeta {
+                len,
+                addr: to_socket_addr(name),
+                segment_size,
+                dst,
+            };
+        }
+        Ok(r)
+    }
+
+    /// Number of packets, starting from `start` in `batch`, that can be sent
+    /// as one GSO buffer.
+    ///
+    /// They must be sent to the same target, and all but the last one must be
+    /// of the same size, and the last one must not be larger.
+    pub fn gso_segments(batch: &SendBatch, start: usize, gso: bool) -> usize {
+        let (first, target) = batch.get(start);
+        if !gso {
+            return 1;
+        }
+        let mut segments = 1;
+        let mut total = first.len();
+        while start + segments < batch.len() {
+            let (p, t) = batch.get(start + segments);
+            if t != target
+                || batch.source(start + segments) != batch.source(start)
+                || p.len() > first.len()
+                || total + p.len() > GSO_MAX_BYTES
+            {
+                break;
+            }
+            segments += 1;
+            total += p.len();
+            if p.len() < first.len() {
+                break;
+            }
+        }
+        segments
+    }
+
+    /// Send packets starting from `start` in `batch`.
+    ///
+    /// Returns the number of packets sent, which is at least 1 on success.
+    pub fn sendmmsg(
+        socket: &std::net::UdpSocket,
+        batch: &SendBatch,
+        start: usize,
+        gso: bool,
+    ) -> io::Result<usize> {
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut controls: [Control; BATCH_SIZE] = [Control::default(); BATCH_SIZE];
+        let mut hdrs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        // Number of packets in each message.
+        let mut segments = [0usize; BATCH_SIZE];
+
+        let mut messages = 0;
+        let mut i = start;
+        while i < batch.len() {
+            let n = gso_segments(batch, i, gso);
+            let first_iov = i - start;
+            for (j, iov) in iovecs[first_iov..first_iov + n].iter_mut().enumerate() {
+                let (p, _) = batch.get(i + j);
+                iov.iov_base = p.as_ptr() as *mut _;
+                iov.iov_len = p.len();
+            }
+            let (first, target) = batch.get(i);
+            let hdr = &mut hdrs[messages].msg_hdr;
+            hdr.msg_name = &mut names[messages] as *mut _ as *mut _;
+            hdr.msg_namelen = from_socket_addr(target, &mut names[messages]);
+            hdr.msg_iov = &mut iovecs[first_iov];
+            hdr.msg_iovlen = n as _;
+            let segment_size = if n > 1 {
+                Some(first.len() as u16)
+            } else {
+                None
+            };
+            write_control(hdr, &mut controls[messages], segment_size, batch.source(i));
+            segments[messages] = n;
+            messages += 1;
+            i += n;
+        }
+
+        let r = unsafe { libc::sendmmsg(socket.as_raw_fd(), hdrs.as_mut_ptr(), messages as _, 0) };
+        if r < 0 {
+            let e = io::Error::last_os_error();
+            if batch.source(start).is_some() && is_bad_source(&e) {
+                // Send the first message without the source address.
+                for i in start..start + segments[0] {
+                    let (p, target) = batch.get(i);
+                    socket.send_to(p, target)?;
+                }
+                return Ok(segments[0]);
+            }
+            return Err(e);
+        }
+        Ok(segments[..r as usize].iter().sum())
+    }
+
+    fn to_socket_addr(name: &libc::sockaddr_storage) -> SocketAddr {
+        match name.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let a = unsafe { &*(name as *const _ as *const libc::sockaddr_in) };
+                SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr)),
+                    u16::from_be(a.sin_port),
+                )
+                .into()
+            }
+            libc::AF_INET6 => {
+                let a = unsafe { &*(name as *const _ as *const libc::sockaddr_in6) };
+                SocketAddrV6::new(
+                    a.sin6_addr.s6_addr.into(),
+                    u16::from_be(a.sin6_port),
+                    a.sin6_flowinfo,
+                    a.sin6_scope_id,
+                )
+                .into()
+            }
+            _ => RecvMeta::default().addr,
+        }
+    }
+
+    // Returns length of the address.
+    fn from_socket_addr(addr: SocketAddr, name: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match addr {
+            SocketAddr::V4(a) => {
+                let sin = unsafe { &mut *(name as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as _;
+                sin.sin_port = a.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>() as _
+            }
+            SocketAddr::V6(a) => {
+                let sin6 = unsafe { &mut *(name as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as _;
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_addr.s6_addr = a.ip().octets();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_scope_id = a.scope_id();
+                mem::size_of::<libc::sockaddr_in6>() as _
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn bind() -> io::Result<(BatchUdpSocket, SocketAddr)> {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_nonblocking(true)?;
+        let addr = socket.local_addr()?;
+        Ok((BatchUdpSocket::from_std(socket)?, addr))
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn gso_segments() {
+        let a: SocketAddr = (Ipv4Addr::LOCALHOST, 1).into();
+        let b: SocketAddr = (Ipv4Addr::LOCALHOST, 2).into();
+        let mut batch = SendBatch::new(64);
+        for &(len, target) in &[(32, a), (32, a), (16, a), (32, a), (32, b), (48, b), (8, b)] {
+            batch.push(len, target, None);
+        }
+        assert_eq!(linux::gso_segments(&batch, 0, true), 3);
+        assert_eq!(linux::gso_segments(&batch, 0, false), 1);
+        assert_eq!(linux::gso_segments(&batch, 3, true), 1);
+        assert_eq!(linux::gso_segments(&batch, 4, true), 1);
+        assert_eq!(linux::gso_segments(&batch, 5, true), 2);
+        assert_eq!(linux::gso_segments(&batch, 6, true), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pmtu_probe() -> anyhow::Result<()> {
+        let (a, _) = bind()?;
+        let modes = || linux::get_pmtu_modes(a.io.get_ref()).0;
+        let default = modes();
+        assert!(default.is_some());
+        assert_ne!(default, Some(linux::PMTUDISC_PROBE));
+
+        a.set_pmtu_probe(true)?;
+        assert_eq!(modes(), Some(linux::PMTUDISC_PROBE));
+        a.set_pmtu_probe(false)?;
+        assert_eq!(modes(), default);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn source_address() -> anyhow::Result<()> {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_nonblocking(true)?;
+        let port = socket.local_addr()?.port();
+        let a = BatchUdpSocket::from_std(socket)?;
+        let (b, b_addr) = bind()?;
+
+        let source: IpAddr = Ipv4Addr::new(127, 0, 0, 2).into();
+        a.send_to(&[1, 2, 3], b_addr, Some(source)).await?;
+        let mut bufs = vec![vec![0u8; 64]];
+        let mut meta = [RecvMeta::default()];
+        b.recv_batch(&mut bufs, &mut meta).await?;
+        assert_eq!(meta[0].addr, (source, port).into());
+        assert_eq!(meta[0].dst, Some(Ipv4Addr::LOCALHOST.into()));
+
+        // Not a local address, sent without it.
+        let source: IpAddr = Ipv4Addr::new(192, 0, 2, 1).into();
+        a.send_to(&[1, 2, 3], b_addr, Some(source)).await?;
+        b.recv_batch(&mut bufs, &mut meta).await?;
+        assert_eq!(meta[0].addr, (Ipv4Addr::LOCALHOST, port).into());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_send_and_recv() -> anyhow::Result<()> {
+        let (a, a_addr) = bind()?;
+        let (b, b_addr) = bind()?;
+
+        let mut batch = SendBatch::new(64);
+        for i in 0..BATCH_SIZE {
+            batch.next_buf()[..4].copy_from_slice(&(i as u32).to_be_bytes());
+            batch.push(4, b_addr, None);
+        }
+        assert!(batch.is_full());
+        a.send_batch(&batch).await?;
+
+        // Packets may be coalesced with GRO.
+        let mut bufs = vec![vec![0u8; 65536]; BATCH_SIZE];
+        let mut meta = [RecvMeta::default(); BATCH_SIZE];
+        let mut received = 0;
+        while received < BATCH_SIZE {
+            let n = b.recv_batch(&mut bufs, &mut meta).await?;
+            for (buf, m) in bufs.iter().zip(&meta[..n]) {
+                assert_eq!(m.addr, a_addr);
+                for p in m.segments(buf) {
+                    assert_eq!(p, (received as u32).to_be_bytes());
+                    received += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}